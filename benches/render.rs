@@ -0,0 +1,117 @@
+//! Report-style render benchmark: a table of `{{#each}}` rows from `vars`, rendered the
+//! way `/send` does (chunked output, then the plain-text part) and the way it did before
+//! (Handlebars' growing string, then chained replaces for the text).
+//!
+//! `cargo bench --bench render` prints, per row count, the time per render, the peak heap
+//! growth during it and the capacity of the HTML string left to build the message from.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::HashMap,
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+use templar::limits::RenderLimits;
+
+/// Counts live heap bytes and their high-water mark.
+struct Counting;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+fn grow(by: usize) {
+    let now = LIVE.fetch_add(by, Ordering::Relaxed) + by;
+    PEAK.fetch_max(now, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        grow(layout.size());
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // Old and new blocks may both be live while the contents are copied
+        grow(new_size);
+        let out = unsafe { System.realloc(ptr, layout, new_size) };
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        out
+    }
+}
+
+#[global_allocator]
+static ALLOC: Counting = Counting;
+
+const TEMPLATE: &str = r#"<html><body><h1>Weekly report for {{account}}</h1>
+<table>
+<tr><th>Date</th><th>Order</th><th>Customer</th><th>Items</th><th>Total</th></tr>
+{{#each rows}}<tr><td>{{date}}</td><td>#{{id}}</td><td>{{customer}} &lt;{{email}}&gt;</td><td>{{items}}</td><td>{{total}}&nbsp;EUR</td></tr>
+{{/each}}</table>
+<p>Questions? Reply to this email &amp; we will get back to you.</p></body></html>"#;
+
+fn vars(rows: usize) -> HashMap<String, Value> {
+    let rows: Vec<Value> = (0..rows)
+        .map(|i| json!({ "date": "2026-10-14", "id": 100_000 + i, "customer": format!("Customer {i}"), "email": format!("c{i}@example.com"), "items": i % 7 + 1, "total": format!("{}.{:02}", i % 500, i % 100) }))
+        .collect();
+    HashMap::from([("account".to_string(), json!("Acme")), ("rows".to_string(), Value::Array(rows))])
+}
+
+/// What rendering and the text part did before chunked output.
+fn before(reg: &handlebars::Handlebars<'_>, vars: &HashMap<String, Value>) -> (String, String) {
+    let html = reg.render_template(TEMPLATE, vars).unwrap();
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    let text = text.replace("&nbsp;", " ").replace("&amp;", "&").replace("&lt;", "<").replace("&gt;", ">");
+    (html, text)
+}
+
+fn now(reg: &handlebars::Handlebars<'_>, vars: &HashMap<String, Value>) -> (String, String) {
+    let limits = RenderLimits { timeout: Duration::ZERO, max_bytes: 0, ..RenderLimits::default() };
+    let html = limits.render_now(reg, TEMPLATE, vars).unwrap();
+    let text = templar::postprocess::plain_text(&html);
+    (html, text)
+}
+
+/// Mean time per run, peak heap growth of one run, and the HTML's capacity.
+fn measure(f: impl Fn() -> (String, String)) -> (Duration, usize, usize) {
+    let base = LIVE.load(Ordering::Relaxed);
+    PEAK.store(base, Ordering::Relaxed);
+    let (html, text) = f();
+    let peak = PEAK.load(Ordering::Relaxed) - base;
+    let capacity = html.capacity();
+    drop((html, text));
+    let runs = 20;
+    let start = Instant::now();
+    for _ in 0..runs {
+        black_box(f());
+    }
+    (start.elapsed() / runs, peak, capacity)
+}
+
+fn main() {
+    let reg = handlebars::Handlebars::new();
+    println!("{:>7} {:>9} {:>12} {:>12} {:>12}", "rows", "path", "time", "peak heap", "html kept");
+    for rows in [100, 1_000, 10_000, 50_000] {
+        let vars = vars(rows);
+        for (name, outcome) in [("before", measure(|| before(&reg, &vars))), ("chunked", measure(|| now(&reg, &vars)))] {
+            let (time, peak, kept) = outcome;
+            println!("{rows:>7} {name:>9} {:>10.2}ms {:>10}KiB {:>10}KiB", time.as_secs_f64() * 1000.0, peak / 1024, kept / 1024);
+        }
+    }
+}
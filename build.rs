@@ -0,0 +1,39 @@
+//! Embeds build info for `GET /version`: the git commit (or `TEMPLAR_GIT_COMMIT` for
+//! builds outside a checkout) and the build time (or `SOURCE_DATE_EPOCH`, for
+//! reproducible builds).
+
+use std::{process::Command, time::{SystemTime, UNIX_EPOCH}};
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=TEMPLAR_GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+
+    let commit = std::env::var("TEMPLAR_GIT_COMMIT").ok().filter(|c| !c.is_empty()).or_else(|| {
+        let out = Command::new("git").args(["rev-parse", "--short=12", "HEAD"]).output().ok()?;
+        out.status.success().then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+    });
+    println!("cargo:rustc-env=TEMPLAR_GIT_COMMIT={}", commit.as_deref().unwrap_or("unknown"));
+
+    let secs = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+    println!("cargo:rustc-env=TEMPLAR_BUILT_AT={}", rfc3339(secs));
+}
+
+/// UTC `YYYY-MM-DDTHH:MM:SSZ` for unix seconds (days-to-civil conversion).
+fn rfc3339(secs: u64) -> String {
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z", rem / 3600, rem / 60 % 60, rem % 60)
+}
@@ -0,0 +1,8 @@
+-- Instance-wide settings changed at runtime and shared by replicas, such as the active
+-- templates root (see src/bluegreen.rs). Times are Unix seconds.
+
+CREATE TABLE settings (
+    key        TEXT PRIMARY KEY,
+    value      TEXT NOT NULL,
+    updated_at BIGINT NOT NULL
+);
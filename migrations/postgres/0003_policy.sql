@@ -0,0 +1,4 @@
+-- Content policy findings recorded with each message (see src/policy.rs): a JSON array of
+-- {"rule":..,"detail":..}, NULL when the message had none.
+
+ALTER TABLE messages ADD COLUMN policy TEXT;
@@ -0,0 +1,13 @@
+-- Feedback loop complaints (see src/feedback.rs), against the message they are about when
+-- the report pointed to one. `recipient` is NULL when the provider redacted it.
+
+CREATE TABLE complaints (
+    message_id    TEXT,
+    recipient     TEXT,
+    feedback_type TEXT NOT NULL,
+    user_agent    TEXT,
+    arrival_date  TEXT,
+    created_at    BIGINT NOT NULL
+);
+CREATE INDEX complaints_message_id ON complaints (message_id);
+CREATE INDEX complaints_recipient ON complaints (recipient);
@@ -0,0 +1,11 @@
+-- API keys made with POST /admin/api-keys (see src/apikeys.rs). Only the SHA-256 of the
+-- key is kept; revoked keys stay listed with `revoked_at` set.
+
+CREATE TABLE api_keys (
+    kid        TEXT PRIMARY KEY,
+    key_hash   TEXT NOT NULL UNIQUE,
+    scope      TEXT NOT NULL,
+    expires_at BIGINT,
+    created_at BIGINT NOT NULL,
+    revoked_at BIGINT
+);
@@ -0,0 +1,5 @@
+-- The API key (kid) that made each scheduled send, so the scheduler can share its batches
+-- fairly between callers (see src/scheduled.rs). NULL for sends made without a key.
+
+ALTER TABLE scheduled_sends ADD COLUMN caller TEXT;
+CREATE INDEX scheduled_sends_caller ON scheduled_sends (caller, send_at);
@@ -0,0 +1,12 @@
+-- Campaigns cancelled with POST /admin/campaigns/{id}/cancel, so the kill switch holds
+-- across restarts and replicas (see src/campaigns.rs), and the campaign of each scheduled
+-- send, so a cancellation can drop the ones still waiting.
+
+CREATE TABLE cancelled_campaigns (
+    campaign_id  TEXT PRIMARY KEY,
+    cancelled_at BIGINT NOT NULL
+);
+
+ALTER TABLE scheduled_sends ADD COLUMN campaign_id TEXT;
+UPDATE scheduled_sends SET campaign_id = request::jsonb ->> 'campaign_id';
+CREATE INDEX scheduled_sends_campaign_id ON scheduled_sends (campaign_id);
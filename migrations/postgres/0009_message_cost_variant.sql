@@ -0,0 +1,5 @@
+-- What each message cost by MESSAGE_COST when it was sent, and the A/B template variant it
+-- got (see src/variants.rs), NULL for the template itself. Both NULL on older rows.
+
+ALTER TABLE messages ADD COLUMN cost DOUBLE PRECISION;
+ALTER TABLE messages ADD COLUMN variant TEXT;
@@ -0,0 +1,35 @@
+-- Messages sent by /send, suppressed recipients, scheduled sends and idempotency keys.
+-- Times are Unix seconds; recipients are stored as ",a@x.com,b@y.com," (see src/storage.rs).
+
+CREATE TABLE messages (
+    id          TEXT PRIMARY KEY,
+    template    TEXT NOT NULL,
+    recipients  TEXT NOT NULL,
+    campaign_id TEXT,
+    created_at  INTEGER NOT NULL
+);
+CREATE INDEX messages_created_at ON messages (created_at);
+
+CREATE TABLE suppressions (
+    address    TEXT PRIMARY KEY,
+    reason     TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE scheduled_sends (
+    id         TEXT PRIMARY KEY,
+    send_at    INTEGER NOT NULL,
+    request    TEXT NOT NULL,
+    recipients TEXT NOT NULL,
+    claimed_at INTEGER,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX scheduled_sends_send_at ON scheduled_sends (send_at);
+
+CREATE TABLE idempotency_keys (
+    key        TEXT PRIMARY KEY,
+    status     INTEGER,
+    body       TEXT,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX idempotency_keys_created_at ON idempotency_keys (created_at);
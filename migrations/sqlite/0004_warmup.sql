@@ -0,0 +1,10 @@
+-- Recipients counted per sending domain and UTC day while it warms up (see src/warmup.rs):
+-- those sent, and those deferred to a later day. `day` is the Unix time of the day's start.
+
+CREATE TABLE warmup (
+    domain   TEXT NOT NULL,
+    day      INTEGER NOT NULL,
+    sent     INTEGER NOT NULL DEFAULT 0,
+    deferred INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (domain, day)
+);
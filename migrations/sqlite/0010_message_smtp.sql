@@ -0,0 +1,4 @@
+-- The relays' final SMTP replies to each message, a JSON array of {code, message, queue_id}
+-- (see src/transport.rs); NULL for the file transport and on older rows.
+
+ALTER TABLE messages ADD COLUMN smtp TEXT;
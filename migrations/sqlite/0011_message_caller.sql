@@ -0,0 +1,4 @@
+-- The API key kid each message was sent with, for GET /admin/stats; NULL without a key
+-- and on older rows.
+
+ALTER TABLE messages ADD COLUMN caller TEXT;
@@ -0,0 +1,300 @@
+//! Operational alerts: with `ALERT_WEBHOOK_URL` set, Templar posts to a Slack or Teams
+//! incoming webhook (`ALERT_WEBHOOK_KIND`) when
+//!
+//! - the share of sends failing over the last `ALERT_WINDOW_SECS` reaches
+//!   `ALERT_ERROR_RATE`, once at least `ALERT_MIN_SENDS` were made. Only failures on
+//!   Templar's side count: the relay refusing or deferring, the preference service; a
+//!   bad template or address is the caller's. A second post says when it recovers.
+//! - a relay is taken out of use: a pool relay marked unhealthy, or the primary of a
+//!   failover pair found unreachable.
+//! - a send is given up for good: a scheduled send dropped on an error retrying will not
+//!   fix, or an outbox row marked failed. Sends of a cancelled campaign do not count.
+//!
+//! Conditions are checked every 30 seconds. While the error rate stands it is posted
+//! again every `ALERT_COOLDOWN_SECS`; a relay going down is posted at most that often
+//! per relay. Sends given up are posted together, at most every `ALERT_COOLDOWN_SECS`,
+//! with how many there were since the last post.
+//! A post that fails is logged and tried at the next check.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::config::{AlertWebhookKind, ApiConfig};
+use crate::email::EmailError;
+use crate::storage;
+
+/// Bounds of `ALERT_WINDOW_SECS`.
+pub const MIN_WINDOW_SECS: u64 = 60;
+pub const MAX_WINDOW_SECS: u64 = 3600;
+/// Sends are counted in buckets this many seconds wide.
+const BUCKET_SECS: i64 = 10;
+/// How often conditions are checked.
+const CHECK_EVERY: Duration = Duration::from_secs(30);
+/// Relays gone down kept for the next check at most.
+const MAX_PENDING: usize = 100;
+/// Sends given up listed in one post at most; the rest are only counted.
+const MAX_LISTED: usize = 5;
+
+/// Whether anything watches the counts; off, recording is skipped.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static MONITOR: Lazy<Mutex<Monitor>> = Lazy::new(|| Mutex::new(Monitor::default()));
+
+#[derive(Default)]
+struct Monitor {
+    /// `(bucket start, sent, failed)`, oldest first
+    buckets: VecDeque<(i64, u32, u32)>,
+    last_error: Option<String>,
+    /// `(relay, why)` gone down since the last check
+    opened: Vec<(String, String)>,
+    /// Sends given up since the last post about them
+    abandoned: u32,
+    /// `(source, id, why)` of the first of those
+    abandoned_listed: Vec<(String, String, String)>,
+}
+
+/// # record_send()
+/// Count one `/send` result towards the error rate.
+pub fn record_send(result: Result<(), &EmailError>) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let failed = result.err().filter(|e| matches!(e, EmailError::SmtpError(_) | EmailError::Preferences(_)));
+    let bucket = storage::now() / BUCKET_SECS * BUCKET_SECS;
+    let mut monitor = MONITOR.lock().unwrap();
+    match monitor.buckets.back_mut() {
+        Some((at, _, _)) if *at == bucket => {}
+        _ => monitor.buckets.push_back((bucket, 0, 0)),
+    }
+    while monitor.buckets.front().is_some_and(|(at, _, _)| bucket - at >= MAX_WINDOW_SECS as i64) {
+        monitor.buckets.pop_front();
+    }
+    let last = monitor.buckets.back_mut().expect("bucket just pushed");
+    last.1 += 1;
+    if let Some(e) = failed {
+        last.2 += 1;
+        monitor.last_error = Some(e.to_string());
+    }
+}
+
+/// # breaker_opened()
+/// A relay was taken out of use, for `reason`.
+pub fn breaker_opened(relay: &str, reason: &str) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut monitor = MONITOR.lock().unwrap();
+    if monitor.opened.len() < MAX_PENDING {
+        monitor.opened.push((relay.to_string(), reason.to_string()));
+    }
+}
+
+/// # send_abandoned()
+/// Send `id` from `source` (`scheduled`, `outbox`) was given up for good, for `reason`.
+pub fn send_abandoned(source: &str, id: &str, reason: &str) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut monitor = MONITOR.lock().unwrap();
+    monitor.abandoned += 1;
+    if monitor.abandoned_listed.len() < MAX_LISTED {
+        monitor.abandoned_listed.push((source.to_string(), id.to_string(), reason.to_string()));
+    }
+}
+
+/// # spike()
+/// Whether `failed` of `sent` reaches `rate`, judged only from `min_sends` sends.
+/// # Example
+/// ```
+/// use templar::alerts::spike;
+/// assert!(spike(40, 10, 20, 0.25));
+/// assert!(!spike(40, 9, 20, 0.25));
+/// assert!(!spike(10, 10, 20, 0.25)); // too few sends to judge
+/// ```
+pub fn spike(sent: u32, failed: u32, min_sends: u32, rate: f64) -> bool {
+    sent > 0 && sent >= min_sends && f64::from(failed) / f64::from(sent) >= rate
+}
+
+/// # message()
+/// The webhook body for an alert, in the format `kind` expects.
+/// # Example
+/// ```
+/// use templar::alerts::message;
+/// use templar::config::AlertWebhookKind;
+/// let slack = message(AlertWebhookKind::Slack, "Relay down", "relay-a: 3 connection failures");
+/// assert_eq!(slack["text"], "*Templar: Relay down*\nrelay-a: 3 connection failures");
+/// let teams = message(AlertWebhookKind::Teams, "Relay down", "relay-a: 3 connection failures");
+/// let card = &teams["attachments"][0]["content"];
+/// assert_eq!(card["type"], "AdaptiveCard");
+/// assert_eq!(card["body"][0]["text"], "Templar: Relay down");
+/// assert_eq!(card["body"][1]["text"], "relay-a: 3 connection failures");
+/// ```
+pub fn message(kind: AlertWebhookKind, title: &str, detail: &str) -> Value {
+    match kind {
+        AlertWebhookKind::Slack => json!({ "text": format!("*Templar: {title}*\n{detail}") }),
+        AlertWebhookKind::Teams => json!({
+            "type": "message",
+            "attachments": [{
+                "contentType": "application/vnd.microsoft.card.adaptive",
+                "contentUrl": null,
+                "content": {
+                    "$schema": "http://adaptivecards.io/schemas/adaptive-card.json",
+                    "type": "AdaptiveCard",
+                    "version": "1.4",
+                    "body": [
+                        { "type": "TextBlock", "text": format!("Templar: {title}"), "weight": "Bolder", "size": "Medium", "wrap": true },
+                        { "type": "TextBlock", "text": detail, "wrap": true },
+                    ],
+                },
+            }],
+        }),
+    }
+}
+
+/// The configured alert webhook and thresholds (`ALERT_*`).
+pub struct Alerts {
+    url: String,
+    kind: AlertWebhookKind,
+    http: reqwest::Client,
+    error_rate: f64,
+    min_sends: u32,
+    window_secs: u64,
+    cooldown: Duration,
+    /// Condition (`error_rate`, `relay:<name>`, `abandoned`) → when last posted
+    posted: HashMap<String, Instant>,
+}
+
+impl Alerts {
+    /// The webhook from `ALERT_*`; `None` when `ALERT_WEBHOOK_URL` is empty.
+    pub fn from_config(config: &ApiConfig) -> Result<Option<Self>, anyhow::Error> {
+        let url = config.alert_webhook_url.trim();
+        if url.is_empty() {
+            return Ok(None);
+        }
+        let http = crate::transport::proxy::http_client(Duration::from_secs(10))?.build()?;
+        Ok(Some(Self {
+            url: url.to_string(),
+            kind: config.alert_webhook_kind,
+            http,
+            error_rate: config.alert_error_rate,
+            min_sends: config.alert_min_sends,
+            window_secs: config.alert_window_secs,
+            cooldown: Duration::from_secs(config.alert_cooldown_secs),
+            posted: HashMap::new(),
+        }))
+    }
+
+    /// Post what stands, went down or recovered since the last check.
+    async fn check(&mut self) {
+        let since = storage::now() - self.window_secs as i64;
+        let (sent, failed, last_error, opened) = {
+            let mut monitor = MONITOR.lock().unwrap();
+            let (sent, failed) = monitor.buckets.iter().filter(|(at, _, _)| at + BUCKET_SECS > since).fold((0, 0), |(s, f), (_, bs, bf)| (s + bs, f + bf));
+            (sent, failed, monitor.last_error.clone(), std::mem::take(&mut monitor.opened))
+        };
+        for (relay, reason) in opened {
+            self.raise(&format!("relay:{relay}"), "SMTP relay out of use", &format!("{relay}: {reason}")).await;
+        }
+        if !self.cooling("abandoned") {
+            let (count, listed) = {
+                let mut monitor = MONITOR.lock().unwrap();
+                (std::mem::take(&mut monitor.abandoned), std::mem::take(&mut monitor.abandoned_listed))
+            };
+            if count > 0 {
+                let mut detail = format!("{count} send(s) given up since the last alert:");
+                for (source, id, reason) in &listed {
+                    detail.push_str(&format!("\n- {source} {id}: {reason}"));
+                }
+                if count as usize > listed.len() {
+                    detail.push_str(&format!("\n- and {} more", count as usize - listed.len()));
+                }
+                if !self.raise("abandoned", "Sends given up", &detail).await {
+                    // Kept for the next check, with any given up meanwhile
+                    let mut monitor = MONITOR.lock().unwrap();
+                    monitor.abandoned += count;
+                    let room = MAX_LISTED.saturating_sub(listed.len());
+                    let newer: Vec<_> = monitor.abandoned_listed.drain(..).take(room).collect();
+                    monitor.abandoned_listed = listed.into_iter().chain(newer).collect();
+                }
+            }
+        }
+
+        let window = self.window_secs;
+        if spike(sent, failed, self.min_sends, self.error_rate) {
+            let detail = format!(
+                "{failed} of {sent} sends failed in the last {window}s ({:.0}%, alerting at {:.0}%). Last error: {}",
+                f64::from(failed) * 100.0 / f64::from(sent),
+                self.error_rate * 100.0,
+                last_error.as_deref().unwrap_or("none recorded"),
+            );
+            self.raise("error_rate", "Send error rate high", &detail).await;
+        } else {
+            self.clear("error_rate", "Send error rate back to normal", &format!("{failed} of {sent} sends failed in the last {window}s")).await;
+        }
+    }
+
+    /// Whether `condition` was posted within `ALERT_COOLDOWN_SECS`.
+    fn cooling(&self, condition: &str) -> bool {
+        self.posted.get(condition).is_some_and(|at| at.elapsed() < self.cooldown)
+    }
+
+    /// Post `condition` unless it was within `ALERT_COOLDOWN_SECS`; whether it was posted.
+    async fn raise(&mut self, condition: &str, title: &str, detail: &str) -> bool {
+        if self.cooling(condition) {
+            return false;
+        }
+        warn!(condition, "Alert: {title}: {detail}");
+        let posted = self.post(title, detail).await;
+        if posted {
+            self.posted.insert(condition.to_string(), Instant::now());
+        }
+        posted
+    }
+
+    /// Post that `condition` no longer stands, when it was posted.
+    async fn clear(&mut self, condition: &str, title: &str, detail: &str) {
+        if !self.posted.contains_key(condition) {
+            return;
+        }
+        info!(condition, "Alert cleared: {title}");
+        if self.post(title, detail).await {
+            self.posted.remove(condition);
+        }
+    }
+
+    async fn post(&self, title: &str, detail: &str) -> bool {
+        let body = message(self.kind, title, detail);
+        match self.http.post(&self.url).json(&body).send().await {
+            Ok(response) if response.status().is_success() => true,
+            Ok(response) => {
+                warn!(status = %response.status(), "Alert webhook refused the alert");
+                false
+            }
+            Err(e) => {
+                warn!("Alert webhook not reached: {}", e.without_url());
+                false
+            }
+        }
+    }
+}
+
+/// Spawn the loop checking alert conditions every 30 seconds.
+pub fn spawn_watcher(mut alerts: Alerts) {
+    ENABLED.store(true, Ordering::Relaxed);
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(CHECK_EVERY);
+        loop {
+            tick.tick().await;
+            alerts.check().await;
+        }
+    });
+}
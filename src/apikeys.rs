@@ -0,0 +1,100 @@
+//! API keys managed at runtime with `POST/GET/DELETE /admin/api-keys`, next to those in
+//! `API_KEY`. Each has a `kid`, a scope (`send` or `admin`, see `scopes`) and an optional
+//! expiry; storage keeps only the SHA-256 of the key, which is shown once, when made.
+//!
+//! The keys are read from storage into memory at startup and every [`REFRESH_SECS`], and
+//! right after a change made through this instance, so a key revoked on one replica is
+//! refused by the others within that time. They are only checked while `API_KEY` is
+//! set: with auth off there is nothing to check, and the first stored key has to be made
+//! with one from `API_KEY`.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+
+use crate::scopes::Scope;
+use crate::storage::{ApiKeyRecord, Storage, Store};
+
+/// Seconds between reads of the stored keys.
+pub const REFRESH_SECS: u64 = 30;
+
+/// The stored keys not revoked, as last read.
+static KEYS: Lazy<ArcSwap<Vec<ApiKeyRecord>>> = Lazy::new(|| ArcSwap::from_pointee(Vec::new()));
+
+/// # generate()
+/// A new random key: `tk_` and 48 hex digits.
+pub fn generate() -> String {
+    format!("tk_{}", crate::crypto::to_hex(&rand::random::<[u8; 24]>()))
+}
+
+/// SHA-256 of `key`, hex, as stored.
+pub fn hash(key: &str) -> String {
+    crate::crypto::to_hex(&Sha256::digest(key.as_bytes()))
+}
+
+/// # valid_kid()
+/// 1 to 64 characters out of letters, digits, `.`, `_` and `-`.
+pub fn valid_kid(kid: &str) -> bool {
+    (1..=64).contains(&kid.len()) && kid.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'))
+}
+
+/// Replace the keys in memory with `records`, leaving revoked ones out.
+pub fn install(records: Vec<ApiKeyRecord>) {
+    KEYS.store(Arc::new(records.into_iter().filter(|r| r.revoked_at.is_none()).collect()));
+}
+
+/// # authenticate()
+/// The `kid` and scope of the stored key `presented` (bare, or `kid:key`) at `now`;
+/// `None` when it is unknown, revoked or expired.
+/// # Example
+/// ```
+/// use templar::{apikeys, scopes::Scope, storage::ApiKeyRecord};
+/// let key = apikeys::generate();
+/// let record = |kid: &str, expires_at| ApiKeyRecord {
+///     kid: kid.into(), hash: apikeys::hash(&key), scope: "send".into(), expires_at, created_at: 0, revoked_at: None,
+/// };
+/// apikeys::install(vec![record("billing", Some(2_000))]);
+/// assert_eq!(apikeys::authenticate(&key, 1_000), Some(("billing".into(), Scope::Send)));
+/// assert_eq!(apikeys::authenticate(&format!("billing:{key}"), 1_000), Some(("billing".into(), Scope::Send)));
+/// assert_eq!(apikeys::authenticate(&format!("other:{key}"), 1_000), None);
+/// assert_eq!(apikeys::authenticate(&key, 2_000), None); // expired
+/// assert_eq!(apikeys::authenticate("tk_guess", 1_000), None);
+/// ```
+pub fn authenticate(presented: &str, now: i64) -> Option<(String, Scope)> {
+    let keys = KEYS.load();
+    let (kid, key) = match presented.split_once(':') {
+        Some((kid, key)) => (Some(kid), key),
+        None => (None, presented),
+    };
+    let hashed = hash(key);
+    let record = keys.iter().find(|r| r.hash == hashed && kid.is_none_or(|kid| kid == r.kid))?;
+    if record.expires_at.is_some_and(|until| now >= until) {
+        return None;
+    }
+    // Rows are written by `POST /admin/api-keys`, which only takes known scopes
+    Some((record.kid.clone(), Scope::parse(&record.scope).unwrap_or(Scope::Send)))
+}
+
+/// Read the stored keys into memory; how many are usable.
+pub async fn refresh(store: &dyn Storage) -> Result<usize, crate::storage::StorageError> {
+    let records = store.api_keys().await?;
+    install(records);
+    Ok(KEYS.load().len())
+}
+
+/// Keep the keys in memory up to date with storage, every [`REFRESH_SECS`].
+pub fn spawn_refresh(store: Store) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(REFRESH_SECS));
+        tick.tick().await;
+        loop {
+            tick.tick().await;
+            if let Err(e) = refresh(&*store).await {
+                tracing::warn!("API keys not refreshed, keeping the ones read before: {e}");
+            }
+        }
+    });
+}
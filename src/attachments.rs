@@ -0,0 +1,362 @@
+//! Attachments fetched by URL: `"attachments": [{"url": "https://..", "filename": "invoice.pdf"}]`
+//! in a `/send` body has Templar download each file while building the message, so
+//! callers do not have to base64 large PDFs into the JSON.
+//!
+//! Only hosts in `ATTACHMENT_ALLOWED_DOMAINS` are fetched, redirects included, and only
+//! over http(s). A download larger than `ATTACHMENT_MAX_BYTES`, slower than
+//! `ATTACHMENT_TIMEOUT_SECS` or answered with an error fails the send before anything
+//! goes out. With `ATTACHMENT_CACHE_SECS` a downloaded file is reused for that long, so
+//! one statement mailed to a batch of recipients is fetched once; the cache is kept in
+//! memory, up to `ATTACHMENT_CACHE_MAX_BYTES`.
+//!
+//! An entry with `template` instead of `url` is rendered to PDF (see `pdf`, `pdf` feature),
+//! one with `data` is generated from `vars` (see `exports`). Filenames may use `vars`
+//! too: `statement-{{month}}.pdf`.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use lettre::message::header::ContentType;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::config::ApiConfig;
+
+/// Redirects followed per download, each to an allowed host.
+const MAX_REDIRECTS: usize = 5;
+
+/// One entry of a request's `attachments`: a `url` to download, a `template` to render
+/// to PDF or `data` to export, exactly one of them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    /// Where the file is downloaded from; the host must be in `ATTACHMENT_ALLOWED_DOMAINS`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Template rendered with the send's `vars` and converted to PDF (`pdf` feature)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// Path in `vars` (`orders`, `report.rows`) of rows written to CSV or XLSX
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    /// Fields of `data` rows to export, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub columns: Vec<crate::exports::Column>,
+    /// `csv` or `xlsx` for `data`, defaulting to the filename's extension, then `csv`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<crate::exports::TableFormat>,
+    /// File name shown to the recipient; `{{..}}` is filled from `vars`
+    pub filename: String,
+    /// MIME type, defaulting to the download's `Content-Type` (`application/pdf` for a template)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+impl Attachment {
+    /// # Attachment::content_type_or()
+    /// The MIME type to send: `content_type` when given, else `fallback` (a served type),
+    /// else `application/octet-stream`.
+    /// # Errors
+    /// `content_type` is not a valid MIME type.
+    pub fn content_type_or(&self, fallback: Option<&str>) -> Result<ContentType, AttachmentError> {
+        match &self.content_type {
+            Some(t) => ContentType::parse(t).map_err(|e| AttachmentError::Refused(format!("{}: content_type `{t}`: {e}", self.filename))),
+            None => Ok(fallback.and_then(|t| ContentType::parse(t).ok()).unwrap_or_else(|| ContentType::parse("application/octet-stream").expect("valid"))),
+        }
+    }
+}
+
+/// A downloaded attachment, ready to go into a message.
+#[derive(Debug, Clone)]
+pub struct File {
+    pub filename: String,
+    pub content_type: ContentType,
+    pub body: Arc<Vec<u8>>,
+}
+
+/// Why an attachment is missing from a send.
+#[derive(Debug, thiserror::Error)]
+pub enum AttachmentError {
+    /// The request is wrong: a host not allowed, a bad URL or file name, a file too large
+    /// or not found. Sending it again will not help.
+    #[error("attachment refused: {0}")]
+    Refused(String),
+    /// The file server could not be reached, timed out or failed (`5xx`, `429`).
+    #[error("attachment not fetched: {0}")]
+    Unavailable(String),
+    /// The virus scanner found `threat` in the file (see `scan`).
+    #[error("attachment {filename} refused: {threat} found")]
+    Infected { filename: String, threat: String },
+}
+
+/// `ATTACHMENT_ALLOWED_DOMAINS`: hosts attachments may be downloaded from, comma-separated.
+/// `files.example.com` allows that host, `*.example.com` any host below `example.com`.
+/// # Example
+/// ```
+/// use templar::attachments::DomainAllowlist;
+/// let allowed: DomainAllowlist = "files.example.com, *.cdn.example.net".parse().unwrap();
+/// assert!(allowed.allows_host("Files.Example.com"));
+/// assert!(allowed.allows_host("eu.cdn.example.net"));
+/// assert!(!allowed.allows_host("cdn.example.net"));
+/// assert!(!allowed.allows_host("files.example.com.evil.io"));
+/// assert!("*".parse::<DomainAllowlist>().is_err());
+/// assert!(DomainAllowlist::default().is_empty());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DomainAllowlist(Vec<String>);
+
+impl DomainAllowlist {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn allows_host(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_lowercase();
+        self.0.iter().any(|d| match d.strip_prefix("*.") {
+            Some(parent) => host.strip_suffix(parent).is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            None => host == *d,
+        })
+    }
+
+    fn allows(&self, url: &url::Url) -> bool {
+        matches!(url.scheme(), "http" | "https") && url.host_str().is_some_and(|h| self.allows_host(h))
+    }
+}
+
+impl FromStr for DomainAllowlist {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut domains = Vec::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let domain = entry.to_lowercase();
+            let name = domain.strip_prefix("*.").unwrap_or(&domain);
+            let valid = name.split('.').all(|label| !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+            if !valid {
+                return Err(format!("`{entry}` is not a host name or `*.domain`"));
+            }
+            domains.push(domain);
+        }
+        Ok(Self(domains))
+    }
+}
+
+impl fmt::Display for DomainAllowlist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0.join(","))
+    }
+}
+
+/// Downloads attachments within the configured limits, caching them when enabled.
+pub struct Fetcher {
+    http: reqwest::Client,
+    allowed: DomainAllowlist,
+    max_bytes: usize,
+    timeout: Duration,
+    cache_for: Duration,
+    cache_max_bytes: usize,
+    cache: Mutex<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<String, Cached>,
+    bytes: usize,
+}
+
+struct Cached {
+    fetched_at: Instant,
+    content_type: Option<String>,
+    body: Arc<Vec<u8>>,
+}
+
+impl Cache {
+    fn get(&self, url: &str, ttl: Duration) -> Option<(Option<String>, Arc<Vec<u8>>)> {
+        self.entries.get(url).filter(|c| c.fetched_at.elapsed() < ttl).map(|c| (c.content_type.clone(), c.body.clone()))
+    }
+
+    /// Keep `body` for `url`, dropping expired entries, then the oldest, to stay within `max_bytes`.
+    fn put(&mut self, url: &str, content_type: Option<String>, body: Arc<Vec<u8>>, ttl: Duration, max_bytes: usize) {
+        if body.len() > max_bytes {
+            return;
+        }
+        self.entries.retain(|u, c| u != url && c.fetched_at.elapsed() < ttl);
+        self.bytes = self.entries.values().map(|c| c.body.len()).sum();
+        while self.bytes + body.len() > max_bytes {
+            let Some(oldest) = self.entries.iter().min_by_key(|(_, c)| c.fetched_at).map(|(u, _)| u.clone()) else { break };
+            if let Some(c) = self.entries.remove(&oldest) {
+                self.bytes -= c.body.len();
+            }
+        }
+        self.bytes += body.len();
+        self.entries.insert(url.to_string(), Cached { fetched_at: Instant::now(), content_type, body });
+    }
+}
+
+impl Fetcher {
+    /// The fetcher for `ATTACHMENT_*`; with no allowed domains every URL is refused.
+    pub fn from_config(config: &ApiConfig) -> Result<Self, anyhow::Error> {
+        let allowed = config.attachment_allowed_domains.clone();
+        let redirects = allowed.clone();
+        let timeout = Duration::from_secs(config.attachment_timeout_secs);
+        let http = crate::transport::proxy::http_client(timeout)?
+            .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error(format!("more than {MAX_REDIRECTS} redirects"))
+                } else if redirects.allows(attempt.url()) {
+                    attempt.follow()
+                } else {
+                    let to = attempt.url().host_str().unwrap_or_default().to_string();
+                    attempt.error(format!("redirected to {to}, which is not in ATTACHMENT_ALLOWED_DOMAINS"))
+                }
+            }))
+            .build()?;
+        Ok(Self {
+            http,
+            allowed,
+            max_bytes: config.attachment_max_bytes,
+            timeout,
+            cache_for: Duration::from_secs(config.attachment_cache_secs),
+            cache_max_bytes: config.attachment_cache_max_bytes,
+            cache: Mutex::new(Cache::default()),
+        })
+    }
+
+    /// # Fetcher::fetch()
+    /// Download the file at `a.url`, or take it from the cache.
+    /// # Errors
+    /// [`AttachmentError::Refused`] for a URL or file name that is not allowed, a file that
+    /// is too large or not found; [`AttachmentError::Unavailable`] when the server fails.
+    pub async fn fetch(&self, a: &Attachment) -> Result<File, AttachmentError> {
+        check_filename(&a.filename).map_err(AttachmentError::Refused)?;
+        let raw = a.url.as_deref().unwrap_or_default();
+        let url = url::Url::parse(raw).map_err(|e| AttachmentError::Refused(format!("{raw}: {e}")))?;
+        if self.allowed.is_empty() {
+            return Err(AttachmentError::Refused("URL attachments are off; set ATTACHMENT_ALLOWED_DOMAINS".into()));
+        }
+        if !self.allowed.allows(&url) {
+            return Err(AttachmentError::Refused(format!("{raw}: not an http(s) URL on a host in ATTACHMENT_ALLOWED_DOMAINS")));
+        }
+        let cached = if self.cache_for.is_zero() { None } else { self.cache.lock().unwrap().get(url.as_str(), self.cache_for) };
+        let (served_type, body) = match cached {
+            Some(hit) => {
+                debug!(url = %url, "Attachment served from cache");
+                hit
+            }
+            None => {
+                let (served_type, body) = self.download(&url).await?;
+                let body = Arc::new(body);
+                if !self.cache_for.is_zero() {
+                    self.cache.lock().unwrap().put(url.as_str(), served_type.clone(), body.clone(), self.cache_for, self.cache_max_bytes);
+                }
+                (served_type, body)
+            }
+        };
+        Ok(File { filename: a.filename.clone(), content_type: a.content_type_or(served_type.as_deref())?, body })
+    }
+
+    /// GET `url`, reading at most `max_bytes`; returns the served `Content-Type` and the body.
+    async fn download(&self, url: &url::Url) -> Result<(Option<String>, Vec<u8>), AttachmentError> {
+        let started = Instant::now();
+        let mut response = self.http.get(url.clone()).send().await.map_err(|e| self.failure(url, &e))?;
+        let status = response.status();
+        if !status.is_success() {
+            let msg = format!("{url} answered {status}");
+            return Err(if status.is_server_error() || status.as_u16() == 429 { AttachmentError::Unavailable(msg) } else { AttachmentError::Refused(msg) });
+        }
+        let too_large = || AttachmentError::Refused(format!("{url} is larger than ATTACHMENT_MAX_BYTES ({} bytes)", self.max_bytes));
+        if self.max_bytes > 0 && response.content_length().is_some_and(|n| n > self.max_bytes as u64) {
+            return Err(too_large());
+        }
+        let served_type = response.headers().get(reqwest::header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(str::to_string);
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(|e| self.failure(url, &e))? {
+            if self.max_bytes > 0 && body.len() + chunk.len() > self.max_bytes {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        debug!(url = %url, bytes = body.len(), elapsed_ms = started.elapsed().as_millis() as u64, "Attachment downloaded");
+        Ok((served_type, body))
+    }
+
+    /// A download that produced no (complete) answer; refused redirects are the request's fault.
+    fn failure(&self, url: &url::Url, e: &reqwest::Error) -> AttachmentError {
+        if e.is_redirect() {
+            return AttachmentError::Refused(format!("{url}: {}", describe(e)));
+        }
+        if e.is_timeout() {
+            return AttachmentError::Unavailable(format!("{url}: no complete answer within {}s", self.timeout.as_secs()));
+        }
+        AttachmentError::Unavailable(format!("{url}: {}", describe(e)))
+    }
+}
+
+/// reqwest's display hides the cause (`error sending request`); include it.
+fn describe(e: &reqwest::Error) -> String {
+    let mut msg = e.to_string();
+    let mut source = std::error::Error::source(e);
+    while let Some(s) = source {
+        msg = format!("{msg}: {s}");
+        source = s.source();
+    }
+    msg
+}
+
+/// No HTML escaping: a file name is not markup.
+static FILENAMES: once_cell::sync::Lazy<handlebars::Handlebars<'static>> = once_cell::sync::Lazy::new(|| {
+    let mut reg = handlebars::Handlebars::new();
+    reg.set_strict_mode(true);
+    reg.register_escape_fn(handlebars::no_escape);
+    reg
+});
+
+/// # filename()
+/// Fill `{{..}}` in an attachment's `filename` from `vars`, then check the result.
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// let vars = HashMap::from([("month".to_string(), serde_json::json!("2026-10"))]);
+/// assert_eq!(templar::attachments::filename("statement-{{month}}.pdf", &vars).unwrap(), "statement-2026-10.pdf");
+/// assert!(templar::attachments::filename("{{missing}}.pdf", &vars).is_err());
+/// ```
+/// # Errors
+/// [`AttachmentError::Refused`] for a template that does not render (strict mode) or a
+/// result that is not a plain file name.
+pub fn filename(template: &str, vars: &HashMap<String, serde_json::Value>) -> Result<String, AttachmentError> {
+    let name = if template.contains("{{") {
+        FILENAMES.render_template(template, vars).map_err(|e| AttachmentError::Refused(format!("filename `{template}`: {e}")))?
+    } else {
+        template.to_string()
+    };
+    check_filename(&name).map_err(AttachmentError::Refused)?;
+    Ok(name)
+}
+
+/// # check_filename()
+/// An attachment name is a plain file name: no path separators or control characters,
+/// at most 255 bytes.
+/// # Example
+/// ```
+/// use templar::attachments::check_filename;
+/// assert!(check_filename("Rechnung März.pdf").is_ok());
+/// assert!(check_filename("../invoice.pdf").is_err());
+/// assert!(check_filename("").is_err());
+/// ```
+/// # Errors
+/// Describes what is wrong with `name`.
+pub fn check_filename(name: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("attachment filename is empty".into());
+    }
+    if name.len() > 255 {
+        return Err(format!("attachment filename is {} bytes, at most 255 are allowed", name.len()));
+    }
+    if name.contains(['/', '\\']) || name.chars().any(char::is_control) || name == ".." {
+        return Err(format!("attachment filename `{}` must be a plain file name", name.escape_debug()));
+    }
+    Ok(())
+}
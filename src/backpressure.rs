@@ -0,0 +1,92 @@
+//! Back-pressure for `/send`: cap the number of sends in flight and turn excess
+//! requests away with `503` and a `Retry-After` instead of piling them onto the relay.
+//!
+//! `Retry-After` comes from the observed drain rate: with `max` slots and an average
+//! send time of `avg`, a slot frees up roughly every `avg / max`. `/ready` reports
+//! `503` while every slot is taken, or during maintenance, so load balancers stop
+//! routing here.
+
+use std::{sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, Instant}};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use tokio::sync::Semaphore;
+
+use crate::maintenance::Maintenance;
+
+/// In-flight limit shared by every `/send` request.
+pub struct InFlight {
+    permits: Semaphore,
+    max: u64,
+    /// Moving average of send duration in milliseconds (0 until the first send).
+    avg_ms: AtomicU64,
+}
+
+impl InFlight {
+    /// Allow up to `max` concurrent sends; `0` means unlimited.
+    pub fn new(max: usize) -> Self {
+        let max = if max == 0 { Semaphore::MAX_PERMITS } else { max };
+        Self { permits: Semaphore::new(max), max: max as u64, avg_ms: AtomicU64::new(0) }
+    }
+
+    /// Every slot is taken.
+    pub fn saturated(&self) -> bool {
+        self.permits.available_permits() == 0
+    }
+
+    /// Sends running right now.
+    pub fn in_flight(&self) -> u64 {
+        self.max - self.permits.available_permits() as u64
+    }
+
+    /// Seconds until a slot is likely to free up, at least 1.
+    pub fn retry_after(&self) -> u64 {
+        self.avg_ms.load(Ordering::Relaxed).div_ceil(self.max.saturating_mul(1000)).max(1)
+    }
+
+    /// Fold one send duration into the average (weight 1/8 per sample).
+    fn record(&self, took: Duration) {
+        let ms = took.as_millis() as u64;
+        let _ = self.avg_ms.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |avg| {
+            Some(if avg == 0 { ms } else { (avg * 7 + ms) / 8 })
+        });
+    }
+}
+
+fn overloaded(retry_after: u64) -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, retry_after.to_string())],
+        Json(serde_json::json!({ "error": "overloaded, retry later" })),
+    )
+        .into_response()
+}
+
+/// Middleware: take a slot for the duration of the request or answer `503`.
+pub async fn limit(State(inflight): State<Arc<InFlight>>, req: Request, next: Next) -> Response {
+    let Ok(_permit) = inflight.permits.try_acquire() else {
+        return overloaded(inflight.retry_after());
+    };
+    let started = Instant::now();
+    let response = next.run(req).await;
+    inflight.record(started.elapsed());
+    response
+}
+
+/// GET `/ready`
+/// - `{"status":"ready"}`, `503` with `Retry-After` while saturated, or `503` with
+///   `"maintenance":true` during maintenance
+pub async fn ready(State(inflight): State<Arc<InFlight>>, Extension(maintenance): Extension<Arc<Maintenance>>) -> Response {
+    if maintenance.enabled() {
+        return maintenance.response();
+    }
+    if inflight.saturated() {
+        return overloaded(inflight.retry_after());
+    }
+    Json(serde_json::json!({ "status": "ready" })).into_response()
+}
@@ -0,0 +1,174 @@
+//! Blue/green template roots: `TEMPLATES_DIR_ACTIVE` serves while the next template set
+//! is copied into `TEMPLATES_DIR_STAGED`, then `POST /admin/templates/promote` swaps them.
+//!
+//! Promoting builds the state for the staged root first, so a set whose `base` partial
+//! or helpers do not load, or that has no templates, is refused and the active one keeps
+//! serving. The state is then replaced at once, as on SIGHUP: each send renders from one
+//! set, never a mix of files from both. The previously active root becomes the staged
+//! one, ready for the next deploy, and promoting again rolls back.
+//!
+//! The active root is recorded in storage (`templates.active`), so a restart or reload
+//! keeps serving it, and other instances sharing `STORAGE_URL` switch within [`FOLLOW_EVERY`].
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::config::ApiConfig;
+use crate::email::SharedState;
+use crate::storage::{Storage, StorageError, Store};
+
+/// Storage setting holding the active root.
+const SETTING: &str = "templates.active";
+
+/// How often an instance checks whether another one promoted.
+pub const FOLLOW_EVERY: Duration = Duration::from_secs(5);
+
+/// Why a promotion did not happen.
+#[derive(Debug, thiserror::Error)]
+pub enum PromoteError {
+    /// The staged set does not load; nothing changed.
+    #[error("staged templates not promoted: {0}")]
+    Invalid(String),
+    /// The choice could not be recorded; nothing changed.
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+/// Result of a promotion, as answered by `POST /admin/templates/promote`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Promotion {
+    pub active: PathBuf,
+    pub staged: PathBuf,
+    /// Templates in the newly active root.
+    pub templates: usize,
+}
+
+/// The two template roots and which one serves.
+pub struct TemplateRoots {
+    roots: [PathBuf; 2],
+    /// Index into `roots` of the active one.
+    active: AtomicUsize,
+    /// One promotion (or follow) at a time.
+    switching: tokio::sync::Mutex<()>,
+}
+
+impl TemplateRoots {
+    /// # TemplateRoots::from_config()
+    /// The roots when `TEMPLATES_DIR_STAGED` is set, starting with `TEMPLATES_DIR_ACTIVE` active.
+    /// # Example
+    /// ```
+    /// let mut config = templar::config::get_defaults();
+    /// assert!(templar::bluegreen::TemplateRoots::from_config(&config).is_none());
+    /// config.templates_dir = "/srv/templates/blue".into();
+    /// config.templates_dir_staged = "/srv/templates/green".into();
+    /// let roots = templar::bluegreen::TemplateRoots::from_config(&config).unwrap();
+    /// assert_eq!(roots.active(), std::path::Path::new("/srv/templates/blue"));
+    /// assert_eq!(roots.staged(), std::path::Path::new("/srv/templates/green"));
+    /// ```
+    pub fn from_config(config: &ApiConfig) -> Option<Arc<Self>> {
+        let staged = config.templates_dir_staged.trim();
+        (!staged.is_empty()).then(|| {
+            Arc::new(Self {
+                roots: [PathBuf::from(&config.templates_dir), PathBuf::from(staged)],
+                active: AtomicUsize::new(0),
+                switching: tokio::sync::Mutex::new(()),
+            })
+        })
+    }
+
+    pub fn active(&self) -> &Path {
+        &self.roots[self.active.load(Ordering::SeqCst)]
+    }
+
+    pub fn staged(&self) -> &Path {
+        &self.roots[1 - self.active.load(Ordering::SeqCst)]
+    }
+
+    /// Point `config.templates_dir` at the active root, for a state rebuilt from config (SIGHUP).
+    pub fn apply(&self, config: &mut ApiConfig) {
+        config.templates_dir = self.active().to_string_lossy().into_owned();
+    }
+
+    /// # TemplateRoots::promote()
+    /// Make the staged root active in `state` and storage.
+    /// # Errors
+    /// [`PromoteError::Invalid`] when the staged set does not load, [`PromoteError::Storage`]
+    /// when the choice cannot be recorded. Either way the active root keeps serving.
+    pub async fn promote(&self, state: &SharedState, store: &dyn Storage) -> Result<Promotion, PromoteError> {
+        let _switching = self.switching.lock().await;
+        let next = 1 - self.active.load(Ordering::SeqCst);
+        let dir = self.roots[next].clone();
+        let templates = count_templates(&dir).map_err(PromoteError::Invalid)?;
+        let fresh = state.load().with_templates(dir.clone()).map_err(|e| PromoteError::Invalid(format!("{}: {e}", dir.display())))?;
+        store.set_setting(SETTING, &dir.to_string_lossy()).await?;
+        self.active.store(next, Ordering::SeqCst);
+        state.store(Arc::new(fresh));
+        info!(active = %dir.display(), templates, "Templates promoted");
+        Ok(Promotion { active: dir, staged: self.roots[1 - next].clone(), templates })
+    }
+
+    /// # TemplateRoots::follow()
+    /// Serve the root recorded in storage, if it is not the active one already
+    /// (startup, or another instance promoted). Returns whether it switched.
+    /// # Errors
+    /// Storage cannot be read; a recorded root that fails to load is logged and skipped.
+    pub async fn follow(&self, state: &SharedState, store: &dyn Storage) -> Result<bool, StorageError> {
+        let _switching = self.switching.lock().await;
+        let Some(recorded) = store.setting(SETTING).await? else { return Ok(false) };
+        let Some(index) = self.roots.iter().position(|r| r.to_string_lossy() == recorded) else {
+            warn!(recorded, "Recorded active templates root is neither TEMPLATES_DIR_ACTIVE nor TEMPLATES_DIR_STAGED, ignored");
+            return Ok(false);
+        };
+        if index == self.active.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        match state.load().with_templates(self.roots[index].clone()) {
+            Ok(fresh) => {
+                self.active.store(index, Ordering::SeqCst);
+                state.store(Arc::new(fresh));
+                info!(active = recorded, "Templates switched to the promoted root");
+                Ok(true)
+            }
+            Err(e) => {
+                warn!(recorded, "Promoted templates root does not load here, keeping the current one: {e}");
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Follow promotions made by other instances.
+pub fn spawn_follow(roots: Arc<TemplateRoots>, state: SharedState, store: Store) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(FOLLOW_EVERY);
+        loop {
+            tick.tick().await;
+            if let Err(e) = roots.follow(&state, &*store).await {
+                warn!("Active templates root not checked: {e}");
+            }
+        }
+    });
+}
+
+/// Sendable templates in `dir` (not `base`); a root without any is refused.
+fn count_templates(dir: &Path) -> Result<usize, String> {
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("{}: {e}", dir.display()))?;
+    let count = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str()?.strip_suffix(".hbs").map(str::to_string))
+        .filter(|name| name != "base")
+        .count();
+    if count == 0 {
+        return Err(format!("{} has no templates", dir.display()));
+    }
+    Ok(count)
+}
@@ -0,0 +1,101 @@
+//! ZIP bundling of attachments: the files of one send become a single `.zip`, with an
+//! AES-256 password when asked, so many files (or a CSV export that compresses well) stay
+//! under the relay's message size limit.
+//!
+//! A request opts in with `"zip": {"filename": "documents.zip", "password": ".."}`; any
+//! send whose attachments total more than `ATTACHMENT_ZIP_OVER_BYTES` is bundled too, as
+//! `ATTACHMENT_ZIP_FILENAME`.
+
+use std::{collections::HashSet, io::Write, sync::Arc};
+
+use lettre::message::header::ContentType;
+use serde::{Deserialize, Serialize};
+use zip::{write::SimpleFileOptions, AesMode, CompressionMethod, ZipWriter};
+
+use crate::attachments::{AttachmentError, File};
+use crate::config::ApiConfig;
+
+/// A request's `zip`: bundle its attachments whatever their size.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ZipRequest {
+    /// Name of the bundle, `{{..}}` filled from `vars`; defaults to `ATTACHMENT_ZIP_FILENAME`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    /// Encrypts every file with AES-256
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+/// When sends are bundled without asking (`ATTACHMENT_ZIP_*`).
+#[derive(Debug, Clone)]
+pub struct ZipPolicy {
+    /// Total attachment bytes above which a send is bundled (`0` = only on request).
+    pub over_bytes: usize,
+    pub filename: String,
+}
+
+impl ZipPolicy {
+    pub fn from_config(config: &ApiConfig) -> Self {
+        Self { over_bytes: config.attachment_zip_over_bytes, filename: config.attachment_zip_filename.clone() }
+    }
+
+    /// # ZipPolicy::applies()
+    /// Whether `files` go out as one ZIP: the request asked, or they are over `over_bytes`.
+    pub fn applies(&self, files: &[File], requested: bool) -> bool {
+        let total: usize = files.iter().map(|f| f.body.len()).sum();
+        !files.is_empty() && (requested || (self.over_bytes > 0 && total > self.over_bytes))
+    }
+}
+
+/// # bundle()
+/// `files` as one ZIP named `filename`, each entry deflated and, with a `password`,
+/// AES-256 encrypted. Entries that share a name get ` (2)`, ` (3)`.. before the extension.
+/// # Example
+/// ```
+/// use std::sync::Arc;
+/// use lettre::message::header::ContentType;
+/// use templar::attachments::File;
+/// let text = ContentType::parse("text/plain").unwrap();
+/// let files = vec![
+///     File { filename: "a.txt".into(), content_type: text.clone(), body: Arc::new(b"one".to_vec()) },
+///     File { filename: "a.txt".into(), content_type: text, body: Arc::new(b"two".to_vec()) },
+/// ];
+/// let zip = templar::bundle::bundle(&files, "docs.zip", Some("s3cret")).unwrap();
+/// assert_eq!(zip.filename, "docs.zip");
+/// assert!(zip.body.starts_with(b"PK"));
+/// ```
+/// # Errors
+/// [`AttachmentError::Refused`] for an empty password or when the archive cannot be written.
+pub fn bundle(files: &[File], filename: &str, password: Option<&str>) -> Result<File, AttachmentError> {
+    if password.is_some_and(str::is_empty) {
+        return Err(AttachmentError::Refused("zip password is empty; leave it out for an unencrypted bundle".into()));
+    }
+    let failed = |e: zip::result::ZipError| AttachmentError::Refused(format!("{filename} not written: {e}"));
+    let mut options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated).large_file(true);
+    if let Some(p) = password {
+        options = options.with_aes_encryption(AesMode::Aes256, p);
+    }
+    let mut zip = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let mut names = HashSet::new();
+    for f in files {
+        zip.start_file(unique(&mut names, &f.filename), options).map_err(failed)?;
+        zip.write_all(&f.body).map_err(|e| failed(e.into()))?;
+    }
+    let body = zip.finish().map_err(failed)?.into_inner();
+    Ok(File { filename: filename.to_string(), content_type: ContentType::parse("application/zip").expect("valid MIME type"), body: Arc::new(body) })
+}
+
+/// `name`, or `name (n).ext` once `name` is taken.
+fn unique(taken: &mut HashSet<String>, name: &str) -> String {
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{ext}")),
+        _ => (name, String::new()),
+    };
+    let mut candidate = name.to_string();
+    let mut n = 1;
+    while !taken.insert(candidate.clone()) {
+        n += 1;
+        candidate = format!("{stem} ({n}){ext}");
+    }
+    candidate
+}
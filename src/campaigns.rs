@@ -0,0 +1,194 @@
+//! Per-campaign send counters behind `GET /campaigns/{id}/stats`.
+//!
+//! A `/send` with a `campaign_id` is counted here and its messages carry an
+//! `X-Campaign-Id` header. Counters live in memory: they start at zero on every
+//! process start and are per instance when several run behind a load balancer.
+//!
+//! `cost` is an estimate: each message at its relay's rate from `RELAY_COSTS`
+//! (`relay-a=0.0004,mx=0`), or `MESSAGE_COST` for a relay not listed and the single
+//! `SMTP_HOST` relay. Message records keep their cost, so `GET /admin/stats` sums it from
+//! storage for every instance and across restarts.
+//!
+//! `POST /admin/campaigns/{id}/cancel` is the kill switch: later sends for the campaign,
+//! direct, scheduled or from the outbox, are refused (`409` on `/send`) and counted as
+//! `refused`, and its scheduled sends still waiting are removed. Unlike the counters, a
+//! cancellation is kept in storage: it holds after a restart, and other instances read
+//! it within [`REFRESH_SECS`].
+
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use serde::Serialize;
+
+use crate::config::ApiConfig;
+use crate::email::{SendOutcome, SentMessage};
+use crate::storage::{Storage, StorageError, Store};
+
+/// Longest accepted campaign id.
+pub const MAX_ID_LEN: usize = 64;
+/// Seconds between reads of the cancellations in storage.
+pub const REFRESH_SECS: u64 = 30;
+
+/// Counters for one campaign.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CampaignStats {
+    /// `/send` requests that succeeded.
+    pub sends: u64,
+    /// Messages handed to the transport (a send split by rules or variants counts each).
+    pub messages: u64,
+    /// Estimated spend for those messages, in the unit `MESSAGE_COST` is given in.
+    pub cost: f64,
+    /// Recipients dropped by `skip` rules.
+    pub skipped: u64,
+    /// `/send` requests that failed (render, transport or config errors).
+    pub failures: u64,
+    /// Unix seconds of the latest send attempt.
+    pub last_send_at: u64,
+    /// Unix seconds the campaign was cancelled, `None` while it is live.
+    pub cancelled_at: Option<u64>,
+    /// Sends refused because the campaign was cancelled.
+    pub refused: u64,
+}
+
+/// Counters for every campaign seen since start.
+#[derive(Debug, Default)]
+pub struct Campaigns {
+    stats: Mutex<HashMap<String, CampaignStats>>,
+    message_cost: f64,
+    relay_costs: HashMap<String, f64>,
+}
+
+impl Campaigns {
+    pub fn from_config(config: &ApiConfig) -> Self {
+        // Validated by `ApiConfig::load`
+        Self::new(config.message_cost, parse_costs(&config.relay_costs).unwrap_or_default())
+    }
+
+    /// Counters that charge a message its relay's rate in `relay_costs`, `message_cost`
+    /// when its relay is not listed.
+    pub fn new(message_cost: f64, relay_costs: HashMap<String, f64>) -> Self {
+        Self { stats: Mutex::default(), message_cost, relay_costs }
+    }
+
+    /// # Campaigns::message_cost()
+    /// What `message` is charged: the rate of the relay that first replied to it.
+    /// # Example
+    /// ```
+    /// use templar::campaigns::{parse_costs, Campaigns};
+    /// use templar::email::SentMessage;
+    /// use templar::transport::SmtpReply;
+    /// let campaigns = Campaigns::new(0.001, parse_costs("relay-a=0.0004").unwrap());
+    /// let reply: SmtpReply = serde_json::from_str(r#"{"code":250,"message":"ok","relay":"relay-a"}"#).unwrap();
+    /// assert_eq!(campaigns.message_cost(&SentMessage { replies: vec![reply], ..SentMessage::default() }), 0.0004);
+    /// assert_eq!(campaigns.message_cost(&SentMessage::default()), 0.001);
+    /// ```
+    pub fn message_cost(&self, message: &SentMessage) -> f64 {
+        let relay = message.replies.first().and_then(|r| r.relay.as_deref());
+        relay.and_then(|r| self.relay_costs.get(r)).copied().unwrap_or(self.message_cost)
+    }
+
+    /// Count one send attempt for `id`; `None` when it failed.
+    pub fn record(&self, id: &str, outcome: Option<&SendOutcome>) {
+        let mut stats = self.stats.lock().unwrap();
+        let entry = stats.entry(id.to_string()).or_default();
+        match outcome {
+            Some(o) => {
+                entry.sends += 1;
+                entry.messages += o.ids.len() as u64;
+                entry.cost += o.messages.iter().map(|m| self.message_cost(m)).sum::<f64>();
+                entry.skipped += o.skipped.len() as u64;
+            }
+            None => entry.failures += 1,
+        }
+        entry.last_send_at = now();
+    }
+
+    /// Refuse every later send for `id`, cancelled at `at` (see [`Storage::cancel_campaign`]).
+    pub fn cancel(&self, id: &str, at: i64) -> CampaignStats {
+        let mut stats = self.stats.lock().unwrap();
+        let entry = stats.entry(id.to_string()).or_default();
+        entry.cancelled_at = Some(at.max(0) as u64);
+        entry.clone()
+    }
+
+    /// Whether `id` is cancelled; counts the refused send when it is.
+    pub fn refuse(&self, id: &str) -> bool {
+        let mut stats = self.stats.lock().unwrap();
+        match stats.get_mut(id).filter(|s| s.cancelled_at.is_some()) {
+            Some(entry) => {
+                entry.refused += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Counters for `id`, `None` if nothing was sent for it since start.
+    pub fn get(&self, id: &str) -> Option<CampaignStats> {
+        self.stats.lock().unwrap().get(id).cloned()
+    }
+}
+
+/// # refresh()
+/// Refuse the campaigns cancelled in storage, by this instance or another; returns how many.
+/// # Errors
+/// Storage cannot be read; the cancellations known before still apply.
+pub async fn refresh(campaigns: &Campaigns, store: &dyn Storage) -> Result<usize, StorageError> {
+    let cancelled = store.cancelled_campaigns().await?;
+    for (id, at) in &cancelled {
+        campaigns.cancel(id, *at);
+    }
+    Ok(cancelled.len())
+}
+
+/// Follow cancellations made on other instances, every [`REFRESH_SECS`].
+pub fn spawn_refresh(campaigns: Arc<Campaigns>, store: Store) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(REFRESH_SECS));
+        tick.tick().await;
+        loop {
+            tick.tick().await;
+            if let Err(e) = refresh(&campaigns, &*store).await {
+                tracing::warn!("Campaign cancellations not refreshed: {e}");
+            }
+        }
+    });
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Campaign ids end up in a mail header and a URL path, so keep them short and plain.
+/// # Example
+/// ```
+/// use templar::campaigns::valid_id;
+/// assert!(valid_id("spring-sale_2026.1"));
+/// assert!(!valid_id("bad\r\nBcc: x@y.z"));
+/// assert!(!valid_id(""));
+/// ```
+pub fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LEN && id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+/// # parse_costs()
+/// `RELAY_COSTS`: `relay=cost` pairs, comma-separated, costs non-negative numbers.
+/// # Example
+/// ```
+/// use templar::campaigns::parse_costs;
+/// assert_eq!(parse_costs("relay-a=0.0004, mx=0").unwrap()["relay-a"], 0.0004);
+/// assert!(parse_costs("relay-a=-1").is_err());
+/// assert!(parse_costs("").unwrap().is_empty());
+/// ```
+pub fn parse_costs(spec: &str) -> Result<HashMap<String, f64>, String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|pair| {
+            let (relay, cost) = pair.split_once('=').ok_or_else(|| format!("`{pair}` is not relay=cost"))?;
+            match cost.trim().parse::<f64>() {
+                Ok(c) if c.is_finite() && c >= 0.0 => Ok((relay.trim().to_string(), c)),
+                _ => Err(format!("cost of `{}` must be a non-negative number", relay.trim())),
+            }
+        })
+        .collect()
+}
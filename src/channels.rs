@@ -0,0 +1,322 @@
+//! Fallback channels: when the relay refuses a send outright (a `5xx`, see
+//! `TransportError::Permanent`), a template whose category is listed in
+//! `FALLBACK_CHANNELS` is delivered another way, and `/send` answers
+//! `"status":"fallback"`. The category is the template's `category` meta key, or its
+//! name (see `meta`).
+//!
+//! - `webhook`: the message as JSON, POSTed to `FALLBACK_WEBHOOK_URL` and signed with
+//!   `WEBHOOK_SECRET` like events (see `webhooks`), for a receiver that has other ways
+//!   to reach people
+//! - `sms`: a text through Twilio to the request's `phone`: the subject, then the text
+//!   part, cut to [`MAX_SMS_CHARS`]
+//!
+//! The text is the template rendered with the request's vars, as for the email, without
+//! its markup (see [`message_text()`]). Only hard
+//! failures fall back: a relay that is down or defers may take the mail later, and the
+//! send is retried as before.
+
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use serde::Serialize;
+
+use crate::config::ApiConfig;
+use crate::crypto::Keyring;
+use crate::email::EmailState;
+use crate::routes::SendRequest;
+
+/// Longest SMS body Twilio takes.
+pub const MAX_SMS_CHARS: usize = 1600;
+
+/// How long Twilio may take to answer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Elements whose content is not text a reader sees.
+const HIDDEN: [&str; 3] = ["head", "style", "script"];
+
+/// A delivery by a channel: done, or why not.
+pub type ChannelFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+
+/// The message handed to a fallback channel.
+#[derive(Debug, Clone, Serialize)]
+pub struct Fallback {
+    pub template: String,
+    pub category: String,
+    /// The email recipients
+    pub recipients: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phone: Option<String>,
+    pub subject: String,
+    /// The message as text, without markup
+    pub text: String,
+    /// Why the email was not delivered
+    pub error: String,
+}
+
+/// `fallback` of a `/send` answer: the channel that delivered instead of email, and why.
+#[derive(Debug, Clone, Serialize, serde::Deserialize)]
+pub struct FallbackDelivery {
+    pub channel: String,
+    pub error: String,
+}
+
+/// A way to deliver a message other than email.
+pub trait Channel: Send + Sync {
+    /// As named in `FALLBACK_CHANNELS`
+    fn name(&self) -> &'static str;
+    fn deliver<'a>(&'a self, message: &'a Fallback) -> ChannelFuture<'a>;
+}
+
+/// The channels `FALLBACK_CHANNELS` names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChannelKind {
+    Webhook,
+    Sms,
+}
+
+/// # parse_routes()
+/// `FALLBACK_CHANNELS`: `category=channel` pairs, comma-separated, with channel `webhook`
+/// or `sms`.
+/// # Example
+/// ```
+/// use templar::channels::{parse_routes, ChannelKind};
+/// let routes = parse_routes("billing=sms, alerts=webhook").unwrap();
+/// assert_eq!(routes["billing"], ChannelKind::Sms);
+/// assert_eq!(routes["alerts"], ChannelKind::Webhook);
+/// assert!(parse_routes("billing=fax").is_err());
+/// assert!(parse_routes("billing").is_err());
+/// ```
+pub fn parse_routes(spec: &str) -> Result<HashMap<String, ChannelKind>, String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|pair| {
+            let (category, channel) = pair.split_once('=').map(|(c, ch)| (c.trim(), ch.trim())).ok_or_else(|| format!("`{pair}` is not category=channel"))?;
+            let kind = match channel {
+                "webhook" => ChannelKind::Webhook,
+                "sms" => ChannelKind::Sms,
+                other => return Err(format!("unknown channel `{other}` for `{category}`, expected webhook or sms")),
+            };
+            Ok((category.to_string(), kind))
+        })
+        .collect()
+}
+
+/// # valid_phone()
+/// An E.164 number: `+` and 8 to 15 digits.
+/// # Example
+/// ```
+/// use templar::channels::valid_phone;
+/// assert!(valid_phone("+5511987654321"));
+/// assert!(!valid_phone("11 98765-4321"));
+/// assert!(!valid_phone("+1234"));
+/// ```
+pub fn valid_phone(phone: &str) -> bool {
+    phone.strip_prefix('+').is_some_and(|digits| (8..=15).contains(&digits.len()) && digits.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// The configured fallback channel of each category.
+pub struct Channels {
+    routes: HashMap<String, Arc<dyn Channel>>,
+}
+
+impl Channels {
+    /// The channels of `FALLBACK_CHANNELS`; `None` when it is empty.
+    /// # Errors
+    /// A channel it names is missing its settings (`FALLBACK_WEBHOOK_URL` and
+    /// `WEBHOOK_SECRET`, or `TWILIO_*`).
+    pub fn from_config(config: &ApiConfig) -> Result<Option<Self>, anyhow::Error> {
+        let routes = parse_routes(&config.fallback_channels).map_err(|e| anyhow::anyhow!("invalid FALLBACK_CHANNELS: {e}"))?;
+        if routes.is_empty() {
+            return Ok(None);
+        }
+        // One of each channel, shared by the categories using it
+        let mut built: HashMap<ChannelKind, Arc<dyn Channel>> = HashMap::new();
+        let mut channels = HashMap::new();
+        for (category, kind) in routes {
+            let channel = match built.get(&kind) {
+                Some(channel) => channel.clone(),
+                None => {
+                    let channel: Arc<dyn Channel> = match kind {
+                        ChannelKind::Webhook => Arc::new(WebhookChannel::from_config(config)?),
+                        ChannelKind::Sms => Arc::new(SmsChannel::from_config(config)?),
+                    };
+                    built.entry(kind).or_insert(channel).clone()
+                }
+            };
+            channels.insert(category, channel);
+        }
+        Ok(Some(Self { routes: channels }))
+    }
+
+    pub fn for_category(&self, category: &str) -> Option<&dyn Channel> {
+        self.routes.get(category).map(|c| c.as_ref())
+    }
+}
+
+/// # message_text()
+/// What a reader sees of rendered `html`, for channels without HTML: `<head>`, styles and
+/// scripts dropped, then the tags, one trimmed line per line of text.
+/// # Example
+/// ```
+/// use templar::channels::message_text;
+/// let html = "<html><head><style>.card{color:#222}</style></head>\n<body>\n  <h1>Hi, Ada!</h1>\n\n  <p>Your invoice is ready.</p>\n</body></html>";
+/// assert_eq!(message_text(html), "Hi, Ada!\nYour invoice is ready.");
+/// ```
+pub fn message_text(html: &str) -> String {
+    let mut visible = html.to_string();
+    for tag in HIDDEN {
+        visible = drop_element(&visible, tag);
+    }
+    let text = crate::postprocess::plain_text(&visible);
+    text.lines().map(str::trim).filter(|l| !l.is_empty()).collect::<Vec<_>>().join("\n")
+}
+
+/// `html` without any `<tag ...>...</tag>`, matched case-insensitively.
+fn drop_element(html: &str, tag: &str) -> String {
+    // ASCII lowercasing keeps byte offsets, so positions found in `lower` cut `html`
+    let lower = html.to_ascii_lowercase();
+    let (open, close) = (format!("<{tag}"), format!("</{tag}"));
+    let mut out = String::with_capacity(html.len());
+    let mut at = 0;
+    while let Some(start) = lower[at..].find(&open).map(|i| at + i) {
+        // `<header>` is not `<head>`
+        let after = lower.as_bytes().get(start + open.len()).copied();
+        if after.is_some_and(|b| b.is_ascii_alphanumeric()) {
+            out.push_str(&html[at..start + open.len()]);
+            at = start + open.len();
+            continue;
+        }
+        out.push_str(&html[at..start]);
+        at = match lower[start..].find(&close).and_then(|i| lower[start + i..].find('>').map(|j| start + i + j + 1)) {
+            Some(end) => end,
+            // Unclosed: the rest is inside it
+            None => html.len(),
+        };
+    }
+    out.push_str(&html[at..]);
+    out
+}
+
+/// # fall_back()
+/// Deliver `request`, refused by the relay with `error`, through `channel`.
+/// # Errors
+/// The template does not render, or the channel fails.
+pub async fn fall_back(state: &EmailState, channel: &dyn Channel, request: &SendRequest, category: &str, error: &str) -> Result<(), String> {
+    let html = crate::email::render_template(&state.registry, &state.templates_dir, &request.template, &request.vars, &state.render_limits).await.map_err(|e| e.to_string())?;
+    let message = Fallback {
+        template: request.template.clone(),
+        category: category.to_string(),
+        recipients: crate::email::parse_recipients(&request.to).map(|r| r.iter().map(|mb| mb.email.to_string()).collect()).unwrap_or_default(),
+        phone: request.phone.clone(),
+        subject: request.subject.clone(),
+        text: message_text(&html),
+        error: error.to_string(),
+    };
+    channel.deliver(&message).await
+}
+
+/// `webhook`: POSTs the message, signed, to `FALLBACK_WEBHOOK_URL`.
+pub struct WebhookChannel(crate::webhooks::Webhook);
+
+impl WebhookChannel {
+    pub fn from_config(config: &ApiConfig) -> Result<Self, anyhow::Error> {
+        Self::new(&config.fallback_webhook_url, &config.webhook_secret)
+    }
+
+    /// The receiver at `url`, signed with the keys of `secret` (as `WEBHOOK_SECRET`).
+    pub fn new(url: &str, secret: &str) -> Result<Self, anyhow::Error> {
+        let url = url.trim();
+        if url.is_empty() {
+            anyhow::bail!("FALLBACK_CHANNELS names webhook, which needs FALLBACK_WEBHOOK_URL");
+        }
+        match url::Url::parse(url) {
+            Ok(u) if matches!(u.scheme(), "http" | "https") => {}
+            _ => anyhow::bail!("invalid FALLBACK_WEBHOOK_URL `{url}`, expected an http:// or https:// URL"),
+        }
+        let keyring = Keyring::parse(secret).map_err(|e| anyhow::anyhow!("FALLBACK_WEBHOOK_URL needs WEBHOOK_SECRET as kid:secret pairs: {e}"))?;
+        Ok(Self(crate::webhooks::Webhook::new(url, keyring)?))
+    }
+}
+
+impl Channel for WebhookChannel {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn deliver<'a>(&'a self, message: &'a Fallback) -> ChannelFuture<'a> {
+        Box::pin(async move {
+            let body = serde_json::json!({ "channel": "webhook", "message": message });
+            let attempt = self.0.deliver(body).await;
+            match attempt.error {
+                None => Ok(()),
+                Some(e) => Err(format!("fallback webhook at {}: {e}", attempt.url)),
+            }
+        })
+    }
+}
+
+/// `sms`: texts the request's `phone` through Twilio's Messages API.
+pub struct SmsChannel {
+    http: reqwest::Client,
+    /// `<TWILIO_API_URL>/2010-04-01/Accounts/<sid>/Messages.json`
+    endpoint: String,
+    account_sid: String,
+    auth_token: String,
+    from: String,
+}
+
+impl SmsChannel {
+    pub fn from_config(config: &ApiConfig) -> Result<Self, anyhow::Error> {
+        Self::new(&config.twilio_api_url, &config.twilio_account_sid, &config.twilio_auth_token, &config.twilio_from)
+    }
+
+    /// Texts from `from` with the Twilio account `account_sid` at `api` (`TWILIO_API_URL`).
+    pub fn new(api: &str, account_sid: &str, auth_token: &str, from: &str) -> Result<Self, anyhow::Error> {
+        let (account_sid, auth_token, from) = (account_sid.trim(), auth_token.trim(), from.trim());
+        if account_sid.is_empty() || auth_token.is_empty() || from.is_empty() {
+            anyhow::bail!("FALLBACK_CHANNELS names sms, which needs TWILIO_ACCOUNT_SID, TWILIO_AUTH_TOKEN and TWILIO_FROM");
+        }
+        let api = api.trim().trim_end_matches('/');
+        match url::Url::parse(api) {
+            Ok(u) if matches!(u.scheme(), "http" | "https") => {}
+            _ => anyhow::bail!("invalid TWILIO_API_URL `{api}`, expected an http:// or https:// URL"),
+        }
+        Ok(Self {
+            http: crate::transport::proxy::http_client(TIMEOUT)?.build()?,
+            endpoint: format!("{api}/2010-04-01/Accounts/{account_sid}/Messages.json"),
+            account_sid: account_sid.to_string(),
+            auth_token: auth_token.to_string(),
+            from: from.to_string(),
+        })
+    }
+}
+
+impl Channel for SmsChannel {
+    fn name(&self) -> &'static str {
+        "sms"
+    }
+
+    fn deliver<'a>(&'a self, message: &'a Fallback) -> ChannelFuture<'a> {
+        Box::pin(async move {
+            let phone = message.phone.as_deref().ok_or("the request has no phone to text")?;
+            let text: String = format!("{}\n\n{}", message.subject, message.text.trim()).chars().take(MAX_SMS_CHARS).collect();
+            let form = url::form_urlencoded::Serializer::new(String::new()).append_pair("To", phone).append_pair("From", &self.from).append_pair("Body", &text).finish();
+            let response = self
+                .http
+                .post(&self.endpoint)
+                .basic_auth(&self.account_sid, Some(&self.auth_token))
+                .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(form)
+                .send()
+                .await
+                .map_err(|e| format!("Twilio: {e}"))?;
+            let status = response.status();
+            if status.is_success() {
+                return Ok(());
+            }
+            // Twilio explains refusals in `message`
+            let reason = response.json::<serde_json::Value>().await.ok().and_then(|v| v["message"].as_str().map(str::to_string)).unwrap_or_default();
+            Err(format!("Twilio answered {status}: {reason}"))
+        })
+    }
+}
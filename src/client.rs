@@ -0,0 +1,106 @@
+//! Typed client for the Templar HTTP API (`client` feature).
+//!
+//! Uses the same [`SendRequest`] / [`SendResponse`] types as the server, so callers
+//! get compile errors instead of 422s when the payload shape changes.
+//!
+//! # Example
+//! ```no_run
+//! # async fn run() -> Result<(), templar::client::ClientError> {
+//! use templar::client::TemplarClient;
+//! use templar::routes::SendRequest;
+//!
+//! let client = TemplarClient::new("http://127.0.0.1:8080").with_api_key("ci:tk_2f9a..");
+//! let sent = client
+//!     .send(&SendRequest {
+//!         to: "alice@example.com".into(),
+//!         subject: "Welcome".into(),
+//!         template: "welcome".into(),
+//!         vars: [("name".to_string(), "Alice".into())].into(),
+//!         campaign_id: None,
+//!         phone: None,
+//!         preheader: None,
+//!         utm: None,
+//!         send_at: None,
+//!         attachments: Vec::new(),
+//!         zip: None,
+//!     })
+//!     .await?;
+//! println!("queued as {}", sent.id);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::time::Duration;
+
+use thiserror::Error;
+
+pub use crate::routes::{SendRequest, SendResponse};
+
+/// Errors returned by [`TemplarClient`].
+#[derive(Debug, Error)]
+pub enum ClientError {
+    /// The request never got an HTTP response (connect, TLS, timeout, bad body).
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    /// Templar answered with an error status; `message` is its `error` field.
+    #[error("templar returned {status}: {message}")]
+    Api {
+        status: u16,
+        message: String,
+        /// Seconds from `Retry-After` on `503`, if present.
+        retry_after: Option<u64>,
+    },
+}
+
+/// Client for one Templar instance.
+#[derive(Debug, Clone)]
+pub struct TemplarClient {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl TemplarClient {
+    /// Client for `base_url` (e.g. `http://templar:8080`) with a 30s request timeout.
+    pub fn new(base_url: impl Into<String>) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .expect("default reqwest client");
+        Self::with_http_client(base_url, http)
+    }
+
+    /// Use a preconfigured `reqwest::Client` (proxies, custom timeouts, mTLS).
+    pub fn with_http_client(base_url: impl Into<String>, http: reqwest::Client) -> Self {
+        let base_url = base_url.into().trim_end_matches('/').to_string();
+        Self { http, base_url, api_key: None }
+    }
+
+    /// Present `key` (an `API_KEY` key or one made with `POST /admin/api-keys`, bare or
+    /// `kid:key`) as `Authorization: Bearer` on every request.
+    pub fn with_api_key(mut self, key: impl Into<String>) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    /// POST `/send`.
+    pub async fn send(&self, req: &SendRequest) -> Result<SendResponse, ClientError> {
+        let mut request = self.http.post(format!("{}/send", self.base_url)).json(req);
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        let resp = request.send().await?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(resp.json().await?);
+        }
+        let retry_after = resp
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+        let body: serde_json::Value = resp.json().await.unwrap_or_default();
+        let message = body["error"].as_str().unwrap_or(status.canonical_reason().unwrap_or("unknown error")).to_string();
+        Err(ClientError::Api { status: status.as_u16(), message, retry_after })
+    }
+}
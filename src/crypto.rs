@@ -0,0 +1,247 @@
+//! HMAC-SHA256 signing and verification for payloads Templar emits.
+//!
+//! Public so consuming services can verify Templar's callbacks with this crate
+//! instead of re-implementing the scheme. Signatures travel as a header value of
+//! the form `kid=<key id>,sig=<lowercase hex>`; a [`Keyring`] signs with its active
+//! key and still accepts signatures from previous keys during a rotation, until the
+//! end date they were given. The same keyring checks inbound API keys.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use thiserror::Error;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Why a signature was rejected.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SignatureError {
+    #[error("malformed signature: {0}")]
+    Malformed(String),
+    #[error("unknown key id: {0}")]
+    UnknownKey(String),
+    #[error("key {0} is past its end date")]
+    Expired(String),
+    #[error("signature mismatch")]
+    Mismatch,
+}
+
+/// Compute the HMAC-SHA256 of `payload` under `secret`, hex-encoded.
+/// # Example
+/// ```
+/// let sig = templar::crypto::sign(b"secret", b"payload");
+/// assert!(templar::crypto::verify(b"secret", b"payload", &sig));
+/// ```
+pub fn sign(secret: &[u8], payload: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    to_hex(&mac.finalize().into_bytes())
+}
+
+/// Check a hex-encoded HMAC-SHA256 of `payload` in constant time.
+pub fn verify(secret: &[u8], payload: &[u8], signature: &str) -> bool {
+    let Some(expected) = from_hex(signature) else { return false };
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    mac.verify_slice(&expected).is_ok()
+}
+
+/// A set of named keys: one active key plus any number of previous keys still
+/// accepted, each until an optional date (the grace period of a rotation).
+#[derive(Clone)]
+pub struct Keyring {
+    /// The first entry is the active key.
+    keys: Vec<Key>,
+}
+
+#[derive(Clone)]
+struct Key {
+    kid: String,
+    secret: Vec<u8>,
+    /// Unix time from which the key is no longer accepted (nor signed with)
+    until: Option<i64>,
+}
+
+impl Key {
+    fn valid_at(&self, now: i64) -> bool {
+        self.until.is_none_or(|until| now < until)
+    }
+}
+
+impl Keyring {
+    /// Keyring whose active key is `secret`, identified as `kid`.
+    pub fn new(kid: impl Into<String>, secret: impl Into<Vec<u8>>) -> Self {
+        Self { keys: vec![Key { kid: kid.into(), secret: secret.into(), until: None }] }
+    }
+
+    /// Keep accepting signatures made with an older key.
+    pub fn with_previous(mut self, kid: impl Into<String>, secret: impl Into<Vec<u8>>) -> Self {
+        self.keys.push(Key { kid: kid.into(), secret: secret.into(), until: None });
+        self
+    }
+
+    /// Keep accepting an older key until Unix time `until`.
+    pub fn with_previous_until(mut self, kid: impl Into<String>, secret: impl Into<Vec<u8>>, until: i64) -> Self {
+        self.keys.push(Key { kid: kid.into(), secret: secret.into(), until: Some(until) });
+        self
+    }
+
+    /// Parse `kid:secret` pairs separated by commas; the first pair is the active key.
+    /// A previous key may end in `@YYYY-MM-DD`: it is accepted until that day starts (UTC).
+    /// # Example
+    /// ```
+    /// use templar::crypto::Keyring;
+    /// let ring = Keyring::parse("v2:new-secret,v1:old-secret@2026-12-01").unwrap();
+    /// assert_eq!(ring.active_kid(), "v2");
+    /// assert!(Keyring::parse("v2:new-secret@2026-12-01").is_err()); // the active key does not expire
+    /// ```
+    pub fn parse(spec: &str) -> Result<Self, SignatureError> {
+        let mut keys = Vec::new();
+        for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (kid, secret) = pair
+                .split_once(':')
+                .filter(|(kid, secret)| !kid.is_empty() && !secret.is_empty())
+                .ok_or_else(|| SignatureError::Malformed(format!("expected kid:secret, got `{pair}`")))?;
+            // A suffix that is not a date belongs to the secret
+            let (secret, until) = match secret.rsplit_once('@').and_then(|(s, d)| Some((s, crate::warmup::parse_date(d)?))) {
+                Some((secret, until)) if !secret.is_empty() => (secret, Some(until)),
+                _ => (secret, None),
+            };
+            if keys.is_empty() && until.is_some() {
+                return Err(SignatureError::Malformed(format!("the active key `{kid}` cannot have an end date")));
+            }
+            keys.push(Key { kid: kid.to_string(), secret: secret.as_bytes().to_vec(), until });
+        }
+        if keys.is_empty() {
+            return Err(SignatureError::Malformed("no keys given".into()));
+        }
+        Ok(Self { keys })
+    }
+
+    /// # Keyring::parse_api_keys()
+    /// `API_KEY`: one bare key (id `default`), or `kid:key` pairs like [`Keyring::parse`].
+    /// A key containing `:` must be given with its id.
+    pub fn parse_api_keys(spec: &str) -> Result<Self, SignatureError> {
+        if spec.contains(':') { Self::parse(spec) } else { Ok(Self::new("default", spec.trim())) }
+    }
+
+    /// Key id used for new signatures.
+    pub fn active_kid(&self) -> &str {
+        &self.keys[0].kid
+    }
+
+    /// Ids of every key, the active one first.
+    pub fn kids(&self) -> impl Iterator<Item = &str> {
+        self.keys.iter().map(|k| k.kid.as_str())
+    }
+
+    /// Sign `payload` with the active key, returning the header value.
+    pub fn sign(&self, payload: &[u8]) -> String {
+        let key = &self.keys[0];
+        format!("kid={},sig={}", key.kid, sign(&key.secret, payload))
+    }
+
+    /// # Keyring::sign_all()
+    /// One header value per key still accepted, the active key's first, so receivers
+    /// that only know a previous key keep verifying until its end date.
+    pub fn sign_all(&self, payload: &[u8]) -> Vec<String> {
+        let now = crate::storage::now();
+        self.keys.iter().filter(|k| k.valid_at(now)).map(|k| format!("kid={},sig={}", k.kid, sign(&k.secret, payload))).collect()
+    }
+
+    /// Verify a `kid=..,sig=..` header value against any key in the ring.
+    /// # Example
+    /// ```
+    /// use templar::crypto::{Keyring, SignatureError};
+    /// let old = Keyring::new("v1", "old-secret");
+    /// let header = old.sign(b"{\"event\":\"sent\"}");
+    /// // After rotating to v2, signatures made with v1 are still accepted.
+    /// let rotated = Keyring::new("v2", "new-secret").with_previous("v1", "old-secret");
+    /// assert!(rotated.verify(b"{\"event\":\"sent\"}", &header).is_ok());
+    /// assert!(rotated.verify(b"tampered", &header).is_err());
+    /// // ..until the end of the grace period
+    /// let ended = Keyring::new("v2", "new-secret").with_previous_until("v1", "old-secret", 1_000_000_000);
+    /// assert_eq!(ended.verify(b"{\"event\":\"sent\"}", &header), Err(SignatureError::Expired("v1".into())));
+    /// ```
+    pub fn verify(&self, payload: &[u8], header: &str) -> Result<(), SignatureError> {
+        let (mut kid, mut sig) = (None, None);
+        for part in header.split(',') {
+            match part.trim().split_once('=') {
+                Some(("kid", v)) => kid = Some(v),
+                Some(("sig", v)) => sig = Some(v),
+                _ => {}
+            }
+        }
+        let (Some(kid), Some(sig)) = (kid, sig) else {
+            return Err(SignatureError::Malformed("expected kid=..,sig=..".into()));
+        };
+        let key = self.keys.iter().find(|k| k.kid == kid).ok_or_else(|| SignatureError::UnknownKey(kid.to_string()))?;
+        if !key.valid_at(crate::storage::now()) {
+            return Err(SignatureError::Expired(kid.to_string()));
+        }
+        if verify(&key.secret, payload, sig) { Ok(()) } else { Err(SignatureError::Mismatch) }
+    }
+
+    /// # Keyring::verify_any()
+    /// Verify a request carrying several signature headers (one per key Templar signs
+    /// with during a rotation): accepted when one of them verifies. The error is that of
+    /// a header whose key is known, if any.
+    pub fn verify_any<'a>(&self, payload: &[u8], headers: impl IntoIterator<Item = &'a str>) -> Result<(), SignatureError> {
+        let mut error = None;
+        for header in headers {
+            match self.verify(payload, header) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    if matches!(e, SignatureError::Mismatch | SignatureError::Expired(_)) || error.is_none() {
+                        error = Some(e);
+                    }
+                }
+            }
+        }
+        Err(error.unwrap_or_else(|| SignatureError::Malformed("no signature".into())))
+    }
+
+    /// # Keyring::authenticate()
+    /// The id of the key `presented` is, compared in constant time; `presented` may
+    /// name it (`kid:secret`) or be the bare secret. Keys past their end date never match.
+    /// # Example
+    /// ```
+    /// use templar::crypto::Keyring;
+    /// let keys = Keyring::parse("2026-10:new-key,2026-04:old-key").unwrap();
+    /// assert_eq!(keys.authenticate("old-key"), Some("2026-04"));
+    /// assert_eq!(keys.authenticate("2026-10:new-key"), Some("2026-10"));
+    /// assert_eq!(keys.authenticate("2026-10:old-key"), None);
+    /// ```
+    pub fn authenticate(&self, presented: &str) -> Option<&str> {
+        let now = crate::storage::now();
+        let named = presented.split_once(':').filter(|(kid, _)| self.keys.iter().any(|k| k.kid == *kid));
+        let key = self.keys.iter().filter(|k| k.valid_at(now)).find(|k| match named {
+            Some((kid, secret)) => k.kid == kid && constant_eq(&k.secret, secret.as_bytes()),
+            None => constant_eq(&k.secret, presented.as_bytes()),
+        })?;
+        Some(&key.kid)
+    }
+
+    /// End date of key `kid`, when it has one.
+    pub fn until(&self, kid: &str) -> Option<i64> {
+        self.keys.iter().find(|k| k.kid == kid)?.until
+    }
+}
+
+/// Byte equality that takes the same time wherever the first difference is.
+fn constant_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok()))
+        .collect()
+}
@@ -0,0 +1,295 @@
+//! DNS preflight for sending domains behind `GET /admin/deliverability`: whether each
+//! domain publishes what receivers look up before they trust its mail.
+//!
+//! - **spf**: one `v=spf1` TXT record, ending in `-all` or `~all`, within the 10 DNS
+//!   lookups RFC 7208 allows (counted on the record itself, not its includes)
+//! - **dkim**: a key at `<selector>._domainkey.<domain>` for `DKIM_SELECTORS`, or for
+//!   common selectors when none are set
+//! - **dmarc**: one `v=DMARC1` record at `_dmarc.<domain>`, with a policy and reports
+//! - **rdns**: every address of each SMTP relay has a PTR name that resolves back to it
+//!
+//! Domains are `MAIL_FROM`'s plus `DELIVERABILITY_DOMAINS`; `?domain=` checks another.
+
+use std::{net::IpAddr, time::Duration};
+
+use hickory_resolver::TokioResolver;
+use serde::Serialize;
+
+use crate::config::{ApiConfig, Transport};
+
+/// How long one lookup may take.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Selectors tried without `DKIM_SELECTORS`: the usual ones of common providers.
+const COMMON_SELECTORS: [&str; 10] = ["default", "dkim", "mail", "s1", "s2", "selector1", "selector2", "google", "k1", "smtp"];
+/// DNS-querying SPF terms allowed per evaluation (RFC 7208 §4.6.4).
+const SPF_MAX_LOOKUPS: usize = 10;
+
+/// How one check came out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// Mail goes out, but some receivers will trust it less.
+    Warn,
+    Fail,
+}
+
+/// One line of a domain's report.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+}
+
+/// Every check for one sending domain.
+#[derive(Debug, Clone, Serialize)]
+pub struct DomainReport {
+    pub domain: String,
+    pub checks: Vec<Check>,
+}
+
+/// What `GET /admin/deliverability` answers.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    /// No check failed
+    pub ok: bool,
+    pub domains: Vec<DomainReport>,
+    /// Reverse DNS of the relays, shared by all domains
+    pub relays: Vec<Check>,
+}
+
+/// Where the configured mail comes from: its domains, DKIM selectors and relays.
+#[derive(Debug, Clone, Default)]
+pub struct Preflight {
+    pub domains: Vec<String>,
+    pub selectors: Vec<String>,
+    /// SMTP relay hosts; empty for the `file` and `mx` transports
+    pub relays: Vec<String>,
+}
+
+impl Preflight {
+    pub fn from_config(config: &ApiConfig) -> Self {
+        let mut domains: Vec<String> = config.mail_from.iter().map(|mb| mb.email.domain().to_lowercase()).collect();
+        for d in config.deliverability_domains.split(',').map(|d| d.trim().trim_end_matches('.').to_lowercase()).filter(|d| !d.is_empty()) {
+            if !domains.contains(&d) {
+                domains.push(d);
+            }
+        }
+        let selectors = config.dkim_selectors.split(',').map(str::trim).filter(|s| !s.is_empty()).map(str::to_string).collect();
+        let relays = match config.transport {
+            Transport::Smtp => {
+                let mut hosts: Vec<String> = crate::doctor::relays(config).into_iter().map(|r| r.1).collect();
+                hosts.sort();
+                hosts.dedup();
+                hosts
+            }
+            Transport::File | Transport::Mx => Vec::new(),
+        };
+        Self { domains, selectors, relays }
+    }
+
+    /// # Preflight::run()
+    /// Checks `domain` (with `selector`, when given) instead of the configured domains,
+    /// or every configured domain.
+    /// # Errors
+    /// The system resolver configuration cannot be read.
+    pub async fn run(&self, domain: Option<&str>, selector: Option<&str>) -> Result<Report, String> {
+        let resolver = TokioResolver::builder_tokio().map_err(|e| format!("DNS resolver: {e}"))?.build();
+        let dns = Dns(resolver);
+        let domains: Vec<String> = match domain {
+            Some(d) => vec![d.trim().trim_end_matches('.').to_lowercase()],
+            None => self.domains.clone(),
+        };
+        let selectors: Vec<String> = match selector {
+            Some(s) => vec![s.trim().to_string()],
+            None => self.selectors.clone(),
+        };
+        let mut reports = Vec::new();
+        for domain in domains {
+            let checks = vec![
+                spf(&dns, &domain).await,
+                dkim(&dns, &domain, &selectors).await,
+                dmarc(&dns, &domain).await,
+            ];
+            reports.push(DomainReport { domain, checks });
+        }
+        let mut relays = Vec::new();
+        for host in &self.relays {
+            relays.extend(rdns(&dns, host).await);
+        }
+        let ok = reports.iter().flat_map(|r| &r.checks).chain(&relays).all(|c| c.status != Status::Fail);
+        Ok(Report { ok, domains: reports, relays })
+    }
+}
+
+struct Dns(TokioResolver);
+
+impl Dns {
+    /// The TXT strings at `name` (each record's parts joined); empty when there are none.
+    async fn txt(&self, name: &str) -> Result<Vec<String>, String> {
+        match tokio::time::timeout(LOOKUP_TIMEOUT, self.0.txt_lookup(name)).await {
+            Ok(Ok(lookup)) => Ok(lookup
+                .iter()
+                .map(|txt| txt.txt_data().iter().map(|part| String::from_utf8_lossy(part)).collect::<String>())
+                .collect()),
+            Ok(Err(e)) if e.is_no_records_found() => Ok(Vec::new()),
+            Ok(Err(e)) => Err(format!("TXT lookup for {name} failed: {e}")),
+            Err(_) => Err(format!("TXT lookup for {name} timed out")),
+        }
+    }
+}
+
+fn check(name: impl Into<String>, status: Status, detail: impl Into<String>) -> Check {
+    Check { name: name.into(), status, detail: detail.into() }
+}
+
+async fn spf(dns: &Dns, domain: &str) -> Check {
+    let records: Vec<String> = match dns.txt(domain).await {
+        Ok(txt) => txt.into_iter().filter(|t| t.to_ascii_lowercase().starts_with("v=spf1")).collect(),
+        Err(e) => return check("spf", Status::Fail, e),
+    };
+    match records.as_slice() {
+        [] => check("spf", Status::Fail, format!("{domain} publishes no SPF record; add a TXT record `v=spf1 include:<your relay> -all`")),
+        [record] => spf_record(record),
+        _ => check("spf", Status::Fail, format!("{} SPF records; receivers treat that as an error (RFC 7208), merge them into one", records.len())),
+    }
+}
+
+/// # spf_record()
+/// The `spf` check for one `v=spf1` record.
+/// # Example
+/// ```
+/// use templar::deliverability::{spf_record, Status};
+/// assert_eq!(spf_record("v=spf1 include:_spf.google.com ~all").status, Status::Ok);
+/// assert_eq!(spf_record("v=spf1 include:_spf.google.com +all").status, Status::Fail);
+/// assert_eq!(spf_record("v=spf1 include:_spf.google.com").status, Status::Warn);
+/// let many = format!("v=spf1 {} -all", "include:x.example ".repeat(11));
+/// assert_eq!(spf_record(&many).status, Status::Fail);
+/// ```
+pub fn spf_record(record: &str) -> Check {
+    let terms: Vec<String> = record.split_whitespace().skip(1).map(str::to_ascii_lowercase).collect();
+    let mechanism = |t: &str| t.trim_start_matches(['+', '-', '~', '?']).split([':', '/', '=']).next().unwrap_or_default().to_string();
+    let lookups = terms.iter().filter(|t| matches!(mechanism(t).as_str(), "include" | "a" | "mx" | "ptr" | "exists" | "redirect")).count();
+    if lookups > SPF_MAX_LOOKUPS {
+        return check("spf", Status::Fail, format!("`{record}` takes {lookups} DNS lookups, over the {SPF_MAX_LOOKUPS} allowed; flatten some includes"));
+    }
+    match terms.iter().find(|t| mechanism(t) == "all").map(String::as_str) {
+        Some("-all" | "~all") => check("spf", Status::Ok, format!("`{record}`")),
+        Some("+all" | "all") => check("spf", Status::Fail, format!("`{record}` lets any host send as the domain; end it with -all or ~all")),
+        Some(_) => check("spf", Status::Warn, format!("`{record}` ends in ?all, which asserts nothing; use ~all or -all")),
+        None if terms.iter().any(|t| t.starts_with("redirect=")) => check("spf", Status::Ok, format!("`{record}`")),
+        None => check("spf", Status::Warn, format!("`{record}` has no `all` term, so unlisted hosts get a neutral result; end it with ~all or -all")),
+    }
+}
+
+async fn dkim(dns: &Dns, domain: &str, selectors: &[String]) -> Check {
+    let (tried, configured): (Vec<String>, bool) = if selectors.is_empty() {
+        (COMMON_SELECTORS.iter().map(|s| s.to_string()).collect(), false)
+    } else {
+        (selectors.to_vec(), true)
+    };
+    let (mut found, mut problems) = (Vec::new(), Vec::new());
+    for selector in &tried {
+        let name = format!("{selector}._domainkey.{domain}");
+        let records = match dns.txt(&name).await {
+            Ok(r) => r,
+            Err(e) => {
+                problems.push(e);
+                continue;
+            }
+        };
+        let Some(key) = records.iter().find(|r| r.contains("p=")) else {
+            if configured {
+                problems.push(format!("no DKIM key at {name}"));
+            }
+            continue;
+        };
+        let p = key.split(';').map(str::trim).find_map(|tag| tag.strip_prefix("p=")).unwrap_or_default();
+        if p.trim().is_empty() {
+            problems.push(format!("the key at {name} is revoked (empty p=)"));
+        } else {
+            found.push(selector.clone());
+        }
+    }
+    match (found.is_empty(), problems.is_empty()) {
+        (true, _) if !configured => check("dkim", Status::Fail, "no DKIM key found for the common selectors; set DKIM_SELECTORS (or ?selector=) to the one the relay signs with"),
+        (true, _) => check("dkim", Status::Fail, problems.join("; ")),
+        (false, true) => check("dkim", Status::Ok, format!("key published for selector(s) {}", found.join(", "))),
+        (false, false) => check("dkim", Status::Warn, format!("key published for {}, but {}", found.join(", "), problems.join("; "))),
+    }
+}
+
+async fn dmarc(dns: &Dns, domain: &str) -> Check {
+    let name = format!("_dmarc.{domain}");
+    let records: Vec<String> = match dns.txt(&name).await {
+        Ok(txt) => txt.into_iter().filter(|t| t.to_ascii_lowercase().starts_with("v=dmarc1")).collect(),
+        Err(e) => return check("dmarc", Status::Fail, e),
+    };
+    match records.as_slice() {
+        [] => check("dmarc", Status::Fail, format!("no DMARC record at {name}; start with `v=DMARC1; p=none; rua=mailto:dmarc@{domain}`")),
+        [record] => dmarc_record(record),
+        _ => check("dmarc", Status::Fail, format!("{} DMARC records at {name}; receivers ignore them all, keep one", records.len())),
+    }
+}
+
+/// # dmarc_record()
+/// The `dmarc` check for one `v=DMARC1` record.
+/// # Example
+/// ```
+/// use templar::deliverability::{dmarc_record, Status};
+/// assert_eq!(dmarc_record("v=DMARC1; p=quarantine; rua=mailto:d@example.com").status, Status::Ok);
+/// assert_eq!(dmarc_record("v=DMARC1; p=none; rua=mailto:d@example.com").status, Status::Warn);
+/// assert_eq!(dmarc_record("v=DMARC1; p=reject").status, Status::Warn);
+/// assert_eq!(dmarc_record("v=DMARC1; rua=mailto:d@example.com").status, Status::Fail);
+/// ```
+pub fn dmarc_record(record: &str) -> Check {
+    let tag = |name: &str| record.split(';').map(str::trim).find_map(|t| t.strip_prefix(name)?.trim_start().strip_prefix('=')).map(|v| v.trim().to_ascii_lowercase());
+    match (tag("p").as_deref(), tag("rua").is_some()) {
+        (None, _) => check("dmarc", Status::Fail, format!("`{record}` has no p= policy")),
+        (Some("quarantine" | "reject"), true) => check("dmarc", Status::Ok, format!("`{record}`")),
+        (Some("none"), _) => check("dmarc", Status::Warn, format!("`{record}` only monitors (p=none); move to quarantine once reports look clean")),
+        (Some("quarantine" | "reject"), false) => check("dmarc", Status::Warn, format!("`{record}` has no rua=, so no aggregate reports show what fails")),
+        (Some(other), _) => check("dmarc", Status::Fail, format!("`{record}` has an unknown policy p={other}")),
+    }
+}
+
+/// PTR of every address of `host`, confirmed by a forward lookup of the name.
+async fn rdns(dns: &Dns, host: &str) -> Vec<Check> {
+    let name = format!("rdns {host}");
+    let addrs: Vec<IpAddr> = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => match tokio::time::timeout(LOOKUP_TIMEOUT, dns.0.lookup_ip(host)).await {
+            Ok(Ok(lookup)) => lookup.iter().collect(),
+            Ok(Err(e)) => return vec![check(name, Status::Fail, format!("cannot resolve {host}: {e}"))],
+            Err(_) => return vec![check(name, Status::Fail, format!("resolving {host} timed out"))],
+        },
+    };
+    let mut checks = Vec::new();
+    for ip in addrs {
+        let name = format!("rdns {host} ({ip})");
+        let ptr = match tokio::time::timeout(LOOKUP_TIMEOUT, dns.0.reverse_lookup(ip)).await {
+            Ok(Ok(lookup)) => lookup.iter().next().map(|p| p.to_utf8().trim_end_matches('.').to_string()),
+            Ok(Err(e)) if e.is_no_records_found() => None,
+            Ok(Err(e)) => {
+                checks.push(check(name, Status::Fail, format!("PTR lookup failed: {e}")));
+                continue;
+            }
+            Err(_) => {
+                checks.push(check(name, Status::Fail, "PTR lookup timed out"));
+                continue;
+            }
+        };
+        let Some(ptr) = ptr else {
+            checks.push(check(name, Status::Fail, format!("{ip} has no PTR record; ask the relay's provider to set one")));
+            continue;
+        };
+        let confirmed = matches!(tokio::time::timeout(LOOKUP_TIMEOUT, dns.0.lookup_ip(ptr.as_str())).await, Ok(Ok(l)) if l.iter().any(|a| a == ip));
+        checks.push(if confirmed {
+            check(name, Status::Ok, format!("{ip} → {ptr} → {ip}"))
+        } else {
+            check(name, Status::Warn, format!("{ip} → {ptr}, but {ptr} does not resolve back to {ip}"))
+        });
+    }
+    checks
+}
@@ -0,0 +1,292 @@
+//! Digests: collect small notification events and send them as one email per
+//! recipient per interval instead of one email per event.
+//!
+//! `POST /digests/{key}/events` appends an event to `DIGEST_DIR/<key>/<recipient>.jsonl`.
+//! Every `DIGEST_INTERVAL_SECS` (or on `POST /admin/digests/flush`) each pending file
+//! is sent as one `/send`-style message, rules and variants included. The template gets:
+//!
+//! |Var|Value|
+//! |:-:|:---:|
+//! |`events`|The events' `vars` objects, oldest first|
+//! |`count`|Number of events|
+//! |`key`|The digest key|
+//!
+//! Template and subject come from the newest event. Events survive restarts. A digest
+//! that fails to send is kept and retried on the next flush.
+//!
+//! `POST /admin/queue/pause` stops flushing (scheduled and on demand) after the digest
+//! being sent; events keep queueing until `POST /admin/queue/resume`.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, Ordering}, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::email::{render_and_send, EmailState, SharedState};
+use crate::routes::SendRequest;
+
+/// Characters kept as-is in a recipient's file name; everything else is percent-encoded.
+const FILE_NAME_SAFE: &AsciiSet = &NON_ALPHANUMERIC.remove(b'@').remove(b'.').remove(b'-').remove(b'_').remove(b'+');
+
+/// Serializes appends with the flush hand-off, so no event is written to a file
+/// that is already being sent.
+static FILES: Mutex<()> = Mutex::new(());
+
+/// Set by `POST /admin/queue/pause`; flushes send nothing while it is.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Hold or release flushing; returns whether it was paused before.
+pub fn set_paused(paused: bool) -> bool {
+    PAUSED.swap(paused, Ordering::Relaxed)
+}
+
+pub fn paused() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+}
+
+/// Events in each digest sent recently, for the drain rate (see `scaling`).
+static DRAINED: Mutex<VecDeque<(Instant, usize)>> = Mutex::new(VecDeque::new());
+
+/// How far back [`drained_within()`] can look.
+const DRAIN_HISTORY: Duration = Duration::from_secs(24 * 3600);
+
+fn record_drained(events: usize) {
+    let mut drained = DRAINED.lock().unwrap();
+    while drained.front().is_some_and(|(at, _)| at.elapsed() > DRAIN_HISTORY) {
+        drained.pop_front();
+    }
+    drained.push_back((Instant::now(), events));
+}
+
+/// Events sent in digests during the last `window` (at most a day).
+pub fn drained_within(window: Duration) -> usize {
+    DRAINED.lock().unwrap().iter().filter(|(at, _)| at.elapsed() <= window).map(|(_, n)| n).sum()
+}
+
+/// One queued notification (the `POST /digests/{key}/events` body).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DigestEvent {
+    /// Single recipient
+    pub to: String,
+    /// Template for the digest email
+    pub template: String,
+    pub subject: String,
+    /// This event's data, one entry of `events` in the template
+    #[serde(default)]
+    pub vars: HashMap<String, Value>,
+}
+
+/// What a flush pass did.
+#[derive(Debug, Default, Serialize)]
+pub struct FlushReport {
+    /// Digests sent (one per key and recipient).
+    pub sent: usize,
+    /// Digests that failed and stay queued.
+    pub failed: usize,
+}
+
+/// What is waiting in the digest directory (`GET /admin/queue`).
+#[derive(Debug, Default, Serialize)]
+pub struct QueueStats {
+    /// Queued events, across all digests.
+    pub depth: usize,
+    /// Digests (key and recipient pairs) with events waiting.
+    pub digests: usize,
+    /// Age of the oldest waiting digest, by when its file was started.
+    pub oldest_age_secs: Option<u64>,
+}
+
+/// Pending file for `recipient` under `key`.
+fn pending_path(dir: &Path, key: &str, recipient: &str) -> PathBuf {
+    let name = utf8_percent_encode(&recipient.to_lowercase(), FILE_NAME_SAFE).to_string();
+    dir.join(key).join(format!("{name}.jsonl"))
+}
+
+/// # append()
+/// Queue `event` for the next flush of digest `key`.
+/// # Errors
+/// The digest directory cannot be created or written.
+pub fn append(dir: &Path, key: &str, event: &DigestEvent) -> io::Result<()> {
+    let path = pending_path(dir, key, &event.to);
+    let mut line = serde_json::to_string(event)?;
+    line.push('\n');
+    let _guard = FILES.lock().unwrap();
+    fs::create_dir_all(path.parent().unwrap())?;
+    fs::OpenOptions::new().create(true).append(true).open(&path)?.write_all(line.as_bytes())
+}
+
+/// # stats()
+/// Count what is queued in `dir`.
+/// # Errors
+/// The directory exists but cannot be read.
+pub fn stats(dir: &Path) -> io::Result<QueueStats> {
+    let mut stats = QueueStats::default();
+    if !dir.is_dir() {
+        return Ok(stats);
+    }
+    let _guard = FILES.lock().unwrap();
+    let mut oldest: Option<SystemTime> = None;
+    for key_dir in fs::read_dir(dir)? {
+        let key_dir = key_dir?.path();
+        if !key_dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&key_dir)? {
+            let path = entry?.path();
+            if !matches!(path.extension().and_then(|x| x.to_str()), Some("jsonl" | "flushing")) {
+                continue;
+            }
+            stats.digests += 1;
+            stats.depth += fs::read_to_string(&path)?.lines().filter(|l| !l.trim().is_empty()).count();
+            // The first event created the file; renaming it to `.flushing` keeps the time
+            let meta = fs::metadata(&path)?;
+            if let Ok(t) = meta.created().or_else(|_| meta.modified()) {
+                oldest = Some(oldest.map_or(t, |o| o.min(t)));
+            }
+        }
+    }
+    stats.oldest_age_secs = oldest.map(|t| t.elapsed().map(|d| d.as_secs()).unwrap_or(0));
+    Ok(stats)
+}
+
+/// # flush()
+/// Send every pending digest in `state.digest_dir` now. Stops early, leaving the rest
+/// queued, when flushing is paused.
+/// # Errors
+/// The digest directory cannot be read; send failures are counted in the report instead.
+pub async fn flush(state: &EmailState) -> io::Result<FlushReport> {
+    let mut report = FlushReport::default();
+    if !state.digest_dir.is_dir() {
+        return Ok(report);
+    }
+    for key_dir in fs::read_dir(&state.digest_dir)? {
+        let key_dir = key_dir?.path();
+        let Some(key) = key_dir.file_name().and_then(|n| n.to_str()).map(str::to_string) else { continue };
+        if !key_dir.is_dir() {
+            continue;
+        }
+        for path in claim_pending(&key_dir)? {
+            if paused() {
+                return Ok(report);
+            }
+            match send_digest(state, &key, &path).await {
+                Ok(events) => {
+                    fs::remove_file(&path)?;
+                    record_drained(events);
+                    report.sent += 1;
+                }
+                Err(e) => {
+                    warn!(digest = %key, file = %path.display(), "Digest not sent, retrying next flush: {e}");
+                    report.failed += 1;
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Move each `.jsonl` in `key_dir` to `.flushing` (unless one is still pending from a
+/// failed flush) and return every `.flushing` file. New events go to a fresh `.jsonl`.
+fn claim_pending(key_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let _guard = FILES.lock().unwrap();
+    let mut claimed = Vec::new();
+    for entry in fs::read_dir(key_dir)? {
+        let path = entry?.path();
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("jsonl") => {
+                let flushing = path.with_extension("flushing");
+                if !flushing.exists() {
+                    fs::rename(&path, &flushing)?;
+                    claimed.push(flushing);
+                }
+            }
+            Some("flushing") => claimed.push(path),
+            _ => {}
+        }
+    }
+    claimed.sort();
+    claimed.dedup();
+    Ok(claimed)
+}
+
+/// Send the digest in `path`; returns how many events it carried.
+async fn send_digest(state: &EmailState, key: &str, path: &Path) -> Result<usize, String> {
+    let events: Vec<DigestEvent> = fs::read_to_string(path)
+        .map_err(|e| e.to_string())?
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("corrupt event: {e}"))?;
+    let Some(last) = events.last() else { return Ok(0) };
+    let vars = HashMap::from([
+        ("events".to_string(), Value::Array(events.iter().map(|e| serde_json::json!(e.vars)).collect())),
+        ("count".to_string(), Value::from(events.len())),
+        ("key".to_string(), Value::from(key)),
+    ]);
+    let req = SendRequest {
+        to: last.to.clone(),
+        subject: last.subject.clone(),
+        template: last.template.clone(),
+        vars,
+        campaign_id: None,
+        phone: None,
+        preheader: None,
+        utm: None,
+        send_at: None,
+        attachments: Vec::new(),
+        zip: None,
+    };
+    render_and_send(state, req).await.map(|_| events.len()).map_err(|e| e.to_string())
+}
+
+/// Remove every queued event for `recipient`, across all keys. Returns files removed.
+pub fn delete_recipient(dir: &Path, recipient: &str) -> io::Result<usize> {
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+    let _guard = FILES.lock().unwrap();
+    let mut removed = 0;
+    for key_dir in fs::read_dir(dir)? {
+        let key_dir = key_dir?.path();
+        let Some(key) = key_dir.file_name().and_then(|n| n.to_str()) else { continue };
+        let pending = pending_path(dir, key, recipient);
+        for path in [pending.clone(), pending.with_extension("flushing")] {
+            if path.exists() {
+                fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Spawn the background flush loop, running once every `every` against the current state.
+pub fn spawn_flush_task(state: SharedState, every: Duration) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(every);
+        tick.tick().await;
+        loop {
+            tick.tick().await;
+            if paused() {
+                info!("Digest flush skipped, the queue is paused");
+                continue;
+            }
+            let state = state.load_full();
+            match flush(&state).await {
+                Ok(r) if r.sent + r.failed > 0 => info!("Digest flush sent {} digest(s), {} failed", r.sent, r.failed),
+                Ok(_) => {}
+                Err(e) => warn!("Digest flush failed: {e}"),
+            }
+        }
+    });
+}
@@ -0,0 +1,214 @@
+//! Deployment self-check behind `templar doctor`: validates the configuration, then
+//! probes every SMTP relay and renders every template, without starting the server.
+//!
+//! Each check is reported independently so one run lists every problem. Storage is
+//! connected to and its pending migrations listed, without applying them. Relay checks
+//! resolve the host, then connect with the same STARTTLS and credentials `/send` uses;
+//! templates are rendered with no vars (missing ones render empty), which catches
+//! syntax errors, unknown helpers and a missing `base` partial.
+
+use std::{collections::HashMap, fs, path::Path, time::Duration};
+
+use serde::Serialize;
+
+use crate::config::{ApiConfig, Transport};
+use crate::email::{build_registry, is_template_name, template_source, EmailState, Helpers, ARCHIVE_DIR};
+use crate::limits::RenderLimits;
+use crate::transport::{build_smtp_client, proxy::OutboundProxy, SmtpClient};
+
+/// How long a single DNS or connection probe may take.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// One line of the report.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    pub detail: String,
+}
+
+/// Every check in the order it ran.
+#[derive(Debug, Default, Serialize)]
+pub struct DoctorReport {
+    pub checks: Vec<Check>,
+}
+
+impl DoctorReport {
+    /// No check failed.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.ok)
+    }
+
+    fn push(&mut self, name: impl Into<String>, result: Result<String, String>) {
+        let (ok, detail) = match result {
+            Ok(d) => (true, d),
+            Err(d) => (false, d),
+        };
+        self.checks.push(Check { name: name.into(), ok, detail });
+    }
+}
+
+/// # run()
+/// Run all checks against the current environment (`.env` already loaded).
+/// An invalid configuration is reported on its own, since nothing else can be checked.
+pub async fn run() -> DoctorReport {
+    let mut report = DoctorReport::default();
+    // Secrets first: the config below is checked with the settings they hold
+    match crate::secrets::SecretStore::from_env() {
+        Ok(Some(store)) => report.push("secrets", store.load().await.map(|l| format!("{} setting(s) read from {}", l.settings, store.source()))),
+        Ok(None) => {}
+        Err(e) => report.push("secrets", Err(e.to_string())),
+    }
+    let config = match ApiConfig::load() {
+        Ok(c) => c,
+        Err(e) => {
+            report.push("config", Err(e.to_string()));
+            return report;
+        }
+    };
+    report.push("config", Ok("all settings parsed".into()));
+    report.push("transport", EmailState::from_config(&config).map(|s| s.mailer.kind().to_string()).map_err(|e| e.to_string()));
+    report.push("storage", check_storage(&config).await);
+    if !config.outbox_url.trim().is_empty() {
+        let table = crate::outbox::Outbox::connect(&config).await.map(|_| format!("{} and its mapped columns found", config.outbox_table));
+        report.push("outbox table", table.map_err(|e| e.to_string()));
+    }
+
+    match config.transport {
+        Transport::File => report.push("outbox", check_outbox(Path::new(&config.outbox_dir))),
+        Transport::Mx => report.push("relays", Ok("none, mx delivers to each recipient domain".into())),
+        Transport::Smtp => {
+            for (label, host, port, user, pass) in relays(&config) {
+                check_relay(&mut report, &label, &host, port, &user, &pass).await;
+            }
+        }
+    }
+    check_templates(&mut report, Path::new(&config.templates_dir), &Helpers::from_config(&config), &RenderLimits::from_config(&config));
+    report
+}
+
+/// `(label, host, port, username, password)` for every relay `/send` may use.
+pub(crate) fn relays(config: &ApiConfig) -> Vec<(String, String, u16, String, String)> {
+    let profiles: Vec<&str> = config.smtp_profiles.split(',').map(str::trim).filter(|n| !n.is_empty()).collect();
+    if !profiles.is_empty() {
+        // Every name was read by ApiConfig::load
+        return profiles
+            .into_iter()
+            .filter_map(|name| config.profiles.get(name))
+            .map(|p| (format!("relay `{}`", p.name), p.host.clone(), p.port, p.username.clone(), p.password.clone()))
+            .collect();
+    }
+    let mut relays = vec![("relay".to_string(), config.smtp_host.clone(), config.smtp_port, config.smtp_username.clone(), config.smtp_password.clone())];
+    let secondary = config.smtp_secondary_host.trim();
+    if !secondary.is_empty() {
+        let or = |v: &str, fallback: &str| if v.is_empty() { fallback.to_string() } else { v.to_string() };
+        relays.push((
+            "secondary relay".into(),
+            secondary.to_string(),
+            config.smtp_secondary_port.unwrap_or(config.smtp_port),
+            or(&config.smtp_secondary_username, &config.smtp_username),
+            or(&config.smtp_secondary_password, &config.smtp_password),
+        ));
+    }
+    relays
+}
+
+/// DNS, then a full STARTTLS + AUTH session (TCP only through `OUTBOUND_PROXY_URL`).
+async fn check_relay(report: &mut DoctorReport, label: &str, host: &str, port: u16, user: &str, pass: &str) {
+    let proxy = OutboundProxy::configured();
+    if proxy.is_none() {
+        let lookup = tokio::time::timeout(PROBE_TIMEOUT, tokio::net::lookup_host((host, port))).await;
+        let dns = match lookup {
+            Ok(Ok(addrs)) => {
+                let addrs: Vec<String> = addrs.map(|a| a.ip().to_string()).collect();
+                Ok(addrs.join(", "))
+            }
+            Ok(Err(e)) => Err(format!("cannot resolve {host}: {e}")),
+            Err(_) => Err(format!("resolving {host} timed out")),
+        };
+        let failed = dns.is_err();
+        report.push(format!("{label} dns {host}"), dns);
+        if failed {
+            return;
+        }
+    }
+    let name = format!("{label} {host}:{port}");
+    let client = match build_smtp_client(host, port, user, pass) {
+        Ok(c) => c,
+        Err(e) => return report.push(name, Err(e.to_string())),
+    };
+    let result = match (&client, proxy) {
+        (SmtpClient::Direct(t), _) => match tokio::time::timeout(PROBE_TIMEOUT, t.test_connection()).await {
+            Ok(Ok(true)) => Ok("STARTTLS and login ok".into()),
+            Ok(Ok(false)) => Err("connected, but the relay did not answer NOOP".into()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("connection timed out".into()),
+        },
+        (SmtpClient::Proxied(_), Some(proxy)) => match tokio::time::timeout(PROBE_TIMEOUT, proxy.connect(host, port)).await {
+            Ok(Ok(_)) => Ok("reachable through OUTBOUND_PROXY_URL (TLS and login not checked)".into()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("connection through the proxy timed out".into()),
+        },
+        (SmtpClient::Proxied(_), None) => Err("proxied client without OUTBOUND_PROXY_URL".into()),
+    };
+    report.push(name, result);
+}
+
+/// Connect to `STORAGE_URL` and compare its schema with the embedded migrations.
+async fn check_storage(config: &ApiConfig) -> Result<String, String> {
+    let store = crate::storage::connect(&config.storage_url).await.map_err(|e| e.to_string())?;
+    let pending = store.pending_migrations().await.map_err(|e| e.to_string())?;
+    match (pending.len(), config.storage_auto_migrate) {
+        (0, _) => Ok(format!("{}, schema up to date", store.backend())),
+        (n, true) => Ok(format!("{}, {n} migration(s) to apply at startup: {}", store.backend(), pending.join(", "))),
+        (n, false) => Err(format!("{n} pending migration(s) with STORAGE_AUTO_MIGRATE=false: {}; run `templar --migrate-only`", pending.join(", "))),
+    }
+}
+
+/// The file transport's directory exists (or can be created) and is writable.
+fn check_outbox(dir: &Path) -> Result<String, String> {
+    let probe = dir.join(".templar-doctor");
+    fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&probe, b""))
+        .and_then(|_| fs::remove_file(&probe))
+        .map(|_| format!("{} is writable", dir.display()))
+        .map_err(|e| format!("{}: {e}", dir.display()))
+}
+
+/// Directory layout, then one render per template.
+fn check_templates(report: &mut DoctorReport, dir: &Path, helpers: &Helpers, limits: &RenderLimits) {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => return report.push("templates", Err(format!("{}: {e}", dir.display()))),
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str()?.strip_suffix(".hbs").map(str::to_string))
+        .filter(|n| n != "base" && n != ARCHIVE_DIR)
+        .collect();
+    names.sort();
+    let layout = match (names.len(), dir.join("base.hbs").exists()) {
+        (0, _) => Err(format!("no .hbs templates in {}", dir.display())),
+        (n, true) => Ok(format!("{n} templates in {}", dir.display())),
+        (n, false) => Ok(format!("{n} templates in {}, no base.hbs layout", dir.display())),
+    };
+    report.push("templates", layout);
+
+    let mut registry = match build_registry(dir, helpers) {
+        Ok(r) => r,
+        Err(e) => return report.push("template helpers", Err(e.to_string())),
+    };
+    // Missing vars render empty here; strict mode would stop at the first one
+    registry.set_strict_mode(false);
+    let vars = HashMap::new();
+    for name in names {
+        let result = if !is_template_name(&name) {
+            Err("file name cannot be used as a template name".into())
+        } else {
+            crate::meta::load(dir, &name)
+                .and_then(|_| template_source(dir, &name).and_then(|src| limits.render_now(&registry, &src, &vars)).map_err(|e| e.to_string()))
+                .map(|html| format!("{} bytes", html.len()))
+        };
+        report.push(format!("template `{name}`"), result);
+    }
+}
@@ -1,45 +1,91 @@
 //! Email state + rendering + sending
 //! Minimal, documented version.
 
-use std::{collections::HashMap, path::PathBuf, time::Duration};
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
 
+use arc_swap::ArcSwap;
 use handlebars::Handlebars;
-use lettre::{message::{header, Mailbox, MultiPart, SinglePart}, transport::file::AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
-use once_cell::sync::OnceCell;
+use lettre::{message::{header, Attachment, Mailbox, MultiPart, SinglePart}, Message};
 use serde_json::Value;
 use thiserror::Error;
 
-static REGISTRY: OnceCell<Handlebars<'static>> = OnceCell::new();
-
-/// Transport selected at runtime (SMTP for prod, FILE for local dev).
-#[derive(Clone)]
-pub enum Mailer {
-    Smtp(AsyncSmtpTransport<Tokio1Executor>),
-    File(AsyncFileTransport<Tokio1Executor>),
-}
-
-impl Mailer {
-    /// Unified `send` so callers don't care which transport we're using.
-    /// We normalize errors to String to avoid mixing different transport error types.
-    pub async fn send(&self, email: Message) -> Result<(), String> {
-        match self {
-            Mailer::Smtp(m) => m.send(email).await.map(|_| ()).map_err(|e| e.to_string()),
-            Mailer::File(f) => f.send(email).await.map(|_| ()).map_err(|e| e.to_string()),
-        }
-    }
-}
+use crate::config::{ApiConfig, Transport};
+use crate::encoding::BodyFormat;
+use crate::postprocess::Utm;
+use crate::transport::{build_file_mailer, build_smtp_client, direct::DirectDelivery, pool::Pool, providers::{ProviderLimits, Throttled}, proxy::OutboundProxy, routing::Routes, Delivery, Mailer, Rejection, SmtpFailover, SmtpProfile, SmtpReply, TransportError};
 
 /// Domain errors we surface to the handler layer.
 #[derive(Debug, Error)]
 pub enum EmailError {
     #[error("template not found: {0}")]
     TemplateNotFound(String),
+    #[error("template archived: {0}")]
+    TemplateArchived(String),
     #[error("render error: {0}")]
     RenderError(String),
     #[error("smtp error: {0}")]
-    SmtpError(String),
+    SmtpError(TransportError),
     #[error("config error: {0}")]
     Config(String),
+    /// `PREFERENCE_FAIL_MODE=closed` and a recipient could not be checked (see `preferences`).
+    #[error("preference service unavailable: {0}")]
+    Preferences(String),
+    #[error("invalid header: {0}")]
+    InvalidHeader(String),
+    /// From [`render_eml`]: the request does not produce exactly one message.
+    #[error("not a single message: {0}")]
+    NotSingleMessage(String),
+    /// A render hit one of the [`RenderLimits`](crate::limits::RenderLimits).
+    #[error("render limit exceeded: {0}")]
+    RenderLimit(String),
+    /// The request's `vars` exceed `VARS_MAX_BYTES` or `VARS_MAX_DEPTH`.
+    #[error("vars too large: {0}")]
+    VarsTooLarge(String),
+    /// Storage could not be read or written (see `storage`).
+    #[error("storage error: {0}")]
+    Storage(String),
+    /// A URL attachment was refused or could not be downloaded (see `attachments`).
+    #[error(transparent)]
+    Attachment(#[from] crate::attachments::AttachmentError),
+    /// `POLICY_MODE=enforce` and the message has banned content or links (see `policy`).
+    #[error("content policy: {0}")]
+    Policy(String),
+    /// The send's campaign was cancelled (see `campaigns`).
+    #[error("campaign cancelled: {0}")]
+    CampaignCancelled(String),
+}
+
+impl EmailError {
+    /// Worth sending again later: the relay or storage was unavailable, not the request wrong.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::SmtpError(e) => e.is_transient(),
+            Self::Storage(_) | Self::Preferences(_) => true,
+            Self::Attachment(e) => matches!(e, crate::attachments::AttachmentError::Unavailable(_)),
+            _ => false,
+        }
+    }
+}
+
+/// Subdirectory of the templates dir holding archived templates: not sendable
+/// or listed, but kept on disk for audits and restore.
+pub const ARCHIVE_DIR: &str = "archive";
+
+/// Subdirectory of the templates dir with images and other files served by
+/// `GET /assets/{path}` and referenced from templates as `assets/<path>`.
+pub const ASSETS_DIR: &str = "assets";
+
+/// Template names are plain file names: no separators, `..` or leading dot, so a name
+/// from a request, rule or digest event can only reach files directly in the templates dir.
+/// # Example
+/// ```
+/// use templar::email::is_template_name;
+/// assert!(is_template_name("welcome.a"));
+/// assert!(!is_template_name("../secrets/welcome"));
+/// assert!(!is_template_name("/etc/passwd"));
+/// ```
+pub fn is_template_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains("..") && name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
 }
 
 /// App-wide email state (transport + addressing + templates location).
@@ -49,76 +95,180 @@ pub struct EmailState {
     pub from: Mailbox,
     pub reply_to: Option<Mailbox>,
     pub templates_dir: PathBuf,
+    /// Where the registry's script and plugin helpers come from (`HELPER_*_DIR`).
+    pub helpers: Helpers,
+    /// Where the file transport writes `.eml` files (`None` for SMTP).
+    pub outbox_dir: Option<PathBuf>,
+    /// Days to keep stored items before the retention purge removes them (0 = forever).
+    pub retention_days: u64,
+    /// Seconds a `/send` answer is repeated for the same `Idempotency-Key`.
+    pub idempotency_ttl_secs: u64,
+    /// Where queued digest events wait for the next flush (see `digests`).
+    pub digest_dir: PathBuf,
+    /// Asked which recipients opted out of a template's category (`PREFERENCE_SERVICE_URL`), `None` when off.
+    pub preferences: Option<Arc<crate::preferences::PreferenceService>>,
+    /// Where mail the relay refuses goes instead, per category (`FALLBACK_CHANNELS`), `None` when off.
+    pub fallback: Option<Arc<crate::channels::Channels>>,
+    /// Default charset and transfer encoding; templates may override (see `encoding`).
+    pub body_format: BodyFormat,
+    /// Link tagging for every send (`UTM_SOURCE`), `None` when off; requests may override.
+    pub utm: Option<Utm>,
+    /// Public URL that `assets/..` image references are rewritten to (`ASSETS_BASE_URL`).
+    pub assets_base_url: Option<String>,
+    /// Receiver for signed events (`WEBHOOK_URL`), `None` when webhooks are off.
+    pub webhook: Option<crate::webhooks::Webhook>,
+    /// Timeout, output cap and partial depth for every render (`RENDER_*`), and the
+    /// `vars` caps (`VARS_*`) handlers check first.
+    pub render_limits: crate::limits::RenderLimits,
+    /// Downloads `attachments` URLs (`ATTACHMENT_*`); shared by reloaded states, cache included.
+    pub attachments: Arc<crate::attachments::Fetcher>,
+    /// Converts `template` attachments to PDF (`PDF_SERVICE_URL`), `None` when off.
+    #[cfg(feature = "pdf")]
+    pub pdf: Option<Arc<crate::pdf::PdfService>>,
+    /// When attachments are bundled into one ZIP without the request asking (`ATTACHMENT_ZIP_*`).
+    pub zip_policy: crate::bundle::ZipPolicy,
+    /// Scans every attachment before it is sent (`SCAN_URL`), `None` when off.
+    pub scanner: Option<Arc<crate::scan::Scanner>>,
+    /// Content checks run on every message before it is sent (`POLICY_*`).
+    pub policy: Arc<crate::policy::ContentPolicy>,
+    /// Scores templates for `/templates/{name}/spamcheck` (`SPAMCHECK_URL`), `None` when off.
+    pub spamcheck: Option<Arc<crate::spamcheck::SpamChecker>>,
+    /// Sending domains, DKIM selectors and relays `/admin/deliverability` checks.
+    pub deliverability: Arc<crate::deliverability::Preflight>,
+    /// Daily recipient limits of warming-up sending domains (`WARMUP_*`).
+    pub warmup: Arc<crate::warmup::Warmup>,
+    /// Handlebars registry with the `base` partial, rebuilt along with the state on reload.
+    pub(crate) registry: Arc<Handlebars<'static>>,
 }
 
+/// State as held by the router: swapped in place on reload (SIGHUP), so in-flight
+/// requests finish on the state they started with.
+pub type SharedState = Arc<ArcSwap<EmailState>>;
+
 impl EmailState {
-    /// Build state from environment variables and initialize the Handlebars registry.
-    ///
-    /// Required envs (for SMTP mode):
-    /// - SMTP_HOST, SMTP_USERNAME, SMTP_PASSWORD, MAIL_FROM
-    /// Optional:
-    /// - SMTP_PORT (default 587), MAIL_REPLY_TO, TEMPLATES_DIR (default "src/templates")
-    /// - MAIL_TRANSPORT = "smtp" (default) | "file"
-    /// - MAIL_FILE_DIR (default "outbox/") — only used when MAIL_TRANSPORT=file
+    /// Build state from the environment: [`ApiConfig::load()`] then [`EmailState::from_config`].
     pub fn from_env() -> Result<Self, anyhow::Error> {
+        Self::from_config(&ApiConfig::load()?)
+    }
+
+    /// Build state from `config` and initialize the Handlebars registry.
+    ///
+    /// - `mail_from` is required; without `mail_reply_to` no Reply-To is sent
+    /// - `transport` = smtp (default) | file (writes to `outbox_dir`) | mx (direct
+    ///   delivery to recipient MX hosts)
+    /// - `smtp_profiles` (comma-separated names) spreads sends across those relays by weight
+    ///   (see `ApiConfig::profiles`); otherwise `smtp_secondary_host` enables failover, its
+    ///   port and credentials defaulting to the primary's
+    /// - `smtp_routes` = `domain=profile` rules sending matching recipients through a
+    ///   dedicated profile (see `transport::routing`)
+    /// - `provider_rates` / `provider_concurrency` put everything behind per-provider
+    ///   buckets (see `transport::providers`)
+    pub fn from_config(config: &ApiConfig) -> Result<Self, anyhow::Error> {
         // Common addressing
-        let from: Mailbox = std::env::var("MAIL_FROM")?
-            .parse()
-            .map_err(|e| anyhow::anyhow!(format!("Invalid MAIL_FROM: {e}")))?;
-        let reply_to = std::env::var("MAIL_REPLY_TO").ok().and_then(|s| s.parse::<Mailbox>().ok());
-        let templates_dir = PathBuf::from(std::env::var("TEMPLATES_DIR").unwrap_or_else(|_| "src/templates".into()));
+        let Some(from) = config.mail_from.clone().map(ascii_domain) else { anyhow::bail!("MAIL_FROM is required") };
+        let reply_to = config.mail_reply_to.clone().map(ascii_domain);
+        // The SMTP and HTTP clients built below go through it
+        OutboundProxy::configure(config)?;
+        let templates_dir = PathBuf::from(&config.templates_dir);
+        let helpers = Helpers::from_config(config);
         // Init HandleBars registry (strict mode, base.hbs partial, etc.)
-        init_registry(&templates_dir)?;
-        // Choose transport
-        let transport = std::env::var("MAIL_TRANSPORT").unwrap_or_else(|_| "smtp".into()).to_lowercase();
-        // Set defaults for SMTP
-        let host = std::env::var("SMTP_HOST").unwrap_or_else(|_| "localhost".into());
-        let port = std::env::var("SMTP_PORT").unwrap_or_else(|_| "587".into()).parse::<u16>()?;
-        let username = std::env::var("SMTP_USERNAME").unwrap_or_else(|_| "user".into());
-        let password = std::env::var("SMTP_PASSWORD").unwrap_or_else(|_| "password".into());
+        let registry = Arc::new(build_registry(&templates_dir, &helpers)?);
+        let (host, port) = (config.smtp_host.as_str(), config.smtp_port);
+        let (username, password) = (config.smtp_username.as_str(), config.smtp_password.as_str());
         // Build transport
-        let mailer;
-        if transport == "file" {mailer = build_file_mailer()?;}
-        else {mailer = build_smtp_mailer(&host, port, &username, &password)?;}
+        let outbox_dir = (config.transport == Transport::File).then(|| PathBuf::from(&config.outbox_dir));
+        let mailer = if let Some(dir) = &outbox_dir {build_file_mailer(dir)?}
+        else if config.transport == Transport::Mx {Mailer::Direct(Arc::new(DirectDelivery::new()?))}
+        else if !config.smtp_profiles.trim().is_empty() {
+            // Every name was read by ApiConfig::load
+            let profiles: Vec<SmtpProfile> = config.smtp_profiles
+                .split(',')
+                .map(str::trim)
+                .filter_map(|n| config.profiles.get(n).cloned())
+                .collect();
+            Mailer::Pool(Arc::new(Pool::new(&profiles)?))
+        }
+        else if !config.smtp_secondary_host.trim().is_empty() {
+            Mailer::Failover(Arc::new(SmtpFailover::new(
+                build_smtp_client(host, port, username, password)?,
+                build_smtp_client(
+                    config.smtp_secondary_host.trim(),
+                    config.smtp_secondary_port.unwrap_or(port),
+                    Some(config.smtp_secondary_username.as_str()).filter(|u| !u.is_empty()).unwrap_or(username),
+                    Some(config.smtp_secondary_password.as_str()).filter(|p| !p.is_empty()).unwrap_or(password),
+                )?,
+                Duration::from_secs(config.smtp_failback_secs),
+            )))
+        }
+        else {Mailer::Smtp(build_smtp_client(host, port, username, password)?)};
+        let mailer = match config.smtp_routes.trim() {
+            "" => mailer,
+            spec => Mailer::Routed(Arc::new(Routes::parse(spec, &config.profiles, mailer)?)),
+        };
+        let provider_limits = ProviderLimits::from_config(config)?;
+        let mailer = if provider_limits.is_empty() { mailer } else { Mailer::Throttled(Arc::new(Throttled::new(provider_limits, mailer)?)) };
         Ok(Self {
             mailer,
             from,
             reply_to,
             templates_dir,
+            helpers,
+            outbox_dir,
+            retention_days: config.retention_days,
+            idempotency_ttl_secs: config.idempotency_ttl_secs,
+            digest_dir: PathBuf::from(&config.digest_dir),
+            preferences: crate::preferences::PreferenceService::from_config(config)?.map(Arc::new),
+            fallback: crate::channels::Channels::from_config(config)?.map(Arc::new),
+            body_format: BodyFormat { charset: config.mail_charset, encoding: config.mail_body_encoding },
+            assets_base_url: Some(config.assets_base_url.trim().to_string()).filter(|u| !u.is_empty()),
+            utm: (!config.utm_source.trim().is_empty()).then(|| Utm {
+                source: config.utm_source.trim().to_string(),
+                medium: config.utm_medium.trim().to_string(),
+                campaign: None,
+            }),
+            webhook: crate::webhooks::Webhook::from_config(config)?,
+            render_limits: crate::limits::RenderLimits::from_config(config),
+            attachments: Arc::new(crate::attachments::Fetcher::from_config(config)?),
+            #[cfg(feature = "pdf")]
+            pdf: crate::pdf::PdfService::from_config(config)?.map(Arc::new),
+            zip_policy: crate::bundle::ZipPolicy::from_config(config),
+            scanner: crate::scan::Scanner::from_config(config)?.map(Arc::new),
+            policy: Arc::new(crate::policy::ContentPolicy::from_config(config)?),
+            spamcheck: crate::spamcheck::SpamChecker::from_config(config)?.map(Arc::new),
+            deliverability: Arc::new(crate::deliverability::Preflight::from_config(config)),
+            warmup: Arc::new(crate::warmup::Warmup::from_config(config)?),
+            registry,
         })
     }
+
+    /// The same state serving the templates in `dir`, with a registry built from it.
+    /// # Errors
+    /// `base.hbs` or a helper in `dir` does not load.
+    pub fn with_templates(&self, dir: PathBuf) -> Result<Self, anyhow::Error> {
+        let registry = Arc::new(build_registry(&dir, &self.helpers)?);
+        Ok(Self { templates_dir: dir, registry, ..self.clone() })
+    }
 }
 
-/// Build a STARTTLS SMTP transport with creds and short timeout.
-fn build_smtp_mailer(
-    host: &str,
-    port: u16,
-    user: &str,
-    pass: &str,
-) -> Result<Mailer, anyhow::Error> {
-    use lettre::transport::smtp::authentication::Credentials;
-
-    let creds = Credentials::new(user.to_string(), pass.to_string());
-    Ok(Mailer::Smtp(
-        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?
-            .port(port)
-            .credentials(creds)
-            .timeout(Some(Duration::from_secs(15)))
-            .build(),
-    ))
-}
-/// Build a file transport (writes `.eml` files), used for local/dev.
-fn build_file_mailer() -> Result<Mailer, anyhow::Error> {
-    use std::fs;
-    use std::path::Path;
-    let dir = std::env::var("MAIL_FILE_DIR").unwrap_or_else(|_| "outbox".into());
-    fs::create_dir_all(&dir)?;
-    let root = Path::new(&dir).to_path_buf();
-    Ok(Mailer::File(AsyncFileTransport::new(root)))
-}
-/// Initialize a global Handlebars registry in strict mode.
+/// Directories of the helpers a registry loads besides its templates.
+#[derive(Debug, Clone, Default)]
+pub struct Helpers {
+    /// `*.rhai` scripts (`rhai-helpers`); `None` is `helpers` in the templates root.
+    pub scripts: Option<PathBuf>,
+    /// `*.wasm` plugins (`wasm-helpers`); `None` loads none.
+    pub plugins: Option<PathBuf>,
+}
+
+impl Helpers {
+    pub fn from_config(config: &ApiConfig) -> Self {
+        let dir = |d: &str| Some(PathBuf::from(d)).filter(|_| !d.is_empty());
+        Self { scripts: dir(&config.helper_scripts_dir), plugins: dir(&config.helper_plugins_dir) }
+    }
+}
+
+/// Build the Handlebars registry in strict mode, with plugin helpers when enabled.
 /// We pre-register the `base` layout as a **partial** (used by `{{#> base}} ... {{/base}}`).
-fn init_registry(dir: &std::path::Path) -> Result<(), anyhow::Error> {
+pub(crate) fn build_registry(dir: &std::path::Path, helpers: &Helpers) -> Result<Handlebars<'static>, anyhow::Error> {
     let mut reg = Handlebars::new();
     reg.set_strict_mode(true);
 
@@ -128,61 +278,467 @@ fn init_registry(dir: &std::path::Path) -> Result<(), anyhow::Error> {
         reg.register_partial("base", base_src)?;
     }
 
-    let _ = REGISTRY.set(reg); // ignore if already set (idempotent on boot)
-    Ok(())
+    // Custom helpers from rhai scripts (`rhai-helpers` feature)
+    #[cfg(feature = "rhai-helpers")]
+    {
+        let scripts = helpers.scripts.clone().unwrap_or_else(|| dir.join("helpers"));
+        crate::scripting::register_script_helpers(&mut reg, &scripts)?;
+    }
+    // Custom helpers from WASM plugins (`wasm-helpers` feature)
+    #[cfg(feature = "wasm-helpers")]
+    if let Some(plugins) = &helpers.plugins {
+        crate::plugins::register_wasm_helpers(&mut reg, plugins)?;
+    }
+    #[cfg(not(any(feature = "rhai-helpers", feature = "wasm-helpers")))]
+    let _ = helpers;
+
+    Ok(reg)
+}
+
+/// One message of a [`SendOutcome`].
+#[derive(Debug, Clone, Default)]
+pub struct SentMessage {
+    pub template: String,
+    /// The A/B variant of `template` it got, `None` for the template itself.
+    pub variant: Option<String>,
+    /// Recipients the transport accepted.
+    pub recipients: Vec<String>,
+    /// Content policy findings (`POLICY_MODE=warn`).
+    pub policy: Vec<crate::policy::Finding>,
+    /// The relays' final replies for it (none for the `file` transport).
+    pub replies: Vec<SmtpReply>,
 }
 
-/// Render the requested template with `vars`, build a multipart (text+html) message,
-/// and send it via SMTP. Returns a pseudo message ID (random nanoid).
+/// What a `/send` did once the template's rules were applied.
+#[derive(Debug, Default)]
+pub struct SendOutcome {
+    /// One pseudo message ID per message sent (several when rules or variants split recipients across templates).
+    pub ids: Vec<String>,
+    /// Recipients dropped by a `skip` rule.
+    pub skipped: Vec<String>,
+    /// Recipients left out as suppressed, before rules ran (see `storage`).
+    pub suppressed: Vec<String>,
+    /// Each message in `ids`, in the same order.
+    pub messages: Vec<SentMessage>,
+    /// Content policy findings of every message sent (`POLICY_MODE=warn`).
+    pub policy: Vec<crate::policy::Finding>,
+    /// Final SMTP replies for the messages sent, in order (empty for the `file` transport).
+    pub replies: Vec<SmtpReply>,
+    /// Recipients that were not delivered while others were. When set, `accepted` lists
+    /// the delivered ones.
+    pub rejected: Vec<Rejection>,
+    pub accepted: Vec<String>,
+}
+
+/// Recipients of a send grouped by the template and variant they get.
+type Groups = Vec<(String, Option<String>, Vec<Mailbox>)>;
+
+/// Apply the template's rules (see `rules`) and pick A/B variants (see `variants`), then
+/// render and send one message per resulting template and variant. Returns the pseudo
+/// message IDs (random nanoids) and skipped recipients.
 pub async fn render_and_send(
     state: &EmailState,
     req: crate::routes::SendRequest,
-) -> Result<String, EmailError> {
+) -> Result<SendOutcome, EmailError> {
+    let mut outcome = SendOutcome::default();
+    let groups = plan(state, &req, &mut outcome.skipped)?;
+    let files = if groups.is_empty() { Vec::new() } else { attachment_files(state, &req).await? };
+
+    // One message per template and variant. A group the transport refuses is reported
+    // as rejected instead of hiding the groups already sent; it fails only if all did.
+    let mut first_error = None;
+    for (template, variant, to) in groups {
+        let addresses: Vec<String> = to.iter().map(|mb| mb.email.to_string()).collect();
+        match send_rendered(state, &req, &template, variant.as_deref(), to, &files).await {
+            Ok((id, delivery, findings)) => {
+                let delivered: Vec<String> = addresses.into_iter().filter(|a| !delivery.rejected.iter().any(|r| r.recipient == *a)).collect();
+                outcome.ids.push(id);
+                outcome.policy.extend(findings.iter().filter(|f| !outcome.policy.contains(f)).cloned().collect::<Vec<_>>());
+                outcome.replies.extend(delivery.replies.iter().cloned());
+                outcome.messages.push(SentMessage { template, variant, recipients: delivered.clone(), policy: findings, replies: delivery.replies });
+                outcome.accepted.extend(delivered);
+                outcome.rejected.extend(delivery.rejected);
+            }
+            Err(EmailError::SmtpError(e)) => {
+                tracing::warn!(template, recipients = addresses.len(), "Recipients not sent: {e}");
+                outcome.rejected.extend(addresses.into_iter().map(|recipient| Rejection { recipient, error: e.to_string() }));
+                first_error.get_or_insert(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if let Some(e) = first_error.filter(|_| outcome.ids.is_empty()) {
+        return Err(EmailError::SmtpError(e));
+    }
+    // `accepted` only matters next to `rejected`
+    if outcome.rejected.is_empty() {
+        outcome.accepted.clear();
+    }
+    Ok(outcome)
+}
+
+/// Validate `req` and group its recipients by template and variant; recipients
+/// dropped by a `skip` rule go to `skipped`.
+fn plan(state: &EmailState, req: &crate::routes::SendRequest, skipped: &mut Vec<String>) -> Result<Groups, EmailError> {
+    use crate::rules::{decide, Action, Subject};
+    use crate::variants::Variants;
+
     // 1) Recipients
     let to_list = parse_recipients(&req.to)
         .map_err(|e| EmailError::Config(format!("invalid recipient: {e}")))?;
 
-    // 2) HTML from Handlebars (strict mode guards missing vars)
-    let html = render_template(&state.templates_dir, &req.template, &req.vars)?;
+    check_subject(&req.subject).map_err(EmailError::InvalidHeader)?;
 
-    // 3) Build the email with multipart/alternative (plaintext + html)
-    let mut builder = Message::builder().from(state.from.clone()).subject(req.subject);
+    // 2) Rules and variants: group recipients by what they get, drop skipped ones
+    if !is_template_name(&req.template) {
+        return Err(EmailError::TemplateNotFound(req.template.clone()));
+    }
+    let rules = crate::rules::load(&state.templates_dir, &req.template).map_err(EmailError::Config)?;
+    let mut variants: HashMap<String, Option<Variants>> = HashMap::new();
+    let mut groups: Groups = Vec::new();
+    for mb in to_list {
+        let address = mb.email.to_string();
+        let subject = Subject { template: &req.template, recipient: &address, vars: &req.vars };
+        let template = match decide(&rules, &subject) {
+            Some(Action::Skip) => {
+                skipped.push(address);
+                continue;
+            }
+            Some(Action::Template(t)) => t.clone(),
+            None => req.template.clone(),
+        };
+        if !is_template_name(&template) {
+            return Err(EmailError::TemplateNotFound(template));
+        }
+        if !variants.contains_key(&template) {
+            let found = crate::variants::load(&state.templates_dir, &template).map_err(EmailError::Config)?;
+            variants.insert(template.clone(), found);
+        }
+        let variant = variants[&template].as_ref().map(|v| v.choose(&template, &address).to_string());
+        match groups.iter_mut().find(|(t, v, _)| *t == template && *v == variant) {
+            Some((_, _, list)) => list.push(mb),
+            None => groups.push((template, variant, vec![mb])),
+        }
+    }
+    Ok(groups)
+}
+
+/// # render_eml()
+/// Build the exact message `/send` would hand to the transport for `req`, as RFC 5322
+/// bytes, without sending it. Rules and variants apply, so the recipients must all end
+/// up with the same template and variant.
+/// # Errors
+/// As [`render_and_send`], plus [`EmailError::NotSingleMessage`] when rules skip every
+/// recipient or split them across several messages.
+pub async fn render_eml(state: &EmailState, req: &crate::routes::SendRequest) -> Result<Vec<u8>, EmailError> {
+    let mut skipped = Vec::new();
+    let mut groups = plan(state, req, &mut skipped)?;
+    if groups.len() > 1 {
+        return Err(EmailError::NotSingleMessage(format!("recipients would get {} different messages; render them separately", groups.len())));
+    }
+    let Some((template, variant, to)) = groups.pop() else {
+        return Err(EmailError::NotSingleMessage("every recipient is skipped by the template's rules".into()));
+    };
+    let files = attachment_files(state, req).await?;
+    Ok(build_message(state, req, &nanoid(), &template, variant.as_deref(), to, &files).await?.0.formatted())
+}
+
+/// Download or render the request's `attachments`, in order, bundled into one ZIP when
+/// the request asks or they are over `ATTACHMENT_ZIP_OVER_BYTES`.
+async fn attachment_files(state: &EmailState, req: &crate::routes::SendRequest) -> Result<Vec<crate::attachments::File>, EmailError> {
+    use crate::attachments::AttachmentError;
+
+    let mut files = Vec::with_capacity(req.attachments.len());
+    for a in &req.attachments {
+        let a = &crate::attachments::Attachment { filename: crate::attachments::filename(&a.filename, &req.vars)?, ..a.clone() };
+        let file = match (&a.url, &a.template, &a.data) {
+            (Some(_), None, None) => state.attachments.fetch(a).await?,
+            (None, Some(template), None) => pdf_attachment(state, req, a, template).await?,
+            (None, None, Some(path)) => crate::exports::generate(a, path, &req.vars)?,
+            _ => return Err(AttachmentError::Refused(format!("{}: set one of url, template or data", a.filename)).into()),
+        };
+        files.push(file);
+    }
+    // Before bundling: an encrypted ZIP cannot be scanned
+    if let Some(scanner) = &state.scanner {
+        for file in &files {
+            scanner.scan(file).await?;
+        }
+    }
+    if !state.zip_policy.applies(&files, req.zip.is_some()) {
+        return Ok(files);
+    }
+    let zip = req.zip.clone().unwrap_or_default();
+    let name = crate::attachments::filename(zip.filename.as_deref().unwrap_or(&state.zip_policy.filename), &req.vars)?;
+    Ok(vec![crate::bundle::bundle(&files, &name, zip.password.as_deref())?])
+}
+
+/// Render `template` with the request's vars and convert it to PDF.
+#[cfg(feature = "pdf")]
+async fn pdf_attachment(
+    state: &EmailState,
+    req: &crate::routes::SendRequest,
+    a: &crate::attachments::Attachment,
+    template: &str,
+) -> Result<crate::attachments::File, EmailError> {
+    use crate::attachments::{check_filename, AttachmentError, File};
+
+    let Some(pdf) = &state.pdf else {
+        return Err(AttachmentError::Refused("PDF attachments are off; set PDF_SERVICE_URL".into()).into());
+    };
+    check_filename(&a.filename).map_err(AttachmentError::Refused)?;
+    let html = render_template(&state.registry, &state.templates_dir, template, &req.vars, &state.render_limits).await?;
+    // The service cannot resolve `assets/..` relative to the templates dir
+    let html = match &state.assets_base_url {
+        Some(base) => crate::postprocess::asset_urls(&html, base),
+        None => html,
+    };
+    let body = pdf.convert(html).await?;
+    Ok(File { filename: a.filename.clone(), content_type: a.content_type_or(Some("application/pdf"))?, body: Arc::new(body) })
+}
+
+#[cfg(not(feature = "pdf"))]
+async fn pdf_attachment(
+    _state: &EmailState,
+    _req: &crate::routes::SendRequest,
+    _a: &crate::attachments::Attachment,
+    _template: &str,
+) -> Result<crate::attachments::File, EmailError> {
+    Err(crate::attachments::AttachmentError::Refused("template attachments need a build with --features pdf".into()).into())
+}
+
+/// Render `template` (or its `variant`) and send it to `to` with `files` attached.
+/// Returns a pseudo message ID (random nanoid), what the transport delivered and the
+/// content policy findings.
+async fn send_rendered(
+    state: &EmailState,
+    req: &crate::routes::SendRequest,
+    template: &str,
+    variant: Option<&str>,
+    to: Vec<Mailbox>,
+    files: &[crate::attachments::File],
+) -> Result<(String, Delivery, Vec<crate::policy::Finding>), EmailError> {
+    #[cfg(feature = "faults")]
+    crate::faults::render().await;
+    let id = nanoid();
+    let (email, findings) = build_message(state, req, &id, template, variant, to, files).await?;
+    if let Some(v) = variant {
+        tracing::info!(template, variant = v, campaign = req.campaign_id.as_deref(), "Sending template variant");
+    }
+
+    // lettre asks for SMTPUTF8 when an address is still non-ASCII and fails if the relay
+    // does not offer it; say which one.
+    let utf8_address = email.envelope().to().iter().chain(email.envelope().from()).find(|a| !a.user().is_ascii()).cloned();
+    let delivery = state.mailer.send(email).await.map_err(|e| match &utf8_address {
+        Some(a) if e.to_string().contains("SMTPUTF8") => EmailError::SmtpError(TransportError::Permanent(format!(
+            "{a} has a non-ASCII local part, which needs a relay with SMTPUTF8 support: {e}"
+        ))),
+        _ => EmailError::SmtpError(e),
+    })?;
+    Ok((id, delivery, findings))
+}
+
+/// Render `template` (or its `variant`) with the request's vars and build the multipart
+/// (text+html, then `files`) message `id` for `to`, headers included, with what the
+/// content policy found in it.
+async fn build_message(
+    state: &EmailState,
+    req: &crate::routes::SendRequest,
+    id: &str,
+    template: &str,
+    variant: Option<&str>,
+    to: Vec<Mailbox>,
+    files: &[crate::attachments::File],
+) -> Result<(Message, Vec<crate::policy::Finding>), EmailError> {
+    // 1) HTML from Handlebars (strict mode guards missing vars)
+    let file = match variant {
+        Some(v) => format!("{template}.{v}"),
+        None => template.to_string(),
+    };
+    let html = render_template(&state.registry, &state.templates_dir, &file, &req.vars, &state.render_limits).await?;
+    let text = crate::postprocess::plain_text(&html);
+    let meta = crate::meta::load(&state.templates_dir, template).map_err(EmailError::Config)?;
+    let html = match req.preheader.as_deref().or(meta.preheader.as_deref()).filter(|p| !p.trim().is_empty()) {
+        Some(p) => crate::postprocess::preheader(&html, p),
+        None => html,
+    };
+    let html = match &meta.dark_mode {
+        Some(colors) => crate::postprocess::dark_mode(&html, colors),
+        None => html,
+    };
+    let html = match &state.assets_base_url {
+        Some(base) => crate::postprocess::asset_urls(&html, base),
+        None => html,
+    };
+    let html = match request_utm(state, req) {
+        Some(utm) => crate::postprocess::utm_links(&html, &utm),
+        None => html,
+    };
+    let findings = state.policy.check(&req.subject, &html, &text);
+    if !findings.is_empty() {
+        let summary = findings.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ");
+        if state.policy.mode == crate::config::PolicyMode::Enforce && findings.iter().any(|f| f.rule.blocks()) {
+            tracing::warn!(template, "Send refused by the content policy: {summary}");
+            return Err(EmailError::Policy(summary));
+        }
+        tracing::warn!(template, "Content policy: {summary}");
+    }
+
+    // 2) Build the email with multipart/alternative (plaintext + html)
+    // `Message-ID: <id@sending domain>` lets feedback loop reports find the message (see `feedback`)
+    let mut builder = Message::builder()
+        .from(state.from.clone())
+        .subject(req.subject.as_str())
+        .message_id(Some(format!("<{id}@{}>", state.from.email.domain())));
     if let Some(rt) = &state.reply_to {
         builder = builder.reply_to(rt.clone());
     }
-    for mb in to_list {
+    for mb in to {
         builder = builder.to(mb);
     }
 
-    let email = builder
-        // `MultiPart::alternative` sets the correct `Content-Type`; no manual header needed.
-        .multipart(
-            MultiPart::alternative()
-                .singlepart(
-                    SinglePart::builder()
-                        .header(header::ContentType::TEXT_PLAIN)
-                        .body(strip_html::strip(&html)),
-                )
-                .singlepart(
-                    SinglePart::builder()
-                        .header(header::ContentType::TEXT_HTML)
-                        .body(html),
-                ),
-        )
+    // Charset and transfer encoding: global default, `<template>.encoding` overrides
+    let format = crate::encoding::load(&state.templates_dir, template, state.body_format).map_err(EmailError::Config)?;
+    let part = |subtype: &str, text: &str| -> Result<SinglePart, EmailError> {
+        let content_type = header::ContentType::parse(&format.content_type(subtype))
+            .map_err(|e| EmailError::Config(format!("content type: {e}")))?;
+        let body = format.body(text).map_err(|e| EmailError::RenderError(format!("{file}: {e}")))?;
+        Ok(SinglePart::builder().header(content_type).body(body))
+    };
+
+    // `MultiPart::alternative` sets the correct `Content-Type`; no manual header needed.
+    let body = MultiPart::alternative()
+        .singlepart(part("plain", &text)?)
+        .singlepart(part("html", &html)?);
+    // Attachments sit next to the alternatives in multipart/mixed
+    let body = files.iter().fold(if files.is_empty() { body } else { MultiPart::mixed().multipart(body) }, |mixed, f| {
+        mixed.singlepart(Attachment::new(f.filename.clone()).body(f.body.to_vec(), f.content_type.clone()))
+    });
+    let mut email = builder
+        .multipart(body)
         .map_err(|e| EmailError::Config(format!("message build error: {e}")))?;
+    if let Some(v) = variant {
+        email.headers_mut().insert_raw(header::HeaderValue::new(
+            header::HeaderName::new_from_ascii_str("X-Template-Variant"),
+            v.to_string(),
+        ));
+    }
+    if let Some(id) = &req.campaign_id {
+        email.headers_mut().insert_raw(header::HeaderValue::new(
+            header::HeaderName::new_from_ascii_str("X-Campaign-Id"),
+            id.clone(),
+        ));
+    }
+
+    check_header_lines(&email).map_err(EmailError::InvalidHeader)?;
+    Ok((email, findings))
+}
+
+/// UTM parameters for `req`: its `utm` fields over `UTM_SOURCE`/`UTM_MEDIUM`, campaign
+/// defaulting to `campaign_id`. `None` when neither enables tagging or `source` is empty.
+fn request_utm(state: &EmailState, req: &crate::routes::SendRequest) -> Option<Utm> {
+    let base = match (&req.utm, &state.utm) {
+        (None, None) => return None,
+        (_, Some(b)) => b.clone(),
+        (Some(_), None) => Utm { source: String::new(), medium: "email".into(), campaign: None },
+    };
+    let custom = req.utm.clone().unwrap_or_default();
+    let utm = Utm {
+        source: custom.source.unwrap_or(base.source),
+        medium: custom.medium.unwrap_or(base.medium),
+        campaign: custom.campaign.or_else(|| req.campaign_id.clone()),
+    };
+    (!utm.source.is_empty()).then_some(utm)
+}
+
+/// Longest header line RFC 5322 allows, CRLF excluded.
+pub const MAX_HEADER_LINE: usize = 998;
 
-    // 4) Send (or write to file, depending on transport)
-    state.mailer.send(email).await.map_err(|e| EmailError::SmtpError(e.to_string()))?;
-    Ok(nanoid())
+/// # check_subject()
+/// Reject subjects lettre would have to smuggle control characters into (an encoded
+/// CR/LF still reaches the reader as a broken subject). Non-ASCII text is fine: it is
+/// RFC 2047-encoded and folded when the message is built.
+/// # Example
+/// ```
+/// use templar::email::check_subject;
+/// assert!(check_subject("Pedido confirmado 🎉").is_ok());
+/// assert!(check_subject("ご注文ありがとうございます").is_ok());
+/// assert!(check_subject("Hi\r\nBcc: x@y.z").is_err());
+/// ```
+/// # Errors
+/// Names the first control character (tabs are allowed).
+pub fn check_subject(subject: &str) -> Result<(), String> {
+    match subject.chars().find(|c| c.is_control() && *c != '\t') {
+        Some(c) => Err(format!("subject contains control character U+{:04X}", c as u32)),
+        None => Ok(()),
+    }
 }
 
-/// Parse comma-separated recipients into `Mailbox`es.
-fn parse_recipients(to: &str) -> Result<Vec<Mailbox>, lettre::address::AddressError> {
-    to.split(',').map(|s| s.trim().parse()).collect()
+/// # check_header_lines()
+/// Make sure every encoded header line of `message` fits [`MAX_HEADER_LINE`]. lettre
+/// folds at spaces and splits encoded words, but cannot fold a long run of ASCII
+/// without spaces, and many relays reject or truncate such a message.
+/// # Example
+/// ```
+/// use lettre::Message;
+/// use templar::email::check_header_lines;
+/// let build = |subject: &str| Message::builder()
+///     .from("Zoë <from@example.com>".parse().unwrap())
+///     .to("to@example.com".parse().unwrap())
+///     .subject(subject)
+///     .body(String::from("hi"))
+///     .unwrap();
+/// assert!(check_header_lines(&build(&"注文確認 🎉 ".repeat(200))).is_ok());
+/// assert!(check_header_lines(&build(&"x".repeat(1200))).is_err());
+/// ```
+/// # Errors
+/// Names the header with the overlong line and its length.
+pub fn check_header_lines(message: &Message) -> Result<(), String> {
+    let headers = message.headers().to_string();
+    let mut name = "";
+    for line in headers.split("\r\n") {
+        if !line.starts_with([' ', '\t']) {
+            name = line.split(':').next().unwrap_or_default();
+        }
+        if line.len() > MAX_HEADER_LINE {
+            return Err(format!("{name} has a {}-byte line after encoding, over the {MAX_HEADER_LINE}-byte limit; add spaces so it can be folded", line.len()));
+        }
+    }
+    Ok(())
+}
+
+/// # ascii_domain()
+/// `mailbox` with an internationalized domain converted to punycode, so the message
+/// needs no SMTPUTF8 for it and MX lookups work. A non-ASCII local part is kept: only a
+/// relay that supports SMTPUTF8 can take it.
+/// # Example
+/// ```
+/// use lettre::message::Mailbox;
+/// use templar::email::ascii_domain;
+/// let mb = ascii_domain("José <josé@exämple.com>".parse::<Mailbox>().unwrap());
+/// assert_eq!(mb.email.to_string(), "josé@xn--exmple-cua.com");
+/// ```
+pub fn ascii_domain(mailbox: Mailbox) -> Mailbox {
+    let domain = mailbox.email.domain();
+    if domain.is_ascii() {
+        return mailbox;
+    }
+    match url::Host::parse(domain) {
+        Ok(url::Host::Domain(ascii)) => match lettre::Address::new(mailbox.email.user(), ascii) {
+            Ok(email) => Mailbox { email, ..mailbox },
+            Err(_) => mailbox,
+        },
+        _ => mailbox,
+    }
+}
+
+/// Parse comma-separated recipients into `Mailbox`es, IDN domains in punycode.
+pub fn parse_recipients(to: &str) -> Result<Vec<Mailbox>, lettre::address::AddressError> {
+    to.split(',').map(|s| s.trim().parse().map(ascii_domain)).collect()
 }
 
 /// Generate a compact pseudo message id (22 chars, URL-safe).
-fn nanoid() -> String {
+pub(crate) fn nanoid() -> String {
     use rand::{distr::Alphanumeric, rng, Rng};
     rng()
         .sample_iter(&Alphanumeric)
@@ -191,44 +747,32 @@ fn nanoid() -> String {
         .collect()
 }
 
-/// Load a `.hbs` file and render with the global registry (which already has `base` partial).
-fn render_template(
+/// Load a `.hbs` file and render with the registry (which already has `base` partial).
+pub(crate) async fn render_template(
+    reg: &Arc<Handlebars<'static>>,
     dir: &std::path::Path,
     name: &str,
     vars: &HashMap<String, Value>,
+    limits: &crate::limits::RenderLimits,
 ) -> Result<String, EmailError> {
-    let reg = REGISTRY.get().expect("registry not initialized");
+    let tpl_src = template_source(dir, name)?;
+    // Using `render_template` renders a raw string (not a named template).
+    // This works with our pre-registered `base` partial for `{{#> base}}...{{/base}}`.
+    limits.render(reg.clone(), tpl_src, vars.clone()).await
+}
 
+/// The source of template `name` in `dir`.
+pub(crate) fn template_source(dir: &std::path::Path, name: &str) -> Result<String, EmailError> {
+    if !is_template_name(name) {
+        return Err(EmailError::TemplateNotFound(name.to_string()));
+    }
     let path = dir.join(format!("{name}.hbs"));
     if !path.exists() {
+        if dir.join(ARCHIVE_DIR).join(format!("{name}.hbs")).exists() {
+            return Err(EmailError::TemplateArchived(name.to_string()));
+        }
         return Err(EmailError::TemplateNotFound(name.to_string()));
     }
 
-    let tpl_src =
-        std::fs::read_to_string(&path).map_err(|e| EmailError::RenderError(e.to_string()))?;
-
-    // Using `render_template` renders a raw string (not a named template).
-    // This works with our pre-registered `base` partial for `{{#> base}}...{{/base}}`.
-    reg.render_template(&tpl_src, vars)
-        .map_err(|e| EmailError::RenderError(e.to_string()))
-}
-
-/// Tiny best-effort HTML→plaintext stripper for the text alternative.
-mod strip_html {
-    pub fn strip(html: &str) -> String {
-        let mut out = String::with_capacity(html.len());
-        let mut in_tag = false;
-        for c in html.chars() {
-            match c {
-                '<' => in_tag = true,
-                '>' => in_tag = false,
-                _ if !in_tag => out.push(c),
-                _ => {}
-            }
-        }
-        out.replace("&nbsp;", " ")
-            .replace("&amp;", "&")
-            .replace("&lt;", "<")
-            .replace("&gt;", ">")
-    }
+    std::fs::read_to_string(&path).map_err(|e| EmailError::RenderError(e.to_string()))
 }
@@ -0,0 +1,175 @@
+//! Body charset and `Content-Transfer-Encoding` for the text and HTML parts.
+//!
+//! `MAIL_CHARSET` (default `utf-8`) and `MAIL_BODY_ENCODING` (default `auto`) apply to
+//! every message. A template can override them in an optional `<template>.encoding`
+//! file next to it (variants use their template's file):
+//!
+//! ```text
+//! # for a recipient gateway that only understands Latin-1
+//! charset iso-8859-1
+//! encoding quoted-printable
+//! ```
+//!
+//! `auto` lets lettre pick `7bit`, `quoted-printable` or `base64` per part. A rendered
+//! body that the charset cannot represent (an emoji in `us-ascii`), or that a forced
+//! `7bit`/`8bit` cannot carry, fails the send instead of producing a broken message.
+
+use std::{fmt, path::Path, str::FromStr};
+
+use lettre::message::{header::ContentTransferEncoding, Body};
+
+/// Charset the body parts are declared in and converted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Charset {
+    Utf8,
+    UsAscii,
+    /// ISO-8859-1 (Latin-1).
+    Latin1,
+}
+
+impl FromStr for Charset {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "utf-8" | "utf8" => Ok(Self::Utf8),
+            "us-ascii" | "ascii" => Ok(Self::UsAscii),
+            "iso-8859-1" | "latin1" => Ok(Self::Latin1),
+            _ => Err("expected utf-8, us-ascii or iso-8859-1".into()),
+        }
+    }
+}
+
+impl fmt::Display for Charset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Utf8 => "utf-8",
+            Self::UsAscii => "us-ascii",
+            Self::Latin1 => "iso-8859-1",
+        })
+    }
+}
+
+/// `Content-Transfer-Encoding` of the body parts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyEncoding {
+    /// Smallest safe encoding for each part.
+    Auto,
+    Base64,
+    QuotedPrintable,
+    SevenBit,
+    EightBit,
+}
+
+impl FromStr for BodyEncoding {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "base64" => Ok(Self::Base64),
+            "quoted-printable" | "qp" => Ok(Self::QuotedPrintable),
+            "7bit" => Ok(Self::SevenBit),
+            "8bit" => Ok(Self::EightBit),
+            _ => Err("expected auto, base64, quoted-printable, 7bit or 8bit".into()),
+        }
+    }
+}
+
+impl fmt::Display for BodyEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Auto => "auto",
+            Self::Base64 => "base64",
+            Self::QuotedPrintable => "quoted-printable",
+            Self::SevenBit => "7bit",
+            Self::EightBit => "8bit",
+        })
+    }
+}
+
+/// Charset and transfer encoding for one message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BodyFormat {
+    pub charset: Charset,
+    pub encoding: BodyEncoding,
+}
+
+impl BodyFormat {
+    /// # BodyFormat::content_type()
+    /// `Content-Type` value for a `text/<subtype>` part in this charset.
+    pub fn content_type(&self, subtype: &str) -> String {
+        format!("text/{subtype}; charset={}", self.charset)
+    }
+
+    /// # BodyFormat::body()
+    /// Convert `text` to the charset and encode it.
+    /// # Example
+    /// ```
+    /// use templar::encoding::{BodyEncoding, BodyFormat, Charset};
+    /// let latin1 = BodyFormat { charset: Charset::Latin1, encoding: BodyEncoding::QuotedPrintable };
+    /// assert_eq!(latin1.body("Olá").unwrap().into_vec(), b"Ol=E1");
+    /// let ascii = BodyFormat { charset: Charset::UsAscii, encoding: BodyEncoding::Auto };
+    /// assert!(ascii.body("Olá").is_err());
+    /// ```
+    /// # Errors
+    /// Names the first character the charset lacks, or the transfer encoding that cannot
+    /// carry the converted body.
+    pub fn body(&self, text: &str) -> Result<Body, String> {
+        let limit = match self.charset {
+            Charset::Utf8 => None,
+            Charset::UsAscii => Some(0x7f),
+            Charset::Latin1 => Some(0xff),
+        };
+        let bytes = match limit {
+            None => None,
+            Some(max) => {
+                let text = text.replace("\r\n", "\n").replace('\n', "\r\n");
+                let bytes = text
+                    .chars()
+                    .map(|c| u8::try_from(c as u32).ok().filter(|b| u32::from(*b) <= max).ok_or(c))
+                    .collect::<Result<Vec<u8>, char>>()
+                    .map_err(|c| format!("character `{c}` (U+{:04X}) cannot be encoded in {}", c as u32, self.charset))?;
+                Some(bytes)
+            }
+        };
+        let transfer = match self.encoding {
+            BodyEncoding::Auto => None,
+            BodyEncoding::Base64 => Some(ContentTransferEncoding::Base64),
+            BodyEncoding::QuotedPrintable => Some(ContentTransferEncoding::QuotedPrintable),
+            BodyEncoding::SevenBit => Some(ContentTransferEncoding::SevenBit),
+            BodyEncoding::EightBit => Some(ContentTransferEncoding::EightBit),
+        };
+        let unfit = |_| format!("body cannot be sent as {} (7bit needs ASCII, and both need lines under 76 characters)", self.encoding);
+        match (bytes, transfer) {
+            (None, None) => Ok(Body::new(text.to_string())),
+            (None, Some(t)) => Body::new_with_encoding(text.to_string(), t).map_err(unfit),
+            (Some(b), None) => Ok(Body::new(b)),
+            (Some(b), Some(t)) => Body::new_with_encoding(b, t).map_err(unfit),
+        }
+    }
+}
+
+/// # load()
+/// Format for `template`: `default` with the overrides of `<dir>/<template>.encoding`.
+/// # Errors
+/// The file is unreadable or has a bad line (with its line number).
+pub fn load(dir: &Path, template: &str, default: BodyFormat) -> Result<BodyFormat, String> {
+    let path = dir.join(format!("{template}.encoding"));
+    let src = match std::fs::read_to_string(&path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(default),
+        Err(e) => return Err(format!("{}: {e}", path.display())),
+    };
+    let mut format = default;
+    for (n, line) in src.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fail = |msg: String| format!("{} line {n}: {msg}", path.display());
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["charset", v] => format.charset = v.parse().map_err(|e| fail(format!("charset `{v}`: {e}")))?,
+            ["encoding", v] => format.encoding = v.parse().map_err(|e| fail(format!("encoding `{v}`: {e}")))?,
+            _ => return Err(fail("expected `charset <name>` or `encoding <name>`".into())),
+        }
+    }
+    Ok(format)
+}
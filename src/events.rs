@@ -0,0 +1,184 @@
+//! Event types of the streams Templar emits (webhooks, and the topics fed from them),
+//! for consumers written in Rust to deserialize instead of copying the structs.
+//!
+//! Every event is an envelope, `{"event":"sent","id":..,"created_at":..,"data":{..}}`:
+//! `event` names the kind, `data` holds its fields. The shapes are stable within a
+//! major version: fields are only added, as optional ones, and new kinds may appear.
+//! Consumers should not deny unknown fields; a kind this version does not know reads
+//! as [`EventKind::Unknown`].
+
+use serde::{Deserialize, Serialize};
+
+/// One emitted event.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    /// Unique per event; receivers use it to drop duplicates
+    pub id: String,
+    /// Unix time the event happened
+    pub created_at: i64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+impl Event {
+    /// # Event::new()
+    /// An event of `kind` happening now, with a fresh ID.
+    /// # Example
+    /// ```
+    /// use templar::events::{Event, EventKind, Suppressed};
+    /// let event = Event::new(EventKind::Suppressed(Suppressed {
+    ///     address: "ada@example.com".into(),
+    ///     reason: "complaint: abuse".into(),
+    ///     message_id: Some("yaIBQECRZKo2O8M2xHK9aI".into()),
+    /// }));
+    /// let json = serde_json::to_value(&event).unwrap();
+    /// assert_eq!(json["event"], "suppressed");
+    /// assert_eq!(json["data"]["address"], "ada@example.com");
+    /// assert_eq!(serde_json::from_value::<Event>(json).unwrap(), event);
+    ///
+    /// let later: Event = serde_json::from_str(r#"{"event":"unsubscribed","id":"x","created_at":1,"data":{"list":"news"}}"#).unwrap();
+    /// assert_eq!(later.kind, EventKind::Unknown);
+    /// ```
+    pub fn new(kind: EventKind) -> Self {
+        Self { id: crate::email::nanoid(), created_at: crate::storage::now(), kind }
+    }
+}
+
+/// What happened, with its fields.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case", try_from = "Tagged")]
+pub enum EventKind {
+    Sent(Sent),
+    Failed(Failed),
+    Bounced(Bounced),
+    Opened(Opened),
+    Clicked(Clicked),
+    Suppressed(Suppressed),
+    AuthLockout(AuthLockout),
+    /// The sample sent by `POST /admin/webhooks/test`
+    Test(Test),
+    /// A kind added after this version; only the envelope is read
+    Unknown,
+}
+
+/// `event` and `data` as received, before the kind is known.
+#[derive(Deserialize)]
+struct Tagged {
+    event: String,
+    #[serde(default)]
+    data: serde_json::Value,
+}
+
+impl TryFrom<Tagged> for EventKind {
+    type Error = serde_json::Error;
+
+    fn try_from(tagged: Tagged) -> Result<Self, Self::Error> {
+        let data = tagged.data;
+        Ok(match tagged.event.as_str() {
+            "sent" => Self::Sent(serde_json::from_value(data)?),
+            "failed" => Self::Failed(serde_json::from_value(data)?),
+            "bounced" => Self::Bounced(serde_json::from_value(data)?),
+            "opened" => Self::Opened(serde_json::from_value(data)?),
+            "clicked" => Self::Clicked(serde_json::from_value(data)?),
+            "suppressed" => Self::Suppressed(serde_json::from_value(data)?),
+            "auth_lockout" => Self::AuthLockout(serde_json::from_value(data)?),
+            "test" => Self::Test(serde_json::from_value(data)?),
+            _ => Self::Unknown,
+        })
+    }
+}
+
+/// A message was accepted by the relay (or written by the `file` transport).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sent {
+    /// The ID `/send` answered with, also in `Message-ID: <id@sending domain>`
+    pub message_id: String,
+    pub template: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    /// Recipients it was delivered to
+    pub recipients: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub campaign_id: Option<String>,
+    /// The relay's queue ID, when its reply named one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_id: Option<String>,
+}
+
+/// Recipients were not sent to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Failed {
+    pub template: String,
+    pub recipients: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub campaign_id: Option<String>,
+    pub error: String,
+    /// The relay or storage was unavailable: the same send may work later
+    pub retryable: bool,
+}
+
+/// A receiver returned a message it had accepted, or refused a recipient after the fact.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bounced {
+    /// The message it is about, when the bounce pointed to one of ours
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    pub recipient: String,
+    /// Enhanced status code, e.g. `5.1.1`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// The receiver's explanation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diagnostic: Option<String>,
+    /// A hard bounce: the address will not accept mail
+    pub permanent: bool,
+}
+
+/// A recipient opened a message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Opened {
+    pub message_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+/// A recipient followed a link in a message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Clicked {
+    pub message_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<String>,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+/// An address will no longer be mailed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Suppressed {
+    pub address: String,
+    /// `unsubscribed`, `bounced`, `complaint: abuse`..
+    pub reason: String,
+    /// The message that led to it, when there is one (a complaint or bounce)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+}
+
+/// A source was locked out after failing to authenticate (see `lockout`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthLockout {
+    /// Client IP, or `key:<prefix>` of the key presented over a Unix socket
+    pub source: String,
+    /// Failed attempts counted from it
+    pub failures: u32,
+    /// How long it is refused
+    pub locked_secs: u64,
+}
+
+/// The sample event.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Test {
+    pub message: String,
+}
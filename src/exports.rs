@@ -0,0 +1,191 @@
+//! Attachments generated from `vars`: `{"data": "orders", "filename": "orders-{{week}}.csv"}`
+//! in a request's `attachments` writes the rows at `vars.orders` to a CSV file, or to an
+//! Excel sheet for `"format": "xlsx"` or a `.xlsx` filename (`xlsx` feature). Simple
+//! exports then need no separate report service.
+//!
+//! Rows are objects, one cell per column, or arrays taken in order. `columns` lists the
+//! fields to export, as names or `{"field": .., "header": ..}`; without it every key of the
+//! first object row is used, alphabetically. Strings, numbers and booleans are written as
+//! they are, `null` and missing fields as empty cells, nested values as JSON.
+
+use std::{collections::HashMap, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::attachments::{Attachment, AttachmentError, File};
+
+/// File type of a `data` attachment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TableFormat {
+    Csv,
+    Xlsx,
+}
+
+/// One exported column: a field name, or a field with its own header.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Column {
+    Field(String),
+    Named { field: String, header: String },
+}
+
+impl Column {
+    pub fn field(&self) -> &str {
+        match self {
+            Self::Field(f) | Self::Named { field: f, .. } => f,
+        }
+    }
+
+    pub fn header(&self) -> &str {
+        match self {
+            Self::Field(f) => f,
+            Self::Named { header, .. } => header,
+        }
+    }
+}
+
+/// # generate()
+/// The file for attachment `a`, from the rows at `path` (`orders`, `report.rows`) in `vars`.
+/// # Errors
+/// [`AttachmentError::Refused`] when `path` is not an array of objects or arrays, or the
+/// format is not built in.
+pub fn generate(a: &Attachment, path: &str, vars: &HashMap<String, Value>) -> Result<File, AttachmentError> {
+    let refused = |msg: String| AttachmentError::Refused(format!("{}: {msg}", a.filename));
+    let rows = lookup(vars, path).ok_or_else(|| refused(format!("vars has no `{path}`")))?;
+    let Value::Array(rows) = rows else { return Err(refused(format!("`{path}` is not an array of rows"))) };
+    if let Some(bad) = rows.iter().position(|r| !r.is_object() && !r.is_array()) {
+        return Err(refused(format!("`{path}` row {bad} is neither an object nor an array")));
+    }
+    let columns = if a.columns.is_empty() { default_columns(rows) } else { a.columns.clone() };
+    let format = a.format.unwrap_or(if a.filename.to_lowercase().ends_with(".xlsx") { TableFormat::Xlsx } else { TableFormat::Csv });
+    let (body, content_type) = match format {
+        TableFormat::Csv => (csv(&columns, rows).into_bytes(), "text/csv; charset=utf-8"),
+        TableFormat::Xlsx => (xlsx(&columns, rows).map_err(refused)?, "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+    };
+    Ok(File { filename: a.filename.clone(), content_type: a.content_type_or(Some(content_type))?, body: Arc::new(body) })
+}
+
+/// `vars[first]`, then down the dotted rest of `path`.
+fn lookup<'a>(vars: &'a HashMap<String, Value>, path: &str) -> Option<&'a Value> {
+    let mut parts = path.split('.');
+    let mut value = vars.get(parts.next()?)?;
+    for part in parts {
+        value = match value {
+            Value::Object(map) => map.get(part)?,
+            Value::Array(items) => items.get(part.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// Every key of the first object row; array rows get no header line.
+fn default_columns(rows: &[Value]) -> Vec<Column> {
+    match rows.first() {
+        Some(Value::Object(first)) => first.keys().cloned().map(Column::Field).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The cells of `row` for `columns`; an array row is taken as it is.
+fn cells<'a>(columns: &[Column], row: &'a Value) -> Vec<Option<&'a Value>> {
+    match row {
+        Value::Object(map) => columns.iter().map(|c| map.get(c.field())).collect(),
+        Value::Array(items) => items.iter().map(Some).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// # csv()
+/// RFC 4180 CSV: a header line when there are columns, CRLF line ends, fields quoted when
+/// needed. Strings starting with `=`, `+`, `-`, `@` or a tab get a leading `'` so
+/// spreadsheets do not run them as formulas.
+/// # Example
+/// ```
+/// use serde_json::json;
+/// use templar::exports::{csv, Column};
+/// let columns = [Column::Field("id".into()), Column::Named { field: "note".into(), header: "Note".into() }];
+/// let rows = [json!({"id": 1, "note": "a, \"b\""}), json!({"id": 2, "note": "=SUM(A1)"}), json!({"id": -3})];
+/// assert_eq!(csv(&columns, &rows), "id,Note\r\n1,\"a, \"\"b\"\"\"\r\n2,'=SUM(A1)\r\n-3,\r\n");
+/// ```
+pub fn csv(columns: &[Column], rows: &[Value]) -> String {
+    let mut out = String::new();
+    let mut line = |fields: Vec<String>| {
+        out.push_str(&fields.iter().map(|f| quote(f)).collect::<Vec<_>>().join(","));
+        out.push_str("\r\n");
+    };
+    if !columns.is_empty() {
+        line(columns.iter().map(|c| c.header().to_string()).collect());
+    }
+    for row in rows {
+        line(cells(columns, row).into_iter().map(csv_cell).collect());
+    }
+    out
+}
+
+fn csv_cell(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) if s.starts_with(['=', '+', '-', '@', '\t', '\r']) => format!("'{s}"),
+        Some(Value::String(s)) => s.clone(),
+        Some(v) => v.to_string(),
+    }
+}
+
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// One sheet with a bold, frozen header row; strings are never read as formulas.
+#[cfg(feature = "xlsx")]
+fn xlsx(columns: &[Column], rows: &[Value]) -> Result<Vec<u8>, String> {
+    use rust_xlsxwriter::{Format, Workbook, XlsxError};
+
+    let write = || -> Result<Vec<u8>, XlsxError> {
+        let mut book = Workbook::new();
+        let sheet = book.add_worksheet();
+        let bold = Format::new().set_bold();
+        let first = u32::from(!columns.is_empty());
+        for (col, c) in columns.iter().enumerate() {
+            sheet.write_string_with_format(0, col as u16, c.header(), &bold)?;
+        }
+        if first == 1 {
+            sheet.set_freeze_panes(1, 0)?;
+        }
+        for (i, row) in rows.iter().enumerate() {
+            let r = first + i as u32;
+            for (col, cell) in cells(columns, row).into_iter().enumerate() {
+                let col = col as u16;
+                match cell {
+                    None | Some(Value::Null) => {}
+                    Some(Value::String(s)) => {
+                        sheet.write_string(r, col, s)?;
+                    }
+                    Some(Value::Number(n)) => {
+                        sheet.write_number(r, col, n.as_f64().unwrap_or_default())?;
+                    }
+                    Some(Value::Bool(b)) => {
+                        sheet.write_boolean(r, col, *b)?;
+                    }
+                    Some(v) => {
+                        sheet.write_string(r, col, v.to_string())?;
+                    }
+                }
+            }
+        }
+        sheet.autofit();
+        book.save_to_buffer()
+    };
+    write().map_err(|e| format!("xlsx not written: {e}"))
+}
+
+#[cfg(not(feature = "xlsx"))]
+fn xlsx(_columns: &[Column], _rows: &[Value]) -> Result<Vec<u8>, String> {
+    Err("xlsx attachments need a build with --features xlsx; use .csv".into())
+}
@@ -0,0 +1,66 @@
+//! Failure injection for staging and client testing, compiled in only with the
+//! `faults` cargo feature (never enable it for production builds).
+//!
+//! Faults are read from the environment on every use, so a SIGHUP reload of `.env`
+//! turns them on or off without a restart:
+//!
+//! |Variable|Effect|
+//! |:------:|:----:|
+//! |`FAULT_SMTP_ERROR_RATE`|Probability (`0.0`–`1.0`) that an SMTP attempt fails as unreachable, exercising failover and pool health|
+//! |`FAULT_SMTP_REJECT_RATE`|Probability that an SMTP attempt is rejected as if the relay refused the message|
+//! |`FAULT_SMTP_DELAY_MS`|Extra latency before each SMTP attempt|
+//! |`FAULT_RENDER_DELAY_MS`|Extra latency before each template render|
+
+use std::time::Duration;
+
+use rand::Rng;
+use tracing::{debug, warn};
+
+use crate::transport::TransportError;
+
+fn rate(name: &str) -> f64 {
+    crate::config::var(name).ok().and_then(|v| v.parse::<f64>().ok()).unwrap_or(0.0).clamp(0.0, 1.0)
+}
+
+fn delay(name: &str) -> Option<Duration> {
+    crate::config::var(name).ok().and_then(|v| v.parse::<u64>().ok()).filter(|ms| *ms > 0).map(Duration::from_millis)
+}
+
+fn roll(name: &str) -> bool {
+    let p = rate(name);
+    p > 0.0 && rand::rng().random_bool(p)
+}
+
+/// Log a warning at startup when any fault is configured.
+pub fn warn_if_enabled() {
+    let active: Vec<&str> = ["FAULT_SMTP_ERROR_RATE", "FAULT_SMTP_REJECT_RATE", "FAULT_SMTP_DELAY_MS", "FAULT_RENDER_DELAY_MS"]
+        .into_iter()
+        .filter(|v| crate::config::var(v).is_ok_and(|s| !s.trim().is_empty()))
+        .collect();
+    if !active.is_empty() {
+        warn!("Fault injection active: {}", active.join(", "));
+    }
+}
+
+/// Called before each SMTP attempt: maybe sleep, maybe fail.
+pub(crate) async fn smtp() -> Result<(), TransportError> {
+    if let Some(d) = delay("FAULT_SMTP_DELAY_MS") {
+        tokio::time::sleep(d).await;
+    }
+    if roll("FAULT_SMTP_ERROR_RATE") {
+        debug!("Injecting SMTP connection failure");
+        return Err(TransportError::Unreachable("injected fault: relay unreachable".into()));
+    }
+    if roll("FAULT_SMTP_REJECT_RATE") {
+        debug!("Injecting SMTP rejection");
+        return Err(TransportError::Permanent("injected fault: relay rejected the message".into()));
+    }
+    Ok(())
+}
+
+/// Called before each template render.
+pub(crate) async fn render() {
+    if let Some(d) = delay("FAULT_RENDER_DELAY_MS") {
+        tokio::time::sleep(d).await;
+    }
+}
@@ -0,0 +1,119 @@
+//! Golden-file rendering: render every template with fixture vars and compare the
+//! HTML against checked-in outputs, so template and helper changes show up as diffs.
+//!
+//! Fixtures are `<vars_dir>/<template>.json` (a JSON object of vars); goldens are
+//! `<golden_dir>/<template>.html`. Templates without a fixture are skipped.
+//! Also available as `templar render-all --vars-dir fixtures/ [--golden-dir DIR] [--update]`.
+
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+
+use serde::Serialize;
+
+use crate::email::{build_registry, template_source, Helpers, ARCHIVE_DIR};
+use crate::limits::RenderLimits;
+
+/// Outcome for one template.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum GoldenOutcome {
+    /// Output equals the golden file.
+    Matched,
+    /// Golden file written (`update`, or it did not exist yet).
+    Written,
+    /// Output differs; `line` is the first differing line (1-based).
+    Mismatched { line: usize, expected: String, actual: String },
+    /// No `<template>.json` fixture.
+    NoFixture,
+    /// Fixture unreadable or render failed.
+    Failed { error: String },
+}
+
+/// Per-template results, sorted by template name.
+#[derive(Debug, Default, Serialize)]
+pub struct GoldenReport {
+    pub results: Vec<(String, GoldenOutcome)>,
+}
+
+impl GoldenReport {
+    /// No mismatches and no failures.
+    pub fn passed(&self) -> bool {
+        !self.results.iter().any(|(_, o)| matches!(o, GoldenOutcome::Mismatched { .. } | GoldenOutcome::Failed { .. }))
+    }
+}
+
+/// # render_all()
+/// Render each template in `templates_dir` (except the `base` layout and archived
+/// ones) with its fixture and the `helpers`, and check it against `golden_dir`. With
+/// `update`, golden files are (re)written instead of compared; missing goldens are
+/// always written.
+/// # Errors
+/// Fails only when the templates directory or the `base` partial cannot be read;
+/// per-template problems are reported in the [`GoldenReport`].
+pub fn render_all(templates_dir: &Path, helpers: &Helpers, vars_dir: &Path, golden_dir: &Path, update: bool) -> Result<GoldenReport, anyhow::Error> {
+    let registry = build_registry(templates_dir, helpers)?;
+    let mut names: Vec<String> = fs::read_dir(templates_dir)?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str()?.strip_suffix(".hbs").map(str::to_string))
+        .filter(|n| n != "base" && n != ARCHIVE_DIR)
+        .collect();
+    names.sort();
+
+    let mut report = GoldenReport::default();
+    for name in names {
+        let outcome = check_one(&registry, templates_dir, vars_dir, golden_dir, &name, update);
+        report.results.push((name, outcome));
+    }
+    Ok(report)
+}
+
+fn check_one(
+    registry: &handlebars::Handlebars<'static>,
+    templates_dir: &Path,
+    vars_dir: &Path,
+    golden_dir: &Path,
+    name: &str,
+    update: bool,
+) -> GoldenOutcome {
+    let fixture = vars_dir.join(format!("{name}.json"));
+    if !fixture.exists() {
+        return GoldenOutcome::NoFixture;
+    }
+    let vars: HashMap<String, serde_json::Value> = match fs::read_to_string(&fixture).map_err(|e| e.to_string())
+        .and_then(|s| serde_json::from_str(&s).map_err(|e| e.to_string()))
+    {
+        Ok(v) => v,
+        Err(e) => return GoldenOutcome::Failed { error: format!("fixture {}: {e}", fixture.display()) },
+    };
+    let html = match template_source(templates_dir, name).and_then(|src| RenderLimits::default().render_now(registry, &src, &vars)) {
+        Ok(h) => h,
+        Err(e) => return GoldenOutcome::Failed { error: e.to_string() },
+    };
+
+    let golden: PathBuf = golden_dir.join(format!("{name}.html"));
+    match fs::read_to_string(&golden) {
+        Ok(expected) if !update => match first_difference(&expected, &html) {
+            None => GoldenOutcome::Matched,
+            Some((line, expected, actual)) => GoldenOutcome::Mismatched { line, expected, actual },
+        },
+        _ => match fs::create_dir_all(golden_dir).and_then(|_| fs::write(&golden, &html)) {
+            Ok(_) => GoldenOutcome::Written,
+            Err(e) => GoldenOutcome::Failed { error: format!("cannot write {}: {e}", golden.display()) },
+        },
+    }
+}
+
+/// First differing line as `(line number, expected, actual)`.
+fn first_difference(expected: &str, actual: &str) -> Option<(usize, String, String)> {
+    if expected == actual {
+        return None;
+    }
+    let (mut e, mut a) = (expected.lines(), actual.lines());
+    let mut line = 1;
+    loop {
+        match (e.next(), a.next()) {
+            (Some(x), Some(y)) if x == y => line += 1,
+            (None, None) => return Some((line, "(trailing newline)".into(), "(trailing newline)".into())),
+            (x, y) => return Some((line, x.unwrap_or("(end of file)").into(), y.unwrap_or("(end of file)").into())),
+        }
+    }
+}
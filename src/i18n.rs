@@ -0,0 +1,209 @@
+//! Error messages in the client's language. With `ERROR_LANGUAGES` listing more than
+//! English, the `error` of every JSON error answer is looked up in [`CATALOG`] and
+//! returned in the language `Accept-Language` prefers among those, with
+//! `Content-Language` naming the one used. Without an `Accept-Language` that matches, the
+//! first of `ERROR_LANGUAGES` is used.
+//!
+//! Only the wording Templar itself adds is translated: details quoted from elsewhere (a
+//! template's render error, the relay's reply, a file name) stay as they came. A message
+//! missing from the catalog is answered in English, with `Content-Language: en`.
+
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::config::ApiConfig;
+
+/// Languages there are translations for; `en` is the one messages are written in.
+pub const LANGUAGES: [&str; 3] = ["en", "es", "pt"];
+
+/// Largest error body translated; anything bigger is passed on as it is.
+const MAX_ERROR_BYTES: u64 = 64 * 1024;
+
+/// English message, then Spanish and Portuguese; `{}` stands for a detail kept as it is.
+pub const CATALOG: &[[&str; 3]] = &[
+    ["unauthorized", "no autorizado", "não autorizado"],
+    ["this API key has the {} scope; {} needs {}", "esta clave de API tiene el ámbito {}; {} requiere {}", "esta chave de API tem o escopo {}; {} requer {}"],
+    ["too many failed authentications, retry later", "demasiadas autenticaciones fallidas, inténtelo más tarde", "muitas falhas de autenticação, tente novamente mais tarde"],
+    ["overloaded, retry later", "sobrecargado, inténtelo más tarde", "sobrecarregado, tente novamente mais tarde"],
+    ["down for maintenance, retry later", "en mantenimiento, inténtelo más tarde", "em manutenção, tente novamente mais tarde"],
+    ["template not found: {}", "plantilla no encontrada: {}", "modelo não encontrado: {}"],
+    ["template archived: {}", "plantilla archivada: {}", "modelo arquivado: {}"],
+    ["render error: {}", "error al generar el mensaje: {}", "erro ao gerar a mensagem: {}"],
+    ["render limit exceeded: {}", "límite de generación superado: {}", "limite de geração excedido: {}"],
+    ["not a single message: {}", "no es un único mensaje: {}", "não é uma única mensagem: {}"],
+    ["vars too large: {}", "vars demasiado grandes: {}", "vars grandes demais: {}"],
+    ["invalid header: {}", "cabecera no válida: {}", "cabeçalho inválido: {}"],
+    ["smtp error: {}", "error de SMTP: {}", "erro de SMTP: {}"],
+    ["storage error: {}", "error de almacenamiento: {}", "erro de armazenamento: {}"],
+    ["preference service unavailable: {}", "servicio de preferencias no disponible: {}", "serviço de preferências indisponível: {}"],
+    ["content policy: {}", "política de contenido: {}", "política de conteúdo: {}"],
+    ["attachment {} refused: {} found", "adjunto {} rechazado: se encontró {}", "anexo {} recusado: {} encontrado"],
+    ["attachment refused: {}", "adjunto rechazado: {}", "anexo recusado: {}"],
+    ["attachment not fetched: {}", "adjunto no descargado: {}", "anexo não baixado: {}"],
+    ["invalid email: {}", "correo electrónico no válido: {}", "e-mail inválido: {}"],
+    ["invalid phone: {}", "teléfono no válido: {}", "telefone inválido: {}"],
+    ["invalid campaign_id: {}", "campaign_id no válido: {}", "campaign_id inválido: {}"],
+    ["campaign cancelled: {}", "campaña cancelada: {}", "campanha cancelada: {}"],
+    ["no sends for campaign: {}", "no hay envíos para la campaña: {}", "nenhum envio para a campanha: {}"],
+    ["message not found: {}", "mensaje no encontrado: {}", "mensagem não encontrada: {}"],
+    ["a request with this Idempotency-Key is still running", "una solicitud con esta Idempotency-Key aún está en curso", "uma solicitação com esta Idempotency-Key ainda está em andamento"],
+    ["Idempotency-Key must be 1 to 255 visible ASCII characters", "Idempotency-Key debe tener de 1 a 255 caracteres ASCII visibles", "Idempotency-Key deve ter de 1 a 255 caracteres ASCII visíveis"],
+];
+
+/// The languages errors may be answered in (`ERROR_LANGUAGES`).
+#[derive(Debug, Clone)]
+pub struct Localizer {
+    /// The first is the fallback
+    languages: Vec<&'static str>,
+}
+
+impl Localizer {
+    pub fn from_config(config: &ApiConfig) -> Self {
+        // Validated by `ApiConfig::load`
+        Self::new(parse_languages(&config.error_languages).unwrap_or_else(|_| vec!["en"]))
+    }
+
+    pub fn new(languages: Vec<&'static str>) -> Self {
+        Self { languages }
+    }
+
+    /// Only English is configured: there is nothing to translate to.
+    pub fn english_only(&self) -> bool {
+        self.languages == ["en"]
+    }
+
+    /// # Localizer::negotiate()
+    /// The configured language `accept` (an `Accept-Language` value) prefers, else the first.
+    /// # Example
+    /// ```
+    /// use templar::i18n::Localizer;
+    /// let l10n = Localizer::new(vec!["en", "es", "pt"]);
+    /// assert_eq!(l10n.negotiate(Some("pt-BR,pt;q=0.9,en;q=0.8")), "pt");
+    /// assert_eq!(l10n.negotiate(Some("fr-FR, es-419;q=0.7, en;q=0.5")), "es");
+    /// assert_eq!(l10n.negotiate(Some("es;q=0, de")), "en");
+    /// assert_eq!(l10n.negotiate(None), "en");
+    /// ```
+    pub fn negotiate(&self, accept: Option<&str>) -> &'static str {
+        let mut ranges: Vec<(&str, f32)> = accept
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let tag = parts.next().filter(|t| !t.is_empty())?;
+                let q = parts.find_map(|p| p.strip_prefix("q=")).map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+                Some((tag, q))
+            })
+            .filter(|(_, q)| *q > 0.0)
+            .collect();
+        // Stable, so equal weights keep the client's order
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranges
+            .iter()
+            .find_map(|(tag, _)| {
+                let primary = tag.split('-').next().unwrap_or_default().to_ascii_lowercase();
+                self.languages.iter().find(|l| primary == **l || primary == "*").copied()
+            })
+            .unwrap_or(self.languages[0])
+    }
+}
+
+/// # parse_languages()
+/// `ERROR_LANGUAGES`: codes out of [`LANGUAGES`], comma-separated.
+pub fn parse_languages(spec: &str) -> Result<Vec<&'static str>, String> {
+    let languages = spec
+        .split(',')
+        .map(|l| l.trim().to_ascii_lowercase())
+        .filter(|l| !l.is_empty())
+        .map(|l| LANGUAGES.iter().find(|known| **known == l).copied().ok_or_else(|| format!("no translations for `{l}`, expected {}", LANGUAGES.join(", "))))
+        .collect::<Result<Vec<_>, _>>()?;
+    if languages.is_empty() {
+        return Err("no languages given".into());
+    }
+    Ok(languages)
+}
+
+/// # translate()
+/// `message` in `language`, when the catalog has it.
+/// # Example
+/// ```
+/// use templar::i18n::translate;
+/// assert_eq!(translate("template not found: welcom", "es").as_deref(), Some("plantilla no encontrada: welcom"));
+/// assert_eq!(translate("this API key has the send scope; /admin/config needs admin", "pt").as_deref(),
+///            Some("esta chave de API tem o escopo send; /admin/config requer admin"));
+/// assert_eq!(translate("unauthorized", "en").as_deref(), Some("unauthorized"));
+/// assert_eq!(translate("something new", "es"), None);
+/// ```
+pub fn translate(message: &str, language: &str) -> Option<String> {
+    let column = LANGUAGES.iter().position(|l| *l == language)?;
+    CATALOG.iter().find_map(|entry| {
+        let details = captures(entry[0], message)?;
+        let mut out = String::new();
+        for (i, piece) in entry[column].split("{}").enumerate() {
+            if i > 0 {
+                out.push_str(details.get(i - 1).copied().unwrap_or_default());
+            }
+            out.push_str(piece);
+        }
+        Some(out)
+    })
+}
+
+/// The details `{}` stands for when `message` reads as `pattern`.
+fn captures<'a>(pattern: &str, message: &'a str) -> Option<Vec<&'a str>> {
+    let pieces: Vec<&str> = pattern.split("{}").collect();
+    let (first, rest) = pieces.split_first()?;
+    let Some((last, middle)) = rest.split_last() else { return (message == *first).then(Vec::new) };
+    let mut tail = message.strip_prefix(first)?.strip_suffix(last)?;
+    let mut details = Vec::new();
+    for piece in middle {
+        let at = tail.find(piece)?;
+        details.push(&tail[..at]);
+        tail = &tail[at + piece.len()..];
+    }
+    details.push(tail);
+    Some(details)
+}
+
+/// Middleware: answer the `error` of JSON error responses in the client's language.
+pub async fn localize(State(l10n): State<Arc<Localizer>>, req: Request, next: Next) -> Response {
+    if l10n.english_only() {
+        return next.run(req).await;
+    }
+    let language = l10n.negotiate(req.headers().get(header::ACCEPT_LANGUAGE).and_then(|v| v.to_str().ok()));
+    let response = next.run(req).await;
+    let status = response.status();
+    let json = response.headers().get(header::CONTENT_TYPE).is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if !(status.is_client_error() || status.is_server_error()) || !json || !small(response.headers(), response.body()) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, MAX_ERROR_BYTES as usize).await else {
+        return Response::from_parts(parts, Body::empty());
+    };
+    let mut value: serde_json::Value = match serde_json::from_slice(&bytes) {
+        Ok(value) => value,
+        Err(_) => return Response::from_parts(parts, Body::from(bytes)),
+    };
+    let translated = value.get("error").and_then(|e| e.as_str()).and_then(|e| translate(e, language));
+    let used = if translated.is_some() { language } else { "en" };
+    if let Some(message) = translated {
+        value["error"] = message.into();
+    }
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(header::CONTENT_LANGUAGE, HeaderValue::from_static(used));
+    parts.headers.append(header::VARY, HeaderValue::from_static("accept-language"));
+    Response::from_parts(parts, Body::from(value.to_string()))
+}
+
+/// The body is known to fit in [`MAX_ERROR_BYTES`] and is not compressed.
+fn small(headers: &HeaderMap, body: &Body) -> bool {
+    use axum::body::HttpBody;
+    !headers.contains_key(header::CONTENT_ENCODING) && body.size_hint().exact().is_some_and(|n| n <= MAX_ERROR_BYTES)
+}
@@ -4,4 +4,59 @@ pub mod email;
 pub mod routes;
 pub mod logger;
 pub mod config;
+pub mod crypto;
+pub mod retention;
+pub mod storage;
+pub mod scheduled;
+pub mod transport;
 
+pub mod systemd;
+pub mod backpressure;
+pub mod pagination;
+pub mod golden;
+pub mod rules;
+pub mod variants;
+pub mod campaigns;
+pub mod digests;
+pub mod preferences;
+pub mod channels;
+pub mod alerts;
+pub mod version;
+pub mod encoding;
+pub mod meta;
+pub mod postprocess;
+pub mod request_log;
+pub mod doctor;
+pub mod webhooks;
+pub mod maintenance;
+pub mod limits;
+pub mod server;
+pub mod scaling;
+pub mod outbox;
+pub mod bluegreen;
+pub mod attachments;
+pub mod exports;
+pub mod bundle;
+pub mod scan;
+pub mod policy;
+pub mod spamcheck;
+pub mod deliverability;
+pub mod warmup;
+pub mod feedback;
+pub mod events;
+pub mod secrets;
+pub mod lockout;
+pub mod scopes;
+pub mod apikeys;
+pub mod i18n;
+pub mod spool;
+#[cfg(feature = "faults")]
+pub mod faults;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "wasm-helpers")]
+pub mod plugins;
+#[cfg(feature = "rhai-helpers")]
+pub mod scripting;
+#[cfg(feature = "pdf")]
+pub mod pdf;
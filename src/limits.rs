@@ -0,0 +1,441 @@
+//! Resource limits around template rendering, so a pathological template cannot hang a
+//! request or balloon memory: a render timeout, a cap on the output size and a limit on
+//! how deeply partials nest.
+//!
+//! Handlebars renders synchronously, so [`RenderLimits::render`] runs it on the blocking
+//! pool and stops waiting at the deadline: the request fails with a render limit on time,
+//! whatever the template does. The render itself cannot be interrupted from outside; it
+//! checks the deadline each time output is written, so a runaway loop that writes stops
+//! at its next write. One that writes nothing (a helper spinning, an `{{#each}}` over a
+//! huge list with an empty body) keeps its blocking thread until it ends on its own.
+//! Each render on the blocking pool holds one of `RENDER_MAX_BLOCKING` permits until its
+//! closure returns, timed out or not, and a render that finds none left fails at once
+//! with a render limit: runaway templates take that many threads at most, not the whole
+//! pool. Partial nesting
+//! is checked before rendering, from the compiled template and the registered partials.
+//! A partial that includes itself, directly or through others, is always refused, since
+//! it would recurse until the stack overflows.
+//!
+//! The request's `vars` are capped too, by serialized size and nesting depth, and checked
+//! by the handlers before anything is rendered (see [`RenderLimits::check_vars`]).
+//!
+//! Output is collected in fixed-size chunks rather than one growing buffer, so a report
+//! with thousands of `{{#each}}` rows is never copied into ever larger allocations while
+//! it renders, and is joined once into a string of exactly its size. The bytes held by
+//! renders in progress are counted for `GET /admin/rendering` (see [`render_stats`]).
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use handlebars::{template::{Parameter, Template, TemplateElement}, Context, Handlebars, Output, RenderContext, Renderable};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::ApiConfig;
+use crate::email::EmailError;
+
+/// Limits applied to every render (`RENDER_*` settings) and to request `vars`
+/// (`VARS_*`); `0` turns one off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderLimits {
+    pub timeout: Duration,
+    pub max_bytes: usize,
+    pub max_partial_depth: usize,
+    /// Largest `vars` as serialized JSON.
+    pub max_vars_bytes: usize,
+    /// Deepest nesting of objects and arrays in `vars`, the `vars` object itself being `1`.
+    pub max_vars_depth: usize,
+    /// Renders that may run on the blocking pool at once, timed-out ones included.
+    pub max_blocking: usize,
+}
+
+impl Default for RenderLimits {
+    fn default() -> Self {
+        Self::from_config(&crate::config::get_defaults())
+    }
+}
+
+impl RenderLimits {
+    pub fn from_config(config: &ApiConfig) -> Self {
+        Self {
+            timeout: Duration::from_millis(config.render_timeout_ms),
+            max_bytes: config.render_max_bytes,
+            max_partial_depth: config.render_max_partial_depth,
+            max_vars_bytes: config.vars_max_bytes,
+            max_vars_depth: config.vars_max_depth,
+            max_blocking: config.render_max_blocking,
+        }
+    }
+
+    /// # check_vars()
+    /// Refuse `vars` larger or more deeply nested than allowed, before they reach a render.
+    /// # Example
+    /// ```
+    /// use std::collections::HashMap;
+    /// use templar::limits::RenderLimits;
+    /// let limits = RenderLimits { max_vars_depth: 2, ..RenderLimits::default() };
+    /// let flat = HashMap::from([("user".to_string(), serde_json::json!({"name": "Ada"}))]);
+    /// assert!(limits.check_vars(&flat).is_ok());
+    /// let deep = HashMap::from([("user".to_string(), serde_json::json!({"row": {"id": 1}}))]);
+    /// assert!(limits.check_vars(&deep).is_err());
+    /// ```
+    /// # Errors
+    /// [`EmailError::VarsTooLarge`] naming the limit exceeded.
+    pub fn check_vars(&self, vars: &HashMap<String, Value>) -> Result<(), EmailError> {
+        if self.max_vars_depth > 0 {
+            let depth = 1 + vars.values().map(nesting).max().unwrap_or(0);
+            if depth > self.max_vars_depth {
+                return Err(EmailError::VarsTooLarge(format!("vars nest {depth} levels deep, more than VARS_MAX_DEPTH={}", self.max_vars_depth)));
+            }
+        }
+        if self.max_vars_bytes > 0 {
+            let mut size = ByteCount(0);
+            serde_json::to_writer(&mut size, vars).map_err(|e| EmailError::RenderError(e.to_string()))?;
+            if size.0 > self.max_vars_bytes {
+                return Err(EmailError::VarsTooLarge(format!("vars are {} bytes as JSON, more than VARS_MAX_BYTES={}", size.0, self.max_vars_bytes)));
+            }
+        }
+        Ok(())
+    }
+
+    /// # render()
+    /// Render the template source `src` with `reg` (and its `base` partial) within the
+    /// limits, on the blocking pool. Gives up after `RENDER_TIMEOUT_MS` even when the
+    /// render is still running; see the module docs for when that render stops. Refused
+    /// while `RENDER_MAX_BLOCKING` renders are running.
+    /// # Example
+    /// ```
+    /// # #[tokio::main] async fn main() {
+    /// use std::{collections::HashMap, sync::Arc};
+    /// use templar::{email::EmailError, limits::RenderLimits};
+    /// let reg = Arc::new(handlebars::Handlebars::new());
+    /// let limits = RenderLimits { max_bytes: 16, ..RenderLimits::default() };
+    /// let vars = HashMap::from([("name".to_string(), serde_json::json!("Ada"))]);
+    /// assert_eq!(limits.render(reg.clone(), "Hi {{name}}".into(), vars).await.unwrap(), "Hi Ada");
+    /// let long = HashMap::from([("name".to_string(), serde_json::json!("a".repeat(64)))]);
+    /// assert!(matches!(limits.render(reg, "Hi {{name}}".into(), long).await, Err(EmailError::RenderLimit(_))));
+    /// # }
+    /// ```
+    /// # Errors
+    /// [`EmailError::RenderLimit`] when a limit is hit, [`EmailError::RenderError`] otherwise.
+    pub async fn render(&self, reg: Arc<Handlebars<'static>>, src: String, vars: HashMap<String, Value>) -> Result<String, EmailError> {
+        let limits = *self;
+        let permit = BlockingPermit::acquire(self.max_blocking)
+            .ok_or_else(|| EmailError::RenderLimit(format!("RENDER_MAX_BLOCKING={} renders are running already", self.max_blocking)))?;
+        let rendering = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            limits.render_now(&reg, &src, &vars)
+        });
+        let joined = if self.timeout.is_zero() {
+            rendering.await
+        } else {
+            tokio::time::timeout(self.timeout, rendering)
+                .await
+                .map_err(|_| EmailError::RenderLimit(format!("rendering took longer than RENDER_TIMEOUT_MS={}", self.timeout.as_millis())))?
+        };
+        joined.map_err(|e| EmailError::RenderError(e.to_string()))?
+    }
+
+    /// # render_now()
+    /// [`render()`](Self::render) on the calling thread, for the command line checks
+    /// (`templar doctor`, `render-all`). The timeout only stops a render as it writes.
+    /// # Example
+    /// ```
+    /// use std::collections::HashMap;
+    /// use templar::{email::EmailError, limits::RenderLimits};
+    /// let reg = handlebars::Handlebars::new();
+    /// let limits = RenderLimits { max_bytes: 16, ..RenderLimits::default() };
+    /// let vars = HashMap::from([("name".to_string(), serde_json::json!("Ada"))]);
+    /// assert_eq!(limits.render_now(&reg, "Hi {{name}}", &vars).unwrap(), "Hi Ada");
+    /// let long = HashMap::from([("name".to_string(), serde_json::json!("a".repeat(64)))]);
+    /// assert!(matches!(limits.render_now(&reg, "Hi {{name}}", &long), Err(EmailError::RenderLimit(_))));
+    /// ```
+    /// # Errors
+    /// As [`render()`](Self::render).
+    pub fn render_now(&self, reg: &Handlebars<'_>, src: &str, vars: &HashMap<String, Value>) -> Result<String, EmailError> {
+        let tpl = Template::compile(src).map_err(|e| EmailError::RenderError(e.to_string()))?;
+        self.check_partials(reg, &tpl).map_err(EmailError::RenderLimit)?;
+
+        let ctx = Context::wraps(vars).map_err(|e| EmailError::RenderError(e.to_string()))?;
+        let mut out = ChunkedOutput {
+            chunks: Vec::new(),
+            len: 0,
+            held: 0,
+            max_bytes: self.max_bytes,
+            deadline: (!self.timeout.is_zero()).then(|| Instant::now() + self.timeout),
+            timeout: self.timeout,
+            hit: None,
+        };
+        // The template compiled above renders as is, rather than being compiled again
+        match tpl.render(reg, &ctx, &mut RenderContext::new(None), &mut out) {
+            Ok(()) => Ok(out.into_string()),
+            Err(e) => Err(match out.hit.take() {
+                Some(reason) => EmailError::RenderLimit(reason),
+                None => EmailError::RenderError(e.to_string()),
+            }),
+        }
+    }
+
+    /// Walk every partial `tpl` can reach; `Err` names the cycle or the depth exceeded.
+    fn check_partials(&self, reg: &Handlebars<'_>, tpl: &Template) -> Result<(), String> {
+        let mut partials: HashMap<&str, &Template> = reg.get_templates().iter().map(|(n, t)| (n.as_str(), t)).collect();
+        for t in reg.get_templates().values().chain([tpl]) {
+            inline_partials(t, &mut partials);
+        }
+        let walk = PartialWalk { partials, max_depth: self.max_partial_depth };
+        walk.depth(tpl, &mut Vec::new()).map(|_| ())
+    }
+}
+
+/// Levels of objects and arrays in `v`; a scalar is `0`.
+fn nesting(v: &Value) -> usize {
+    match v {
+        Value::Array(items) => 1 + items.iter().map(nesting).max().unwrap_or(0),
+        Value::Object(fields) => 1 + fields.values().map(nesting).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Counts serialized bytes without keeping them.
+struct ByteCount(usize);
+
+impl io::Write for ByteCount {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Chunks start small for ordinary emails and double up to `CHUNK_BYTES`; a segment
+/// longer than the next chunk gets one of its own size.
+const FIRST_CHUNK_BYTES: usize = 1024;
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// Renders since startup and the memory they held (`GET /admin/rendering`).
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct RenderStats {
+    pub renders: u64,
+    /// Output bytes of every render that completed.
+    pub bytes: u64,
+    /// Output bytes of the largest one.
+    pub largest_bytes: u64,
+    /// Chunk memory held right now by renders in progress.
+    pub in_flight_bytes: u64,
+    /// The most chunk memory renders in progress have held at once.
+    pub peak_in_flight_bytes: u64,
+    /// Renders on the blocking pool right now, timed-out ones still running included.
+    pub blocking: usize,
+}
+
+static RENDERS: AtomicU64 = AtomicU64::new(0);
+static RENDERED_BYTES: AtomicU64 = AtomicU64::new(0);
+static LARGEST: AtomicU64 = AtomicU64::new(0);
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+static PEAK_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+static BLOCKING: AtomicUsize = AtomicUsize::new(0);
+
+/// One of the `RENDER_MAX_BLOCKING` permits of a counting semaphore kept across reloads,
+/// so renders left running by a previous state still count; given back on drop.
+struct BlockingPermit;
+
+impl BlockingPermit {
+    /// `None` while `max` renders hold one (`0` = no limit).
+    fn acquire(max: usize) -> Option<Self> {
+        BLOCKING.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (max == 0 || n < max).then_some(n + 1)).ok().map(|_| Self)
+    }
+}
+
+impl Drop for BlockingPermit {
+    fn drop(&mut self) {
+        BLOCKING.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// # render_stats()
+/// Counters kept by [`RenderLimits::render`] and [`RenderLimits::render_now`].
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// let before = templar::limits::render_stats().renders;
+/// let reg = handlebars::Handlebars::new();
+/// templar::limits::RenderLimits::default().render_now(&reg, "<p>Hi</p>", &HashMap::new()).unwrap();
+/// let stats = templar::limits::render_stats();
+/// assert!(stats.renders > before);
+/// assert!(stats.peak_in_flight_bytes >= 9);
+/// ```
+pub fn render_stats() -> RenderStats {
+    RenderStats {
+        renders: RENDERS.load(Ordering::Relaxed),
+        bytes: RENDERED_BYTES.load(Ordering::Relaxed),
+        largest_bytes: LARGEST.load(Ordering::Relaxed),
+        in_flight_bytes: IN_FLIGHT.load(Ordering::Relaxed) as u64,
+        peak_in_flight_bytes: PEAK_IN_FLIGHT.load(Ordering::Relaxed) as u64,
+        blocking: BLOCKING.load(Ordering::Acquire),
+    }
+}
+
+/// Collects the output in chunks, stopping the render at the size cap or after the deadline.
+struct ChunkedOutput {
+    chunks: Vec<String>,
+    /// Bytes written.
+    len: usize,
+    /// Bytes allocated for `chunks`, counted in [`IN_FLIGHT`].
+    held: usize,
+    max_bytes: usize,
+    deadline: Option<Instant>,
+    timeout: Duration,
+    /// Why the render was stopped, read back once it fails.
+    hit: Option<String>,
+}
+
+impl ChunkedOutput {
+    fn reserve(&mut self, capacity: usize) {
+        self.chunks.push(String::with_capacity(capacity));
+        self.held += capacity;
+        let now = IN_FLIGHT.fetch_add(capacity, Ordering::Relaxed) + capacity;
+        PEAK_IN_FLIGHT.fetch_max(now, Ordering::Relaxed);
+    }
+
+    /// The output as one string of exactly its length, releasing chunks as they are copied.
+    fn into_string(mut self) -> String {
+        RENDERS.fetch_add(1, Ordering::Relaxed);
+        RENDERED_BYTES.fetch_add(self.len as u64, Ordering::Relaxed);
+        LARGEST.fetch_max(self.len as u64, Ordering::Relaxed);
+        let mut out = String::with_capacity(self.len);
+        for chunk in std::mem::take(&mut self.chunks) {
+            out.push_str(&chunk);
+        }
+        out
+    }
+}
+
+impl Output for ChunkedOutput {
+    fn write(&mut self, seg: &str) -> io::Result<()> {
+        if self.deadline.is_some_and(|d| Instant::now() > d) {
+            self.hit = Some(format!("rendering took longer than RENDER_TIMEOUT_MS={}", self.timeout.as_millis()));
+        } else if self.max_bytes > 0 && self.len + seg.len() > self.max_bytes {
+            self.hit = Some(format!("output is larger than RENDER_MAX_BYTES={}", self.max_bytes));
+        }
+        if self.hit.is_some() {
+            return Err(io::Error::other("render limit"));
+        }
+        let fits = self.chunks.last().is_some_and(|c| c.capacity() - c.len() >= seg.len());
+        if !fits {
+            self.reserve(seg.len().max(self.len.clamp(FIRST_CHUNK_BYTES, CHUNK_BYTES)));
+        }
+        if let Some(chunk) = self.chunks.last_mut() {
+            chunk.push_str(seg);
+        }
+        self.len += seg.len();
+        Ok(())
+    }
+}
+
+impl Drop for ChunkedOutput {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(self.held, Ordering::Relaxed);
+    }
+}
+
+struct PartialWalk<'a> {
+    partials: HashMap<&'a str, &'a Template>,
+    max_depth: usize,
+}
+
+impl<'a> PartialWalk<'a> {
+    /// How deeply partials nest below `tpl`; `stack` holds the partials being rendered.
+    fn depth(&self, tpl: &'a Template, stack: &mut Vec<&'a str>) -> Result<usize, String> {
+        let mut deepest = 0;
+        for el in &tpl.elements {
+            let d = match el {
+                TemplateElement::PartialExpression(p) | TemplateElement::PartialBlock(p) => {
+                    // A block's content renders inside the partial, one level down
+                    let mut d = match &p.template {
+                        Some(t) => self.depth(t, stack)?,
+                        None => 0,
+                    };
+                    let names: Vec<&str> = match partial_name(&p.name) {
+                        // `@partial-block` is the caller's block, counted above
+                        Some(n) if n.starts_with('@') => Vec::new(),
+                        Some(n) => vec![n],
+                        // Chosen at render time: could be any of them
+                        None => self.partials.keys().copied().collect(),
+                    };
+                    for name in names {
+                        // Missing partials are left for Handlebars to report
+                        let Some(&partial) = self.partials.get(name) else { continue };
+                        if stack.contains(&name) {
+                            return Err(format!("partial `{name}` includes itself"));
+                        }
+                        if self.max_depth > 0 && stack.len() >= self.max_depth {
+                            return Err(format!("partials nest deeper than RENDER_MAX_PARTIAL_DEPTH={}", self.max_depth));
+                        }
+                        stack.push(name);
+                        d = d.max(self.depth(partial, stack)?);
+                        stack.pop();
+                    }
+                    d + 1
+                }
+                TemplateElement::HelperBlock(h) => {
+                    let mut d = 0;
+                    for t in h.template.iter().chain(h.inverse.iter()) {
+                        d = d.max(self.depth(t, stack)?);
+                    }
+                    d
+                }
+                // Inline partial bodies are walked where they are included
+                _ => 0,
+            };
+            deepest = deepest.max(d);
+        }
+        Ok(deepest)
+    }
+}
+
+/// `{{#*inline "name"}}` definitions anywhere in `tpl`.
+fn inline_partials<'a>(tpl: &'a Template, out: &mut HashMap<&'a str, &'a Template>) {
+    for el in &tpl.elements {
+        match el {
+            TemplateElement::DecoratorBlock(d) => {
+                if let (Some("inline"), Some(Parameter::Literal(Value::String(name))), Some(body)) =
+                    (d.name.as_name(), d.params.first(), &d.template)
+                {
+                    out.insert(name, body);
+                }
+                if let Some(t) = &d.template {
+                    inline_partials(t, out);
+                }
+            }
+            TemplateElement::PartialBlock(d) => {
+                if let Some(t) = &d.template {
+                    inline_partials(t, out);
+                }
+            }
+            TemplateElement::HelperBlock(h) => {
+                for t in h.template.iter().chain(h.inverse.iter()) {
+                    inline_partials(t, out);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Name of an included partial, `None` when a subexpression picks it at render time.
+fn partial_name(p: &Parameter) -> Option<&str> {
+    match p {
+        Parameter::Literal(Value::String(s)) => Some(s),
+        _ => p.as_name(),
+    }
+}
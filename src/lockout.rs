@@ -0,0 +1,242 @@
+//! Lockout of clients guessing the API key. Failed authentications (`401` answers) are
+//! counted per source: the client's IP (named by `X-Forwarded-For` when the connection
+//! comes from one of `TRUSTED_PROXIES`), or over a Unix socket the first characters of
+//! the key the request presents (`Authorization: Bearer` or `X-Api-Key`).
+//!
+//! `AUTH_LOCKOUT_THRESHOLD` failures lock the source out for `AUTH_LOCKOUT_SECS`. Each
+//! failure after that locks it out again for twice as long, up to
+//! `AUTH_LOCKOUT_MAX_SECS`; a source that goes `AUTH_LOCKOUT_MAX_SECS` without failing
+//! starts over. While locked out, every request from it except `/ready` is answered
+//! `429` with `Retry-After`, whatever key it presents. Each lockout is logged as a
+//! warning and sent to `WEBHOOK_URL` as an `auth_lockout` event. Counts are kept per
+//! instance, in memory.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::config::ApiConfig;
+use crate::email::SharedState;
+use crate::events::{AuthLockout as LockoutEvent, Event, EventKind};
+use crate::server::Peer;
+
+/// Characters of the presented key a source without an IP is told apart by.
+const KEY_PREFIX_CHARS: usize = 4;
+/// Sources tracked before quiet ones are forgotten early.
+const PRUNE_ABOVE: usize = 4096;
+
+/// Failure counts and lockouts per source (`AUTH_LOCKOUT_*`).
+#[derive(Debug)]
+pub struct AuthLockout {
+    threshold: u32,
+    base: Duration,
+    max: Duration,
+    proxies: Vec<IpAddr>,
+    sources: Mutex<HashMap<String, Entry>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    failures: u32,
+    /// Lockouts so far; the next one lasts `base * 2^lockouts`
+    lockouts: u32,
+    locked_until: Option<Instant>,
+    last_failure: Instant,
+}
+
+/// A lockout that just began.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lockout {
+    pub duration: Duration,
+    /// Failures counted from the source
+    pub failures: u32,
+}
+
+/// A source locked out right now, for `GET /admin/lockouts`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Locked {
+    pub source: String,
+    pub failures: u32,
+    pub lockouts: u32,
+    /// Seconds until it may try again
+    pub retry_after: u64,
+}
+
+impl AuthLockout {
+    pub fn from_config(config: &ApiConfig) -> Self {
+        // Validated by `ApiConfig::load`
+        let proxies = parse_proxies(&config.trusted_proxies).unwrap_or_default();
+        Self::new(config.auth_lockout_threshold, Duration::from_secs(config.auth_lockout_secs), Duration::from_secs(config.auth_lockout_max_secs), proxies)
+    }
+
+    /// # AuthLockout::new()
+    /// Lock a source out after `threshold` failures (`0` = never), for `base` and then
+    /// twice as long with each further failure, up to `max`.
+    /// # Example
+    /// ```
+    /// use std::time::{Duration, Instant};
+    /// use templar::lockout::AuthLockout;
+    /// let lockout = AuthLockout::new(3, Duration::from_secs(60), Duration::from_secs(3600), vec![]);
+    /// let now = Instant::now();
+    /// assert!(lockout.fail("203.0.113.9", now).is_none());
+    /// assert!(lockout.fail("203.0.113.9", now).is_none());
+    /// assert_eq!(lockout.fail("203.0.113.9", now).unwrap().duration, Duration::from_secs(60));
+    /// assert_eq!(lockout.retry_after("203.0.113.9", now), Some(60));
+    /// assert_eq!(lockout.retry_after("198.51.100.4", now), None);
+    /// // Once it ends, a single failure locks the source out again, for twice as long
+    /// let later = now + Duration::from_secs(61);
+    /// assert_eq!(lockout.retry_after("203.0.113.9", later), None);
+    /// assert_eq!(lockout.fail("203.0.113.9", later).unwrap().duration, Duration::from_secs(120));
+    /// ```
+    pub fn new(threshold: u32, base: Duration, max: Duration, proxies: Vec<IpAddr>) -> Self {
+        Self { threshold, base, max, proxies, sources: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.threshold > 0
+    }
+
+    /// Seconds `source` is still locked out for at `now`, at least 1; `None` when it is not.
+    pub fn retry_after(&self, source: &str, now: Instant) -> Option<u64> {
+        let sources = self.sources.lock().expect("lockout lock poisoned");
+        let until = sources.get(source)?.locked_until.filter(|until| *until > now)?;
+        Some((until - now).as_secs_f64().ceil().max(1.0) as u64)
+    }
+
+    /// # AuthLockout::fail()
+    /// Count a failed authentication from `source` at `now`; the lockout it starts, if any.
+    pub fn fail(&self, source: &str, now: Instant) -> Option<Lockout> {
+        let mut sources = self.sources.lock().expect("lockout lock poisoned");
+        if sources.len() > PRUNE_ABOVE {
+            sources.retain(|_, e| now.saturating_duration_since(e.last_failure) < self.max);
+        }
+        let fresh = Entry { failures: 0, lockouts: 0, locked_until: None, last_failure: now };
+        let entry = sources.entry(source.to_string()).or_insert(fresh);
+        if now.saturating_duration_since(entry.last_failure) >= self.max {
+            *entry = fresh;
+        }
+        entry.failures += 1;
+        entry.last_failure = now;
+        // Requests that were already running when the lockout began
+        if entry.locked_until.is_some_and(|until| until > now) {
+            return None;
+        }
+        if entry.lockouts == 0 && entry.failures < self.threshold {
+            return None;
+        }
+        let duration = self.base.saturating_mul(2u32.saturating_pow(entry.lockouts)).min(self.max);
+        entry.lockouts += 1;
+        entry.locked_until = Some(now + duration);
+        Some(Lockout { duration, failures: entry.failures })
+    }
+
+    /// The sources locked out at `now`, longest lockout first.
+    pub fn locked(&self, now: Instant) -> Vec<Locked> {
+        let sources = self.sources.lock().expect("lockout lock poisoned");
+        let mut locked: Vec<Locked> = sources
+            .iter()
+            .filter_map(|(source, e)| {
+                let until = e.locked_until.filter(|until| *until > now)?;
+                let retry_after = (until - now).as_secs_f64().ceil() as u64;
+                Some(Locked { source: source.clone(), failures: e.failures, lockouts: e.lockouts, retry_after })
+            })
+            .collect();
+        locked.sort_by(|a, b| b.retry_after.cmp(&a.retry_after).then_with(|| a.source.cmp(&b.source)));
+        locked
+    }
+
+    /// # AuthLockout::source()
+    /// Who a request counts against: the client IP, taken from `X-Forwarded-For` behind a
+    /// trusted proxy, or `key:<prefix>` of the key in `Authorization`/`X-Api-Key` without one.
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use axum::http::HeaderMap;
+    /// use templar::{lockout::AuthLockout, server::Peer};
+    /// let proxy = "10.0.0.2".parse().unwrap();
+    /// let lockout = AuthLockout::new(10, Duration::from_secs(60), Duration::from_secs(3600), vec![proxy]);
+    /// let mut headers = HeaderMap::new();
+    /// headers.insert("x-forwarded-for", "198.51.100.7, 203.0.113.9, 10.0.0.2".parse().unwrap());
+    /// assert_eq!(lockout.source(Peer(Some(proxy)), &headers), "203.0.113.9");
+    /// // Anyone else's X-Forwarded-For is not believed
+    /// assert_eq!(lockout.source(Peer(Some("192.0.2.1".parse().unwrap())), &headers), "192.0.2.1");
+    /// // Over a Unix socket, each presented key counts on its own
+    /// headers.insert("authorization", "Bearer tk_2f9a51".parse().unwrap());
+    /// assert_eq!(lockout.source(Peer(None), &headers), "key:tk_2");
+    /// assert_eq!(lockout.source(Peer(None), &HeaderMap::new()), "key:");
+    /// ```
+    pub fn source(&self, peer: Peer, headers: &HeaderMap) -> String {
+        match peer.0 {
+            Some(ip) if self.proxies.contains(&ip) => {
+                // The nearest address not added by one of our proxies is the client's
+                let forwarded = headers.get_all("x-forwarded-for").iter().filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(',')).collect::<Vec<_>>();
+                let client = forwarded.iter().rev().filter_map(|a| a.trim().parse::<IpAddr>().ok()).find(|a| !self.proxies.contains(a));
+                client.unwrap_or(ip).to_string()
+            }
+            Some(ip) => ip.to_string(),
+            // A Unix socket has no peer address: tell clients apart by the key they present
+            None => {
+                let key = crate::scopes::presented_key(headers).unwrap_or_default();
+                format!("key:{}", key.chars().take(KEY_PREFIX_CHARS).collect::<String>())
+            }
+        }
+    }
+}
+
+/// # parse_proxies()
+/// `TRUSTED_PROXIES`: IP addresses, comma-separated.
+pub fn parse_proxies(spec: &str) -> Result<Vec<IpAddr>, String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| p.parse::<IpAddr>().map_err(|_| format!("`{p}` is not an IP address")))
+        .collect()
+}
+
+fn locked_out(retry_after: u64) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after.to_string())],
+        Json(serde_json::json!({ "error": "too many failed authentications, retry later" })),
+    )
+        .into_response()
+}
+
+/// Middleware: answer `429` to locked-out sources, and count the `401`s of the others.
+pub async fn guard(State((lockout, state)): State<(Arc<AuthLockout>, SharedState)>, req: Request, next: Next) -> Response {
+    if !lockout.enabled() || req.uri().path() == "/ready" {
+        return next.run(req).await;
+    }
+    let peer = req.extensions().get::<Peer>().copied().unwrap_or(Peer(None));
+    let source = lockout.source(peer, req.headers());
+    if let Some(retry_after) = lockout.retry_after(&source, Instant::now()) {
+        return locked_out(retry_after);
+    }
+    let response = next.run(req).await;
+    if response.status() != StatusCode::UNAUTHORIZED {
+        return response;
+    }
+    if let Some(started) = lockout.fail(&source, Instant::now()) {
+        let secs = started.duration.as_secs();
+        tracing::warn!(source, failures = started.failures, secs, "Auth lockout: too many failed authentications");
+        if let Some(webhook) = state.load().webhook.clone() {
+            let event = Event::new(EventKind::AuthLockout(LockoutEvent { source, failures: started.failures, locked_secs: secs }));
+            tokio::spawn(async move {
+                if let Some(e) = webhook.deliver(serde_json::to_value(event).expect("events serialize")).await.error {
+                    tracing::warn!("Auth lockout alert not delivered: {e}");
+                }
+            });
+        }
+    }
+    response
+}
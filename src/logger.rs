@@ -1,48 +1,94 @@
 
 //! Logger configuration.
 
-use std::fs;
+use std::{fmt, fs, io};
 use std::fs::OpenOptions;
+use std::os::unix::net::UnixDatagram;
 use std::str::FromStr;
-use tracing_subscriber::{Layer, Registry, filter};
-use tracing::{debug, info, Level};
+use std::sync::{atomic::{AtomicU64, Ordering}, OnceLock};
+use serde::Serialize;
+use tracing_appender::non_blocking::{ErrorCounter, NonBlockingBuilder, WorkerGuard};
+use tracing_subscriber::{Layer, Registry, filter, filter::FilterExt, fmt::MakeWriter, layer::Filter, registry::LookupSpan};
+use tracing::{debug, info, Level, Metadata, Subscriber};
+use crate::config::LogFilter;
 use tracing_subscriber::layer::SubscriberExt;
 
+/// Where log output goes (`LOG_TARGETS`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogTarget {
+    Stdout,
+    /// `LOG_DIR/LOG_FILE`.
+    File,
+    /// The local syslog daemon through `/dev/log` (rsyslog, syslog-ng).
+    Syslog,
+    /// systemd-journald's native protocol, with structured fields.
+    Journald,
+}
+
+impl FromStr for LogTarget {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "stdout" => Ok(Self::Stdout),
+            "file" => Ok(Self::File),
+            "syslog" => Ok(Self::Syslog),
+            "journald" => Ok(Self::Journald),
+            other => Err(format!("unknown target `{other}`, expected stdout, file, syslog or journald")),
+        }
+    }
+}
+
+impl fmt::Display for LogTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Stdout => "stdout",
+            Self::File => "file",
+            Self::Syslog => "syslog",
+            Self::Journald => "journald",
+        })
+    }
+}
+
 /// Sets the logger global features for the application.
-/// * `lvl` - Log level (DEBUG, INFO, WARN, ERROR).
-/// * `tf` - Whether to log to file (true/false).
-/// * `ts` - Whether to log to stdout (true/false).
+/// * `lvl` - Log level (DEBUG, INFO, WARN, ERROR), or `EnvFilter` directives such as
+///   `info,templar::email=debug,hyper=warn`.
+/// * `targets` - Outputs to enable (stdout, file, syslog, journald).
 /// * `ld` - Directory to store log files (default: "logs").
 /// * `file` - Log file name (default: "app.log").
+/// * `buffer` - Lines the file writer may queue before dropping new ones. The file is
+///   written from a background thread, so a slow disk never stalls a request.
 /// # Usage
-/// At the start of the application, call this function to set up the logger.
-/// ```
+/// At the start of the application, call this function to set up the logger and
+/// keep the returned guard until exit.
+/// ```ignore
 /// use templar::logger::set_logger;
-/// set_logger(level, to_file, to_stdout, log_dir, log_file).unwrap();
+/// let _logger = set_logger(level, &targets, log_dir, log_file, buffer).unwrap();
 /// ```
 /// # Example
-/// ```
-/// use templar::logger::set_logger;
-/// set_logger("INFO", true, true, "logs", "app.log").unwrap();
+/// ```no_run
+/// use templar::logger::{set_logger, LogTarget};
+/// let _logger = set_logger("INFO".into(), &[LogTarget::Stdout, LogTarget::File], "logs".into(), "app.log".into(), 128_000).unwrap();
 /// ```
 /// # Errors
-/// 1) Returns an error if the log directory cannot be created or the log file cannot be opened.
-/// 2) Returns an error if the global subscriber cannot be set.
+/// 1) Returns an error if `lvl` does not parse, or if the log directory cannot be created or the log file cannot be opened.
+/// 2) Returns an error if syslog or journald is requested but its socket is not there.
+/// 3) Returns an error if the global subscriber cannot be set (see [`try_set_logger()`]).
 pub fn set_logger(
     lvl:String,
-    tf:bool,
-    ts:bool,
+    targets:&[LogTarget],
     ld:String,
-    file:String
-    ) -> Result<(), Box<dyn std::error::Error>>{
+    file:String,
+    buffer:usize
+    ) -> Result<LoggerGuard, Box<dyn std::error::Error>>{
+    let tf = targets.contains(&LogTarget::File);
+    let ts = targets.contains(&LogTarget::Stdout);
 
-    // Set up the log level and filter.
-    let ll = Level::from_str(&lvl).unwrap_or(Level::INFO);
-    let lf = filter::LevelFilter::from_level(ll);
+    // Set up the log level and filter; each layer gets its own copy.
+    let ll: LogFilter = lvl.parse()?;
 
     // If stdout logging is enabled, set up the stdout logging layer.
     let lys  = if ts{
-        let lys = tracing_subscriber::fmt::layer().compact().with_ansi(true).with_filter(lf.clone());
+        let lys = tracing_subscriber::fmt::layer().compact().with_ansi(true).with_filter(ll.env_filter().and(sampling()));
         Some(lys)
     }else {None};
 
@@ -52,9 +98,14 @@ pub fn set_logger(
     // If file logging is enabled, create the directory.
     if tf{fs::create_dir_all(&ld)?;}
     // If file logging is enabled, set up the file logging layer.
+    let mut workers = Vec::new();
     let lyf = if tf{
         let f = OpenOptions::new().append(true).create(true).open(p.clone())?;
-        let lyf = tracing_subscriber::fmt::layer().compact().with_ansi(false).with_writer(f).with_filter(lf.clone());
+        // Lossy: when `buffer` lines are queued, new ones are counted and dropped instead of blocking
+        let (w, guard) = NonBlockingBuilder::default().buffered_lines_limit(buffer.max(1)).lossy(true).thread_name("templar-log-file").finish(f);
+        let _ = FILE_DROPS.set(w.error_counter());
+        workers.push(guard);
+        let lyf = tracing_subscriber::fmt::layer().compact().with_ansi(false).with_writer(w).with_filter(ll.env_filter().and(sampling()));
         Some(lyf)
     }else{None};
     const BANNER: &str = r#"
@@ -67,12 +118,152 @@ pub fn set_logger(
 |                   /_/                    |
 |-----------@isopropilick - 2025-----------|
     "#;
-    let s = Registry::default().with(lys).with(lyf);
+    // Syslog gets the bare message; the daemon adds time, host and severity.
+    let lysl = if targets.contains(&LogTarget::Syslog){
+        let lysl = tracing_subscriber::fmt::layer().compact().with_ansi(false).without_time().with_level(false).with_writer(Syslog::connect()?).with_filter(ll.env_filter().and(sampling()));
+        Some(lysl)
+    }else{None};
+    let lyj = if targets.contains(&LogTarget::Journald){
+        let lyj = tracing_journald::layer().map_err(|e| format!("journald: cannot connect to /run/systemd/journal/socket: {e}"))?.with_syslog_identifier("templar".into()).with_filter(ll.env_filter().and(sampling()));
+        Some(lyj)
+    }else{None};
+    let s = Registry::default().with(lys).with(lyf).with(lysl).with(lyj);
     tracing::subscriber::set_global_default(s)?;
     info!("{}",BANNER);
     info!("Logger initialized, log level set to: {}",ll);
     if ts{debug!("Logging to stdout.")}
     if tf{debug!("Logging to file: {}", p.replace("\\","\\\\"))}
-    Ok(())
+    if targets.contains(&LogTarget::Syslog){debug!("Logging to syslog.")}
+    if targets.contains(&LogTarget::Journald){debug!("Logging to journald.")}
+    Ok(LoggerGuard { _workers: workers })
+
+}
+
+/// # try_set_logger()
+/// [`set_logger()`], but a no-op returning `None` when a global subscriber is already
+/// set, so tests and embedding applications can call it as often as they like.
+/// # Example
+/// ```
+/// use templar::logger::{try_set_logger, LogTarget};
+/// let first = try_set_logger("info".into(), &[LogTarget::Stdout], "logs".into(), "app.log".into(), 1024).unwrap();
+/// assert!(first.is_some());
+/// let again = try_set_logger("debug".into(), &[LogTarget::Stdout], "logs".into(), "app.log".into(), 1024).unwrap();
+/// assert!(again.is_none());
+/// ```
+/// # Errors
+/// Same as [`set_logger()`], except when the subscriber is already set.
+pub fn try_set_logger(
+    lvl:String,
+    targets:&[LogTarget],
+    ld:String,
+    file:String,
+    buffer:usize
+    ) -> Result<Option<LoggerGuard>, Box<dyn std::error::Error>>{
+    // Skip opening files and sockets when there is nothing to install them into
+    if tracing::dispatcher::has_been_set(){return Ok(None)}
+    match set_logger(lvl, targets, ld, file, buffer){
+        Ok(g) => Ok(Some(g)),
+        // Lost a race with another caller
+        Err(e) if e.is::<tracing::subscriber::SetGlobalDefaultError>() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returned by [`set_logger()`]; keep it alive until the process exits. Dropping it
+/// flushes the file writer's queue and stops its thread.
+#[must_use = "dropping the guard early can lose buffered log lines"]
+pub struct LoggerGuard {
+    _workers: Vec<WorkerGuard>,
+}
+
+/// Lines the file writer queued past its buffer (set once the file target is up).
+static FILE_DROPS: OnceLock<ErrorCounter> = OnceLock::new();
+/// Syslog datagrams the daemon had no room for.
+static SYSLOG_DROPS: AtomicU64 = AtomicU64::new(0);
+
+/// Log lines lost since start because an output could not keep up.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct DroppedLines {
+    pub file: u64,
+    pub syslog: u64,
+}
+
+/// # dropped_lines()
+/// Counters behind `GET /admin/logging`.
+pub fn dropped_lines() -> DroppedLines {
+    DroppedLines {
+        file: FILE_DROPS.get().map_or(0, |c| c.dropped_lines() as u64),
+        syslog: SYSLOG_DROPS.load(Ordering::Relaxed),
+    }
+}
+
+/// Drops the events `request_log` sampled out (see `LOG_SAMPLE_RATE`).
+fn sampling<S: Subscriber + for<'a> LookupSpan<'a>>() -> impl Filter<S> {
+    filter::dynamic_filter_fn(|meta, cx| {
+        meta.is_span() || !cx.lookup_current().is_some_and(|span| crate::request_log::sampled_out(meta, span.scope().map(|s| s.metadata())))
+    })
+}
+
+/// Syslog socket, one datagram per event with the priority taken from its level.
+pub struct Syslog {
+    socket: UnixDatagram,
+}
+
+impl Syslog {
+    /// Connect to the local daemon at `/dev/log`. Sends never block: a line the
+    /// daemon has no room for is dropped and counted.
+    pub fn connect() -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect("/dev/log").map_err(|e| io::Error::new(e.kind(), format!("syslog: cannot connect to /dev/log: {e}")))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket })
+    }
+}
+
+/// One event being formatted for [`Syslog`]; sent when dropped.
+pub struct SyslogLine<'a> {
+    socket: &'a UnixDatagram,
+    severity: u8,
+    buf: Vec<u8>,
+}
+
+impl io::Write for SyslogLine<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SyslogLine<'_> {
+    fn drop(&mut self) {
+        let text = String::from_utf8_lossy(&self.buf);
+        // Facility 3 (daemon), RFC 3164 framing
+        let line = format!("<{}>templar[{}]: {}", 3 * 8 + self.severity, std::process::id(), text.trim_end());
+        // Logging must never fail or stall the caller; a full or missing socket drops the line.
+        if self.socket.send(line.as_bytes()).is_err() {
+            SYSLOG_DROPS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = SyslogLine<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogLine { socket: &self.socket, severity: 6, buf: Vec::new() }
+    }
 
-}
\ No newline at end of file
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let severity = match *meta.level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            _ => 7,
+        };
+        SyslogLine { socket: &self.socket, severity, buf: Vec::new() }
+    }
+}
@@ -1,44 +1,682 @@
 //! Binary entrypoint: loads config, sets up logging, builds Axum app, and serves `/send`.
-use std::{net::SocketAddr, sync::Arc};
-use axum::{routing::post, Router};
+use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
+use axum::{extract::DefaultBodyLimit, middleware, routing::{delete, get, post, put}, serve::ListenerExt, Extension, Router};
+use arc_swap::ArcSwap;
 use dotenvy::dotenv;
-use tracing::{debug, info};
-use templar::{email,routes,logger,config::get_defaults as df};
-use templar::config::ApiConfig;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::{TcpListener, UnixListener}, task::JoinSet};
+use tower_http::{compression::CompressionLayer, decompression::RequestDecompressionLayer};
+use tracing::{debug, error, info, warn};
+use templar::{alerts,apikeys,backpressure,bluegreen,campaigns,digests,email,feedback,i18n,lockout,maintenance,outbox,routes,logger,request_log,retention,scaling,scheduled,scopes,secrets,server,spool,storage,systemd,version,config};
+use templar::config::{ApiConfig, Mode};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    fn env_var(name: &str) -> Option<String> {
-        std::env::var(name).ok()
-    }
-    // 1) Load environment (.env is optional)
+    // 1) Load environment (.env is optional); take systemd-passed sockets first
+    let activated = systemd::listen_fds()?;
     dotenv().ok();
-    let config:ApiConfig = ApiConfig::from(df());
-    let lvl = env_var("LOG_LEVEL").unwrap_or(config.log_level);
-    let tf = env_var("LOG_TO_FILE").unwrap_or(config.log_to_file.to_string())== "true";
-    let ts = env_var("LOG_TO_STDOUT").unwrap_or(config.log_to_stdout.to_string())== "true";
-    let ld = env_var("LOG_DIR").unwrap_or(config.log_dir);
-    let file = env_var("LOG_FILE").unwrap_or(config.log_file);
+    // Subcommands run instead of the server; `doctor` reports a bad config instead of failing on it
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("doctor") {
+        return doctor_command().await;
+    }
+    // Settings held in Vault or AWS Secrets Manager win over the environment, so they are read first
+    let secrets = match secrets::SecretStore::from_env()? {
+        Some(store) => {
+            let loaded = store.load().await.map_err(|e| anyhow::anyhow!("cannot read secrets: {e}"))?;
+            Some((store, loaded))
+        }
+        None => None,
+    };
+    let config = ApiConfig::load()?;
+    match args.first().map(String::as_str) {
+        Some("render-all") => return render_all_command(&args[1..], &config),
+        Some("send") => return send_command(&args[1..], &config).await,
+        Some("bulk") => return bulk_command(&args[1..], &config).await,
+        Some("--migrate-only") => return migrate_command(&config).await,
+        Some(other) => anyhow::bail!("unknown command `{other}` (available: render-all, send, bulk, doctor, --migrate-only)"),
+        None => {}
+    }
     // 2) Set up logging
-    logger::set_logger(lvl,tf,ts,ld,file).unwrap();
-    // 3) Build app state (SMTP client, addresses, templates path) from env
-    let state = Arc::new(email::EmailState::from_env()?);
-    debug!("Templates directory: {}", state.templates_dir.display());
-    // 4) Router
-    let app = Router::new()
-        .route("/send", post(routes::send_email))
-        .with_state(state);
-
-    // 5) Bind address
-    let ip = env_var("LISTEN_ADDR").unwrap_or(config.listen_addr);
-    let port = env_var("LISTEN_PORT").unwrap_or(config.listen_port.to_string());
-    let addr: SocketAddr = format!("{ip}:{port}").parse()?;
-
-    info!("Starting server on {addr}");
-
-    // 6) Serve
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app.into_make_service()).await?;
+    let _logger = logger::set_logger(config.log_level.to_string(), &config.log_targets, config.log_dir.clone(), config.log_file.clone(), config.log_buffer_lines).map_err(|e| anyhow::anyhow!("cannot set up logging: {e}"))?;
+    let deprecated = config::deprecated_vars();
+    if !deprecated.is_empty() {
+        warn!("Unprefixed settings are deprecated, rename them to {}<NAME>: {}", config::ENV_PREFIX, deprecated.join(", "));
+    }
+    for (old, new) in config::renamed_vars() {
+        warn!("{old} is deprecated, use {new}");
+    }
+    #[cfg(feature = "faults")]
+    templar::faults::warn_if_enabled();
+    if let Some((store, loaded)) = secrets {
+        info!("Secrets: {} setting(s) read from {}", loaded.settings, store.source());
+        secrets::spawn_refresh(store, loaded);
+    }
+    // 3) Build app state (SMTP client, addresses, templates path) from the config
+    let state: email::SharedState = Arc::new(ArcSwap::from_pointee(email::EmailState::from_config(&config)?));
+    let build = version::BuildInfo::current();
+    info!(
+        "Templar {} ({}, built {}), features: [{}], transport: {}",
+        build.version, build.git_commit, build.built_at, build.features.join(", "), state.load().mailer.kind()
+    );
+    debug!("Templates directory: {}", state.load().templates_dir.display());
+    // 4) Storage, then retention purges and digest flushes in the background
+    let store = open_store(&config).await?;
+    info!("Storage: {}, recipients kept: {}", store.backend(), config.history_pii_mode);
+    // Sends the last shutdown cut short go out again as scheduled sends
+    let spool = Path::new(&config.shutdown_spool_file);
+    match spool::reload(spool, &*store).await {
+        Ok(0) => {}
+        Ok(n) => info!("Shutdown spool: {n} send(s) from {} scheduled", spool.display()),
+        Err(e) => error!("Shutdown spool {} not reloaded: {e}", spool.display()),
+    }
+    // Blue/green template roots: serve the one last promoted, and follow other instances' promotions
+    let roots = bluegreen::TemplateRoots::from_config(&config);
+    if let Some(roots) = &roots {
+        roots.follow(&state, &*store).await?;
+        info!("Templates: {} active, {} staged", roots.active().display(), roots.staged().display());
+        bluegreen::spawn_follow(roots.clone(), state.clone(), store.clone());
+    }
+    // API keys made at runtime, followed so revocations on other instances apply here too
+    let stored_keys = apikeys::refresh(&*store).await?;
+    if stored_keys > 0 {
+        info!("API keys: {stored_keys} from storage");
+    }
+    apikeys::spawn_refresh(store.clone());
+    spawn_reload_on_sighup(state.clone(), roots.clone());
+    retention::spawn_purge_task(state.clone(), store.clone(), Duration::from_secs(config.purge_interval_secs));
+    digests::spawn_flush_task(state.clone(), Duration::from_secs(config.digest_interval_secs));
+    if let Some(alerts) = alerts::Alerts::from_config(&config)? {
+        alerts::spawn_watcher(alerts);
+    }
+    // MODE=worker runs only the background tasks above, plus the admin listener if configured
+    let worker = config.mode == Mode::Worker;
+    // 5) Routers: the public `/send` surface and the operational `/admin/*` endpoints
+    let inflight = Arc::new(backpressure::InFlight::new(config.max_in_flight));
+    let scaling = Arc::new(scaling::Scaling::new(&config, inflight.clone()));
+    let maintenance = Arc::new(maintenance::Maintenance::new(config.maintenance_mode));
+    if config.maintenance_mode {
+        warn!("Starting in maintenance mode, /send answers 503");
+    }
+    let campaigns = Arc::new(campaigns::Campaigns::from_config(&config));
+    // Cancelled campaigns stay refused after a restart, and follow other instances' cancellations
+    let cancelled = campaigns::refresh(&campaigns, &*store).await?;
+    if cancelled > 0 {
+        info!("Campaigns: {cancelled} cancelled");
+    }
+    campaigns::spawn_refresh(campaigns.clone(), store.clone());
+    // Scheduled sends count towards campaigns like direct ones
+    let fair = Arc::new(scheduled::FairShare::from_config(&config));
+    scheduled::spawn_scheduler(state.clone(), store.clone(), campaigns.clone(), fair.clone());
+    if !config.outbox_url.trim().is_empty() {
+        let outbox = outbox::Outbox::connect(&config).await?;
+        outbox::spawn_consumer(state.clone(), store.clone(), campaigns.clone(), outbox);
+    }
+    if let Some(inbox) = feedback::FeedbackInbox::from_config(&config)? {
+        feedback::spawn_poller(store.clone(), inbox);
+    }
+    let public = Router::new()
+        .route("/send", post(routes::send_email)
+            .route_layer(middleware::from_fn_with_state(inflight.clone(), backpressure::limit))
+            .route_layer(middleware::from_fn_with_state(maintenance.clone(), maintenance::guard))
+            .route_layer(Extension(store.clone()))
+            .layer(Extension(campaigns.clone())))
+        .route("/render/eml", post(routes::render_eml))
+        .route("/feedback/arf", post(routes::ingest_feedback).route_layer(Extension(store.clone())))
+        .route("/ready", get(backpressure::ready).with_state(inflight.clone()).layer(Extension(maintenance.clone())))
+        .route("/version", get(routes::version))
+        .route("/digests/{key}/events", post(routes::queue_digest_event))
+        .route("/campaigns/{id}/stats", get(routes::campaign_stats).with_state(campaigns.clone()))
+        .route("/assets/{*path}", get(routes::get_asset))
+        .route("/templates", get(routes::list_templates))
+        .route("/templates/{name}/archive", post(routes::archive_template))
+        .route("/templates/{name}/restore", post(routes::restore_template))
+        .route("/templates/{name}/spamcheck", post(routes::spamcheck_template));
+    let admin = Router::new()
+        .route("/admin/config", get(routes::show_config))
+        .route("/admin/logging", get(routes::logging_stats))
+        .route("/admin/deliverability", get(routes::deliverability))
+        .route("/admin/warmup", get(routes::warmup_stats))
+        .route("/admin/providers", get(routes::provider_stats))
+        .route("/admin/rendering", get(routes::rendering_stats))
+        .route("/admin/connections", get(routes::connection_stats))
+        .route("/admin/maintenance", get(routes::show_maintenance).post(routes::set_maintenance).with_state(maintenance))
+        .route("/admin/purge", post(routes::purge))
+        .route("/admin/campaigns/{id}/cancel", post(routes::cancel_campaign).with_state(campaigns).layer(Extension(store.clone())))
+        .route("/admin/digests/flush", post(routes::flush_digests))
+        .route("/admin/queue", get(routes::queue_stats).layer(Extension(inflight.clone())))
+        .route("/admin/scaling", get(routes::scaling_signal).layer(Extension(scaling)))
+        .route("/admin/queue/pause", post(routes::pause_queue))
+        .route("/admin/queue/resume", post(routes::resume_queue))
+        .route("/admin/webhooks/test", post(routes::test_webhook))
+        .route("/admin/suppressions", get(routes::list_suppressions))
+        .route("/admin/api-keys", post(routes::create_api_key).get(routes::list_api_keys))
+        .route("/admin/api-keys/{kid}", delete(routes::revoke_api_key))
+        .route("/admin/suppressions/{email}", put(routes::suppress).delete(routes::unsuppress))
+        .route("/admin/messages/{id}", get(routes::get_message))
+        .route("/admin/stats", get(routes::message_stats))
+        .route("/admin/data/{email}", delete(routes::delete_data))
+        .route("/admin/templates/promote", post(routes::promote_templates).layer(Extension(roots)))
+        .layer(Extension(store))
+        .layer(Extension(fair));
+    // With ADMIN_LISTEN_PORT set (or a systemd socket named `admin`), admin routes only exist on their own listener
+    let admin_port = config.admin_listen_port;
+    let admin_socket = activated.iter().any(|s| s.name == "admin");
+    let lockout = Arc::new(lockout::AuthLockout::from_config(&config));
+    let admin = admin.route("/admin/lockouts", get(routes::list_lockouts).layer(Extension(lockout.clone())));
+    let guarded = (lockout, state.clone());
+    let (public, admin) = if admin_port.is_none() && !admin_socket {
+        (public.merge(admin).with_state(state), None)
+    } else {
+        (public.with_state(state.clone()), Some(admin.with_state(state)))
+    };
+    // Bodies may arrive gzip- or zstd-encoded, and MAX_BODY_BYTES caps them once decompressed;
+    // responses are compressed for clients that accept it
+    // BODY_READ_TIMEOUT_SECS bounds how long the decompressed body may take to arrive
+    let body_deadline = (Duration::from_secs(config.body_read_timeout_secs), config.max_body_bytes);
+    let http = |r: Router| if body_deadline.0.is_zero() { r } else { r.layer(middleware::from_fn_with_state(body_deadline, server::body_deadline)) };
+    let http = |r: Router| http(r).layer(DefaultBodyLimit::max(config.max_body_bytes)).layer(RequestDecompressionLayer::new()).layer(CompressionLayer::new());
+    // Every route but /ready and assets needs a key (401); `send` keys get 403 on admin routes and template changes
+    let scoped = |r: Router| r.layer(middleware::from_fn(scopes::guard));
+    let (public, admin) = (scoped(public), admin.map(scoped));
+    // Sources that keep failing to authenticate are turned away before anything else runs
+    let guard = |r: Router| r.layer(middleware::from_fn_with_state(guarded.clone(), lockout::guard));
+    let (public, admin) = (guard(public), admin.map(guard));
+    // Error messages in the client's language (ERROR_LANGUAGES), before responses are compressed
+    let l10n = Arc::new(i18n::Localizer::from_config(&config));
+    let localized = |r: Router| r.layer(middleware::from_fn_with_state(l10n.clone(), i18n::localize));
+    let (public, admin) = (localized(public), admin.map(localized));
+    let (public, admin) = (http(public), admin.map(http));
+    // Every request gets a `request` span; LOG_SAMPLE_RATE decides which are logged fully
+    let sampler = Arc::new(request_log::Sampler::new(config.log_sample_rate));
+    let public = public.layer(middleware::from_fn_with_state(sampler.clone(), request_log::log_request));
+    let admin = admin.map(|a| a.layer(middleware::from_fn_with_state(sampler, request_log::log_request)));
+
+    // 6) Serve; the first listener to fail stops the process, and so does SIGTERM/SIGINT
+    let mut servers = JoinSet::new();
+    let (stop, stopped) = tokio::sync::watch::channel(false);
+    let until_stopped = || {
+        let mut stopped = stopped.clone();
+        async move {
+            let _ = stopped.wait_for(|s| *s).await;
+        }
+    };
+    let uds = config.listen_uds.clone();
+    // HTTP/2, keep-alive and TCP_NODELAY settings, and TLS for the TCP listeners when configured
+    let tuning = Arc::new(server::ServerTuning::from_config(&config));
+    let tls = match (&config.tls_cert_file, &config.tls_key_file) {
+        (Some(cert), Some(key)) => Some(server::tls_acceptor(cert, key, tuning.http2)?),
+        _ => None,
+    };
+    let nodelay = tuning.tcp_nodelay;
+    let tcp = move |l: TcpListener| l.tap_io(move |s| { let _ = s.set_nodelay(nodelay); });
+    if !activated.is_empty() {
+        // Socket activation: systemd's sockets replace LISTEN_*; one named `admin` replaces ADMIN_LISTEN_*
+        for socket in activated {
+            let app = match (&admin, socket.name.as_str()) {
+                (Some(admin), "admin") => admin.clone(),
+                _ if worker => {
+                    warn!("Worker mode: ignoring systemd socket `{}`", socket.name);
+                    continue;
+                }
+                _ => public.clone(),
+            };
+            info!("Starting server on systemd socket `{}`", socket.name);
+            match socket.listener {
+                systemd::ActivatedListener::Tcp(l) => {
+                    l.set_nonblocking(true)?;
+                    let listener = tcp(TcpListener::from_std(l)?);
+                    let (stopping, tuning, tls) = (until_stopped(), tuning.clone(), tls.clone());
+                    servers.spawn(async move { server::serve(listener, app, &tuning, tls, stopping).await });
+                }
+                systemd::ActivatedListener::Unix(l) => {
+                    l.set_nonblocking(true)?;
+                    let listener = UnixListener::from_std(l)?;
+                    let (stopping, tuning) = (until_stopped(), tuning.clone());
+                    servers.spawn(async move { server::serve(listener, app, &tuning, None, stopping).await });
+                }
+            }
+        }
+    } else if worker {
+        info!("Running in worker mode, no public listener");
+    } else if let Some(uds) = uds {
+        // Unix domain socket instead of TCP for the public routes
+        let listener = bind_uds(&uds, config.listen_uds_mode)?;
+        info!("Starting server on unix:{}", uds.display());
+        let (stopping, tuning) = (until_stopped(), tuning.clone());
+        servers.spawn(async move { server::serve(listener, public, &tuning, None, stopping).await });
+    } else {
+        // One listener per entry in LISTEN_ADDR
+        for &addr in &config.listen_addr {
+            let listener = tcp(bind(addr)?);
+            info!("Starting server on {}://{addr}", if tls.is_some() { "https" } else { "http" });
+            let app = public.clone();
+            let (stopping, tuning, tls) = (until_stopped(), tuning.clone(), tls.clone());
+            servers.spawn(async move { server::serve(listener, app, &tuning, tls, stopping).await });
+        }
+    }
+    if let Some(admin) = admin.filter(|_| !admin_socket) {
+        for &addr in &config.admin_listen_addr {
+            let listener = tcp(bind(addr)?);
+            info!("Starting admin server on {}://{addr}", if tls.is_some() { "https" } else { "http" });
+            let app = admin.clone();
+            let (stopping, tuning, tls) = (until_stopped(), tuning.clone(), tls.clone());
+            servers.spawn(async move { server::serve(listener, app, &tuning, tls, stopping).await });
+        }
+    }
+    systemd::notify("READY=1");
+    systemd::spawn_watchdog();
+    // A worker without an admin listener has no servers and just waits for the signal
+    tokio::select! {
+        Some(result) = servers.join_next() => result??,
+        _ = shutdown_signal() => {}
+    }
+
+    // 7) Stop accepting, let running sends finish for up to SHUTDOWN_GRACE_SECS, then report
+    let grace = Duration::from_secs(config.shutdown_grace_secs);
+    let running = inflight.in_flight();
+    info!(connections = server::connection_stats().open, "Shutting down, waiting up to {}s for {running} running sends", grace.as_secs());
+    systemd::notify("STOPPING=1");
+    let _ = stop.send(true);
+    let drained = tokio::time::timeout(grace, async {
+        while let Some(result) = servers.join_next().await {
+            if let Ok(Err(e)) = result {
+                warn!("Listener stopped with an error: {e}");
+            }
+        }
+    })
+    .await;
+    let abandoned = inflight.in_flight();
+    let connections = server::connection_stats().open;
+    if drained.is_ok() {
+        info!(drained = running, spooled = 0, connections, "Shutdown complete");
+    } else {
+        match spool::write(spool) {
+            Ok(spooled) => warn!(
+                drained = running.saturating_sub(abandoned),
+                spooled,
+                connections,
+                "Grace period over, sends still running spooled to {} for the next start; their clients get no answer",
+                spool.display()
+            ),
+            Err(e) => error!(
+                drained = running.saturating_sub(abandoned),
+                abandoned,
+                connections,
+                "Grace period over, sends still running are lost, {} not written: {e}",
+                spool.display()
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolve on the first SIGTERM (systemd, Kubernetes, `docker stop`) or SIGINT (Ctrl-C).
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut term) => tokio::select! {
+            _ = term.recv() => info!("SIGTERM received"),
+            _ = tokio::signal::ctrl_c() => info!("SIGINT received"),
+        },
+        Err(e) => {
+            error!("SIGTERM handler not installed, only Ctrl-C stops the server: {e}");
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+/// Bind a TCP listener; the IPv6 unspecified address (`[::]`) also accepts IPv4.
+fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Bind a Unix domain socket at `path` with `mode` permissions. A socket file left
+/// behind by a previous run is removed first; any other file at `path` is an error.
+fn bind_uds(path: &Path, mode: u32) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// On SIGHUP, or when refreshed secrets changed, re-read `.env` and rebuild the email
+/// state (transports, addressing, templates, retention) without touching the listeners. Requests already running
+/// keep the state they started with; a failed rebuild keeps the current one. With
+/// blue/green template roots, the active one is kept whatever `.env` says.
+fn spawn_reload_on_sighup(state: email::SharedState, roots: Option<Arc<bluegreen::TemplateRoots>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => return error!("SIGHUP handler not installed, reload disabled: {e}"),
+    };
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                hangup = hangups.recv() => match hangup {
+                    Some(()) => info!("SIGHUP received, reloading configuration"),
+                    None => break,
+                },
+                () = secrets::changed() => info!("Secrets changed, reloading configuration"),
+            }
+            systemd::notify("RELOADING=1");
+            dotenvy::dotenv_override().ok();
+            let fresh = ApiConfig::load().and_then(|mut config| {
+                if let Some(roots) = &roots {
+                    roots.apply(&mut config);
+                }
+                email::EmailState::from_config(&config)
+            });
+            match fresh {
+                Ok(fresh) => {
+                    state.store(Arc::new(fresh));
+                    info!("Configuration reloaded");
+                }
+                Err(e) => error!("Reload failed, keeping the previous configuration: {e}"),
+            }
+            systemd::notify("READY=1");
+        }
+    });
+}
 
+/// `templar render-all --vars-dir DIR [--golden-dir DIR] [--templates-dir DIR] [--update]`
+/// Render every template with its fixture and compare against golden HTML; fails on any mismatch.
+fn render_all_command(args: &[String], config: &ApiConfig) -> anyhow::Result<()> {
+    use templar::golden::{render_all, GoldenOutcome};
+    let mut templates_dir = std::path::PathBuf::from(&config.templates_dir);
+    let (mut vars_dir, mut golden_dir, mut update) = (None, None, false);
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let mut value = || it.next().map(std::path::PathBuf::from).ok_or_else(|| anyhow::anyhow!("{arg} needs a value"));
+        match arg.as_str() {
+            "--vars-dir" => vars_dir = Some(value()?),
+            "--golden-dir" => golden_dir = Some(value()?),
+            "--templates-dir" => templates_dir = value()?,
+            "--update" => update = true,
+            other => anyhow::bail!("unknown render-all option `{other}`"),
+        }
+    }
+    let vars_dir = vars_dir.ok_or_else(|| anyhow::anyhow!("render-all needs --vars-dir"))?;
+    let golden_dir = golden_dir.unwrap_or_else(|| vars_dir.join("golden"));
+
+    let report = render_all(&templates_dir, &email::Helpers::from_config(config), &vars_dir, &golden_dir, update)?;
+    for (name, outcome) in &report.results {
+        match outcome {
+            GoldenOutcome::Matched => println!("ok        {name}"),
+            GoldenOutcome::Written => println!("written   {name}"),
+            GoldenOutcome::NoFixture => println!("skipped   {name} (no {name}.json fixture)"),
+            GoldenOutcome::Failed { error } => println!("FAILED    {name}: {error}"),
+            GoldenOutcome::Mismatched { line, expected, actual } => {
+                println!("MISMATCH  {name} at line {line}\n  expected: {expected}\n  actual:   {actual}")
+            }
+        }
+    }
+    if !report.passed() {
+        anyhow::bail!("golden check failed (rerun with --update to accept the new output)");
+    }
+    Ok(())
+}
+
+/// `templar --migrate-only`
+/// Apply pending storage migrations to `STORAGE_URL` and exit, whatever
+/// `STORAGE_AUTO_MIGRATE` says (deploy hooks, DBA-run schema changes).
+async fn migrate_command(config: &ApiConfig) -> anyhow::Result<()> {
+    let store = storage::connect(&config.storage_url).await?;
+    let applied = store.migrate().await?;
+    if applied.is_empty() {
+        println!("{}: schema up to date", store.backend());
+    } else {
+        for migration in &applied {
+            println!("{}: applied {migration}", store.backend());
+        }
+    }
+    Ok(())
+}
+
+/// Open `STORAGE_URL` as the server does: migrations as `STORAGE_AUTO_MIGRATE` says, then
+/// recipients kept as `HISTORY_PII_MODE` says.
+async fn open_store(config: &ApiConfig) -> anyhow::Result<storage::Store> {
+    storage::protect_recipients(config.history_pii_mode, &config.history_pii_secret)?;
+    Ok(storage::open(&config.storage_url, config.storage_auto_migrate).await?)
+}
+
+/// The campaigns of a CLI run, refusing those cancelled in `store`.
+async fn cli_campaigns(config: &ApiConfig, store: &dyn storage::Storage) -> anyhow::Result<campaigns::Campaigns> {
+    let campaigns = campaigns::Campaigns::from_config(config);
+    campaigns::refresh(&campaigns, store).await?;
+    Ok(campaigns)
+}
+
+/// `templar send --to ADDR[,ADDR..] --template NAME [--subject TEXT] [--vars JSON] [--campaign ID] [--preheader TEXT]`
+/// Send one message as `/send` would, without the server: suppressions, preferences,
+/// warm-up and fallback channels apply, and the message is recorded in `STORAGE_URL`.
+/// Prints the `/send` response body; fails on an error or when any recipient was rejected.
+async fn send_command(args: &[String], config: &ApiConfig) -> anyhow::Result<()> {
+    let (mut to, mut template, mut subject, mut vars) = (None, None, String::new(), std::collections::HashMap::new());
+    let (mut campaign_id, mut preheader) = (None, None);
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let mut value = || it.next().cloned().ok_or_else(|| anyhow::anyhow!("{arg} needs a value"));
+        match arg.as_str() {
+            "--to" => to = Some(value()?),
+            "--template" => template = Some(value()?),
+            "--subject" => subject = value()?,
+            "--vars" => vars = serde_json::from_str(&value()?).map_err(|e| anyhow::anyhow!("--vars must be a JSON object: {e}"))?,
+            "--campaign" => campaign_id = Some(value()?),
+            "--preheader" => preheader = Some(value()?),
+            other => anyhow::bail!("unknown send option `{other}`"),
+        }
+    }
+    if let Some(id) = campaign_id.as_deref().filter(|id| !campaigns::valid_id(id)) {
+        anyhow::bail!("invalid --campaign `{id}`");
+    }
+    let request = routes::SendRequest {
+        to: to.ok_or_else(|| anyhow::anyhow!("send needs --to"))?,
+        template: template.ok_or_else(|| anyhow::anyhow!("send needs --template"))?,
+        subject,
+        vars,
+        campaign_id,
+        phone: None,
+        preheader,
+        utm: None,
+        send_at: None,
+        attachments: Vec::new(),
+        zip: None,
+    };
+    let state = email::EmailState::from_config(config)?;
+    let store = open_store(config).await?;
+    let campaigns = cli_campaigns(config, &*store).await?;
+    let response = routes::deliver(&state, &*store, &campaigns, None, request).await?;
+    println!("{}", serde_json::to_string(&response)?);
+    if response.status == "partial" {
+        anyhow::bail!("{} of {} recipients were rejected", response.rejected.len(), response.rejected.len() + response.accepted.len());
+    }
+    Ok(())
+}
+
+/// One `templar bulk` line: a `/send` body, plus the key that keeps it from being sent twice.
+#[derive(serde::Deserialize)]
+struct BulkLine {
+    #[serde(flatten)]
+    request: routes::SendRequest,
+    /// Like `/send`'s `Idempotency-Key`, and sharing its keys and `IDEMPOTENCY_TTL_SECS` window
+    #[serde(default)]
+    idempotency_key: Option<String>,
+}
+
+/// `templar bulk [--file PATH|-] [--concurrency N] [--rate-per-min N]`
+/// Send every line of a JSONL file (or stdin) as `/send` would (see `send`), `concurrency` at a
+/// time and at most `rate-per-min` per minute (0 = unlimited). Prints one JSON result
+/// per line as it finishes, tagged with its `line` number; fails if any line failed.
+/// A line whose `idempotency_key` was already sent reports `"status":"duplicate"` instead.
+async fn bulk_command(args: &[String], config: &ApiConfig) -> anyhow::Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+    let (mut file, mut concurrency, mut rate_per_min) = (String::from("-"), 4usize, 0u32);
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let mut value = || it.next().cloned().ok_or_else(|| anyhow::anyhow!("{arg} needs a value"));
+        match arg.as_str() {
+            "--file" => file = value()?,
+            "--concurrency" => concurrency = value()?.parse().map_err(|e| anyhow::anyhow!("--concurrency: {e}"))?,
+            "--rate-per-min" => rate_per_min = value()?.parse().map_err(|e| anyhow::anyhow!("--rate-per-min: {e}"))?,
+            other => anyhow::bail!("unknown bulk option `{other}`"),
+        }
+    }
+    if concurrency == 0 {
+        anyhow::bail!("--concurrency must be at least 1");
+    }
+    let input: Box<dyn AsyncRead + Unpin> = if file == "-" {
+        Box::new(tokio::io::stdin())
+    } else {
+        Box::new(tokio::fs::File::open(&file).await.map_err(|e| anyhow::anyhow!("{file}: {e}"))?)
+    };
+    let state = Arc::new(email::EmailState::from_config(config)?);
+    let store = open_store(config).await?;
+    let campaigns = Arc::new(cli_campaigns(config, &*store).await?);
+    let slots = Arc::new(tokio::sync::Semaphore::new(concurrency));
+    let mut pace = (rate_per_min > 0).then(|| {
+        let mut i = tokio::time::interval(Duration::from_secs(60) / rate_per_min);
+        i.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        i
+    });
+    let print = |mut result: serde_json::Value, line: usize| {
+        result["line"] = serde_json::json!(line);
+        println!("{result}");
+    };
+    let (mut sent, mut duplicates, mut failed) = (0usize, 0usize, 0usize);
+    let mut tally = |result: &serde_json::Value| match result.get("status").and_then(|s| s.as_str()) {
+        Some("ok" | "skipped" | "deferred" | "fallback") => sent += 1,
+        Some("duplicate") => duplicates += 1,
+        _ => failed += 1,
+    };
+    let mut running = JoinSet::new();
+    let mut lines = BufReader::new(input).lines();
+    let mut n = 0;
+    while let Some(raw) = lines.next_line().await? {
+        n += 1;
+        if raw.trim().is_empty() {
+            continue;
+        }
+        let line = match serde_json::from_str::<BulkLine>(&raw) {
+            Ok(l) if l.request.campaign_id.as_deref().is_some_and(|id| !campaigns::valid_id(id)) => Err("invalid campaign_id".to_string()),
+            Ok(l) if l.idempotency_key.as_deref().is_some_and(|k| k.is_empty() || k.len() > 255 || !k.bytes().all(|b| b.is_ascii_graphic() || b == b' ')) => {
+                Err("idempotency_key must be 1 to 255 visible ASCII characters".to_string())
+            }
+            Ok(l) => state.render_limits.check_vars(&l.request.vars).map(|_| l).map_err(|e| e.to_string()),
+            Err(e) => Err(format!("invalid request: {e}")),
+        };
+        let (request, key) = match line {
+            Ok(l) => (l.request, l.idempotency_key),
+            Err(error) => {
+                let result = serde_json::json!({ "error": error });
+                tally(&result);
+                print(result, n);
+                continue;
+            }
+        };
+        // A key sent before (by this run, an earlier one or `/send`) is not sent again
+        let keyed = match &key {
+            Some(key) => {
+                let now = storage::now();
+                let duplicate = match store.claim_idempotency(key, now, now - state.idempotency_ttl_secs as i64).await {
+                    Ok(storage::IdempotencyClaim::Claimed) => None,
+                    Ok(storage::IdempotencyClaim::InProgress) => Some(serde_json::json!({ "status": "duplicate", "idempotency_key": key, "running": true })),
+                    Ok(storage::IdempotencyClaim::Done(_, body)) => {
+                        let first: serde_json::Value = serde_json::from_str(&body).unwrap_or_default();
+                        Some(serde_json::json!({ "status": "duplicate", "idempotency_key": key, "first": first }))
+                    }
+                    Err(e) => Some(serde_json::json!({ "error": format!("idempotency_key not checked: {e}") })),
+                };
+                if let Some(result) = duplicate {
+                    tally(&result);
+                    print(result, n);
+                    continue;
+                }
+                Some(key.clone())
+            }
+            None => None,
+        };
+        if let Some(pace) = pace.as_mut() {
+            pace.tick().await;
+        }
+        let slot = slots.clone().acquire_owned().await?;
+        let (state, store, campaigns) = (state.clone(), store.clone(), campaigns.clone());
+        running.spawn(async move {
+            let result = match routes::deliver(&state, &*store, &campaigns, None, request).await {
+                Ok(response) => serde_json::json!(response),
+                Err(e) => serde_json::json!({ "error": e.to_string() }),
+            };
+            drop(slot);
+            // Only sends are kept: a line that failed is tried again by the next run
+            if let Some(key) = keyed {
+                let kept = match result.get("status").and_then(|s| s.as_str()) {
+                    // As `/send` answers them
+                    Some(status) => {
+                        let code = match status {
+                            "partial" => 207,
+                            "deferred" => 202,
+                            _ => 200,
+                        };
+                        store.complete_idempotency(&key, code, &result.to_string()).await
+                    }
+                    None => store.release_idempotency(&key).await,
+                };
+                if let Err(e) = kept {
+                    tracing::warn!("idempotency_key answer not stored: {e}");
+                }
+            }
+            (n, result)
+        });
+        // Report whatever finished meanwhile, so results stream instead of piling up
+        while let Some(done) = running.try_join_next() {
+            let (line, result) = done?;
+            tally(&result);
+            print(result, line);
+        }
+    }
+    while let Some(done) = running.join_next().await {
+        let (line, result) = done?;
+        tally(&result);
+        print(result, line);
+    }
+    eprintln!("bulk: {sent} sent, {duplicates} duplicate, {failed} failed");
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} lines failed", sent + duplicates + failed);
+    }
+    Ok(())
+}
+
+/// `templar doctor`
+/// Check config, relays and templates, printing one green/red line per check; fails if any check fails.
+async fn doctor_command() -> anyhow::Result<()> {
+    use std::io::IsTerminal;
+    let report = templar::doctor::run().await;
+    let color = std::io::stdout().is_terminal();
+    for check in &report.checks {
+        let (mark, paint) = if check.ok { ("ok  ", "\x1b[32m") } else { ("FAIL", "\x1b[31m") };
+        if color {
+            println!("{paint}{mark}\x1b[0m  {}: {}", check.name, check.detail);
+        } else {
+            println!("{mark}  {}: {}", check.name, check.detail);
+        }
+    }
+    if !report.passed() {
+        anyhow::bail!("doctor found problems");
+    }
     Ok(())
 }
@@ -0,0 +1,74 @@
+//! Maintenance mode: while on, `/send` answers `503` with a maintenance message and
+//! `/ready` reports `503`, so load balancers and clients back off during a relay
+//! migration. Background work keeps going: queued digests are still flushed and
+//! retention still runs.
+//!
+//! Starts from `MAINTENANCE_MODE` and is toggled at runtime with `POST /admin/maintenance`;
+//! the toggle lasts until restart.
+
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+/// Message used when the toggle gives none.
+pub const DEFAULT_MESSAGE: &str = "down for maintenance, retry later";
+
+/// Shared on/off switch plus the message clients see.
+pub struct Maintenance {
+    on: AtomicBool,
+    message: Mutex<String>,
+}
+
+/// Current state, as `GET`/`POST /admin/maintenance` return it.
+#[derive(Debug, Clone, Serialize)]
+pub struct MaintenanceState {
+    pub enabled: bool,
+    pub message: String,
+}
+
+impl Maintenance {
+    pub fn new(enabled: bool) -> Self {
+        Self { on: AtomicBool::new(enabled), message: Mutex::new(DEFAULT_MESSAGE.into()) }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.on.load(Ordering::Relaxed)
+    }
+
+    /// Turn maintenance on or off; `message` replaces the current one when given.
+    pub fn set(&self, enabled: bool, message: Option<String>) -> MaintenanceState {
+        if let Some(m) = message.filter(|m| !m.trim().is_empty()) {
+            *self.message.lock().unwrap() = m;
+        }
+        self.on.store(enabled, Ordering::Relaxed);
+        self.state()
+    }
+
+    pub fn state(&self) -> MaintenanceState {
+        MaintenanceState { enabled: self.enabled(), message: self.message.lock().unwrap().clone() }
+    }
+
+    /// The `503` answered while maintenance is on.
+    pub fn response(&self) -> Response {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({ "error": self.message.lock().unwrap().clone(), "maintenance": true })),
+        )
+            .into_response()
+    }
+}
+
+/// Middleware: answer `503` instead of running the request while maintenance is on.
+pub async fn guard(State(maintenance): State<Arc<Maintenance>>, req: Request, next: Next) -> Response {
+    if maintenance.enabled() {
+        return maintenance.response();
+    }
+    next.run(req).await
+}
@@ -0,0 +1,86 @@
+//! Template metadata: an optional `<template>.meta` file next to the template, one
+//! `key: value` per line (variants use their template's file).
+//!
+//! ```text
+//! # welcome.meta
+//! preheader: Confirm your address to get started
+//! ```
+//!
+//! |Key|Effect|
+//! |:-:|:----:|
+//! |`preheader`|Inbox preview text when the request has no `preheader` (see `postprocess`)|
+//! |`dark_mode`|`on` adds dark-mode meta tags and overrides (see `postprocess::dark_mode`)|
+//! |`dark_background` / `dark_text` / `dark_link`|Dark-mode colours, e.g. `#121212`|
+//! |`dark_css`|Extra CSS applied in dark mode, for the template's own selectors|
+//! |`category`|Kind of mail (`billing`, `marketing`..) the preference service is asked about and that picks the fallback channel (see `preferences`, `channels`)|
+//!
+//! Unknown keys are an error, so a typo fails the send instead of being ignored. The
+//! file is read on every send, like the template.
+
+use std::path::Path;
+
+use crate::config::parse_bool;
+use crate::postprocess::DarkMode;
+
+/// Settings from one `.meta` file; all optional.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TemplateMeta {
+    pub preheader: Option<String>,
+    pub category: Option<String>,
+    /// Set when `dark_mode` is on, with any colours from the file.
+    pub dark_mode: Option<DarkMode>,
+}
+
+/// # load()
+/// Metadata for `template` from `<dir>/<template>.meta`; defaults when there is no file.
+/// # Errors
+/// The file is unreadable or has a bad line (with its line number).
+pub fn load(dir: &Path, template: &str) -> Result<TemplateMeta, String> {
+    let path = dir.join(format!("{template}.meta"));
+    let src = match std::fs::read_to_string(&path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(TemplateMeta::default()),
+        Err(e) => return Err(format!("{}: {e}", path.display())),
+    };
+    let mut meta = TemplateMeta::default();
+    let (mut dark, mut colors) = (false, DarkMode::default());
+    for (n, line) in src.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fail = |msg: String| format!("{} line {n}: {msg}", path.display());
+        let Some((key, value)) = line.split_once(':') else {
+            return Err(fail("expected `<key>: <value>`".into()));
+        };
+        let value = value.trim().to_string();
+        let key = key.trim();
+        // Colours and CSS land inside a <style> block; keep them from closing it
+        if key.starts_with("dark_") && value.contains('<') {
+            return Err(fail(format!("`{key}` must not contain `<`")));
+        }
+        let color = |v: String| if v.contains([';', '{', '}']) { Err(fail(format!("`{key}` must be a single colour"))) } else { Ok(v) };
+        match key {
+            "preheader" => meta.preheader = Some(value).filter(|v| !v.is_empty()),
+            "dark_mode" => dark = parse_bool(&value).map_err(|e| fail(format!("dark_mode `{value}`: {e}")))?,
+            "dark_background" => colors.background = color(value)?,
+            "dark_text" => colors.text = color(value)?,
+            "dark_link" => colors.link = color(value)?,
+            "dark_css" => colors.css = value,
+            "category" => meta.category = Some(value).filter(|v| !v.is_empty()),
+            other => return Err(fail(format!("unknown key `{other}`, expected preheader, dark_mode, dark_background, dark_text, dark_link, dark_css or category"))),
+        }
+    }
+    meta.dark_mode = dark.then_some(colors);
+    Ok(meta)
+}
+
+/// # category()
+/// The `category` of `template`, or its name when it has none. A meta file that does not
+/// load counts as none; the render reports it.
+pub fn category(dir: &Path, template: &str) -> String {
+    Some(template)
+        .filter(|t| crate::email::is_template_name(t))
+        .and_then(|t| load(dir, t).ok())
+        .and_then(|meta| meta.category)
+        .unwrap_or_else(|| template.to_string())
+}
@@ -0,0 +1,363 @@
+//! Transactional-outbox consumer: sends the rows an upstream application writes to its
+//! own database, so it gets email out of a transaction without calling `/send` from
+//! inside it.
+//!
+//! With `OUTBOX_URL` set, every `OUTBOX_POLL_SECS` the rows of `OUTBOX_TABLE` whose
+//! `processed_at` column is `NULL` are read (up to `OUTBOX_BATCH`, lowest id first),
+//! turned into a [`SendRequest`] through the `OUTBOX_COLUMNS` mapping and sent as
+//! `/send` would. A sent row gets `processed_at = CURRENT_TIMESTAMP`; a row that cannot
+//! be sent (unknown template, bad `vars`, refused by the relay) too, with the reason in
+//! the `error` column when one is mapped. Transient failures leave the row for the next
+//! poll.
+//!
+//! Each row is claimed as the idempotency key `outbox:<table>:<id>` in storage before
+//! it is sent, so a row is mailed once even when marking it fails, the process dies
+//! before the update, or several replicas (sharing `STORAGE_URL`) poll the same table.
+//! A row whose send was cut short by a crash is left alone until its key expires
+//! (`IDEMPOTENCY_TTL_SECS`): a late retry rather than a possible duplicate.
+
+use std::{collections::HashMap, fmt, str::FromStr, sync::Arc, time::Duration};
+
+use sqlx::any::{AnyConnectOptions, AnyPoolOptions};
+use sqlx::{AnyPool, ConnectOptions, Row};
+use tracing::{info, warn};
+
+use crate::campaigns::Campaigns;
+use crate::config::ApiConfig;
+use crate::email::SharedState;
+use crate::routes::SendRequest;
+use crate::storage::{self, IdempotencyClaim, Store};
+
+/// Which upstream column holds each [`SendRequest`] field (`OUTBOX_COLUMNS`).
+///
+/// `vars` holds a JSON object (`json`, `jsonb` or text). `campaign_id` and `error` are
+/// optional and unmapped by default.
+/// # Example
+/// ```
+/// use templar::outbox::OutboxColumns;
+/// let columns: OutboxColumns = "to=recipient,vars=payload,error=last_error".parse().unwrap();
+/// assert_eq!(columns.to, "recipient");
+/// assert_eq!(columns.subject, "subject");
+/// assert_eq!(columns.error.as_deref(), Some("last_error"));
+/// assert!("to=recipient; DROP TABLE x".parse::<OutboxColumns>().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboxColumns {
+    pub id: String,
+    pub to: String,
+    pub subject: String,
+    pub template: String,
+    pub vars: String,
+    pub campaign_id: Option<String>,
+    /// Nullable timestamp (or text) column; `NULL` marks a row to send.
+    pub processed_at: String,
+    /// Text column for why a row was given up on.
+    pub error: Option<String>,
+}
+
+impl Default for OutboxColumns {
+    fn default() -> Self {
+        Self {
+            id: "id".into(),
+            to: "to".into(),
+            subject: "subject".into(),
+            template: "template".into(),
+            vars: "vars".into(),
+            campaign_id: None,
+            processed_at: "processed_at".into(),
+            error: None,
+        }
+    }
+}
+
+impl FromStr for OutboxColumns {
+    type Err = String;
+    /// `field=column` pairs, comma-separated, over the defaults; `campaign_id=` or
+    /// `error=` unmaps an optional field.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut columns = Self::default();
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (field, column) = pair.split_once('=').ok_or_else(|| format!("`{pair}` is not field=column"))?;
+            let column = column.trim();
+            if !column.is_empty() {
+                check_identifier(column)?;
+            }
+            let required = |slot: &mut String| {
+                if column.is_empty() {
+                    return Err(format!("`{}` needs a column", field.trim()));
+                }
+                *slot = column.to_string();
+                Ok(())
+            };
+            match field.trim() {
+                "id" => required(&mut columns.id)?,
+                "to" => required(&mut columns.to)?,
+                "subject" => required(&mut columns.subject)?,
+                "template" => required(&mut columns.template)?,
+                "vars" => required(&mut columns.vars)?,
+                "processed_at" => required(&mut columns.processed_at)?,
+                "campaign_id" => columns.campaign_id = Some(column.to_string()).filter(|c| !c.is_empty()),
+                "error" => columns.error = Some(column.to_string()).filter(|c| !c.is_empty()),
+                other => {
+                    return Err(format!(
+                        "unknown field `{other}`, expected id, to, subject, template, vars, campaign_id, processed_at or error"
+                    ));
+                }
+            }
+        }
+        Ok(columns)
+    }
+}
+
+impl fmt::Display for OutboxColumns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "id={},to={},subject={},template={},vars={},campaign_id={},processed_at={},error={}",
+            self.id,
+            self.to,
+            self.subject,
+            self.template,
+            self.vars,
+            self.campaign_id.as_deref().unwrap_or_default(),
+            self.processed_at,
+            self.error.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+/// # check_identifier()
+/// Accept a table or column name made of letters, digits and `_` (a table may be
+/// `schema.table`). Names are spliced into SQL, so anything else is refused.
+/// # Example
+/// ```
+/// assert!(templar::outbox::check_identifier("app.email_outbox").is_ok());
+/// assert!(templar::outbox::check_identifier("x\"; --").is_err());
+/// ```
+pub fn check_identifier(name: &str) -> Result<(), String> {
+    let part_ok = |p: &str| p.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') && p.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if name.split('.').count() <= 2 && name.split('.').all(part_ok) {
+        Ok(())
+    } else {
+        Err(format!("`{name}` is not a plain SQL name (letters, digits, _)"))
+    }
+}
+
+/// `"schema"."table"`: quoted, so reserved words like `to` work as column names.
+fn quote(name: &str) -> String {
+    name.split('.').map(|p| format!("\"{p}\"")).collect::<Vec<_>>().join(".")
+}
+
+/// One unprocessed upstream row, every column read as text.
+struct OutboxRow {
+    id: String,
+    to: Option<String>,
+    subject: Option<String>,
+    template: Option<String>,
+    vars: Option<String>,
+    campaign_id: Option<String>,
+}
+
+impl OutboxRow {
+    /// The row as a `/send` body.
+    fn request(&self) -> Result<SendRequest, String> {
+        let field = |value: &Option<String>, name: &str| value.clone().filter(|v| !v.is_empty()).ok_or_else(|| format!("{name} is empty"));
+        let vars = match self.vars.as_deref().map(str::trim).filter(|v| !v.is_empty()) {
+            Some(v) => serde_json::from_str::<HashMap<String, serde_json::Value>>(v).map_err(|e| format!("vars is not a JSON object: {e}"))?,
+            None => HashMap::new(),
+        };
+        Ok(SendRequest {
+            to: field(&self.to, "to")?,
+            subject: field(&self.subject, "subject")?,
+            template: field(&self.template, "template")?,
+            vars,
+            campaign_id: self.campaign_id.clone().filter(|c| !c.is_empty()),
+            phone: None,
+            preheader: None,
+            utm: None,
+            send_at: None,
+            attachments: Vec::new(),
+            zip: None,
+        })
+    }
+}
+
+/// A connection to the upstream database and the statements built from the mapping.
+pub struct Outbox {
+    pool: AnyPool,
+    table: String,
+    select: String,
+    mark: String,
+    maps_error: bool,
+    batch: u32,
+    poll_every: Duration,
+}
+
+impl Outbox {
+    /// # connect()
+    /// Connect to `OUTBOX_URL` and check that the mapped table and columns exist.
+    /// # Errors
+    /// An unsupported URL, the connection failing, or a missing table or column.
+    pub async fn connect(config: &ApiConfig) -> anyhow::Result<Self> {
+        let url = config.outbox_url.trim();
+        match url.split_once(':').map(|(scheme, _)| scheme) {
+            Some("sqlite") => {}
+            #[cfg(feature = "postgres")]
+            Some("postgres" | "postgresql") => {}
+            #[cfg(not(feature = "postgres"))]
+            Some("postgres" | "postgresql") => anyhow::bail!("a postgres OUTBOX_URL needs a build with --features postgres"),
+            _ => anyhow::bail!("unsupported OUTBOX_URL `{url}`, expected sqlite:// or postgres://"),
+        }
+        sqlx::any::install_default_drivers();
+        // Polled every few seconds, like storage; keep statements out of debug logs
+        let options = AnyConnectOptions::from_str(url).map_err(|e| anyhow::anyhow!("OUTBOX_URL: {e}"))?.disable_statement_logging();
+        let pool = AnyPoolOptions::new().max_connections(2).connect_with(options).await.map_err(|e| anyhow::anyhow!("OUTBOX_URL: {e}"))?;
+        let c = &config.outbox_columns;
+        let table = quote(&config.outbox_table);
+        let text = |column: Option<&String>| column.map_or_else(|| "NULL".to_string(), |c| format!("CAST({} AS TEXT)", quote(c)));
+        let select = format!(
+            "SELECT {}, {}, {}, {}, {}, {} FROM {table} WHERE {} IS NULL ORDER BY {} LIMIT $1",
+            text(Some(&c.id)),
+            text(Some(&c.to)),
+            text(Some(&c.subject)),
+            text(Some(&c.template)),
+            text(Some(&c.vars)),
+            text(c.campaign_id.as_ref()),
+            quote(&c.processed_at),
+            quote(&c.id)
+        );
+        let error = c.error.as_ref().map(|e| format!(", {} = $2", quote(e))).unwrap_or_default();
+        // The id is compared as text since its type upstream is unknown (integer, uuid..)
+        let mark = format!(
+            "UPDATE {table} SET {processed} = CURRENT_TIMESTAMP{error} WHERE CAST({} AS TEXT) = $1 AND {processed} IS NULL",
+            quote(&c.id),
+            processed = quote(&c.processed_at)
+        );
+        let outbox = Self {
+            pool,
+            table: config.outbox_table.clone(),
+            select,
+            mark,
+            maps_error: c.error.is_some(),
+            batch: config.outbox_batch,
+            poll_every: Duration::from_secs(config.outbox_poll_secs),
+        };
+        // LIMIT 0 reads nothing but fails on a wrong table or column name
+        sqlx::query(&outbox.select).bind(0_i64).fetch_all(&outbox.pool).await.map_err(|e| anyhow::anyhow!("OUTBOX_TABLE/OUTBOX_COLUMNS: {e}"))?;
+        Ok(outbox)
+    }
+
+    /// Rows still to send, as many as `OUTBOX_BATCH`.
+    async fn pending(&self) -> Result<Vec<OutboxRow>, sqlx::Error> {
+        let rows = sqlx::query(&self.select).bind(i64::from(self.batch)).fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|r| {
+                Ok(OutboxRow {
+                    id: r.try_get::<Option<String>, _>(0)?.unwrap_or_default(),
+                    to: r.try_get(1)?,
+                    subject: r.try_get(2)?,
+                    template: r.try_get(3)?,
+                    vars: r.try_get(4)?,
+                    campaign_id: r.try_get(5)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Set `processed_at` (and `error`, when mapped) on a row.
+    async fn mark(&self, id: &str, error: Option<&str>) -> Result<(), sqlx::Error> {
+        let mut query = sqlx::query(&self.mark).bind(id.to_string());
+        if self.maps_error {
+            query = query.bind(error.map(str::to_string));
+        }
+        query.execute(&self.pool).await?;
+        Ok(())
+    }
+}
+
+/// Spawn the polling loop.
+pub fn spawn_consumer(state: SharedState, store: Store, campaigns: Arc<Campaigns>, outbox: Outbox) {
+    info!(table = outbox.table, "Outbox consumer polling every {}s", outbox.poll_every.as_secs());
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(outbox.poll_every);
+        loop {
+            tick.tick().await;
+            let rows = match outbox.pending().await {
+                Ok(rows) => rows,
+                Err(e) => {
+                    warn!(table = outbox.table, "Outbox not polled: {e}");
+                    continue;
+                }
+            };
+            for row in rows {
+                consume(&outbox, &state, &store, &campaigns, row).await;
+            }
+        }
+    });
+}
+
+/// Send one row at most once, then mark it.
+async fn consume(outbox: &Outbox, state: &SharedState, store: &Store, campaigns: &Campaigns, row: OutboxRow) {
+    let id = row.id.clone();
+    let key = format!("outbox:{}:{id}", outbox.table);
+    let state = state.load_full();
+    let now = storage::now();
+    let error = match store.claim_idempotency(&key, now, now - state.idempotency_ttl_secs as i64).await {
+        Err(e) => return warn!(id, "Outbox row not claimed: {e}"),
+        // Another replica is sending it, or a send was cut short
+        Ok(IdempotencyClaim::InProgress) => return,
+        // Handled before, but the row was not marked
+        Ok(IdempotencyClaim::Done(status, body)) => (status >= 300).then(|| failure_of(&body)),
+        Ok(IdempotencyClaim::Claimed) => {
+            let mut cancelled = false;
+            let sent = match row.request() {
+                Ok(request) => match crate::routes::deliver(&state, &**store, campaigns, None, request).await {
+                    Ok(response) => Ok(serde_json::json!(response)),
+                    Err(e) if e.is_retryable() => {
+                        warn!(id, "Outbox row failed, retrying: {e}");
+                        if let Err(e) = store.release_idempotency(&key).await {
+                            warn!(id, "Outbox row claim not released, retried after IDEMPOTENCY_TTL_SECS: {e}");
+                        }
+                        return;
+                    }
+                    Err(e) => {
+                        cancelled = matches!(e, crate::email::EmailError::CampaignCancelled(_));
+                        Err(e.to_string())
+                    }
+                },
+                Err(e) => Err(e),
+            };
+            let (status, body) = match &sent {
+                Ok(response) => (200, response.to_string()),
+                Err(e) => (422, serde_json::json!({ "error": e }).to_string()),
+            };
+            if let Err(e) = store.complete_idempotency(&key, status, &body).await {
+                warn!(id, "Outbox row answer not stored: {e}");
+            }
+            match sent {
+                Ok(response) => {
+                    info!(id, status = response["status"].as_str().unwrap_or_default(), "Outbox row sent");
+                    None
+                }
+                Err(e) => {
+                    warn!(id, "Outbox row given up: {e}");
+                    if !cancelled {
+                        crate::alerts::send_abandoned("outbox", &id, &e);
+                    }
+                    Some(e)
+                }
+            }
+        }
+    };
+    if let Err(e) = outbox.mark(&id, error.as_deref()).await {
+        warn!(id, "Outbox row not marked processed, marked on the next poll: {e}");
+    }
+}
+
+/// The `error` of a stored answer, or the whole body.
+fn failure_of(body: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|v| v["error"].as_str().map(str::to_string))
+        .unwrap_or_else(|| body.to_string())
+}
@@ -0,0 +1,115 @@
+//! Shared pagination for list endpoints.
+//!
+//! Every list endpoint accepts the same query parameters and answers with the same
+//! envelope, so clients can walk any collection the same way:
+//!
+//! |Param|Meaning|
+//! |:---:|:-----:|
+//! |`limit`|Page size, default `50`, at most `500`|
+//! |`sort`|Sort field, `-field` for descending; endpoints list the fields they support|
+//! |`cursor`|Opaque `next_cursor` from the previous page (keyset: stable while items are added)|
+//! |`offset`|Skip this many items instead of using a cursor|
+//!
+//! `{"items": [..], "next_cursor": "..." | null, "total": 123}`, where `total` counts
+//! every item matching the endpoint's filters, not just this page. Filters are
+//! endpoint-specific query params applied before paginating.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+
+/// Page size when `limit` is not given.
+pub const DEFAULT_LIMIT: usize = 50;
+/// Largest accepted `limit`.
+pub const MAX_LIMIT: usize = 500;
+
+/// Query parameters common to all list endpoints.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PageParams {
+    pub limit: Option<usize>,
+    pub sort: Option<String>,
+    pub cursor: Option<String>,
+    pub offset: Option<usize>,
+}
+
+/// Response envelope for list endpoints.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+    pub total: usize,
+}
+
+/// A sortable field: its `sort` name and the item key it orders by.
+/// Keys should be unique (e.g. end with an id) so cursors never skip ties.
+pub type SortKey<T> = (&'static str, fn(&T) -> String);
+
+/// # paginate()
+/// Sort `items` as requested by `params` and cut one page out of them.
+/// The first entry of `sorts` is the default sort.
+/// # Example
+/// ```
+/// use templar::pagination::{paginate, PageParams};
+/// let names = vec!["c".to_string(), "a".to_string(), "b".to_string()];
+/// let by_name: (&str, fn(&String) -> String) = ("name", |s| s.clone());
+///
+/// let first = paginate(names.clone(), &PageParams { limit: Some(2), ..Default::default() }, &[by_name]).unwrap();
+/// assert_eq!(first.items, ["a", "b"]);
+/// assert_eq!(first.total, 3);
+///
+/// let next = PageParams { limit: Some(2), cursor: first.next_cursor, ..Default::default() };
+/// let second = paginate(names, &next, &[by_name]).unwrap();
+/// assert_eq!(second.items, ["c"]);
+/// assert!(second.next_cursor.is_none());
+/// ```
+/// # Errors
+/// Returns a message for an unknown `sort` field, a malformed cursor, a cursor
+/// issued for a different sort, or `cursor` combined with `offset`.
+pub fn paginate<T>(mut items: Vec<T>, params: &PageParams, sorts: &[SortKey<T>]) -> Result<Page<T>, String> {
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let sort = params.sort.as_deref().map(str::trim).filter(|s| !s.is_empty()).unwrap_or(sorts[0].0);
+    let (field, descending) = match sort.strip_prefix('-') {
+        Some(f) => (f, true),
+        None => (sort, false),
+    };
+    let key = sorts
+        .iter()
+        .find(|(name, _)| *name == field)
+        .map(|(_, key)| *key)
+        .ok_or_else(|| {
+            let names: Vec<&str> = sorts.iter().map(|(n, _)| *n).collect();
+            format!("unknown sort `{field}`, expected one of: {}", names.join(", "))
+        })?;
+    if params.cursor.is_some() && params.offset.is_some() {
+        return Err("use either cursor or offset, not both".into());
+    }
+
+    let total = items.len();
+    let mut keyed: Vec<(String, T)> = items.drain(..).map(|item| (key(&item), item)).collect();
+    keyed.sort_by(|a, b| if descending { b.0.cmp(&a.0) } else { a.0.cmp(&b.0) });
+
+    let start = match (&params.cursor, params.offset) {
+        (Some(cursor), _) => {
+            let after = decode_cursor(cursor, sort)?;
+            keyed.partition_point(|(k, _)| if descending { *k >= after } else { *k <= after })
+        }
+        (None, Some(offset)) => offset.min(total),
+        (None, None) => 0,
+    };
+    let end = (start + limit).min(total);
+    let next_cursor = (end < total).then(|| encode_cursor(sort, &keyed[end - 1].0));
+    let items = keyed.drain(start..end).map(|(_, item)| item).collect();
+    Ok(Page { items, next_cursor, total })
+}
+
+fn encode_cursor(sort: &str, key: &str) -> String {
+    URL_SAFE_NO_PAD.encode(format!("{sort}\n{key}"))
+}
+
+fn decode_cursor(cursor: &str, sort: &str) -> Result<String, String> {
+    let raw = URL_SAFE_NO_PAD.decode(cursor).ok().and_then(|b| String::from_utf8(b).ok()).ok_or("malformed cursor")?;
+    match raw.split_once('\n') {
+        Some((s, key)) if s == sort => Ok(key.to_string()),
+        Some(_) => Err("cursor was issued for a different sort".into()),
+        None => Err("malformed cursor".into()),
+    }
+}
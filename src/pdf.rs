@@ -0,0 +1,81 @@
+//! PDF attachments rendered from a template (`pdf` feature).
+//!
+//! `{"template": "invoice-pdf", "filename": "invoice.pdf"}` in a request's `attachments`
+//! renders `invoice-pdf.hbs` with the send's `vars`, like the email itself, and has the
+//! HTML-to-PDF service at `PDF_SERVICE_URL` convert it. `PDF_SERVICE_KIND` picks how the
+//! HTML is handed over:
+//!
+//! - `html` (default): POSTed as the body with `Content-Type: text/html`, the PDF being
+//!   the answer, as WeasyPrint-based services do
+//! - `gotenberg`: sent as `index.html` in a multipart form, for Gotenberg's
+//!   `/forms/chromium/convert/html` (headless Chromium)
+//!
+//! The service must answer within `PDF_TIMEOUT_SECS` with at most `ATTACHMENT_MAX_BYTES`.
+
+use std::time::Duration;
+
+use crate::attachments::AttachmentError;
+use crate::config::{ApiConfig, PdfServiceKind};
+
+/// The configured HTML-to-PDF service.
+pub struct PdfService {
+    url: String,
+    kind: PdfServiceKind,
+    http: reqwest::Client,
+    timeout: Duration,
+    max_bytes: usize,
+}
+
+impl PdfService {
+    /// The service from `PDF_SERVICE_URL`; `None` when it is empty.
+    pub fn from_config(config: &ApiConfig) -> Result<Option<Self>, anyhow::Error> {
+        let url = config.pdf_service_url.trim();
+        if url.is_empty() {
+            return Ok(None);
+        }
+        let timeout = Duration::from_secs(config.pdf_timeout_secs);
+        let http = crate::transport::proxy::http_client(timeout)?.build()?;
+        Ok(Some(Self { url: url.to_string(), kind: config.pdf_service_kind, http, timeout, max_bytes: config.attachment_max_bytes }))
+    }
+
+    /// # PdfService::convert()
+    /// The PDF the service makes of `html`.
+    /// # Errors
+    /// [`AttachmentError::Unavailable`] when the service cannot be reached, times out, fails
+    /// or does not answer with a PDF; [`AttachmentError::Refused`] when it refuses the
+    /// document (`4xx`) or the PDF is over `ATTACHMENT_MAX_BYTES`.
+    pub async fn convert(&self, html: String) -> Result<Vec<u8>, AttachmentError> {
+        let request = match self.kind {
+            PdfServiceKind::Html => self.http.post(&self.url).header(reqwest::header::CONTENT_TYPE, "text/html; charset=utf-8").body(html),
+            PdfServiceKind::Gotenberg => {
+                let page = reqwest::multipart::Part::text(html).file_name("index.html").mime_str("text/html").expect("valid MIME type");
+                self.http.post(&self.url).multipart(reqwest::multipart::Form::new().part("files", page))
+            }
+        };
+        let unavailable = |e: reqwest::Error| {
+            AttachmentError::Unavailable(if e.is_timeout() {
+                format!("PDF service gave no complete answer within {}s", self.timeout.as_secs())
+            } else {
+                format!("PDF service: {e}")
+            })
+        };
+        let mut response = request.send().await.map_err(unavailable)?;
+        let status = response.status();
+        if !status.is_success() {
+            let detail = response.text().await.unwrap_or_default();
+            let msg = format!("PDF service answered {status}: {}", detail.trim().chars().take(200).collect::<String>());
+            return Err(if status.is_client_error() && status.as_u16() != 429 { AttachmentError::Refused(msg) } else { AttachmentError::Unavailable(msg) });
+        }
+        let mut pdf = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(unavailable)? {
+            if self.max_bytes > 0 && pdf.len() + chunk.len() > self.max_bytes {
+                return Err(AttachmentError::Refused(format!("rendered PDF is larger than ATTACHMENT_MAX_BYTES ({} bytes)", self.max_bytes)));
+            }
+            pdf.extend_from_slice(&chunk);
+        }
+        if !pdf.starts_with(b"%PDF-") {
+            return Err(AttachmentError::Unavailable(format!("PDF service at {} did not answer with a PDF; check PDF_SERVICE_URL and PDF_SERVICE_KIND", self.url)));
+        }
+        Ok(pdf)
+    }
+}
@@ -0,0 +1,113 @@
+//! Handlebars helpers implemented as WASM modules (`wasm-helpers` feature).
+//!
+//! Every `*.wasm` (or `*.wat`) file in `HELPER_PLUGINS_DIR` is loaded at startup and
+//! on reload. Each export named `helper_<name>` becomes the helper `{{name ..}}`.
+//!
+//! ## ABI
+//! A module exports `memory`, `templar_alloc(len: i32) -> i32` and any number of
+//! `helper_<name>(ptr: i32, len: i32) -> i64`. The host writes the call as UTF-8 JSON
+//! `{"params":[..],"hash":{..}}` into a buffer from `templar_alloc` and calls the
+//! helper, which returns `(out_ptr << 32) | out_len` pointing at its UTF-8 result.
+//! Output is escaped like any `{{value}}` unless the template uses `{{{ }}}`.
+//!
+//! ## Sandbox
+//! Modules get no imports (no WASI, so no files, network, clock or env), a fresh
+//! instance per call so no state leaks between renders, a fuel budget and a memory cap.
+//! A module that needs any import fails to load.
+
+use std::path::Path;
+
+use handlebars::{Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, RenderErrorReason, ScopedJson};
+use serde_json::{json, Map, Value};
+use tracing::info;
+use wasmtime::{Config, Engine, Instance, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+/// Instructions (roughly) one helper call may execute.
+const FUEL_PER_CALL: u64 = 10_000_000;
+/// Linear memory a helper instance may grow to.
+const MAX_MEMORY_BYTES: usize = 16 << 20;
+/// Largest result a helper may return.
+const MAX_OUTPUT_BYTES: usize = 1 << 20;
+
+struct WasmHelper {
+    engine: Engine,
+    module: Module,
+    export: String,
+}
+
+impl WasmHelper {
+    fn invoke(&self, input: &[u8]) -> Result<String, anyhow::Error> {
+        let limits = StoreLimitsBuilder::new().memory_size(MAX_MEMORY_BYTES).instances(1).build();
+        let mut store: Store<StoreLimits> = Store::new(&self.engine, limits);
+        store.limiter(|l| l);
+        store.set_fuel(FUEL_PER_CALL)?;
+        let instance = Instance::new(&mut store, &self.module, &[])?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("module exports no `memory`"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "templar_alloc")?;
+        let helper = instance.get_typed_func::<(i32, i32), i64>(&mut store, &self.export)?;
+
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, input)?;
+        let packed = helper.call(&mut store, (ptr, len))? as u64;
+        let (out_ptr, out_len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        if out_len > MAX_OUTPUT_BYTES {
+            anyhow::bail!("helper output too large ({out_len} bytes)");
+        }
+        let mut out = vec![0u8; out_len];
+        memory.read(&store, out_ptr, &mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+}
+
+impl HelperDef for WasmHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let params: Vec<Value> = h.params().iter().map(|p| p.value().clone()).collect();
+        let hash: Map<String, Value> = h.hash().iter().map(|(k, v)| (k.to_string(), v.value().clone())).collect();
+        let input = json!({ "params": params, "hash": hash }).to_string();
+        let out = self
+            .invoke(input.as_bytes())
+            .map_err(|e| RenderErrorReason::Other(format!("helper `{}` failed: {e:#}", h.name())))?;
+        Ok(ScopedJson::Derived(Value::String(out)))
+    }
+}
+
+/// # register_wasm_helpers()
+/// Compile every module in `dir` and register its `helper_*` exports on `reg`.
+/// Returns the registered helper names.
+/// # Errors
+/// Fails on unreadable or invalid modules, or modules that declare imports.
+pub fn register_wasm_helpers(reg: &mut Handlebars<'static>, dir: &Path) -> Result<Vec<String>, anyhow::Error> {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config)?;
+
+    let mut files: Vec<_> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|x| x == "wasm" || x == "wat"))
+        .collect();
+    files.sort();
+
+    let mut names = Vec::new();
+    for path in files {
+        let module = Module::from_file(&engine, &path).map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        if let Some(import) = module.imports().next() {
+            anyhow::bail!("{}: imports `{}::{}`, plugins may not import anything", path.display(), import.module(), import.name());
+        }
+        for export in module.exports() {
+            let Some(name) = export.name().strip_prefix("helper_") else { continue };
+            reg.register_helper(name, Box::new(WasmHelper { engine: engine.clone(), module: module.clone(), export: export.name().to_string() }));
+            info!(helper = name, plugin = %path.display(), "Registered WASM helper");
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
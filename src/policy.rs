@@ -0,0 +1,159 @@
+//! Content checks run on every rendered message before it is sent (`POLICY_*`), so an
+//! internal email that looks like phishing, or one that hurts the sending reputation,
+//! is caught before it goes out:
+//!
+//! - banned phrases and patterns, from `POLICY_BANNED_FILE`, in the subject and the text
+//! - links to hosts outside `POLICY_LINK_DOMAINS`
+//! - more images than `POLICY_MAX_IMAGES_PER_100_WORDS` for the text around them
+//!
+//! With `POLICY_MODE=warn` findings are logged, returned in the `/send` answer and kept
+//! with the message record; `enforce` refuses a send with banned content or links
+//! (`422`). The image ratio only ever warns.
+
+use std::fmt;
+
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::attachments::DomainAllowlist;
+use crate::config::{ApiConfig, PolicyMode};
+
+/// Which check a finding comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    BannedPhrase,
+    LinkDomain,
+    ImageRatio,
+}
+
+impl Rule {
+    /// Whether `enforce` refuses a send for it.
+    pub fn blocks(self) -> bool {
+        !matches!(self, Self::ImageRatio)
+    }
+}
+
+/// One thing a check found in a message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Finding {
+    pub rule: Rule,
+    pub detail: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.detail)
+    }
+}
+
+/// A `POLICY_BANNED_FILE` entry: a phrase, or a `/regex/`.
+#[derive(Debug, Clone)]
+struct Banned {
+    entry: String,
+    pattern: Regex,
+}
+
+/// The configured content checks.
+#[derive(Debug, Clone, Default)]
+pub struct ContentPolicy {
+    pub mode: PolicyMode,
+    banned: Vec<Banned>,
+    link_domains: DomainAllowlist,
+    max_images_per_100_words: f64,
+}
+
+impl ContentPolicy {
+    /// The checks from `POLICY_*`, the banned list read from `POLICY_BANNED_FILE`.
+    pub fn from_config(config: &ApiConfig) -> Result<Self, anyhow::Error> {
+        let mut policy = Self {
+            mode: config.policy_mode,
+            link_domains: config.policy_link_domains.clone(),
+            max_images_per_100_words: config.policy_max_images_per_100_words,
+            ..Self::default()
+        };
+        let path = config.policy_banned_file.trim();
+        if config.policy_mode != PolicyMode::Off && !path.is_empty() {
+            let text = std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("POLICY_BANNED_FILE {path}: {e}"))?;
+            policy = policy.with_banned(&text).map_err(|e| anyhow::anyhow!("POLICY_BANNED_FILE {path}: {e}"))?;
+        }
+        Ok(policy)
+    }
+
+    /// # ContentPolicy::with_banned()
+    /// The policy banning the entries of `list`, one per line: a phrase matched
+    /// case-insensitively with runs of whitespace as one space, or `/regex/`.
+    /// Blank lines and `#` comments are skipped.
+    /// # Example
+    /// ```
+    /// use templar::config::PolicyMode;
+    /// use templar::policy::{ContentPolicy, Rule};
+    /// let mut policy = ContentPolicy::default().with_banned("# phishing\nverify your password\n/wire\\s+transfer/\n").unwrap();
+    /// policy.mode = PolicyMode::Enforce;
+    /// let found = policy.check("Action needed", "<p>Please Verify  your password today</p>", "Please Verify  your password today");
+    /// assert_eq!(found[0].rule, Rule::BannedPhrase);
+    /// assert!(policy.check("Invoice", "<p>Paid by card</p>", "Paid by card").is_empty());
+    /// assert!(ContentPolicy::default().with_banned("/(unclosed/").is_err());
+    /// ```
+    /// # Errors
+    /// A `/regex/` line that does not compile, with its line number.
+    pub fn with_banned(mut self, list: &str) -> Result<Self, String> {
+        for (n, line) in list.lines().enumerate() {
+            let entry = line.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+            let source = match entry.strip_prefix('/').and_then(|e| e.strip_suffix('/')).filter(|e| !e.is_empty()) {
+                Some(regex) => regex.to_string(),
+                None => entry.split_whitespace().map(regex::escape).collect::<Vec<_>>().join(r"\s+"),
+            };
+            let pattern = RegexBuilder::new(&source).case_insensitive(true).size_limit(1 << 20).build().map_err(|e| format!("line {}: {e}", n + 1))?;
+            self.banned.push(Banned { entry: entry.to_string(), pattern });
+        }
+        Ok(self)
+    }
+
+    /// # ContentPolicy::check()
+    /// What the checks find in a message with `subject`, final `html` and its `text` part;
+    /// nothing while `POLICY_MODE` is `off`.
+    pub fn check(&self, subject: &str, html: &str, text: &str) -> Vec<Finding> {
+        if self.mode == PolicyMode::Off {
+            return Vec::new();
+        }
+        let mut found = Vec::new();
+        for b in &self.banned {
+            if let Some(m) = b.pattern.find(subject).or_else(|| b.pattern.find(text)) {
+                found.push(Finding { rule: Rule::BannedPhrase, detail: format!("banned `{}`: \"{}\"", b.entry, m.as_str()) });
+            }
+        }
+        if !self.link_domains.is_empty() {
+            let mut hosts: Vec<String> = crate::postprocess::links(html)
+                .iter()
+                .filter_map(|l| url::Url::parse(l).ok())
+                .filter(|u| matches!(u.scheme(), "http" | "https"))
+                .filter_map(|u| u.host_str().map(str::to_lowercase))
+                .filter(|h| !self.link_domains.allows_host(h))
+                .collect();
+            hosts.sort();
+            hosts.dedup();
+            found.extend(hosts.into_iter().map(|h| Finding { rule: Rule::LinkDomain, detail: format!("link to {h}, not in POLICY_LINK_DOMAINS") }));
+        }
+        if self.max_images_per_100_words > 0.0 {
+            let images = images(html);
+            let words = text.split_whitespace().count();
+            if images > 0 && images as f64 * 100.0 > self.max_images_per_100_words * words as f64 {
+                found.push(Finding {
+                    rule: Rule::ImageRatio,
+                    detail: format!("{images} image(s) for {words} word(s), over POLICY_MAX_IMAGES_PER_100_WORDS ({})", self.max_images_per_100_words),
+                });
+            }
+        }
+        found
+    }
+}
+
+/// `<img>` tags in `html`.
+fn images(html: &str) -> usize {
+    let lower = html.to_ascii_lowercase();
+    lower.match_indices("<img").filter(|(i, _)| matches!(lower.as_bytes().get(i + 4), Some(b' ' | b'\t' | b'\r' | b'\n' | b'/' | b'>'))).count()
+}
@@ -0,0 +1,321 @@
+//! Changes made to the rendered HTML before it is sent. The plain-text part is taken
+//! from the HTML ([`plain_text()`]) before these run, so it never shows the injected markup.
+
+/// # plain_text()
+/// Best-effort text alternative of `html`: tags dropped and `&nbsp;`, `&amp;`, `&lt;` and
+/// `&gt;` decoded, in one pass over the HTML.
+/// # Example
+/// ```
+/// use templar::postprocess::plain_text;
+/// assert_eq!(plain_text("<p>Fish &amp; chips&nbsp;<b>&lt;3</b></p>"), "Fish & chips <3");
+/// assert_eq!(plain_text("&amp;lt; stays literal"), "&lt; stays literal");
+/// ```
+pub fn plain_text(html: &str) -> String {
+    const ENTITIES: [(&str, char); 4] = [("&nbsp;", ' '), ("&amp;", '&'), ("&lt;", '<'), ("&gt;", '>')];
+    let mut out = String::with_capacity(html.len() / 2);
+    let mut in_tag = false;
+    let mut rest = html;
+    while let Some(c) = rest.chars().next() {
+        let mut step = c.len_utf8();
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if in_tag => {}
+            '&' => match ENTITIES.iter().find(|(entity, _)| rest.starts_with(entity)) {
+                Some((entity, decoded)) => {
+                    out.push(*decoded);
+                    step = entity.len();
+                }
+                None => out.push('&'),
+            },
+            _ => out.push(c),
+        }
+        rest = &rest[step..];
+    }
+    out
+}
+
+/// Invisible characters appended to the preheader so the inbox preview stops there
+/// instead of running on into the first visible text of the layout.
+const PREVIEW_FILLER: &str = "&#847;&zwnj;&nbsp;";
+
+/// # preheader()
+/// Insert `text` as hidden preview text right after the opening `<body>` tag (or at
+/// the start when the HTML has none). `text` is HTML-escaped.
+/// # Example
+/// ```
+/// use templar::postprocess::preheader;
+/// let html = preheader("<html><body class=\"x\"><h1>Hi</h1></body></html>", "Your <order> shipped");
+/// let (before, after) = html.split_once("<h1>").unwrap();
+/// assert!(before.starts_with("<html><body class=\"x\"><div style=\"display:none"));
+/// assert!(before.contains("Your &lt;order&gt; shipped"));
+/// assert_eq!(after, "Hi</h1></body></html>");
+/// ```
+pub fn preheader(html: &str, text: &str) -> String {
+    let snippet = format!(
+        "<div style=\"display:none;max-height:0;overflow:hidden;mso-hide:all;font-size:1px;line-height:1px;color:transparent;opacity:0\">{}{}</div>",
+        escape(text.trim()),
+        PREVIEW_FILLER.repeat(40),
+    );
+    let at = body_start(html).unwrap_or(0);
+    let mut out = String::with_capacity(html.len() + snippet.len());
+    out.push_str(&html[..at]);
+    out.push_str(&snippet);
+    out.push_str(&html[at..]);
+    out
+}
+
+/// Dark-mode colours for [`dark_mode()`]; the defaults suit the bundled base layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DarkMode {
+    pub background: String,
+    pub text: String,
+    pub link: String,
+    /// Extra rules for the template's own selectors, applied in dark mode only.
+    pub css: String,
+}
+
+impl Default for DarkMode {
+    fn default() -> Self {
+        Self { background: "#121212".into(), text: "#e8e8e8".into(), link: "#8ab4f8".into(), css: String::new() }
+    }
+}
+
+/// # dark_mode()
+/// Declare light and dark support and add overrides for dark mode: a `color-scheme`
+/// meta tag (Apple Mail, iOS), a `prefers-color-scheme` media query, and the same
+/// rules under Outlook.com's `[data-ogsc]`/`[data-ogsb]` attributes. Inserted before
+/// `</head>`, or after `<html>` (or at the start) when there is no head.
+/// # Example
+/// ```
+/// use templar::postprocess::{dark_mode, DarkMode};
+/// let html = dark_mode("<html><head><title>x</title></head><body>Hi</body></html>", &DarkMode::default());
+/// assert!(html.contains(r#"<meta name="color-scheme" content="light dark">"#));
+/// assert!(html.contains("@media (prefers-color-scheme: dark)"));
+/// assert!(html.find("color-scheme").unwrap() < html.find("</head>").unwrap());
+/// ```
+pub fn dark_mode(html: &str, colors: &DarkMode) -> String {
+    let DarkMode { background, text, link, css } = colors;
+    let text_rules = |scope: &str| {
+        format!("{scope}body,{scope}h1,{scope}h2,{scope}h3,{scope}p,{scope}td,{scope}li{{color:{text} !important}}{scope}a{{color:{link} !important}}")
+    };
+    let snippet = format!(
+        "<meta name=\"color-scheme\" content=\"light dark\"><meta name=\"supported-color-schemes\" content=\"light dark\">\
+         <style>:root{{color-scheme:light dark;supported-color-schemes:light dark}}\
+         @media (prefers-color-scheme: dark){{body{{background-color:{background} !important}}{}{css}}}\
+         [data-ogsb] body{{background-color:{background} !important}}{}</style>",
+        text_rules(""),
+        text_rules("[data-ogsc] "),
+    );
+    let at = match find_ci(html, "</head>") {
+        Some(i) => i,
+        None => tag_end(html, "html").unwrap_or(0),
+    };
+    let mut out = String::with_capacity(html.len() + snippet.len());
+    out.push_str(&html[..at]);
+    out.push_str(&snippet);
+    out.push_str(&html[at..]);
+    out
+}
+
+/// UTM parameters added to outbound links by [`utm_links()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Utm {
+    pub source: String,
+    pub medium: String,
+    pub campaign: Option<String>,
+}
+
+/// # utm_links()
+/// Append `utm_source`, `utm_medium` and `utm_campaign` to every `http(s)` link in an
+/// `href` attribute. A parameter the link already has is left as the author wrote it;
+/// `mailto:`, `tel:`, relative and `#` links are untouched.
+/// # Example
+/// ```
+/// use templar::postprocess::{utm_links, Utm};
+/// let utm = Utm { source: "templar".into(), medium: "email".into(), campaign: Some("spring-sale".into()) };
+/// let html = utm_links(r#"<a href="https://example.com/p?id=1&amp;x=2">p</a> <a href="mailto:a@b.c">m</a>"#, &utm);
+/// assert_eq!(html, r#"<a href="https://example.com/p?id=1&amp;x=2&amp;utm_source=templar&amp;utm_medium=email&amp;utm_campaign=spring-sale">p</a> <a href="mailto:a@b.c">m</a>"#);
+/// // Handlebars escapes `=` in `{{url}}` as `&#x3D;`
+/// let escaped = utm_links(r#"<a href="https://example.com/verify?token&#x3D;abc">v</a>"#, &utm);
+/// assert!(escaped.contains("?token=abc&amp;utm_source=templar"));
+/// let kept = utm_links(r#"<a HREF='https://example.com/?utm_source=blog'>b</a>"#, &utm);
+/// assert_eq!(kept, r#"<a HREF='https://example.com/?utm_source=blog&amp;utm_medium=email&amp;utm_campaign=spring-sale'>b</a>"#);
+/// ```
+pub fn utm_links(html: &str, utm: &Utm) -> String {
+    let lower = html.to_ascii_lowercase();
+    let mut out = String::with_capacity(html.len() + 64);
+    let mut copied = 0;
+    let mut from = 0;
+    while let Some(i) = lower[from..].find("href=") {
+        let start = from + i + "href=".len();
+        from = start;
+        // `xhref=` or `data-href=` is not an href attribute
+        if !lower[..start - 5].ends_with(|c: char| c.is_ascii_whitespace()) {
+            continue;
+        }
+        let Some(quote) = html[start..].chars().next().filter(|c| *c == '"' || *c == '\'') else { continue };
+        let Some(len) = html[start + 1..].find(quote) else { break };
+        let (value_start, value_end) = (start + 1, start + 1 + len);
+        if let Some(tagged) = tag_url(&html[value_start..value_end], utm) {
+            out.push_str(&html[copied..value_start]);
+            out.push_str(&tagged);
+            copied = value_end;
+        }
+        from = value_end;
+    }
+    out.push_str(&html[copied..]);
+    out
+}
+
+/// `raw` (an escaped attribute value) with the UTM parameters added, or `None` when it
+/// is not an absolute http(s) URL or already has all of them.
+fn tag_url(raw: &str, utm: &Utm) -> Option<String> {
+    let mut url = url::Url::parse(&unescape(raw.trim())).ok()?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return None;
+    }
+    let present: Vec<String> = url.query_pairs().map(|(k, _)| k.into_owned()).collect();
+    let wanted = [("utm_source", Some(&utm.source)), ("utm_medium", Some(&utm.medium)), ("utm_campaign", utm.campaign.as_ref())];
+    let missing: Vec<(&str, &String)> = wanted
+        .into_iter()
+        .filter_map(|(k, v)| Some((k, v?)))
+        .filter(|(k, v)| !v.is_empty() && !present.iter().any(|p| p == k))
+        .collect();
+    if missing.is_empty() {
+        return None;
+    }
+    url.query_pairs_mut().extend_pairs(missing);
+    Some(url.as_str().replace('&', "&amp;"))
+}
+
+/// # links()
+/// The `href` targets of `html`, entities decoded, in order (see `policy`).
+/// # Example
+/// ```
+/// use templar::postprocess::links;
+/// let html = r#"<a href="https://a.example/?x=1&amp;y=2">a</a> <a data-href="no"> <A HREF='mailto:x@y.z'>m</A>"#;
+/// assert_eq!(links(html), ["https://a.example/?x=1&y=2", "mailto:x@y.z"]);
+/// ```
+pub fn links(html: &str) -> Vec<String> {
+    let lower = html.to_ascii_lowercase();
+    let mut found = Vec::new();
+    let mut from = 0;
+    while let Some(i) = lower[from..].find("href=") {
+        let start = from + i + "href=".len();
+        from = start;
+        if !lower[..start - 5].ends_with(|c: char| c.is_ascii_whitespace()) {
+            continue;
+        }
+        let Some(quote) = html[start..].chars().next().filter(|c| *c == '"' || *c == '\'') else { continue };
+        let Some(len) = html[start + 1..].find(quote) else { break };
+        found.push(unescape(html[start + 1..start + 1 + len].trim()));
+        from = start + 1 + len;
+    }
+    found
+}
+
+/// # asset_urls()
+/// Point `src="assets/.."` and `src="/assets/.."` references at `base` (the public URL
+/// of `GET /assets`, or a CDN in front of it) so emails never link the service host.
+/// # Example
+/// ```
+/// use templar::postprocess::asset_urls;
+/// let html = asset_urls(r#"<img src="assets/logo.png"><img src='/assets/img/x.gif'><img src="https://x.y/z.png">"#, "https://cdn.example.com/mail/");
+/// assert_eq!(html, r#"<img src="https://cdn.example.com/mail/logo.png"><img src='https://cdn.example.com/mail/img/x.gif'><img src="https://x.y/z.png">"#);
+/// ```
+pub fn asset_urls(html: &str, base: &str) -> String {
+    let base = base.trim_end_matches('/');
+    let lower = html.to_ascii_lowercase();
+    let mut out = String::with_capacity(html.len() + 64);
+    let mut copied = 0;
+    let mut from = 0;
+    while let Some(i) = lower[from..].find("src=") {
+        let start = from + i + "src=".len();
+        from = start;
+        if !lower[..start - 4].ends_with(|c: char| c.is_ascii_whitespace()) {
+            continue;
+        }
+        let Some(quote) = html[start..].chars().next().filter(|c| *c == '"' || *c == '\'') else { continue };
+        let value = start + 1;
+        let rest = &html[value..];
+        let Some(path_len) = ["/assets/", "assets/"].iter().find(|p| rest.starts_with(**p)).map(|p| p.len()) else { continue };
+        out.push_str(&html[copied..value]);
+        out.push_str(base);
+        out.push('/');
+        copied = value + path_len;
+        from = html[value..].find(quote).map_or(value, |end| value + end);
+    }
+    out.push_str(&html[copied..]);
+    out
+}
+
+/// Byte offset just past the opening `<body ...>` tag, matched case-insensitively.
+fn body_start(html: &str) -> Option<usize> {
+    tag_end(html, "body")
+}
+
+/// Byte offset just past the first opening `<{name} ...>` tag, matched case-insensitively.
+fn tag_end(html: &str, name: &str) -> Option<usize> {
+    let lower = html.to_ascii_lowercase();
+    let open = format!("<{name}");
+    let mut from = 0;
+    while let Some(i) = lower[from..].find(&open) {
+        let tag = from + i;
+        // `<bodyfoo>` is not a body tag
+        match lower.as_bytes().get(tag + open.len()) {
+            Some(b'>' | b' ' | b'\t' | b'\r' | b'\n' | b'/') => return lower[tag..].find('>').map(|end| tag + end + 1),
+            _ => from = tag + open.len(),
+        }
+    }
+    None
+}
+
+/// Byte offset of the first case-insensitive match of the ASCII `needle`.
+fn find_ci(html: &str, needle: &str) -> Option<usize> {
+    html.to_ascii_lowercase().find(needle)
+}
+
+/// Decode the entities an attribute value can hold: the named ones Handlebars and
+/// editors emit, plus numeric `&#61;` / `&#x3D;`. Unknown entities are kept as written.
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let decoded = rest.find(';').filter(|end| *end <= 10).and_then(|end| {
+            let c = match &rest[1..end] {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" => '\'',
+                num => {
+                    let code = match num.strip_prefix("#x").or_else(|| num.strip_prefix("#X")) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                        None => num.strip_prefix('#')?.parse().ok()?,
+                    };
+                    char::from_u32(code)?
+                }
+            };
+            Some((c, end + 1))
+        });
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
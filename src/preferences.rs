@@ -0,0 +1,177 @@
+//! Notification preferences kept outside Templar: with `PREFERENCE_SERVICE_URL` set, every
+//! recipient of a send is checked with that service first, and those who opted out of the
+//! template's category are left out and listed under `opted_out` in the answer.
+//!
+//! Each check is one `POST` with `{"recipient":..,"category":..,"template":..}`, answered
+//! with `{"allowed": true}` or `false`. The category is the template's `category` meta key
+//! (see `meta`), or the template name without one. Answers are kept for
+//! `PREFERENCE_CACHE_SECS`, so a batch to the same people asks once. At most
+//! `PREFERENCE_CONCURRENCY` checks are in flight at a time, across all sends; the rest wait
+//! for a free slot.
+//!
+//! When the service cannot be reached, times out or answers anything else,
+//! `PREFERENCE_FAIL_MODE` decides: `open` sends to the recipient as if allowed (and logs a
+//! warning), `closed` answers `503` and sends nothing, for the caller to retry.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::config::{ApiConfig, PreferenceFailMode};
+
+/// Answers kept at most; past that, expired ones are dropped, then everything.
+const MAX_CACHED: usize = 100_000;
+
+/// The service's answer for one recipient.
+#[derive(Debug, Deserialize)]
+struct Answer {
+    allowed: bool,
+}
+
+/// The configured preference service (`PREFERENCE_SERVICE_URL`).
+pub struct PreferenceService {
+    url: String,
+    http: reqwest::Client,
+    fail_mode: PreferenceFailMode,
+    cache_for: Duration,
+    /// One permit per check in flight (`PREFERENCE_CONCURRENCY`)
+    slots: Arc<Semaphore>,
+    /// `(address, category)` → allowed, and when it was asked
+    cache: Mutex<HashMap<(String, String), (bool, Instant)>>,
+}
+
+impl PreferenceService {
+    /// # PreferenceService::new()
+    /// The service at `url` (http or https), giving up on a check after `timeout`, with at
+    /// most `concurrency` checks in flight (at least one).
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use templar::config::PreferenceFailMode;
+    /// use templar::preferences::PreferenceService;
+    /// let ok = |url| PreferenceService::new(url, Duration::from_secs(5), Duration::from_secs(300), 16, PreferenceFailMode::Open).is_ok();
+    /// assert!(ok("https://prefs.internal/v1/check"));
+    /// assert!(!ok("prefs.internal/v1/check"));
+    /// assert!(!ok("ftp://prefs.internal/check"));
+    /// ```
+    /// # Errors
+    /// `url` is not an http(s) URL with a host.
+    pub fn new(url: &str, timeout: Duration, cache_for: Duration, concurrency: usize, fail_mode: PreferenceFailMode) -> Result<Self, String> {
+        let parsed = url::Url::parse(url).map_err(|e| format!("`{url}`: {e}"))?;
+        if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none_or(str::is_empty) {
+            return Err(format!("`{url}`: expected an http:// or https:// URL"));
+        }
+        let http = crate::transport::proxy::http_client(timeout).and_then(|b| Ok(b.build()?)).map_err(|e| e.to_string())?;
+        let slots = Arc::new(Semaphore::new(concurrency.max(1)));
+        Ok(Self { url: parsed.to_string(), http, fail_mode, cache_for, slots, cache: Mutex::new(HashMap::new()) })
+    }
+
+    /// The service from `PREFERENCE_*`; `None` when `PREFERENCE_SERVICE_URL` is empty.
+    pub fn from_config(config: &ApiConfig) -> Result<Option<Self>, anyhow::Error> {
+        let url = config.preference_service_url.trim();
+        if url.is_empty() {
+            return Ok(None);
+        }
+        Self::new(
+            url,
+            Duration::from_secs(config.preference_timeout_secs),
+            Duration::from_secs(config.preference_cache_secs),
+            config.preference_concurrency,
+            config.preference_fail_mode,
+        )
+        .map(Some)
+        .map_err(|e| anyhow::anyhow!("invalid PREFERENCE_SERVICE_URL {e}"))
+    }
+
+    /// # PreferenceService::opted_out()
+    /// Those of `addresses` (lowercase) who do not want mail of `category`.
+    /// # Errors
+    /// A check failed under `PREFERENCE_FAIL_MODE=closed`; the first failure.
+    pub async fn opted_out(&self, addresses: &[String], category: &str, template: &str) -> Result<Vec<String>, String> {
+        let mut answers = HashMap::new();
+        let mut asking = JoinSet::new();
+        for address in addresses {
+            if answers.contains_key(address) {
+                continue;
+            }
+            match self.cached(address, category) {
+                Some(allowed) => {
+                    answers.insert(address.clone(), allowed);
+                }
+                None => {
+                    let (http, url, address, slots) = (self.http.clone(), self.url.clone(), address.clone(), self.slots.clone());
+                    let body = serde_json::json!({ "recipient": address, "category": category, "template": template });
+                    asking.spawn(async move {
+                        let allowed = match slots.acquire_owned().await {
+                            Ok(_slot) => ask(&http, &url, &body).await,
+                            Err(e) => Err(e.to_string()),
+                        };
+                        (address, allowed)
+                    });
+                }
+            }
+        }
+        let mut failure = None;
+        while let Some(joined) = asking.join_next().await {
+            let (address, allowed) = joined.map_err(|e| e.to_string())?;
+            match allowed {
+                Ok(allowed) => {
+                    self.remember(&address, category, allowed);
+                    answers.insert(address, allowed);
+                }
+                Err(e) => {
+                    tracing::warn!(recipient = address, category, fail_mode = %self.fail_mode, "Preference check failed: {e}");
+                    failure.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = failure.filter(|_| self.fail_mode == PreferenceFailMode::Closed) {
+            return Err(e);
+        }
+        let mut out: Vec<String> = Vec::new();
+        for address in addresses.iter().filter(|a| answers.get(*a) == Some(&false)) {
+            if !out.contains(address) {
+                out.push(address.clone());
+            }
+        }
+        Ok(out)
+    }
+
+    fn cached(&self, address: &str, category: &str) -> Option<bool> {
+        if self.cache_for.is_zero() {
+            return None;
+        }
+        let cache = self.cache.lock().unwrap();
+        cache.get(&(address.to_string(), category.to_string())).filter(|(_, at)| at.elapsed() < self.cache_for).map(|(allowed, _)| *allowed)
+    }
+
+    fn remember(&self, address: &str, category: &str, allowed: bool) {
+        if self.cache_for.is_zero() {
+            return;
+        }
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED {
+            cache.retain(|_, (_, at)| at.elapsed() < self.cache_for);
+            if cache.len() >= MAX_CACHED {
+                cache.clear();
+            }
+        }
+        cache.insert((address.to_string(), category.to_string()), (allowed, Instant::now()));
+    }
+}
+
+/// One check: whether the service allows the mail described by `body`.
+async fn ask(http: &reqwest::Client, url: &str, body: &serde_json::Value) -> Result<bool, String> {
+    let response = http.post(url).json(body).send().await.map_err(|e| format!("preference service at {url}: {e}"))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("preference service at {url} answered {status}"));
+    }
+    let answer: Answer = response.json().await.map_err(|e| format!("preference service at {url}: unreadable answer: {e}"))?;
+    Ok(answer.allowed)
+}
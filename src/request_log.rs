@@ -0,0 +1,61 @@
+//! Request logging with sampling: every request runs inside a `request` span, and
+//! with `LOG_SAMPLE_RATE=N` only one in `N` is logged fully.
+//!
+//! The decision is made when the request arrives and recorded on the span as
+//! `sampled`. Inside an unsampled request, events below `WARN` are dropped. A request
+//! that ends in a `4xx`/`5xx` always gets its completion line, sampled or not, so
+//! failures are never lost; only the debug trail of a sampled-out failure is.
+
+use std::{sync::atomic::{AtomicU64, Ordering}, sync::Arc, time::Instant};
+
+use axum::{extract::{Request, State}, middleware::Next, response::Response};
+use tracing::{info, warn, Instrument, Level, Metadata};
+
+/// Span target of sampled-out requests, matched by [`sampled_out()`].
+const UNSAMPLED_TARGET: &str = "templar::request_log::unsampled";
+
+/// Picks which requests are logged fully: every `rate`-th one.
+pub struct Sampler {
+    rate: u64,
+    seen: AtomicU64,
+}
+
+impl Sampler {
+    /// Log one request in `rate` fully; `1` logs all of them.
+    pub fn new(rate: u32) -> Self {
+        Self { rate: u64::from(rate.max(1)), seen: AtomicU64::new(0) }
+    }
+
+    fn sample(&self) -> bool {
+        self.seen.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.rate)
+    }
+}
+
+/// # log_request()
+/// Middleware: run the request in a `request` span and log its outcome.
+pub async fn log_request(State(sampler): State<Arc<Sampler>>, req: Request, next: Next) -> Response {
+    let (method, path) = (req.method().clone(), req.uri().path().to_string());
+    let sampled = sampler.sample();
+    // The span target carries the decision so the subscriber's filter can see it
+    let span = if sampled {
+        tracing::info_span!("request", %method, %path, sampled)
+    } else {
+        tracing::info_span!(target: UNSAMPLED_TARGET, "request", %method, %path, sampled)
+    };
+    let start = Instant::now();
+    let response = next.run(req).instrument(span).await;
+    let (status, ms) = (response.status().as_u16(), start.elapsed().as_millis() as u64);
+    if response.status().is_client_error() || response.status().is_server_error() {
+        warn!(%method, %path, status, ms, sampled, "Request failed");
+    } else if sampled {
+        info!(%method, %path, status, ms, "Request completed");
+    }
+    response
+}
+
+/// # sampled_out()
+/// Whether an event is below `WARN` inside a sampled-out request, for the
+/// subscriber's filter. `spans` are the metadata of the spans it fires in.
+pub fn sampled_out(event: &Metadata<'_>, mut spans: impl Iterator<Item = &'static Metadata<'static>>) -> bool {
+    *event.level() > Level::WARN && spans.any(|s| s.target() == UNSAMPLED_TARGET)
+}
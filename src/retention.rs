@@ -0,0 +1,191 @@
+//! Retention: bounded lifetime for stored recipient data.
+//!
+//! Templar keeps two kinds of data on disk: the file transport outbox (`.eml`
+//! files) and queued digest events (flushed, then deleted, every digest interval).
+//! Storage (see `storage`) adds message records, scheduled sends and complaints.
+//! Outbox entries, message records, complaints and stored `Idempotency-Key` answers older
+//! than `RETENTION_DAYS` are removed by a periodic task spawned from `main.rs`, or
+//! immediately via `POST /admin/purge`.
+//! Data subject requests (`DELETE /admin/data/{email}`) remove everything that
+//! references one address regardless of age, except its suppression, which keeps it
+//! from being mailed again; the free-text reason it was given is replaced, and so are the
+//! stored `Idempotency-Key` answers and outbox claims that mention it.
+
+use std::{fs, io, path::Path, time::{Duration, SystemTime}};
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::email::{EmailState, SharedState};
+use crate::storage::{self, Storage, Store};
+
+/// What a purge pass removed, per store.
+#[derive(Debug, Default, Serialize)]
+pub struct PurgeReport {
+    /// `.eml` files removed from the outbox directory.
+    pub outbox: usize,
+    /// Message records removed from storage.
+    pub messages: u64,
+    /// Stored `Idempotency-Key` answers and outbox claims removed from storage.
+    pub idempotency: u64,
+}
+
+/// What a data subject deletion removed, per store.
+#[derive(Debug, Default, Serialize)]
+pub struct DeletionReport {
+    /// `.eml` files removed from the outbox directory.
+    pub outbox: usize,
+    /// Pending digest files removed (one per digest key).
+    pub digests: usize,
+    /// Message records removed from storage.
+    pub messages: u64,
+    /// Scheduled sends removed from storage.
+    pub scheduled: u64,
+    /// Feedback loop complaints removed from storage.
+    pub complaints: u64,
+    /// Stored `Idempotency-Key` answers that mentioned it, replaced (the key stays).
+    pub idempotency: u64,
+    /// Outbox consumer claims whose stored outcome mentioned it, replaced (the claim stays).
+    pub outbox_claims: u64,
+    /// Suppressions whose reason was replaced (the suppression itself stays).
+    pub suppressions: usize,
+}
+
+/// Reason a suppression is left with after a data subject deletion.
+pub const ERASED_REASON: &str = "erased on request";
+
+/// Remove every stored item older than the configured retention.
+/// A retention of `0` days means "keep forever" and removes nothing.
+pub async fn purge(state: &EmailState, store: &dyn Storage) -> io::Result<PurgeReport> {
+    let mut report = PurgeReport::default();
+    if state.retention_days == 0 {
+        return Ok(report);
+    }
+    let max_age = Duration::from_secs(state.retention_days * 24 * 60 * 60);
+    if let Some(dir) = &state.outbox_dir {
+        report.outbox = purge_outbox(dir, max_age)?;
+    }
+    let before = storage::now() - max_age.as_secs() as i64;
+    report.messages = store.purge_messages(before).await.map_err(io::Error::other)?;
+    // Usually gone after IDEMPOTENCY_TTL_SECS already; not kept past retention either way
+    report.idempotency = store.expire_idempotency(before).await.map_err(io::Error::other)?;
+    Ok(report)
+}
+
+/// Delete `.eml` files in `dir` whose last modification is older than `max_age`; none
+/// while `dir` does not exist yet (nothing was sent).
+fn purge_outbox(dir: &Path, max_age: Duration) -> io::Result<usize> {
+    let now = SystemTime::now();
+    let mut removed = 0;
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "eml") {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        if now.duration_since(modified).unwrap_or_default() > max_age {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// # subject_forms()
+/// `address` lowercase, as sends store it (IDN domain in punycode, see
+/// [`crate::email::ascii_domain`]), then as given when that differs.
+/// # Example
+/// ```
+/// use templar::retention::subject_forms;
+/// assert_eq!(subject_forms("Ana@Example.com"), ["ana@example.com"]);
+/// assert_eq!(subject_forms("josé@Exämple.com"), ["josé@xn--exmple-cua.com", "josé@exämple.com"]);
+/// ```
+pub fn subject_forms(address: &str) -> Vec<String> {
+    let given = address.trim().to_lowercase();
+    let ascii = given.parse::<lettre::message::Mailbox>().map(|mb| crate::email::ascii_domain(mb).email.to_string().to_lowercase());
+    match ascii {
+        Ok(ascii) if ascii != given => vec![ascii, given],
+        _ => vec![given],
+    }
+}
+
+/// Remove every stored item that references `address` (case-insensitive, either form of
+/// an IDN domain), and replace the reason of its suppression and the stored answers and
+/// outbox claims that mention it.
+pub async fn delete_subject(state: &EmailState, store: &dyn Storage, address: &str) -> io::Result<DeletionReport> {
+    let mut report = DeletionReport::default();
+    let forms = subject_forms(address);
+    if let Some(dir) = &state.outbox_dir {
+        report.outbox = delete_outbox_mentions(dir, &forms)?;
+    }
+    for form in &forms {
+        report.digests += crate::digests::delete_recipient(&state.digest_dir, form)?;
+        let stored = store.delete_recipient(form).await.map_err(io::Error::other)?;
+        report.messages += stored.messages;
+        report.scheduled += stored.scheduled;
+        report.complaints += stored.complaints;
+        report.idempotency += stored.idempotency;
+        report.outbox_claims += stored.outbox_claims;
+    }
+    // The address stays suppressed; what was written about it goes
+    for suppression in store.suppressed(&forms).await.map_err(io::Error::other)? {
+        if suppression.reason != ERASED_REASON {
+            store.suppress(&storage::Suppression { reason: ERASED_REASON.into(), ..suppression }).await.map_err(io::Error::other)?;
+            report.suppressions += 1;
+        }
+    }
+    Ok(report)
+}
+
+/// Delete `.eml` files in `dir` that mention any of `addresses` (lowercase) anywhere
+/// (headers or body). Files are compared as bytes, so one that is not UTF-8 is still read.
+fn delete_outbox_mentions(dir: &Path, addresses: &[String]) -> io::Result<usize> {
+    let mut removed = 0;
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "eml") {
+            continue;
+        }
+        let raw = fs::read(&path)?.to_ascii_lowercase();
+        if addresses.iter().any(|a| storage::mentions(&raw, a.as_bytes())) {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Spawn the background purge loop, running once every `every` against the current state.
+/// Passes are skipped while retention is disabled, so a reload can turn it on or off.
+pub fn spawn_purge_task(state: SharedState, store: Store, every: Duration) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(every);
+        loop {
+            tick.tick().await;
+            let state = state.load_full();
+            if state.retention_days == 0 {
+                continue;
+            }
+            match purge(&state, &*store).await {
+                Ok(report) => info!(
+                    "Retention purge removed {} outbox file(s), {} message record(s), {} idempotency key(s)",
+                    report.outbox,
+                    report.messages,
+                    report.idempotency
+                ),
+                Err(e) => warn!("Retention purge failed: {e}"),
+            }
+        }
+    });
+}
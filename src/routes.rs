@@ -1,69 +1,1257 @@
 //! Route handlers: defines `/send` endpoint and a thin auth check.
 
-use std::{collections::HashMap, sync::Arc};
+use std::collections::{BTreeMap, HashMap};
 
-use axum::{extract::State, http::StatusCode, Json};
-use serde::Deserialize;
+use std::sync::Arc;
 
-use crate::email::{render_and_send, EmailError, EmailState};
+use axum::{extract::{Path, Query, State}, http::{header, HeaderMap, StatusCode}, response::{IntoResponse, Response}, Extension, Json};
+use serde::{Deserialize, Serialize};
 
-/// JSON payload for `/send`
-#[derive(Deserialize)]
+use crate::attachments::AttachmentError;
+use crate::backpressure::InFlight;
+use crate::bluegreen::{PromoteError, TemplateRoots};
+use crate::campaigns::{self, Campaigns};
+use crate::digests::{self, DigestEvent};
+use crate::maintenance::Maintenance;
+use crate::email::{is_template_name, render_and_send, EmailError, EmailState, SendOutcome, SharedState, ARCHIVE_DIR, ASSETS_DIR};
+use lettre::Address;
+use crate::retention;
+use crate::scopes::Caller;
+use crate::scaling::Scaling;
+use crate::storage::{self, IdempotencyClaim, MessageRecord, ScheduledSend, Storage, Store, Suppression};
+use crate::transport::{Rejection, SmtpReply, TransportError};
+use crate::pagination::{paginate, PageParams};
+
+/// JSON payload for `/send` (also what `client::TemplarClient` sends)
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SendRequest {
     /// Comma-separated list or single recipient
-    pub(crate) to: String,
-    pub(crate) subject: String,
+    pub to: String,
+    pub subject: String,
     /// Template filename (without `.hbs`)
-    pub(crate) template: String,
+    pub template: String,
     /// Arbitrary key/value vars for Handlebars
     #[serde(default)]
-    pub(crate) vars: HashMap<String, serde_json::Value>,
+    pub vars: HashMap<String, serde_json::Value>,
+    /// Groups sends for `GET /campaigns/{id}/stats`; sent as `X-Campaign-Id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub campaign_id: Option<String>,
+    /// Number (E.164) the `sms` fallback channel texts when the relay refuses the mail (see `channels`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phone: Option<String>,
+    /// Inbox preview text, overriding the template's `.meta` default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preheader: Option<String>,
+    /// Link tagging for this send, overriding `UTM_SOURCE`/`UTM_MEDIUM`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub utm: Option<UtmParams>,
+    /// Unix time to send at; a future time stores the request and answers `202`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send_at: Option<i64>,
+    /// Files downloaded and attached when the message is built (see `attachments`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<crate::attachments::Attachment>,
+    /// Send the attachments as one ZIP, optionally password-protected (see `bundle`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zip: Option<crate::bundle::ZipRequest>,
+}
+
+/// Per-request UTM values (`SendRequest::utm`); unset fields fall back to config.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UtmParams {
+    /// `utm_source`; an empty string turns tagging off for the send
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub medium: Option<String>,
+    /// `utm_campaign`, defaults to `campaign_id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub campaign: Option<String>,
 }
 
-/// Naive API key auth for demo.
-/// - Expects `API_KEY` set in env.
-/// - Compares against a pseudo header provided via env `API_KEY_CURRENT_REQUEST`.
-/// - If no `API_KEY` is set, auth is disabled (dev convenience).
-fn is_authorized() -> bool {
-    match std::env::var("API_KEY") {
-        Ok(key) if !key.is_empty() => {
-            let provided = std::env::var("API_KEY_CURRENT_REQUEST").unwrap_or_default();
-            key == provided
+/// Successful `/send` response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendResponse {
+    /// `"ok"`, `"skipped"` when rules dropped every recipient, `"partial"` when some
+    /// recipients were rejected, `"deferred"` when the warm-up limit held all of them over,
+    /// or `"fallback"` when the relay refused the mail and a fallback channel delivered it
+    pub status: String,
+    /// Message ID assigned to the send (the first one if the send was split, empty if skipped)
+    pub id: String,
+    /// Every message ID, present only when rules or variants split the send across messages
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ids: Vec<String>,
+    /// Recipients dropped by a `skip` rule
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<String>,
+    /// Recipients left out because they opted out of the template's category (`PREFERENCE_SERVICE_URL`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub opted_out: Vec<String>,
+    /// Recipients left out because they are suppressed (`/admin/suppressions`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub suppressed: Vec<String>,
+    /// The relay's final reply per SMTP transaction (absent for the `file` transport)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub smtp: Vec<SmtpReply>,
+    /// Delivered recipients, present only next to `rejected`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub accepted: Vec<String>,
+    /// Recipients that failed while others were delivered, with the reason
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rejected: Vec<Rejection>,
+    /// The channel that delivered the message instead of email (`FALLBACK_CHANNELS`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<crate::channels::FallbackDelivery>,
+    /// Content policy findings of the messages sent (`POLICY_MODE=warn`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub policy: Vec<crate::policy::Finding>,
+    /// Recipients held over to the next day by the sending domain's warm-up limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deferred: Option<crate::warmup::Deferral>,
+}
+
+impl From<SendOutcome> for SendResponse {
+    fn from(outcome: SendOutcome) -> Self {
+        let status = if !outcome.rejected.is_empty() {
+            "partial"
+        } else if outcome.ids.is_empty() {
+            "skipped"
+        } else {
+            "ok"
+        };
+        SendResponse {
+            status: status.into(),
+            id: outcome.ids.first().cloned().unwrap_or_default(),
+            ids: if outcome.ids.len() > 1 { outcome.ids } else { Vec::new() },
+            skipped: outcome.skipped,
+            opted_out: Vec::new(),
+            suppressed: outcome.suppressed,
+            smtp: outcome.replies,
+            accepted: outcome.accepted,
+            rejected: outcome.rejected,
+            fallback: None,
+            policy: outcome.policy,
+            deferred: None,
         }
-        Ok(_) => false,
-        Err(_) => true,
     }
 }
 
 /// POST `/send`
 /// - Requires a valid `SendRequest` JSON body
-/// - Returns `{"status":"ok","id":..}` or `{"error":..}`
+/// - Counts the send towards its `campaign_id`, if any
+/// - With `PREFERENCE_SERVICE_URL`, leaves out those who opted out of the template's category, listing them in `opted_out`
+/// - When the relay refuses the mail and the template's category has a fallback channel, delivers
+///   through that channel instead and answers `"status":"fallback"`
+/// - Refuses sends for a cancelled campaign with `409`
+/// - Leaves suppressed recipients out, listing them in `suppressed`
+/// - With a future `send_at`, stores the request and answers `202` with `"status":"scheduled"`
+/// - Past the sending domain's warm-up limit for the day, holds the recipients over the limit
+///   until the next day under `deferred`; `202` with `"status":"deferred"` when that is all of them
+/// - With an `Idempotency-Key` header, repeats the first answer for that key instead of sending again
+/// - Returns `{"status":"ok","id":..}` or `{"error":..}`; `207` with `"status":"partial"`
+///   and `accepted`/`rejected` lists when only some recipients were delivered
 pub async fn send_email(
-    State(state): State<Arc<EmailState>>,
+    State(state): State<SharedState>,
+    Extension(campaigns): Extension<Arc<Campaigns>>,
+    Extension(store): Extension<Store>,
+    caller: Option<Extension<Caller>>,
+    headers: HeaderMap,
+    Json(payload): Json<SendRequest>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, Json<serde_json::Value>)> {
+    let caller = caller.map(|Extension(c)| c.kid);
+    let campaign = payload.campaign_id.clone();
+    if let Some(id) = campaign.as_deref().filter(|id| !campaigns::valid_id(id)) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": format!("invalid campaign_id: {id}") })),
+        ));
+    }
+    if let Some(phone) = payload.phone.as_deref().filter(|p| !crate::channels::valid_phone(p)) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": format!("invalid phone: {phone}") })),
+        ));
+    }
+
+    let state = state.load_full();
+    state.render_limits.check_vars(&payload.vars).map_err(send_error)?;
+
+    // 1) A retried request gets the first answer back
+    let key = match headers.get("idempotency-key").map(|v| v.to_str()) {
+        None => None,
+        Some(Ok(k)) if !k.is_empty() && k.len() <= 255 => Some(k.to_string()),
+        Some(_) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({ "error": "Idempotency-Key must be 1 to 255 visible ASCII characters" })),
+            ));
+        }
+    };
+    if let Some(key) = &key {
+        let now = storage::now();
+        let since = now - state.idempotency_ttl_secs as i64;
+        match store.claim_idempotency(key, now, since).await.map_err(|e| send_error(EmailError::Storage(e.0)))? {
+            IdempotencyClaim::Claimed => {}
+            IdempotencyClaim::InProgress => {
+                return Err((
+                    StatusCode::CONFLICT,
+                    Json(serde_json::json!({ "error": "a request with this Idempotency-Key is still running" })),
+                ));
+            }
+            IdempotencyClaim::Done(code, body) => {
+                let code = StatusCode::from_u16(code).unwrap_or(StatusCode::OK);
+                let body: serde_json::Value = serde_json::from_str(&body).unwrap_or_default();
+                return if code.is_success() { Ok((code, Json(body))) } else { Err((code, Json(body))) };
+            }
+        }
+    }
+
+    // 2) Hold it for later, or render + send now
+    let answer = match payload.send_at.filter(|at| *at > storage::now()) {
+        Some(_) if campaign.as_deref().is_some_and(|id| campaigns.refuse(id)) => {
+            Err(send_error(EmailError::CampaignCancelled(campaign.unwrap_or_default())))
+        }
+        Some(send_at) => schedule(&*store, payload, send_at, caller).await,
+        None => {
+            // Spooled if a shutdown cuts it short
+            let _running = crate::spool::track(caller.as_deref(), &payload);
+            deliver(&state, &*store, &campaigns, caller.as_deref(), payload)
+                .await
+                .map(|response| {
+                    let code = match response.status.as_str() {
+                        "partial" => StatusCode::MULTI_STATUS,
+                        "deferred" => StatusCode::ACCEPTED,
+                        _ => StatusCode::OK,
+                    };
+                    (code, Json(serde_json::json!(response)))
+                })
+                .map_err(send_error)
+        }
+    };
+    if let Some(key) = &key {
+        // Answers worth retrying are not kept, so the retry runs again
+        let kept = match &answer {
+            Ok((code, Json(body))) | Err((code, Json(body))) if !code.is_server_error() => store.complete_idempotency(key, code.as_u16(), &body.to_string()).await,
+            _ => store.release_idempotency(key).await,
+        };
+        if let Err(e) = kept {
+            tracing::warn!("Idempotency-Key answer not stored: {e}");
+        }
+    }
+    answer
+}
+
+/// Store `payload` to be sent at `send_at` by the scheduler (see `scheduled`), on behalf of `caller`.
+async fn schedule(
+    store: &dyn Storage,
+    payload: SendRequest,
+    send_at: i64,
+    caller: Option<String>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, Json<serde_json::Value>)> {
+    let send = ScheduledSend { id: crate::email::nanoid(), send_at, request: payload, caller, created_at: storage::now() };
+    match store.schedule(&send).await {
+        Ok(()) => Ok((
+            StatusCode::ACCEPTED,
+            Json(serde_json::json!({ "status": "scheduled", "id": send.id, "send_at": send_at })),
+        )),
+        Err(e) => Err(send_error(EmailError::Storage(e.0))),
+    }
+}
+
+/// # deliver()
+/// Send `payload` now: leave suppressed and opted-out recipients out, defer those over the
+/// warm-up limit, render and send, record the messages and count them towards the campaign.
+/// Mail the relay refuses goes to the category's fallback channel, if any.
+/// Used by `/send` and the scheduler; recipients deferred by the warm-up are scheduled on
+/// behalf of `caller`, the `kid` of the key the send was made with.
+/// # Errors
+/// As [`render_and_send`]; [`EmailError::CampaignCancelled`] for a cancelled campaign,
+/// [`EmailError::Storage`] when suppressions or warm-up counts cannot be read,
+/// [`EmailError::Preferences`] when preferences cannot (`PREFERENCE_FAIL_MODE=closed`).
+pub async fn deliver(state: &EmailState, store: &dyn Storage, campaigns: &Campaigns, caller: Option<&str>, mut payload: SendRequest) -> Result<SendResponse, EmailError> {
+    let campaign = payload.campaign_id.clone();
+    if let Some(id) = campaign.as_deref().filter(|id| campaigns.refuse(id)) {
+        return Err(EmailError::CampaignCancelled(id.to_string()));
+    }
+    let mut suppressed = Vec::new();
+    // Unparsable recipients are left for `render_and_send` to report
+    if let Ok(recipients) = crate::email::parse_recipients(&payload.to) {
+        let addresses: Vec<String> = recipients.iter().map(|mb| mb.email.to_string().to_lowercase()).collect();
+        let found = store.suppressed(&addresses).await.map_err(|e| EmailError::Storage(e.0))?;
+        if !found.is_empty() {
+            let (keep, dropped): (Vec<_>, Vec<_>) =
+                recipients.into_iter().partition(|mb| !found.iter().any(|s| s.address == mb.email.to_string().to_lowercase()));
+            suppressed = dropped.iter().map(|mb| mb.email.to_string()).collect();
+            if keep.is_empty() {
+                let outcome = SendOutcome { suppressed, ..SendOutcome::default() };
+                return Ok(SendResponse::from(outcome));
+            }
+            payload.to = keep.iter().map(|mb| mb.to_string()).collect::<Vec<_>>().join(", ");
+        }
+    }
+    let category = crate::meta::category(&state.templates_dir, &payload.template);
+    let mut opted_out = Vec::new();
+    if let Some(preferences) = &state.preferences
+        && let Ok(recipients) = crate::email::parse_recipients(&payload.to)
+    {
+        let addresses: Vec<String> = recipients.iter().map(|mb| mb.email.to_string().to_lowercase()).collect();
+        let found = preferences.opted_out(&addresses, &category, &payload.template).await.map_err(EmailError::Preferences);
+        if let Err(e) = &found {
+            crate::alerts::record_send(Err(e));
+        }
+        let found = found?;
+        if !found.is_empty() {
+            tracing::info!(template = payload.template, category, recipients = found.len(), "Recipients left out: opted out of the category");
+            let (keep, dropped): (Vec<_>, Vec<_>) = recipients.into_iter().partition(|mb| !found.contains(&mb.email.to_string().to_lowercase()));
+            opted_out = dropped.iter().map(|mb| mb.email.to_string()).collect();
+            if keep.is_empty() {
+                let mut response = SendResponse::from(SendOutcome { suppressed, ..SendOutcome::default() });
+                response.opted_out = opted_out;
+                return Ok(response);
+            }
+            payload.to = keep.iter().map(|mb| mb.to_string()).collect::<Vec<_>>().join(", ");
+        }
+    }
+
+    let admitted = state.warmup.admit(store, &mut payload, caller, storage::now()).await.map_err(|e| EmailError::Storage(e.0))?;
+    if admitted.day.is_some() && admitted.reserved == 0 {
+        let deferred = admitted.deferred;
+        let mut response = SendResponse::from(SendOutcome { suppressed, ..SendOutcome::default() });
+        response.opted_out = opted_out;
+        response.status = "deferred".into();
+        response.id = deferred.as_ref().map(|d| d.id.clone()).unwrap_or_default();
+        response.deferred = deferred;
+        return Ok(response);
+    }
+
+    // Mail the relay refuses goes to the category's fallback channel, if any
+    let channel = state.fallback.as_ref().and_then(|channels| channels.for_category(&category));
+    let request = channel.map(|_| payload.clone());
+    let result = render_and_send(state, payload).await;
+    crate::alerts::record_send(result.as_ref().map(|_| ()));
+    if let Some(id) = &campaign {
+        campaigns.record(id, result.as_ref().ok());
+    }
+    if result.is_err() {
+        state.warmup.release(store, &admitted).await;
+    }
+    if let Err(EmailError::SmtpError(TransportError::Permanent(refused))) = &result
+        && let Some((channel, request)) = channel.zip(request)
+    {
+        match crate::channels::fall_back(state, channel, &request, &category, refused).await {
+            Ok(()) => {
+                tracing::info!(template = request.template, category, channel = channel.name(), "Relay refused the mail; delivered by the fallback channel");
+                let mut response = SendResponse::from(SendOutcome { suppressed, ..SendOutcome::default() });
+                response.status = "fallback".into();
+                response.opted_out = opted_out;
+                response.fallback = Some(crate::channels::FallbackDelivery { channel: channel.name().into(), error: refused.clone() });
+                return Ok(response);
+            }
+            Err(e) => tracing::warn!(template = request.template, category, channel = channel.name(), "Fallback channel failed too: {e}"),
+        }
+    }
+    let mut outcome = result?;
+    let created_at = storage::now();
+    for (id, message) in outcome.ids.iter().zip(&outcome.messages) {
+        let record = MessageRecord {
+            id: id.clone(),
+            template: message.template.clone(),
+            recipients: message.recipients.clone(),
+            campaign_id: campaign.clone(),
+            policy: message.policy.clone(),
+            cost: Some(campaigns.message_cost(message)),
+            variant: message.variant.clone(),
+            smtp: message.replies.clone(),
+            caller: caller.map(str::to_string),
+            created_at,
+        };
+        // The mail is out either way; a missing record only hides it from `/admin/messages`
+        if let Err(e) = store.record_message(&record).await {
+            tracing::warn!(id, "Message not recorded: {e}");
+        }
+    }
+    outcome.suppressed = suppressed;
+    let mut response = SendResponse::from(outcome);
+    response.opted_out = opted_out;
+    response.deferred = admitted.deferred;
+    Ok(response)
+}
+
+/// Map domain error → status code
+fn send_error(e: EmailError) -> (StatusCode, Json<serde_json::Value>) {
+    let code = match e {
+        EmailError::TemplateNotFound(_) => StatusCode::NOT_FOUND,
+        EmailError::TemplateArchived(_) => StatusCode::GONE,
+        EmailError::RenderError(_) | EmailError::NotSingleMessage(_) | EmailError::RenderLimit(_) | EmailError::Policy(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        EmailError::InvalidHeader(_) => StatusCode::BAD_REQUEST,
+        EmailError::VarsTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        EmailError::CampaignCancelled(_) => StatusCode::CONFLICT,
+        EmailError::Storage(_) | EmailError::Preferences(_) => StatusCode::SERVICE_UNAVAILABLE,
+        EmailError::Attachment(AttachmentError::Refused(_) | AttachmentError::Infected { .. }) => StatusCode::UNPROCESSABLE_ENTITY,
+        EmailError::Attachment(AttachmentError::Unavailable(_)) => StatusCode::BAD_GATEWAY,
+        // Worth retrying later vs. refused by the relay vs. our own fault
+        EmailError::SmtpError(ref t) if t.is_transient() => StatusCode::SERVICE_UNAVAILABLE,
+        EmailError::SmtpError(TransportError::Auth(_) | TransportError::Permanent(_)) => StatusCode::BAD_GATEWAY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (code, Json(serde_json::json!({ "error": e.to_string() })))
+}
+
+/// POST `/render/eml`
+/// - Same body as `/send`; builds the message `/send` would deliver, without sending it
+/// - Returns the RFC 5322 bytes as `message/rfc822`, or `{"error":..}` with `/send`'s statuses;
+///   `422` when the recipients would get more than one message or none
+pub async fn render_eml(
+    State(state): State<SharedState>,
     Json(payload): Json<SendRequest>,
+) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
+    if let Some(id) = payload.campaign_id.as_deref().filter(|id| !campaigns::valid_id(id)) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": format!("invalid campaign_id: {id}") })),
+        ));
+    }
+
+    let state = state.load();
+    state.render_limits.check_vars(&payload.vars).map_err(send_error)?;
+    match crate::email::render_eml(&state, &payload).await {
+        Ok(eml) => Ok((
+            [
+                (header::CONTENT_TYPE, "message/rfc822".to_string()),
+                (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.eml\"", payload.template)),
+            ],
+            eml,
+        ).into_response()),
+        Err(e) => Err(send_error(e)),
+    }
+}
+
+/// GET `/version`
+/// - Build info (version, git commit, build time, features) and the active transport
+/// - Returns `{"version":..,"git_commit":..,"built_at":..,"features":[..],"transport":..}`
+pub async fn version(
+    State(state): State<SharedState>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let mut body = serde_json::json!(crate::version::BuildInfo::current());
+    body["transport"] = serde_json::json!(state.load().mailer.kind());
+    Ok(Json(body))
+}
+
+/// GET `/admin/config`
+/// - Effective value and source (`default`, `file` for `.env`, `env`) of every setting
+/// - Secrets are masked; values are as of now, so edits not yet reloaded show up here first
+/// - Returns `{"config":{"SMTP_HOST":{"value":..,"source":..},..}}`
+pub async fn show_config() -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    Ok(Json(serde_json::json!({ "config": crate::config::effective_settings() })))
+}
+
+/// GET `/admin/providers`
+/// - Each per-provider bucket (`PROVIDER_RATES`, `PROVIDER_CONCURRENCY`): its limits, what runs and waits now, and totals
+/// - Returns `{"buckets":[{"provider":..,"rate_per_sec":..,"concurrency":..,"in_flight":..,"waiting":..,"sent":..,"refused":..}]}`;
+///   `buckets` is empty when no provider is limited
+pub async fn provider_stats(
+    State(state): State<SharedState>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let buckets = state.load().mailer.providers().map(|p| p.stats()).unwrap_or_default();
+    Ok(Json(serde_json::json!({ "buckets": buckets })))
+}
+
+/// GET `/admin/warmup`
+/// - Each warming-up sending domain: its ramp day, today's limit, what was sent and what is left
+/// - Returns `{"curve":[..],"domains":[{"domain":..,"started":..,"sending":..,"warm":..,"day":..,"limit":..,"sent":..,"remaining":..,"history":[{"day":..,"sent":..,"deferred":..}]}]}`
+pub async fn warmup_stats(
+    State(state): State<SharedState>,
+    Extension(store): Extension<Store>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let warmup = state.load().warmup.clone();
+    warmup.stats(&*store, storage::now()).await.map(Json).map_err(|e| send_error(EmailError::Storage(e.0)))
+}
+
+/// Query of `GET /admin/deliverability`.
+#[derive(Debug, Default, Deserialize)]
+pub struct DeliverabilityQuery {
+    /// Check this domain instead of the configured ones
+    pub domain: Option<String>,
+    /// DKIM selector to look for instead of `DKIM_SELECTORS`
+    pub selector: Option<String>,
+}
+
+/// GET `/admin/deliverability`
+/// - SPF, DKIM and DMARC of each sending domain, reverse DNS of each relay
+/// - Returns `{"ok":..,"domains":[{"domain":..,"checks":[{"name":..,"status":..,"detail":..}]}],"relays":[..]}`,
+///   `400` for a `domain` or `selector` that is not a DNS name, `500` when DNS cannot be used
+pub async fn deliverability(
+    State(state): State<SharedState>,
+    Query(query): Query<DeliverabilityQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let dns_name = |v: &str| !v.is_empty() && v.len() <= 253 && v.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+    for (field, value) in [("domain", &query.domain), ("selector", &query.selector)] {
+        if let Some(v) = value.as_deref().filter(|v| !dns_name(v.trim())) {
+            return Err((StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": format!("invalid {field}: {v}") }))));
+        }
+    }
+    let preflight = state.load().deliverability.clone();
+    match preflight.run(query.domain.as_deref(), query.selector.as_deref()).await {
+        Ok(report) => Ok(Json(serde_json::json!(report))),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({ "error": e })))),
+    }
+}
+
+/// GET `/admin/logging`
+/// - Log lines dropped since start because an output could not keep up
+/// - Returns `{"dropped_lines":{"file":..,"syslog":..}}`
+pub async fn logging_stats() -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    Ok(Json(serde_json::json!({ "dropped_lines": crate::logger::dropped_lines() })))
+}
+
+/// GET `/admin/rendering`
+/// - Renders since start, their output size, and the memory renders in progress hold
+/// - Returns `{"renders":..,"bytes":..,"largest_bytes":..,"in_flight_bytes":..,"peak_in_flight_bytes":..}`
+pub async fn rendering_stats() -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    Ok(Json(serde_json::json!(crate::limits::render_stats())))
+}
+
+/// GET `/admin/connections`
+/// - Connections served since start: open, accepted, closed, slow clients cut off and lifetimes
+/// - Returns `{"open":..,"accepted":..,"closed":..,"timeouts":{"header":..,"body":..,"write":..},..}`
+pub async fn connection_stats() -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    Ok(Json(serde_json::json!(crate::server::connection_stats())))
+}
+
+/// GET `/admin/lockouts`
+/// - Sources locked out for failing to authenticate, longest lockout first (see `lockout`)
+/// - Returns `{"enabled":true,"locked":[{"source":..,"failures":..,"lockouts":..,"retry_after":..}]}`
+pub async fn list_lockouts(Extension(lockout): Extension<Arc<crate::lockout::AuthLockout>>) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    Ok(Json(serde_json::json!({ "enabled": lockout.enabled(), "locked": lockout.locked(std::time::Instant::now()) })))
+}
+
+/// POST `/admin/webhooks/test`
+/// - Sends a signed sample `test` event to `WEBHOOK_URL` once, with no retries
+/// - Returns the attempt: `{"url":..,"event":{..},"signature":..,"status":200,"latency_ms":..,"delivered":true}`,
+///   with `error` when the receiver failed; `409` when no `WEBHOOK_URL` is configured
+pub async fn test_webhook(
+    State(state): State<SharedState>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let Some(webhook) = state.load().webhook.clone() else {
+        return Err((
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({ "error": "no WEBHOOK_URL configured" })),
+        ));
+    };
+
+    let attempt = webhook.test_fire().await;
+    match &attempt.error {
+        None => tracing::info!(url = webhook.url(), status = attempt.status, ms = attempt.latency_ms, "Webhook test delivered"),
+        Some(e) => tracing::warn!(url = webhook.url(), status = attempt.status, ms = attempt.latency_ms, "Webhook test failed: {e}"),
+    }
+    Ok(Json(serde_json::json!(attempt)))
+}
+
+/// GET `/admin/maintenance`
+/// - Returns `{"enabled":..,"message":..}`
+pub async fn show_maintenance(
+    State(maintenance): State<Arc<Maintenance>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    Ok(Json(serde_json::json!(maintenance.state())))
+}
+
+/// Body of `POST /admin/maintenance`.
+#[derive(Debug, Deserialize)]
+pub struct MaintenanceToggle {
+    pub enabled: bool,
+    /// Shown to `/send` clients; keeps the previous message when absent
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+/// POST `/admin/maintenance`
+/// - `{"enabled":true,"message":".."}` makes `/send` and `/ready` answer `503`; `{"enabled":false}` ends it
+/// - Background digest flushes and purges keep running
+/// - Returns the new state `{"enabled":..,"message":..}`
+pub async fn set_maintenance(
+    State(maintenance): State<Arc<Maintenance>>,
+    Json(toggle): Json<MaintenanceToggle>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let state = maintenance.set(toggle.enabled, toggle.message);
+    if state.enabled {
+        tracing::warn!(message = %state.message, "Maintenance mode on, /send answers 503");
+    } else {
+        tracing::info!("Maintenance mode off");
+    }
+    Ok(Json(serde_json::json!(state)))
+}
+
+/// POST `/admin/purge`
+/// - Applies the retention policy right away instead of waiting for the scheduled pass
+/// - Returns `{"status":"ok","removed":{..}}` or `{"error":..}`
+pub async fn purge(
+    State(state): State<SharedState>,
+    Extension(store): Extension<Store>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    match retention::purge(&state.load_full(), &*store).await {
+        Ok(report) => Ok(Json(serde_json::json!({
+            "status": "ok",
+            "removed": report,
+        }))),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": format!("purge failed: {e}") })),
+        )),
+    }
+}
+
+/// DELETE `/admin/data/{email}`
+/// - Removes every stored record referencing the address (data subject request)
+/// - Returns `{"status":"ok","email":..,"deleted":{..}}` or `{"error":..}`
+pub async fn delete_data(
+    State(state): State<SharedState>,
+    Extension(store): Extension<Store>,
+    Path(email): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    if let Err(e) = email.parse::<Address>() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": format!("invalid email: {e}") })),
+        ));
+    }
+
+    match retention::delete_subject(&state.load_full(), &*store, &email).await {
+        Ok(report) => Ok(Json(serde_json::json!({
+            "status": "ok",
+            "email": email,
+            "deleted": report,
+        }))),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": format!("deletion failed: {e}") })),
+        )),
+    }
+}
+
+/// Body of `PUT /admin/suppressions/{email}`
+#[derive(Debug, Default, Deserialize)]
+pub struct SuppressRequest {
+    #[serde(default)]
+    pub reason: String,
+}
+
+/// Body of `POST /admin/api-keys`
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKeyRequest {
+    /// Name the key is listed and revoked by; a random one when missing
+    #[serde(default)]
+    pub kid: Option<String>,
+    /// `send` or `admin`
+    pub scope: String,
+    /// `YYYY-MM-DD`: refused from the start of that day (UTC)
+    #[serde(default)]
+    pub expires: Option<String>,
+}
+
+/// POST `/admin/api-keys`
+/// - Makes a key with `scope` (`send` or `admin`) and an optional `expires` date; only its hash is stored
+/// - Returns `201` with `{"kid":..,"key":..,"scope":..,"expires_at":..,"created_at":..}`, the only time
+///   `key` is shown; `409` when the `kid` is taken (here or in `API_KEY`), or `{"error":..}`
+pub async fn create_api_key(
+    Extension(store): Extension<Store>,
+    Json(body): Json<CreateApiKeyRequest>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, Json<serde_json::Value>)> {
+    let bad_request = |error: String| (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": error })));
+    let Some(scope) = crate::scopes::Scope::parse(&body.scope) else {
+        return Err(bad_request(format!("unknown scope `{}`, expected send or admin", body.scope)));
+    };
+    let kid = body.kid.unwrap_or_else(crate::email::nanoid);
+    if !crate::apikeys::valid_kid(&kid) {
+        return Err(bad_request("kid must be 1 to 64 letters, digits, `.`, `_` or `-`".into()));
+    }
+    let now = storage::now();
+    let expires_at = match body.expires.as_deref().map(|d| crate::warmup::parse_date(d).ok_or(d)) {
+        None => None,
+        Some(Ok(until)) if until > now => Some(until),
+        Some(Ok(_)) => return Err(bad_request("expires must be a future date".into())),
+        Some(Err(d)) => return Err(bad_request(format!("invalid expires `{d}`, expected YYYY-MM-DD"))),
+    };
+    let taken = (StatusCode::CONFLICT, Json(serde_json::json!({ "error": format!("kid already in use: {kid}") })));
+    let env_keys = crate::config::var("API_KEY").ok().and_then(|k| crate::crypto::Keyring::parse_api_keys(&k).ok());
+    if env_keys.is_some_and(|keys| keys.kids().any(|k| k == kid)) {
+        return Err(taken);
+    }
+
+    let key = crate::apikeys::generate();
+    let record = storage::ApiKeyRecord { kid, hash: crate::apikeys::hash(&key), scope: scope.as_str().into(), expires_at, created_at: now, revoked_at: None };
+    if !store.create_api_key(&record).await.map_err(|e| send_error(EmailError::Storage(e.0)))? {
+        return Err(taken);
+    }
+    if let Err(e) = crate::apikeys::refresh(&*store).await {
+        tracing::warn!("API keys not refreshed after a change: {e}");
+    }
+    tracing::info!(kid = record.kid, scope = record.scope, "API key created");
+    let mut body = serde_json::json!(record);
+    body["key"] = key.into();
+    Ok((StatusCode::CREATED, Json(body)))
+}
+
+/// GET `/admin/api-keys`
+/// - Lists the keys made with `POST /admin/api-keys`, revoked ones included, paginated as in `pagination`
+/// - Sort: `created_at` (default), `kid`, or either with `-`
+/// - Returns `{"items":[{"kid":..,"scope":..,"expires_at":..,"created_at":..,"revoked_at":..}],"next_cursor":..,"total":..}`
+///   without the keys, or `{"error":..}`
+pub async fn list_api_keys(
+    Extension(store): Extension<Store>,
+    Query(page): Query<PageParams>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let items = store.api_keys().await.map_err(|e| send_error(EmailError::Storage(e.0)))?;
+    let sorts: [crate::pagination::SortKey<storage::ApiKeyRecord>; 2] =
+        [("created_at", |k| format!("{:020}", k.created_at)), ("kid", |k| k.kid.clone())];
+    match paginate(items, &page, &sorts) {
+        Ok(page) => Ok(Json(serde_json::json!(page))),
+        Err(e) => Err((StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": e })))),
+    }
+}
+
+/// DELETE `/admin/api-keys/{kid}`
+/// - Revokes a key made with `POST /admin/api-keys`; other instances refuse it within `apikeys::REFRESH_SECS`
+/// - Returns `{"status":"revoked","kid":..}`, `404` when there is no such key not yet revoked, or `{"error":..}`
+pub async fn revoke_api_key(
+    Extension(store): Extension<Store>,
+    Path(kid): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    match store.revoke_api_key(&kid, storage::now()).await {
+        Ok(true) => {
+            if let Err(e) = crate::apikeys::refresh(&*store).await {
+                tracing::warn!("API keys not refreshed after a change: {e}");
+            }
+            tracing::info!(kid, "API key revoked");
+            Ok(Json(serde_json::json!({ "status": "revoked", "kid": kid })))
+        }
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": format!("no such API key: {kid}") })),
+        )),
+        Err(e) => Err(send_error(EmailError::Storage(e.0))),
+    }
+}
+
+/// GET `/admin/suppressions`
+/// - Lists suppressed recipients, paginated as in `pagination`
+/// - Sort: `created_at` (default), `address`, or either with `-`
+/// - Returns `{"items":[{"address":..,"reason":..,"created_at":..}],"next_cursor":..,"total":..}` or `{"error":..}`
+pub async fn list_suppressions(
+    Extension(store): Extension<Store>,
+    Query(page): Query<PageParams>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
-    // 1) Auth
-    if !is_authorized() {
+    let items = store.suppressions().await.map_err(|e| send_error(EmailError::Storage(e.0)))?;
+    let sorts: [crate::pagination::SortKey<Suppression>; 2] =
+        [("created_at", |s| format!("{:020}", s.created_at)), ("address", |s| s.address.clone())];
+    match paginate(items, &page, &sorts) {
+        Ok(page) => Ok(Json(serde_json::json!(page))),
+        Err(e) => Err((StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": e })))),
+    }
+}
+
+/// PUT `/admin/suppressions/{email}`
+/// - Stops `/send` and scheduled sends from mailing the address; body `{"reason":..}` is optional
+/// - Returns `{"status":"suppressed","address":..}` or `{"error":..}`
+pub async fn suppress(
+    Extension(store): Extension<Store>,
+    Path(email): Path<String>,
+    body: Option<Json<SuppressRequest>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    if let Err(e) = email.parse::<Address>() {
         return Err((
-            StatusCode::UNAUTHORIZED,
-            Json(serde_json::json!({ "error": "unauthorized" })),
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": format!("invalid email: {e}") })),
         ));
     }
 
-    // 2) Try to render + send
-    match render_and_send(state.as_ref(), payload).await {
-        Ok(message_id) => Ok(Json(serde_json::json!({
+    let Json(body) = body.unwrap_or_default();
+    let suppression = Suppression { address: email.to_lowercase(), reason: body.reason, created_at: storage::now() };
+    store.suppress(&suppression).await.map_err(|e| send_error(EmailError::Storage(e.0)))?;
+    Ok(Json(serde_json::json!({ "status": "suppressed", "address": suppression.address })))
+}
+
+/// DELETE `/admin/suppressions/{email}`
+/// - Lets `/send` mail the address again
+/// - Returns `{"status":"ok","address":..}`, `404` when it was not suppressed, or `{"error":..}`
+pub async fn unsuppress(
+    Extension(store): Extension<Store>,
+    Path(email): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    match store.unsuppress(&email).await {
+        Ok(true) => Ok(Json(serde_json::json!({ "status": "ok", "address": email.to_lowercase() }))),
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": format!("not suppressed: {email}") })),
+        )),
+        Err(e) => Err(send_error(EmailError::Storage(e.0))),
+    }
+}
+
+/// GET `/admin/messages/{id}`
+/// - The record `/send` kept for a message ID: template, delivered recipients, campaign, time
+/// - Returns `{"id":..,"template":..,"recipients":[..],"created_at":..}`, with `policy` when the
+///   content policy flagged it and `complaints` when recipients reported it, `404`, or `{"error":..}`
+pub async fn get_message(
+    Extension(store): Extension<Store>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    match store.message(&id).await {
+        Ok(Some(message)) => {
+            let complaints = store.complaints(&id).await.map_err(|e| send_error(EmailError::Storage(e.0)))?;
+            let mut body = serde_json::json!(message);
+            if !complaints.is_empty() {
+                body["complaints"] = serde_json::json!(complaints);
+            }
+            Ok(Json(body))
+        }
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": format!("message not found: {id}") })),
+        )),
+        Err(e) => Err(send_error(EmailError::Storage(e.0))),
+    }
+}
+
+/// Query of `GET /admin/stats`.
+#[derive(Debug, Default, Deserialize)]
+pub struct StatsQuery {
+    /// Unix seconds to count from; every record still kept (`RETENTION_DAYS`) by default
+    pub since: Option<i64>,
+}
+
+/// Messages and spend of one caller or template in `GET /admin/stats`.
+#[derive(Debug, Default, Serialize)]
+struct Totals {
+    messages: i64,
+    cost: f64,
+}
+
+/// GET `/admin/stats`
+/// - Messages recorded since `since`, and their estimated cost, per caller (API key `kid`) and per
+///   template, split per A/B variant for templates that have them
+/// - Returns `{"since":..,"messages":..,"cost":..,"callers":[{"caller":..,"messages":..,"cost":..}],"templates":[{"template":..,..,"variants":[..]}]}`,
+///   `caller` `null` for messages sent without a key, `variant` `null` for the template itself, or `{"error":..}`
+pub async fn message_stats(
+    Extension(store): Extension<Store>,
+    Query(query): Query<StatsQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let since = query.since.unwrap_or(0);
+    let rows = store.message_totals(since).await.map_err(|e| send_error(EmailError::Storage(e.0)))?;
+    let (mut total, mut callers) = (Totals::default(), BTreeMap::<Option<String>, Totals>::new());
+    let mut templates = BTreeMap::<String, (Totals, BTreeMap<Option<String>, Totals>)>::new();
+    for row in rows {
+        let (template, variants) = templates.entry(row.template).or_default();
+        for t in [&mut total, callers.entry(row.caller).or_default(), template, variants.entry(row.variant).or_default()] {
+            t.messages += row.messages;
+            t.cost += row.cost;
+        }
+    }
+    let callers: Vec<_> = callers.into_iter().map(|(caller, t)| serde_json::json!({ "caller": caller, "messages": t.messages, "cost": t.cost })).collect();
+    let templates: Vec<_> = templates
+        .into_iter()
+        .map(|(template, (t, variants))| {
+            let mut entry = serde_json::json!({ "template": template, "messages": t.messages, "cost": t.cost });
+            if variants.keys().any(Option::is_some) {
+                let variants: Vec<_> = variants.into_iter().map(|(variant, t)| serde_json::json!({ "variant": variant, "messages": t.messages, "cost": t.cost })).collect();
+                entry["variants"] = serde_json::json!(variants);
+            }
+            entry
+        })
+        .collect();
+    Ok(Json(serde_json::json!({ "since": since, "messages": total.messages, "cost": total.cost, "callers": callers, "templates": templates })))
+}
+
+/// POST `/feedback/arf`
+/// - Requires an `admin` key and a feedback loop (ARF) report, the raw message, as the body
+/// - Records the complaint against its message and suppresses the complaining recipients (see `feedback`)
+/// - Returns `{"status":"recorded","feedback_type":..,"message":..,"recipients":[..],"suppressed":[..]}`,
+///   `422` when the body is not a report, or `{"error":..}`
+pub async fn ingest_feedback(
+    Extension(store): Extension<Store>,
+    body: axum::body::Bytes,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let report = crate::feedback::parse_arf(&body).map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({ "error": e }))))?;
+    let recorded = crate::feedback::record(&*store, &report, storage::now()).await.map_err(|e| send_error(EmailError::Storage(e.0)))?;
+    let mut body = serde_json::json!(recorded);
+    body["status"] = "recorded".into();
+    Ok(Json(body))
+}
+
+/// POST `/digests/{key}/events`
+/// - Requires a `DigestEvent` JSON body with a single recipient
+/// - Queues it for the next flush of the `key` digest
+/// - Returns `{"status":"queued","key":..}` or `{"error":..}`
+pub async fn queue_digest_event(
+    State(state): State<SharedState>,
+    Path(key): Path<String>,
+    Json(event): Json<DigestEvent>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let fail = |code: StatusCode, msg: String| (code, Json(serde_json::json!({ "error": msg })));
+    if !campaigns::valid_id(&key) {
+        return Err(fail(StatusCode::BAD_REQUEST, format!("invalid digest key: {key}")));
+    }
+    if let Err(e) = event.to.trim().parse::<Address>() {
+        return Err(fail(StatusCode::BAD_REQUEST, format!("invalid recipient: {e}")));
+    }
+
+    let state = state.load();
+    if let Err(e) = state.render_limits.check_vars(&event.vars) {
+        return Err(fail(StatusCode::PAYLOAD_TOO_LARGE, e.to_string()));
+    }
+    let known = is_template_name(&event.template)
+        && (state.templates_dir.join(format!("{}.hbs", event.template)).exists()
+            || matches!(crate::variants::load(&state.templates_dir, &event.template), Ok(Some(_))));
+    if !known {
+        return Err(fail(StatusCode::NOT_FOUND, format!("template not found: {}", event.template)));
+    }
+    let event = DigestEvent { to: event.to.trim().to_string(), ..event };
+    match digests::append(&state.digest_dir, &key, &event) {
+        Ok(()) => Ok(Json(serde_json::json!({ "status": "queued", "key": key }))),
+        Err(e) => Err(fail(StatusCode::INTERNAL_SERVER_ERROR, format!("cannot queue event: {e}"))),
+    }
+}
+
+/// POST `/admin/digests/flush`
+/// - Sends every pending digest now instead of waiting for the next interval
+/// - Returns `{"status":"ok","sent":..,"failed":..}` or `{"error":..}`
+pub async fn flush_digests(
+    State(state): State<SharedState>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    if digests::paused() {
+        return Err((
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({ "error": "the queue is paused, POST /admin/queue/resume first" })),
+        ));
+    }
+    match digests::flush(&state.load_full()).await {
+        Ok(report) => Ok(Json(serde_json::json!({
             "status": "ok",
-            "id": message_id,
+            "sent": report.sent,
+            "failed": report.failed,
+        }))),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": format!("flush failed: {e}") })),
+        )),
+    }
+}
+
+/// GET `/admin/queue`
+/// - Queued digest events and sends running now, and the scheduler's share per caller (see `scheduled`)
+/// - Returns `{"paused":..,"depth":..,"digests":..,"in_flight":..,"oldest_age_secs":..,"scheduled":[{"caller":..,"weight":..,"due":..,..}]}`
+pub async fn queue_stats(
+    State(state): State<SharedState>,
+    Extension(inflight): Extension<Arc<InFlight>>,
+    Extension(fair): Extension<Arc<crate::scheduled::FairShare>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    match digests::stats(&state.load().digest_dir) {
+        Ok(stats) => {
+            let mut body = serde_json::json!(stats);
+            body["paused"] = serde_json::json!(digests::paused());
+            body["in_flight"] = serde_json::json!(inflight.in_flight());
+            body["scheduled"] = serde_json::json!(fair.stats());
+            Ok(Json(body))
+        }
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": format!("cannot read the queue: {e}") })),
+        )),
+    }
+}
+
+/// GET `/admin/scaling`
+/// - Backlog (queued digest events plus running sends), drain rate and a suggested replica count
+/// - Returns `{"depth":..,"in_flight":..,"backlog":..,"drain_per_min":..,"suggested_replicas":..,"items":[..]}`
+pub async fn scaling_signal(
+    State(state): State<SharedState>,
+    Extension(scaling): Extension<Arc<Scaling>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    match digests::stats(&state.load().digest_dir) {
+        Ok(queue) => Ok(Json(serde_json::json!(scaling.signal(&queue)))),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": format!("cannot read the queue: {e}") })),
+        )),
+    }
+}
+
+/// POST `/admin/queue/pause`
+/// - Stops digest flushes after the digest being sent; events keep queueing
+/// - Returns `{"status":"paused"}`
+pub async fn pause_queue() -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    if !digests::set_paused(true) {
+        tracing::warn!("Queue paused, digests are not flushed until resumed");
+    }
+    Ok(Json(serde_json::json!({ "status": "paused" })))
+}
+
+/// POST `/admin/queue/resume`
+/// - Lets digest flushes run again; the next scheduled flush sends what queued meanwhile
+/// - Returns `{"status":"running"}`
+pub async fn resume_queue() -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    if digests::set_paused(false) {
+        tracing::info!("Queue resumed");
+    }
+    Ok(Json(serde_json::json!({ "status": "running" })))
+}
+
+/// Filters for `GET /templates`
+#[derive(Deserialize)]
+pub struct TemplateFilter {
+    /// Only names starting with this prefix
+    pub prefix: Option<String>,
+}
+
+/// Entry in the `/templates` list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateSummary {
+    /// Name to pass as `template` to `/send`
+    pub name: String,
+}
+
+/// GET `/templates`
+/// - Lists sendable templates (the `base` layout is excluded), paginated as in `pagination`
+/// - Sort: `name` (default) or `-name`; filter: `prefix`
+/// - Returns `{"items":[{"name":..}],"next_cursor":..,"total":..}` or `{"error":..}`
+pub async fn list_templates(
+    State(state): State<SharedState>,
+    Query(page): Query<PageParams>,
+    Query(filter): Query<TemplateFilter>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let dir = state.load().templates_dir.clone();
+    let entries = std::fs::read_dir(&dir).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": format!("cannot read templates: {e}") })),
+        )
+    })?;
+    let prefix = filter.prefix.unwrap_or_default();
+    let templates: Vec<TemplateSummary> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str()?.strip_suffix(".hbs").map(str::to_string))
+        .filter(|name| name != "base" && name.starts_with(&prefix))
+        .map(|name| TemplateSummary { name })
+        .collect();
+
+    match paginate(templates, &page, &[("name", |t: &TemplateSummary| t.name.clone())]) {
+        Ok(page) => Ok(Json(serde_json::json!(page))),
+        Err(e) => Err((StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": e })))),
+    }
+}
+
+/// Move `{name}.hbs` from `from` to `to`, refusing to overwrite.
+/// Names must pass `is_template_name` so they cannot escape the templates dir.
+fn move_template(from: &std::path::Path, to: &std::path::Path, name: &str) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let fail = |code: StatusCode, msg: String| (code, Json(serde_json::json!({ "error": msg })));
+    if name == "base" || !is_template_name(name) {
+        return Err(fail(StatusCode::BAD_REQUEST, format!("invalid template name: {name}")));
+    }
+    let file = format!("{name}.hbs");
+    if to.join(&file).exists() {
+        return Err(fail(StatusCode::CONFLICT, format!("template already exists there: {name}")));
+    }
+    if !from.join(&file).exists() {
+        return Err(fail(StatusCode::NOT_FOUND, format!("template not found: {name}")));
+    }
+    std::fs::create_dir_all(to)
+        .and_then(|_| std::fs::rename(from.join(&file), to.join(&file)))
+        .map_err(|e| fail(StatusCode::INTERNAL_SERVER_ERROR, format!("cannot move template: {e}")))
+}
+
+/// POST `/templates/{name}/archive`
+/// - Moves the template into `archive/`: `/send` then answers `410 Gone` and `/templates` omits it
+/// - Returns `{"status":"ok","template":..,"archived":true}` or `{"error":..}`
+pub async fn archive_template(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let dir = state.load().templates_dir.clone();
+    move_template(&dir, &dir.join(ARCHIVE_DIR), &name)?;
+    Ok(Json(serde_json::json!({ "status": "ok", "template": name, "archived": true })))
+}
+
+/// POST `/templates/{name}/restore`
+/// - Moves an archived template back so it can be sent again
+/// - Returns `{"status":"ok","template":..,"archived":false}` or `{"error":..}`
+pub async fn restore_template(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let dir = state.load().templates_dir.clone();
+    move_template(&dir.join(ARCHIVE_DIR), &dir, &name)?;
+    Ok(Json(serde_json::json!({ "status": "ok", "template": name, "archived": false })))
+}
+
+/// Body of `POST /templates/{name}/spamcheck`; every field is optional.
+#[derive(Debug, Default, Deserialize)]
+pub struct SpamcheckRequest {
+    /// Merged over the template's `<name>.sample.json`
+    #[serde(default)]
+    pub vars: HashMap<String, serde_json::Value>,
+    /// Defaults to the template name; the subject counts towards the score
+    pub subject: Option<String>,
+    /// Defaults to `MAIL_FROM`
+    pub to: Option<String>,
+    pub preheader: Option<String>,
+}
+
+/// POST `/templates/{name}/spamcheck`
+/// - Builds the message `/send` would deliver for the template with its sample vars and has
+///   the filter at `SPAMCHECK_URL` score it
+/// - Returns `{"template":..,"score":2.1,"threshold":5.0,"spam":false,"rules":[..]}` or
+///   `{"error":..}` with `/render/eml`'s statuses; `409` when `SPAMCHECK_URL` is unset, `502`
+///   when the filter fails
+pub async fn spamcheck_template(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+    body: Option<Json<SpamcheckRequest>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let state = state.load();
+    let Some(checker) = state.spamcheck.clone() else {
+        return Err((StatusCode::CONFLICT, Json(serde_json::json!({ "error": "spam checks are off; set SPAMCHECK_URL" }))));
+    };
+    if !is_template_name(&name) {
+        return Err(send_error(EmailError::TemplateNotFound(name)));
+    }
+    let body = body.map(|Json(b)| b).unwrap_or_default();
+    let mut vars = crate::spamcheck::sample_vars(&state.templates_dir, &name)
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({ "error": e }))))?;
+    vars.extend(body.vars);
+    let request = SendRequest {
+        to: body.to.unwrap_or_else(|| state.from.to_string()),
+        subject: body.subject.unwrap_or_else(|| name.clone()),
+        template: name.clone(),
+        vars,
+        campaign_id: None,
+        phone: None,
+        preheader: body.preheader,
+        utm: None,
+        send_at: None,
+        attachments: Vec::new(),
+        zip: None,
+    };
+    state.render_limits.check_vars(&request.vars).map_err(send_error)?;
+    let eml = crate::email::render_eml(&state, &request).await.map_err(send_error)?;
+    match checker.check(&eml).await {
+        Ok(report) => {
+            let mut answer = serde_json::json!(report);
+            answer["template"] = serde_json::json!(name);
+            Ok(Json(answer))
+        }
+        Err(e) => Err((StatusCode::BAD_GATEWAY, Json(serde_json::json!({ "error": format!("spam check failed: {e}") })))),
+    }
+}
+
+/// POST `/admin/templates/promote`
+/// - Makes `TEMPLATES_DIR_STAGED` the active templates root once it loads, swapping the two
+/// - Returns `{"status":"ok","active":..,"staged":..,"templates":N}` or `{"error":..}`:
+///   `409` without a staged root, `422` when it does not load, `503` when storage fails
+pub async fn promote_templates(
+    State(state): State<SharedState>,
+    Extension(roots): Extension<Option<Arc<TemplateRoots>>>,
+    Extension(store): Extension<Store>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let Some(roots) = roots else {
+        return Err((
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({ "error": "TEMPLATES_DIR_STAGED is not set" })),
+        ));
+    };
+    match roots.promote(&state, &*store).await {
+        Ok(promotion) => Ok(Json(serde_json::json!({
+            "status": "ok",
+            "active": promotion.active,
+            "staged": promotion.staged,
+            "templates": promotion.templates,
         }))),
         Err(e) => {
-            // Map domain error → status code
-            let (code, msg) = match e {
-                EmailError::TemplateNotFound(_) => (StatusCode::NOT_FOUND, e.to_string()),
-                EmailError::RenderError(_) => (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()),
-                _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+            let code = match e {
+                PromoteError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                PromoteError::Storage(_) => StatusCode::SERVICE_UNAVAILABLE,
             };
-            Err((code, Json(serde_json::json!({ "error": msg }))))
+            Err((code, Json(serde_json::json!({ "error": e.to_string() }))))
+        }
+    }
+}
+
+/// GET `/assets/{path}`
+/// - Serves `TEMPLATES_DIR/assets/{path}` without auth, for images in sent emails
+/// - Only plain file names in each path segment; unknown extensions are refused
+/// - Returns the file with a `Cache-Control` of one day, or `{"error":..}`
+pub async fn get_asset(
+    State(state): State<SharedState>,
+    Path(path): Path<String>,
+) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
+    let fail = |code: StatusCode, msg: String| (code, Json(serde_json::json!({ "error": msg })));
+    if !path.split('/').all(is_template_name) {
+        return Err(fail(StatusCode::BAD_REQUEST, format!("invalid asset path: {path}")));
+    }
+    let extension = path.rsplit_once('.').map(|(_, x)| x.to_ascii_lowercase()).unwrap_or_default();
+    let content_type = match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "css" => "text/css",
+        _ => return Err(fail(StatusCode::NOT_FOUND, format!("asset not found: {path}"))),
+    };
+    let file = state.load().templates_dir.join(ASSETS_DIR).join(&path);
+    match tokio::fs::read(&file).await {
+        Ok(bytes) => Ok((
+            [(header::CONTENT_TYPE, content_type), (header::CACHE_CONTROL, "public, max-age=86400")],
+            bytes,
+        )
+            .into_response()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(fail(StatusCode::NOT_FOUND, format!("asset not found: {path}"))),
+        Err(e) => Err(fail(StatusCode::INTERNAL_SERVER_ERROR, format!("cannot read asset: {e}"))),
+    }
+}
+
+/// GET `/campaigns/{id}/stats`
+/// - Counters for sends tagged with `campaign_id` since this instance started
+/// - Returns `{"campaign_id":..,"sends":..,"messages":..,"cost":..,"skipped":..,"failures":..,"last_send_at":..,"cancelled_at":..,"refused":..}`,
+///   `404` if nothing was sent for the campaign
+pub async fn campaign_stats(
+    State(campaigns): State<Arc<Campaigns>>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    match campaigns.get(&id) {
+        Some(stats) => {
+            let mut body = serde_json::json!(stats);
+            body["campaign_id"] = serde_json::json!(id);
+            Ok(Json(body))
         }
+        None => Err((
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": format!("no sends for campaign: {id}") })),
+        )),
     }
 }
+
+/// POST `/admin/campaigns/{id}/cancel`
+/// - Kill switch: every later send with this `campaign_id`, direct, scheduled or from the outbox,
+///   is refused (`409` on `/send`), and its scheduled sends still waiting are removed
+/// - Kept in storage, so it holds after a restart and other instances follow within `campaigns::REFRESH_SECS`
+/// - Returns the campaign's stats with `cancelled_at` set and `scheduled_removed`, or `{"error":..}`
+pub async fn cancel_campaign(
+    State(campaigns): State<Arc<Campaigns>>,
+    Extension(store): Extension<Store>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    if !campaigns::valid_id(&id) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": format!("invalid campaign_id: {id}") })),
+        ));
+    }
+
+    let (cancelled_at, removed) = store.cancel_campaign(&id, storage::now()).await.map_err(|e| send_error(EmailError::Storage(e.0)))?;
+    let stats = campaigns.cancel(&id, cancelled_at);
+    tracing::warn!(campaign = %id, scheduled_removed = removed, "Campaign cancelled, further sends are refused");
+    let mut body = serde_json::json!(stats);
+    body["campaign_id"] = serde_json::json!(id);
+    body["scheduled_removed"] = serde_json::json!(removed);
+    Ok(Json(body))
+}
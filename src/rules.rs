@@ -0,0 +1,261 @@
+//! Per-template sending rules, checked before rendering.
+//!
+//! A template may have a `<template>.rules` file next to it, one rule per line:
+//!
+//! ```text
+//! # comments and blank lines are ignored
+//! skip if vars.amount == 0
+//! template invoice_gov if recipient.domain ends_with "gov.br"
+//! skip if vars.plan == "free" && vars.opted_out == true
+//! ```
+//!
+//! Rules are evaluated for every recipient; the first matching rule decides. `skip`
+//! drops the recipient, `template <name>` sends them `<name>` instead (that template's
+//! own rules are not applied). Recipients that end up on different templates get
+//! separate messages.
+//!
+//! Conditions compare a *path* with a JSON literal (`0`, `"text"`, `true`, `null`)
+//! using `==`, `!=`, `<`, `<=`, `>`, `>=`, `contains`, `starts_with` or `ends_with`,
+//! joined by `&&` and `||` (`&&` binds tighter). Paths are `vars.<key>[.<key>..]`,
+//! `recipient` (the address), `recipient.domain`, `recipient.local` and `template`;
+//! a missing path is `null`. The file is read on every send, like the template.
+
+use std::{collections::HashMap, path::Path};
+
+use serde_json::Value;
+
+/// What to do with a recipient matched by a rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Skip,
+    Template(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+    StartsWith,
+    EndsWith,
+}
+
+#[derive(Debug, Clone)]
+struct Comparison {
+    path: String,
+    op: Op,
+    value: Value,
+}
+
+/// One `<action> if <condition>` line.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub action: Action,
+    /// OR of ANDs of comparisons.
+    any_of: Vec<Vec<Comparison>>,
+}
+
+/// Facts a condition can look at for one recipient.
+pub struct Subject<'a> {
+    pub template: &'a str,
+    pub recipient: &'a str,
+    pub vars: &'a HashMap<String, Value>,
+}
+
+impl Rule {
+    /// # Rule::parse()
+    /// Parse one rule line.
+    /// # Example
+    /// ```
+    /// use std::collections::HashMap;
+    /// use templar::rules::{Action, Rule, Subject};
+    /// let rule = Rule::parse(r#"template invoice_gov if recipient.domain ends_with "gov.br" && vars.amount > 0"#).unwrap();
+    /// assert_eq!(rule.action, Action::Template("invoice_gov".into()));
+    ///
+    /// let vars = HashMap::from([("amount".to_string(), serde_json::json!(12.5))]);
+    /// let at = |recipient| Subject { template: "invoice", recipient, vars: &vars };
+    /// assert!(rule.matches(&at("ana@receita.gov.br")));
+    /// assert!(!rule.matches(&at("ana@example.com")));
+    /// ```
+    /// # Errors
+    /// Describes the first problem: unknown action or operator, bad literal, missing `if`.
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let tokens = tokenize(line)?;
+        let if_at = tokens.iter().position(|t| t == "if").ok_or("expected `<action> if <condition>`")?;
+        let action = match &tokens[..if_at] {
+            [a] if a == "skip" => Action::Skip,
+            [a, name] if a == "template" => Action::Template(name.clone()),
+            _ => return Err("action must be `skip` or `template <name>`".into()),
+        };
+        let mut any_of = vec![Vec::new()];
+        let mut rest = &tokens[if_at + 1..];
+        loop {
+            let [path, op, value, tail @ ..] = rest else {
+                return Err("condition must be `<path> <op> <value>`".into());
+            };
+            let op = match op.as_str() {
+                "==" => Op::Eq,
+                "!=" => Op::Ne,
+                "<" => Op::Lt,
+                "<=" => Op::Le,
+                ">" => Op::Gt,
+                ">=" => Op::Ge,
+                "contains" => Op::Contains,
+                "starts_with" => Op::StartsWith,
+                "ends_with" => Op::EndsWith,
+                other => return Err(format!("unknown operator `{other}`")),
+            };
+            let value: Value = serde_json::from_str(value).map_err(|_| format!("`{value}` is not a JSON literal (quote strings)"))?;
+            any_of.last_mut().unwrap().push(Comparison { path: path.clone(), op, value });
+            match tail {
+                [] => break,
+                [join, more @ ..] if join == "&&" => rest = more,
+                [join, more @ ..] if join == "||" => {
+                    any_of.push(Vec::new());
+                    rest = more;
+                }
+                [other, ..] => return Err(format!("expected `&&` or `||`, found `{other}`")),
+            }
+        }
+        Ok(Self { action, any_of })
+    }
+
+    /// Whether the condition holds for `subject`.
+    pub fn matches(&self, subject: &Subject) -> bool {
+        self.any_of.iter().any(|all| all.iter().all(|c| c.holds(subject)))
+    }
+}
+
+impl Comparison {
+    fn holds(&self, s: &Subject) -> bool {
+        let actual = resolve(&self.path, s);
+        let num = |v: &Value| v.as_f64();
+        let text = |v: &Value| v.as_str().map(str::to_string);
+        match self.op {
+            Op::Eq => json_eq(&actual, &self.value),
+            Op::Ne => !json_eq(&actual, &self.value),
+            Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+                let ord = match (num(&actual), num(&self.value)) {
+                    (Some(a), Some(b)) => a.partial_cmp(&b),
+                    _ => match (text(&actual), text(&self.value)) {
+                        (Some(a), Some(b)) => Some(a.cmp(&b)),
+                        _ => None,
+                    },
+                };
+                ord.is_some_and(|o| match self.op {
+                    Op::Lt => o.is_lt(),
+                    Op::Le => o.is_le(),
+                    Op::Gt => o.is_gt(),
+                    _ => o.is_ge(),
+                })
+            }
+            Op::Contains => match (&actual, &self.value) {
+                (Value::String(a), Value::String(b)) => a.contains(b.as_str()),
+                (Value::Array(items), v) => items.iter().any(|i| json_eq(i, v)),
+                _ => false,
+            },
+            Op::StartsWith | Op::EndsWith => match (text(&actual), text(&self.value)) {
+                (Some(a), Some(b)) if self.op == Op::StartsWith => a.starts_with(&b),
+                (Some(a), Some(b)) => a.ends_with(&b),
+                _ => false,
+            },
+        }
+    }
+}
+
+/// JSON equality where `1` and `1.0` are equal.
+fn json_eq(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(x), Some(y)) => x == y,
+        _ => a == b,
+    }
+}
+
+fn resolve(path: &str, s: &Subject) -> Value {
+    let (domain, local) = match s.recipient.rsplit_once('@') {
+        Some((l, d)) => (d.to_lowercase(), l.to_string()),
+        None => (String::new(), s.recipient.to_string()),
+    };
+    match path {
+        "template" => Value::from(s.template),
+        "recipient" => Value::from(s.recipient),
+        "recipient.domain" => Value::from(domain),
+        "recipient.local" => Value::from(local),
+        _ => {
+            let Some(rest) = path.strip_prefix("vars.") else { return Value::Null };
+            let mut keys = rest.split('.');
+            let mut current = keys.next().and_then(|k| s.vars.get(k)).cloned().unwrap_or(Value::Null);
+            for key in keys {
+                current = current.get(key).cloned().unwrap_or(Value::Null);
+            }
+            current
+        }
+    }
+}
+
+/// Split on whitespace, keeping `"quoted strings"` (with `\"` escapes) as one token.
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            let mut tok = String::from(chars.next().unwrap());
+            loop {
+                match chars.next() {
+                    Some('\\') => {
+                        tok.push('\\');
+                        tok.extend(chars.next());
+                    }
+                    Some('"') => {
+                        tok.push('"');
+                        break;
+                    }
+                    Some(ch) => tok.push(ch),
+                    None => return Err("unterminated string".into()),
+                }
+            }
+            tokens.push(tok);
+        } else {
+            let mut tok = String::new();
+            while let Some(&ch) = chars.peek() {
+                if ch.is_whitespace() {
+                    break;
+                }
+                tok.push(ch);
+                chars.next();
+            }
+            tokens.push(tok);
+        }
+    }
+    Ok(tokens)
+}
+
+/// # load()
+/// Rules for `template` from `<dir>/<template>.rules`; empty when there is no file.
+/// # Errors
+/// The file is unreadable, or a line fails to parse (with its line number).
+pub fn load(dir: &Path, template: &str) -> Result<Vec<Rule>, String> {
+    let path = dir.join(format!("{template}.rules"));
+    let src = match std::fs::read_to_string(&path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("{}: {e}", path.display())),
+    };
+    src.lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l.trim()))
+        .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'))
+        .map(|(n, l)| Rule::parse(l).map_err(|e| format!("{} line {n}: {e}", path.display())))
+        .collect()
+}
+
+/// First matching rule's action for `subject`, `None` to send as requested.
+pub fn decide<'r>(rules: &'r [Rule], subject: &Subject) -> Option<&'r Action> {
+    rules.iter().find(|r| r.matches(subject)).map(|r| &r.action)
+}
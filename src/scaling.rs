@@ -0,0 +1,102 @@
+//! Autoscaling signal behind `GET /admin/scaling`: the backlog, how fast it drains and
+//! a suggested replica count, so a deployment can scale its workers on backlog.
+//!
+//! The backlog is the digest events queued in `DIGEST_DIR` plus the `/send` requests
+//! running on this instance. The drain rate counts the events flushed over the last
+//! `DIGEST_INTERVAL_SECS` (at least a minute), so it covers one flush. The suggestion is
+//! `ceil(backlog / SCALING_TARGET_BACKLOG)`, clamped to
+//! `SCALING_MIN_REPLICAS..=SCALING_MAX_REPLICAS`.
+//!
+//! Besides the plain fields, `items` repeats the numbers in the shape of the Kubernetes
+//! external metrics API (`metricName`/`value`), so KEDA's `metrics-api` scaler can read
+//! either `suggested_replicas` or `items.N.value`.
+
+use std::{sync::Arc, time::Duration};
+
+use serde::Serialize;
+
+use crate::backpressure::InFlight;
+use crate::config::ApiConfig;
+use crate::digests::{self, QueueStats};
+
+/// Heuristic settings (`SCALING_*`) and this instance's running sends.
+pub struct Scaling {
+    inflight: Arc<InFlight>,
+    target_backlog: u64,
+    min_replicas: u32,
+    max_replicas: u32,
+    window: Duration,
+}
+
+/// One answer of `GET /admin/scaling`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScalingSignal {
+    /// Queued digest events.
+    pub depth: u64,
+    /// `/send` requests running on this instance.
+    pub in_flight: u64,
+    pub backlog: u64,
+    /// Events flushed per minute over the drain window.
+    pub drain_per_min: f64,
+    /// Time to empty the queue at that rate; `None` while nothing drains.
+    pub drain_eta_secs: Option<u64>,
+    pub suggested_replicas: u32,
+    pub items: Vec<MetricValue>,
+}
+
+/// An external metrics API item.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricValue {
+    pub metric_name: &'static str,
+    /// A Kubernetes quantity, as a string.
+    pub value: String,
+}
+
+impl Scaling {
+    pub fn new(config: &ApiConfig, inflight: Arc<InFlight>) -> Self {
+        Self {
+            inflight,
+            target_backlog: config.scaling_target_backlog,
+            min_replicas: config.scaling_min_replicas,
+            max_replicas: config.scaling_max_replicas,
+            window: Duration::from_secs(config.digest_interval_secs.max(60)),
+        }
+    }
+
+    /// # signal()
+    /// The current signal for a queue in the state `queue`.
+    pub fn signal(&self, queue: &QueueStats) -> ScalingSignal {
+        let (depth, in_flight) = (queue.depth as u64, self.inflight.in_flight());
+        let backlog = depth + in_flight;
+        let drain_per_min = digests::drained_within(self.window) as f64 * 60.0 / self.window.as_secs_f64();
+        let drain_eta_secs = (drain_per_min > 0.0).then(|| (depth as f64 / drain_per_min * 60.0).ceil() as u64);
+        let suggested_replicas = self.suggest(backlog);
+        let items = vec![
+            MetricValue { metric_name: "templar_backlog", value: backlog.to_string() },
+            MetricValue { metric_name: "templar_queue_depth", value: depth.to_string() },
+            MetricValue { metric_name: "templar_drain_per_min", value: format!("{}m", (drain_per_min * 1000.0).round() as u64) },
+            MetricValue { metric_name: "templar_suggested_replicas", value: suggested_replicas.to_string() },
+        ];
+        ScalingSignal { depth, in_flight, backlog, drain_per_min, drain_eta_secs, suggested_replicas, items }
+    }
+
+    /// Replicas for `backlog`: one per `target_backlog` queued, within the bounds.
+    /// # Example
+    /// ```
+    /// use std::sync::Arc;
+    /// use templar::{backpressure::InFlight, scaling::Scaling};
+    /// let mut config = templar::config::get_defaults();
+    /// config.scaling_target_backlog = 100;
+    /// config.scaling_max_replicas = 5;
+    /// let inflight = Arc::new(InFlight::new(config.max_in_flight));
+    /// let scaling = Scaling::new(&config, inflight);
+    /// assert_eq!(scaling.suggest(0), 1);
+    /// assert_eq!(scaling.suggest(250), 3);
+    /// assert_eq!(scaling.suggest(10_000), 5);
+    /// ```
+    pub fn suggest(&self, backlog: u64) -> u32 {
+        let wanted = backlog.div_ceil(self.target_backlog.max(1));
+        wanted.clamp(u64::from(self.min_replicas), u64::from(self.max_replicas)) as u32
+    }
+}
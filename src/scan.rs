@@ -0,0 +1,221 @@
+//! Virus scanning of attachments before a send: with `SCAN_URL` set, every attachment is
+//! streamed to a scanner once it is downloaded, rendered or generated, and a send with an
+//! infected file is refused (`422`) before anything goes out.
+//!
+//! - `clamd://host:3310`: ClamAV's daemon, over its `INSTREAM` command
+//! - `icap://host:1344/service`: an ICAP server (c-icap, Kaspersky, Sophos, ..), as a
+//!   `RESPMOD` with the file as the response body; `204` is clean
+//!
+//! A scanner that cannot be reached, times out after `SCAN_TIMEOUT_SECS` or fails answers
+//! `502` and the send is retried like any unavailable attachment: files are never sent
+//! unscanned.
+
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::attachments::{AttachmentError, File};
+use crate::config::ApiConfig;
+
+/// Bytes sent per `INSTREAM` chunk; clamd's default `StreamMaxLength` is 25 MiB overall.
+const CHUNK: usize = 64 * 1024;
+/// Longest reply accepted from a scanner, headers included.
+const MAX_REPLY: usize = 64 * 1024;
+
+/// Where attachments are scanned (`SCAN_URL`).
+#[derive(Debug, Clone)]
+pub struct Scanner {
+    protocol: Protocol,
+    /// `host:port` to connect to
+    addr: String,
+    /// The ICAP service URI, `icap://host:port/service`
+    uri: String,
+    timeout: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Clamd,
+    Icap,
+}
+
+impl Scanner {
+    /// # Scanner::new()
+    /// The scanner at `url`, `clamd://host[:port]` (port 3310) or `icap://host[:port]/service`
+    /// (port 1344), giving up on one file after `timeout`.
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use templar::scan::Scanner;
+    /// assert!(Scanner::new("clamd://clamav:3310", Duration::from_secs(30)).is_ok());
+    /// assert!(Scanner::new("icap://icap.internal/avscan", Duration::from_secs(30)).is_ok());
+    /// assert!(Scanner::new("icap://icap.internal", Duration::from_secs(30)).is_err());
+    /// assert!(Scanner::new("http://clamav:3310", Duration::from_secs(30)).is_err());
+    /// ```
+    /// # Errors
+    /// `url` is not a `clamd://` or `icap://` URL with a host, or an ICAP URL names no service.
+    pub fn new(url: &str, timeout: Duration) -> Result<Self, String> {
+        let parsed = url::Url::parse(url).map_err(|e| format!("`{url}`: {e}"))?;
+        let (protocol, default_port) = match parsed.scheme() {
+            "clamd" => (Protocol::Clamd, 3310),
+            "icap" => (Protocol::Icap, 1344),
+            other => return Err(format!("`{url}`: scheme `{other}`, expected clamd:// or icap://")),
+        };
+        let host = parsed.host_str().filter(|h| !h.is_empty()).ok_or_else(|| format!("`{url}` has no host"))?;
+        if protocol == Protocol::Icap && parsed.path().trim_matches('/').is_empty() {
+            return Err(format!("`{url}` names no ICAP service, as in icap://{host}/avscan"));
+        }
+        let port = parsed.port().unwrap_or(default_port);
+        let uri = format!("icap://{host}:{port}{}", parsed.path());
+        Ok(Self { protocol, addr: format!("{host}:{port}"), uri, timeout })
+    }
+
+    /// The scanner from `SCAN_URL`; `None` when it is empty.
+    pub fn from_config(config: &ApiConfig) -> Result<Option<Self>, anyhow::Error> {
+        let url = config.scan_url.trim();
+        if url.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self::new(url, Duration::from_secs(config.scan_timeout_secs)).map_err(|e| anyhow::anyhow!("invalid SCAN_URL {e}"))?))
+    }
+
+    /// # Scanner::scan()
+    /// Streams `file` to the scanner and waits for its verdict.
+    /// # Errors
+    /// [`AttachmentError::Infected`] when the scanner finds something,
+    /// [`AttachmentError::Refused`] when the file is larger than the scanner accepts and
+    /// [`AttachmentError::Unavailable`] when it cannot be reached, times out or fails.
+    pub async fn scan(&self, file: &File) -> Result<(), AttachmentError> {
+        let verdict = tokio::time::timeout(self.timeout, async {
+            let mut stream = TcpStream::connect(&self.addr).await.map_err(|e| format!("{}: {e}", self.addr))?;
+            match self.protocol {
+                Protocol::Clamd => clamd(&mut stream, &file.body).await,
+                Protocol::Icap => icap(&mut stream, &self.uri, file).await,
+            }
+        })
+        .await
+        .map_err(|_| format!("no verdict within {}s", self.timeout.as_secs()))
+        .and_then(|v| v);
+        match verdict {
+            Ok(Verdict::Clean) => Ok(()),
+            Ok(Verdict::Infected(threat)) => {
+                tracing::warn!(filename = file.filename, threat, "Attachment refused by the virus scanner");
+                Err(AttachmentError::Infected { filename: file.filename.clone(), threat })
+            }
+            Ok(Verdict::TooLarge) => Err(AttachmentError::Refused(format!("{}: larger than the virus scanner accepts", file.filename))),
+            Err(e) => Err(AttachmentError::Unavailable(format!("virus scanner: {e}"))),
+        }
+    }
+}
+
+enum Verdict {
+    Clean,
+    Infected(String),
+    TooLarge,
+}
+
+/// `zINSTREAM`: length-prefixed chunks, a zero length to end, then one reply such as
+/// `stream: OK` or `stream: Eicar-Test-Signature FOUND`.
+async fn clamd<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, body: &[u8]) -> Result<Verdict, String> {
+    let io = |e: std::io::Error| format!("clamd: {e}");
+    stream.write_all(b"zINSTREAM\0").await.map_err(io)?;
+    for chunk in body.chunks(CHUNK) {
+        stream.write_all(&(chunk.len() as u32).to_be_bytes()).await.map_err(io)?;
+        if let Err(e) = stream.write_all(chunk).await {
+            // clamd closes the stream once StreamMaxLength is passed; its reply says so
+            tracing::debug!("clamd stopped reading: {e}");
+            break;
+        }
+    }
+    let _ = stream.write_all(&0u32.to_be_bytes()).await;
+    let mut reply = Vec::new();
+    BufReader::new(stream).take(MAX_REPLY as u64).read_until(b'\0', &mut reply).await.map_err(io)?;
+    let reply = String::from_utf8_lossy(&reply).trim_end_matches('\0').trim().to_string();
+    let verdict = reply.strip_prefix("stream:").map(str::trim).unwrap_or(&reply);
+    if verdict == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(threat) = verdict.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected(threat.trim().to_string()))
+    } else if verdict.contains("size limit exceeded") {
+        Ok(Verdict::TooLarge)
+    } else if verdict.is_empty() {
+        Err("clamd closed the connection without a verdict".into())
+    } else {
+        Err(format!("clamd answered `{verdict}`"))
+    }
+}
+
+/// `RESPMOD` with `file` as the body of an HTTP response; `204` is clean, a `200` (the
+/// file replaced by a block page) or an infection header is a detection.
+async fn icap<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, uri: &str, file: &File) -> Result<Verdict, String> {
+    let io = |e: std::io::Error| format!("ICAP: {e}");
+    let host = uri.trim_start_matches("icap://").split('/').next().unwrap_or_default();
+    let req_hdr = format!("GET /{} HTTP/1.1\r\nHost: templar\r\n\r\n", percent_encoding::utf8_percent_encode(&file.filename, percent_encoding::NON_ALPHANUMERIC));
+    // Servers may skip types they trust; octet-stream is scanned by all of them
+    let res_hdr = format!("HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n", file.body.len());
+    let head = format!(
+        "RESPMOD {uri} ICAP/1.0\r\nHost: {host}\r\nAllow: 204\r\nEncapsulated: req-hdr=0, res-hdr={}, res-body={}\r\n\r\n{req_hdr}{res_hdr}",
+        req_hdr.len(),
+        req_hdr.len() + res_hdr.len(),
+    );
+    let sent = async {
+        stream.write_all(head.as_bytes()).await?;
+        for chunk in file.body.chunks(CHUNK) {
+            stream.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+            stream.write_all(chunk).await?;
+            stream.write_all(b"\r\n").await?;
+        }
+        stream.write_all(b"0\r\n\r\n").await?;
+        stream.flush().await
+    }
+    .await;
+
+    // A server may answer and close before the whole body is in; its answer still counts
+    let mut reader = BufReader::new(stream).take(MAX_REPLY as u64);
+    let mut status = String::new();
+    reader.read_line(&mut status).await.map_err(io)?;
+    let Some(code) = status.split_whitespace().nth(1).and_then(|c| c.parse::<u16>().ok()) else {
+        return Err(match sent {
+            Err(e) => io(e),
+            Ok(()) => format!("ICAP server answered `{}`", status.trim()),
+        });
+    };
+    let mut threat = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await.map_err(io)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            threat = threat.or_else(|| infection(name.trim(), value.trim()));
+        }
+    }
+    match (code, threat) {
+        (_, Some(threat)) => Ok(Verdict::Infected(threat)),
+        (204, None) => Ok(Verdict::Clean),
+        (200, None) => Ok(Verdict::Infected("blocked by the ICAP server".into())),
+        (413, None) => Ok(Verdict::TooLarge),
+        (code, None) => Err(format!("ICAP server answered {code}: {}", status.trim())),
+    }
+}
+
+/// The threat named by an ICAP header: `X-Infection-Found: Type=0; Resolution=2; Threat=Eicar;`,
+/// or `X-Virus-ID: Eicar`.
+fn infection(name: &str, value: &str) -> Option<String> {
+    match name.to_ascii_lowercase().as_str() {
+        "x-infection-found" => Some(
+            value
+                .split(';')
+                .find_map(|p| p.trim().strip_prefix("Threat="))
+                .unwrap_or(value)
+                .trim()
+                .to_string(),
+        ),
+        "x-virus-id" => Some(value.to_string()),
+        _ => None,
+    }
+    .filter(|t| !t.is_empty())
+}
@@ -0,0 +1,226 @@
+//! Scheduled sends: `/send` requests with a future `send_at`, held in storage until due.
+//!
+//! Every instance polls storage and claims what is due, so with a shared backend each
+//! send goes out once whichever replica picks it up. A send whose instance died mid-way
+//! is claimed again after [`STALE_CLAIM_SECS`]. Sends that fail with a transient error
+//! (relay down, storage unavailable) are retried the same way; anything else is logged
+//! and dropped, as `/send` would have answered it with an error.
+//!
+//! Each poll's [`BATCH`] is shared between callers, the API keys (`kid`) the sends were
+//! made with, so one caller scheduling a flood does not hold everyone else's sends back
+//! until it drains. The batch is claimed in rounds: each round, every caller with sends
+//! due gets its weight in tokens (`FAIR_SHARE_WEIGHTS`, `1` by default), and spends one
+//! per send, oldest first. How long each caller's sends waited past `send_at` is shown by
+//! `GET /admin/queue`.
+//!
+//! The same loop ages out idempotency keys older than `IDEMPOTENCY_TTL_SECS`.
+
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::campaigns::Campaigns;
+use crate::config::ApiConfig;
+use crate::email::SharedState;
+use crate::storage::{self, Store};
+
+/// How often storage is polled for due sends.
+const POLL_EVERY: Duration = Duration::from_secs(5);
+
+/// A claim older than this is taken to belong to a dead instance.
+pub const STALE_CLAIM_SECS: i64 = 300;
+
+/// Sends claimed per poll.
+const BATCH: u32 = 50;
+
+/// Highest weight `FAIR_SHARE_WEIGHTS` takes.
+pub const MAX_WEIGHT: u32 = 100;
+
+/// Callers' weights, and what the scheduler saw of each (`FAIR_SHARE_WEIGHTS`).
+#[derive(Debug, Default)]
+pub struct FairShare {
+    weights: HashMap<String, u32>,
+    callers: Mutex<HashMap<Option<String>, Waits>>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Waits {
+    due: i64,
+    claimed: u64,
+    /// Moving average, weight 1/8 per send (0 until the first)
+    avg_wait_secs: u64,
+    max_wait_secs: u64,
+}
+
+/// One caller's share, for `GET /admin/queue`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CallerStats {
+    /// `kid` of the API key, `null` for sends made without one
+    pub caller: Option<String>,
+    pub weight: u32,
+    /// Due and waiting at the last poll
+    pub due: i64,
+    /// Sends taken from the queue since startup
+    pub claimed: u64,
+    /// How long sends waited past `send_at`
+    pub avg_wait_secs: u64,
+    pub max_wait_secs: u64,
+}
+
+impl FairShare {
+    pub fn from_config(config: &ApiConfig) -> Self {
+        // Validated by `ApiConfig::load`
+        Self::new(parse_weights(&config.fair_share_weights).unwrap_or_default())
+    }
+
+    pub fn new(weights: HashMap<String, u32>) -> Self {
+        Self { weights, callers: Mutex::new(HashMap::new()) }
+    }
+
+    fn weight(&self, caller: Option<&str>) -> u32 {
+        caller.and_then(|kid| self.weights.get(kid)).copied().unwrap_or(1)
+    }
+
+    /// # FairShare::plan()
+    /// The claims that share `budget` sends between the callers `due` (as
+    /// `Storage::due_callers` lists them): one `(caller, count)` per caller and round.
+    /// # Example
+    /// ```
+    /// use std::collections::HashMap;
+    /// use templar::scheduled::FairShare;
+    /// let fair = FairShare::new(HashMap::from([("billing".to_string(), 2)]));
+    /// let (flood, billing) = (Some("import".to_string()), Some("billing".to_string()));
+    /// let due = [(flood.clone(), 10_000), (billing.clone(), 3), (None, 1)];
+    /// assert_eq!(
+    ///     fair.plan(&due, 8),
+    ///     vec![(flood.clone(), 1), (billing.clone(), 2), (None, 1), (flood.clone(), 1), (billing, 1), (flood.clone(), 1), (flood, 1)],
+    /// );
+    /// ```
+    pub fn plan(&self, due: &[(Option<String>, i64)], budget: u32) -> Vec<(Option<String>, u32)> {
+        let mut left: Vec<(Option<String>, i64)> = due.to_vec();
+        let (mut budget, mut plan) = (budget, Vec::new());
+        while budget > 0 && left.iter().any(|(_, n)| *n > 0) {
+            for (caller, n) in left.iter_mut().filter(|(_, n)| *n > 0) {
+                let take = self.weight(caller.as_deref()).min(budget).min(*n as u32);
+                if take == 0 {
+                    break;
+                }
+                plan.push((caller.clone(), take));
+                *n -= i64::from(take);
+                budget -= take;
+            }
+        }
+        plan
+    }
+
+    /// Remember what is due per caller; callers no longer listed have nothing due.
+    fn set_due(&self, due: &[(Option<String>, i64)]) {
+        let mut callers = self.callers.lock().expect("fair share lock poisoned");
+        callers.values_mut().for_each(|w| w.due = 0);
+        for (caller, n) in due {
+            callers.entry(caller.clone()).or_default().due = *n;
+        }
+    }
+
+    /// Count one send of `caller` taken after waiting `wait_secs` past its `send_at`.
+    fn claimed(&self, caller: &Option<String>, wait_secs: u64) {
+        let mut callers = self.callers.lock().expect("fair share lock poisoned");
+        let w = callers.entry(caller.clone()).or_default();
+        w.due = (w.due - 1).max(0);
+        w.avg_wait_secs = if w.claimed == 0 { wait_secs } else { (w.avg_wait_secs * 7 + wait_secs) / 8 };
+        w.max_wait_secs = w.max_wait_secs.max(wait_secs);
+        w.claimed += 1;
+    }
+
+    /// Every caller seen since startup, by `kid` (`null` first).
+    pub fn stats(&self) -> Vec<CallerStats> {
+        let callers = self.callers.lock().expect("fair share lock poisoned");
+        let mut stats: Vec<CallerStats> = callers
+            .iter()
+            .map(|(caller, w)| CallerStats {
+                caller: caller.clone(),
+                weight: self.weight(caller.as_deref()),
+                due: w.due,
+                claimed: w.claimed,
+                avg_wait_secs: w.avg_wait_secs,
+                max_wait_secs: w.max_wait_secs,
+            })
+            .collect();
+        stats.sort_by(|a, b| a.caller.cmp(&b.caller));
+        stats
+    }
+}
+
+/// # parse_weights()
+/// `FAIR_SHARE_WEIGHTS`: `kid=weight` pairs, comma-separated, weights 1 to [`MAX_WEIGHT`].
+pub fn parse_weights(spec: &str) -> Result<HashMap<String, u32>, String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|pair| {
+            let (kid, weight) = pair.split_once('=').ok_or_else(|| format!("`{pair}` is not kid=weight"))?;
+            match weight.trim().parse::<u32>() {
+                Ok(w) if (1..=MAX_WEIGHT).contains(&w) => Ok((kid.trim().to_string(), w)),
+                _ => Err(format!("weight of `{}` must be 1 to {MAX_WEIGHT}", kid.trim())),
+            }
+        })
+        .collect()
+}
+
+/// Spawn the polling loop against the current state.
+pub fn spawn_scheduler(state: SharedState, store: Store, campaigns: Arc<Campaigns>, fair: Arc<FairShare>) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(POLL_EVERY);
+        loop {
+            tick.tick().await;
+            let state = state.load_full();
+            let now = storage::now();
+            if let Err(e) = store.expire_idempotency(now - state.idempotency_ttl_secs as i64).await {
+                warn!("Idempotency keys not expired: {e}");
+            }
+            let due = match store.due_callers(now, STALE_CLAIM_SECS).await {
+                Ok(due) => due,
+                Err(e) => {
+                    warn!("Scheduled sends not polled: {e}");
+                    continue;
+                }
+            };
+            fair.set_due(&due);
+            for (caller, count) in fair.plan(&due, BATCH) {
+                let claimed = match store.claim_due(caller.as_deref(), now, STALE_CLAIM_SECS, count).await {
+                    Ok(claimed) => claimed,
+                    Err(e) => {
+                        warn!("Scheduled sends not claimed: {e}");
+                        break;
+                    }
+                };
+                for send in claimed {
+                    deliver(&state, &store, &campaigns, &fair, send).await;
+                }
+            }
+        }
+    });
+}
+
+/// Send one claimed send, then remove it unless it is worth retrying.
+async fn deliver(state: &crate::email::EmailState, store: &Store, campaigns: &Campaigns, fair: &FairShare, send: storage::ScheduledSend) {
+    let id = send.id.clone();
+    fair.claimed(&send.caller, (storage::now() - send.send_at).max(0) as u64);
+    match crate::routes::deliver(state, &**store, campaigns, send.caller.as_deref(), send.request).await {
+        Ok(response) => info!(id, status = response.status, "Scheduled send delivered"),
+        Err(e) if e.is_retryable() => {
+            warn!(id, "Scheduled send failed, retrying in {STALE_CLAIM_SECS}s: {e}");
+            return;
+        }
+        Err(e) => {
+            warn!(id, "Scheduled send dropped: {e}");
+            if !matches!(e, crate::email::EmailError::CampaignCancelled(_)) {
+                crate::alerts::send_abandoned("scheduled", &id, &e.to_string());
+            }
+        }
+    }
+    if let Err(e) = store.finish_scheduled(&id).await {
+        warn!(id, "Scheduled send not marked done, it may go out again: {e}");
+    }
+}
@@ -0,0 +1,247 @@
+//! API key scopes. `API_KEY_SCOPES` gives each `kid` of `API_KEY` a scope: `send` keys
+//! may send, render and read templates, `admin` keys may also use `/admin/*`, change
+//! templates (archive, restore) and post feedback loop reports. Keys it does not list are `admin`, so setups with a
+//! single key keep working as before.
+//!
+//! Keys made with `POST /admin/api-keys` carry their scope instead (see `apikeys`).
+//!
+//! Requests present their key as `Authorization: Bearer <key>` or `X-Api-Key: <key>`.
+//! One middleware in front of every route checks it once: a missing or wrong key gets
+//! `401`, a valid `send` key on an admin route `403`. Handlers that need to know who
+//! called read the [`Caller`] it leaves in the request's extensions.
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::config::var;
+use crate::crypto::Keyring;
+
+/// What a key may do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    Send,
+    Admin,
+}
+
+impl Scope {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "send" => Some(Scope::Send),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Send => "send",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+/// # parse_scopes()
+/// `API_KEY_SCOPES`: `kid=scope` pairs, comma-separated. A single `API_KEY` without
+/// `:` has the kid `default`.
+/// # Example
+/// ```
+/// use templar::scopes::{parse_scopes, Scope};
+/// let scopes = parse_scopes("mobile=send, ops=admin").unwrap();
+/// assert_eq!(scopes["mobile"], Scope::Send);
+/// assert_eq!(scopes["ops"], Scope::Admin);
+/// assert!(parse_scopes("mobile=root").is_err());
+/// ```
+pub fn parse_scopes(spec: &str) -> Result<HashMap<String, Scope>, String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|pair| {
+            let (kid, scope) = pair.split_once('=').ok_or_else(|| format!("`{pair}` is not kid=scope"))?;
+            let scope = Scope::parse(scope.trim()).ok_or_else(|| format!("unknown scope `{}` for `{}`, expected send or admin", scope.trim(), kid.trim()))?;
+            Ok((kid.trim().to_string(), scope))
+        })
+        .collect()
+}
+
+/// # validate()
+/// Check `API_KEY_SCOPES` against the kids of `API_KEY`, for `ApiConfig::load`.
+pub fn validate(scopes: &str, api_key: &str) -> Result<(), String> {
+    let scopes = parse_scopes(scopes)?;
+    if scopes.is_empty() {
+        return Ok(());
+    }
+    let keys = Keyring::parse_api_keys(api_key).map_err(|e| e.to_string())?;
+    match scopes.keys().find(|kid| !keys.kids().any(|k| k == kid.as_str())) {
+        Some(kid) => Err(format!("`{kid}` is not a kid of API_KEY")),
+        None => Ok(()),
+    }
+}
+
+/// # required()
+/// The scope a request needs: `admin` for `/admin/*`, for changes to templates and for
+/// feedback loop reports (they suppress recipients), `send` for everything else.
+/// # Example
+/// ```
+/// use axum::http::Method;
+/// use templar::scopes::{required, Scope::{Admin, Send}};
+/// // Every route served, with example path parameters
+/// let routes = [
+///     (Method::POST, "/send", Send),
+///     (Method::POST, "/render/eml", Send),
+///     (Method::POST, "/feedback/arf", Admin),
+///     (Method::GET, "/ready", Send),
+///     (Method::GET, "/version", Send),
+///     (Method::POST, "/digests/weekly/events", Send),
+///     (Method::GET, "/campaigns/spring/stats", Send),
+///     (Method::GET, "/assets/img/logo.png", Send),
+///     (Method::GET, "/templates", Send),
+///     (Method::POST, "/templates/welcome/archive", Admin),
+///     (Method::POST, "/templates/welcome/restore", Admin),
+///     (Method::POST, "/templates/welcome/spamcheck", Send),
+///     (Method::GET, "/admin/config", Admin),
+///     (Method::GET, "/admin/logging", Admin),
+///     (Method::GET, "/admin/deliverability", Admin),
+///     (Method::GET, "/admin/warmup", Admin),
+///     (Method::GET, "/admin/providers", Admin),
+///     (Method::GET, "/admin/rendering", Admin),
+///     (Method::GET, "/admin/connections", Admin),
+///     (Method::GET, "/admin/maintenance", Admin),
+///     (Method::POST, "/admin/maintenance", Admin),
+///     (Method::POST, "/admin/purge", Admin),
+///     (Method::POST, "/admin/campaigns/spring/cancel", Admin),
+///     (Method::POST, "/admin/digests/flush", Admin),
+///     (Method::GET, "/admin/queue", Admin),
+///     (Method::GET, "/admin/scaling", Admin),
+///     (Method::POST, "/admin/queue/pause", Admin),
+///     (Method::POST, "/admin/queue/resume", Admin),
+///     (Method::POST, "/admin/webhooks/test", Admin),
+///     (Method::GET, "/admin/suppressions", Admin),
+///     (Method::PUT, "/admin/suppressions/ada@example.com", Admin),
+///     (Method::DELETE, "/admin/suppressions/ada@example.com", Admin),
+///     (Method::GET, "/admin/api-keys", Admin),
+///     (Method::POST, "/admin/api-keys", Admin),
+///     (Method::DELETE, "/admin/api-keys/ci", Admin),
+///     (Method::GET, "/admin/messages/yaIBQECRZKo2O8M2xHK9aI", Admin),
+///     (Method::GET, "/admin/stats", Admin),
+///     (Method::DELETE, "/admin/data/ada@example.com", Admin),
+///     (Method::POST, "/admin/templates/promote", Admin),
+///     (Method::GET, "/admin/lockouts", Admin),
+/// ];
+/// for (method, path, scope) in routes {
+///     assert_eq!(required(&method, path), scope, "{method} {path}");
+/// }
+/// ```
+pub fn required(method: &Method, path: &str) -> Scope {
+    // Feedback reports suppress recipients, like `PUT /admin/suppressions`
+    if path == "/admin" || path.starts_with("/admin/") || path.starts_with("/feedback/") {
+        return Scope::Admin;
+    }
+    // Every template route but reads and the spam check changes what is served
+    match path.strip_prefix("/templates/") {
+        Some(rest) if *method != Method::GET && !rest.ends_with("/spamcheck") => Scope::Admin,
+        _ => Scope::Send,
+    }
+}
+
+/// # presented_key()
+/// The key a request presents: `Authorization: Bearer <key>`, or `X-Api-Key: <key>`.
+/// Either may be the bare key or `kid:key`.
+/// # Example
+/// ```
+/// use axum::http::HeaderMap;
+/// use templar::scopes::presented_key;
+/// let mut headers = HeaderMap::new();
+/// assert_eq!(presented_key(&headers), None);
+/// headers.insert("x-api-key", "ops:k3y".parse().unwrap());
+/// assert_eq!(presented_key(&headers), Some("ops:k3y"));
+/// headers.insert("authorization", "bearer tk_2f9a".parse().unwrap());
+/// assert_eq!(presented_key(&headers), Some("tk_2f9a"));
+/// headers.insert("authorization", "Basic b3BzOms=".parse().unwrap());
+/// assert_eq!(presented_key(&headers), Some("ops:k3y"));
+/// ```
+pub fn presented_key(headers: &HeaderMap) -> Option<&str> {
+    let bearer = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()).and_then(|v| {
+        let (scheme, key) = v.trim().split_once(' ')?;
+        scheme.eq_ignore_ascii_case("bearer").then(|| key.trim())
+    });
+    let api_key = || headers.get("x-api-key").and_then(|v| v.to_str().ok()).map(str::trim);
+    bearer.or_else(api_key).filter(|k| !k.is_empty())
+}
+
+/// The key a request was authenticated with, put in its extensions by [`guard`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caller {
+    pub kid: String,
+    pub scope: Scope,
+}
+
+/// Whether a request may go on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Presented {
+    /// No `API_KEY`: auth is disabled (dev convenience).
+    Open,
+    /// A valid key, from `API_KEY` or storage.
+    Key(Caller),
+    /// No key presented, or one that is unknown, revoked or past its end date.
+    Refused,
+}
+
+/// # authenticate()
+/// Check `presented` against `API_KEY` (one key, or `kid:key` pairs, see
+/// `Keyring::parse_api_keys`), then against the keys made with `POST /admin/api-keys`,
+/// which are only checked while `API_KEY` is set. A key of `API_KEY` gets its scope from
+/// `API_KEY_SCOPES`, stored keys carry their own.
+pub fn authenticate(presented: Option<&str>) -> Presented {
+    let Some(api_key) = var("API_KEY").ok().filter(|k| !k.trim().is_empty()) else { return Presented::Open };
+    let (Ok(keys), Some(presented)) = (Keyring::parse_api_keys(&api_key), presented) else { return Presented::Refused };
+    let Some(kid) = keys.authenticate(presented) else {
+        return match crate::apikeys::authenticate(presented, crate::storage::now()) {
+            Some((kid, scope)) => Presented::Key(Caller { kid, scope }),
+            None => Presented::Refused,
+        };
+    };
+    if let Some(until) = keys.until(kid) {
+        tracing::info!(kid, until = crate::warmup::format_date(until), "Request authenticated with a retiring API key");
+    }
+    let kid = kid.to_string();
+    // A spec broken after startup (a secret store refresh) gives no key more than `send`
+    let scope = match parse_scopes(&var("API_KEY_SCOPES").unwrap_or_default()) {
+        Ok(scopes) => scopes.get(&kid).copied().unwrap_or(Scope::Admin),
+        Err(_) => Scope::Send,
+    };
+    Presented::Key(Caller { kid, scope })
+}
+
+/// Routes served without a key: the readiness probe and the images of sent emails.
+fn open_route(path: &str) -> bool {
+    path == "/ready" || path.starts_with("/assets/")
+}
+
+/// Middleware: authenticate the key presented with every request but [`open_route`]s once,
+/// answering `401` to a missing or wrong key and `403` to a key whose scope does not cover
+/// the route. Handlers find the key as an `Extension<Caller>`.
+pub async fn guard(mut req: Request, next: Next) -> Response {
+    if open_route(req.uri().path()) {
+        return next.run(req).await;
+    }
+    let caller = match authenticate(presented_key(req.headers())) {
+        Presented::Open => return next.run(req).await,
+        Presented::Refused => return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({ "error": "unauthorized" }))).into_response(),
+        Presented::Key(caller) => caller,
+    };
+    let needed = required(req.method(), req.uri().path());
+    if caller.scope < needed {
+        tracing::info!(kid = caller.kid, scope = caller.scope.as_str(), "Request refused: API key scope too narrow");
+        let error = format!("this API key has the {} scope; {} needs {}", caller.scope.as_str(), req.uri().path(), needed.as_str());
+        return (StatusCode::FORBIDDEN, Json(serde_json::json!({ "error": error }))).into_response();
+    }
+    req.extensions_mut().insert(caller);
+    next.run(req).await
+}
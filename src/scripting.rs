@@ -0,0 +1,57 @@
+//! Handlebars helpers written as rhai scripts (`rhai-helpers` feature), a lighter
+//! alternative to WASM plugins for simple formatting.
+//!
+//! Each `<name>.rhai` in `HELPER_SCRIPTS_DIR` (default `TEMPLATES_DIR/helpers`) becomes
+//! the helper `{{name ..}}`. Scripts see `params` (array) and `hash` (map) and their
+//! last expression is the output, e.g. `params[0].to_upper()`.
+//!
+//! Scripts run sandboxed: no `import`, `eval` or output, and bounded operations,
+//! call depth and value sizes. Exceeding a limit fails the render.
+
+use std::path::Path;
+
+use handlebars::Handlebars;
+use rhai::{module_resolvers::DummyModuleResolver, Engine};
+use tracing::info;
+
+/// Engine with I/O removed and execution budgets set.
+fn sandboxed_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_module_resolver(DummyModuleResolver::new());
+    engine.disable_symbol("eval");
+    engine.on_print(|_| {});
+    engine.on_debug(|_, _, _| {});
+    engine.set_max_operations(100_000);
+    engine.set_max_call_levels(16);
+    engine.set_max_expr_depths(64, 32);
+    engine.set_max_string_size(1 << 20);
+    engine.set_max_array_size(10_000);
+    engine.set_max_map_size(10_000);
+    engine
+}
+
+/// # register_script_helpers()
+/// Install the sandboxed engine on `reg` and register every `*.rhai` in `dir`.
+/// A missing `dir` registers nothing. Returns the registered helper names.
+/// # Errors
+/// Fails when a script cannot be read or does not compile.
+pub fn register_script_helpers(reg: &mut Handlebars<'static>, dir: &Path) -> Result<Vec<String>, anyhow::Error> {
+    reg.set_engine(sandboxed_engine());
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut scripts: Vec<_> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|x| x == "rhai"))
+        .collect();
+    scripts.sort();
+
+    let mut names = Vec::new();
+    for path in scripts {
+        let Some(name) = path.file_stem().and_then(|s| s.to_str()).map(str::to_string) else { continue };
+        reg.register_script_helper_file(&name, &path).map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        info!(helper = %name, script = %path.display(), "Registered script helper");
+        names.push(name);
+    }
+    Ok(names)
+}
@@ -0,0 +1,338 @@
+//! Secrets read from HashiCorp Vault or AWS Secrets Manager instead of long-lived
+//! environment variables (`SECRETS_BACKEND`).
+//!
+//! The secret at `SECRETS_PATH` is a JSON object of settings keyed by their names,
+//! e.g. `{"SMTP_PASSWORD":"..","API_KEY":"..","SMTP_PROFILE_RELAY_A_PASSWORD":".."}`
+//! (a `TEMPLAR_` prefix is optional). It is read at startup before the configuration,
+//! and each of its settings wins over the environment (see [`crate::config::var`]).
+//! It is read again every `SECRETS_REFRESH_SECS`, or halfway through the Vault lease
+//! when that is sooner; when a value changed, the configuration is reloaded as on
+//! SIGHUP, so rotated credentials take effect without a restart. A failed refresh
+//! keeps the values read before.
+//!
+//! - `vault`: a KV v1 or v2 secret, `GET $VAULT_ADDR/v1/<SECRETS_PATH>` with
+//!   `VAULT_TOKEN` (`secret/data/templar` for KV v2). The token is renewed on each refresh.
+//! - `aws`: `GetSecretValue` of the secret named (or ARN) `SECRETS_PATH`, its
+//!   `SecretString` holding the object. Credentials and region come from the standard
+//!   `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`, `AWS_SESSION_TOKEN` and `AWS_REGION`
+//!   variables; requests are signed with Signature Version 4.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::sync::Notify;
+
+use crate::transport::proxy::{proxied_client, OutboundProxy};
+
+/// Seconds between reads of the secret when `SECRETS_REFRESH_SECS` is unset.
+pub const DEFAULT_REFRESH_SECS: u64 = 300;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The settings last read, and the store they came from.
+#[derive(Debug, Default)]
+struct Installed {
+    source: String,
+    values: HashMap<String, String>,
+}
+
+static INSTALLED: Lazy<ArcSwap<Installed>> = Lazy::new(ArcSwap::default);
+static CHANGED: Notify = Notify::const_new();
+
+/// # get()
+/// Setting `name` (unprefixed) as read from the secret store; `None` without a store,
+/// or when its secret does not hold `name`.
+pub fn get(name: &str) -> Option<String> {
+    INSTALLED.load().values.get(name).cloned()
+}
+
+/// The store settings were read from (`vault:secret/data/templar`), once they were.
+pub fn source() -> Option<String> {
+    let installed = INSTALLED.load();
+    (!installed.source.is_empty()).then(|| installed.source.clone())
+}
+
+/// Resolves when a refresh changed a setting.
+pub async fn changed() {
+    CHANGED.notified().await
+}
+
+#[derive(Debug, Clone)]
+enum Backend {
+    Vault { addr: String, token: String },
+    Aws { region: String, endpoint: String },
+}
+
+/// The configured secret store (`SECRETS_*`).
+#[derive(Debug, Clone)]
+pub struct SecretStore {
+    backend: Backend,
+    path: String,
+    refresh: Duration,
+    http: reqwest::Client,
+}
+
+/// What [`SecretStore::load`] read.
+#[derive(Debug, Clone, Copy)]
+pub struct Loaded {
+    /// Settings the secret holds
+    pub settings: usize,
+    /// A value differs from the ones read before
+    pub changed: bool,
+    /// Vault lease of the secret, when it has one
+    lease: Option<Duration>,
+}
+
+impl SecretStore {
+    /// Read `SECRETS_BACKEND` and its settings; `None` when it is unset or empty.
+    pub fn from_env() -> Result<Option<Self>, anyhow::Error> {
+        let setting = |name: &str| crate::config::var(name).map(|v| v.trim().to_string()).unwrap_or_default();
+        let kind = setting("SECRETS_BACKEND").to_lowercase();
+        if kind.is_empty() {
+            return Ok(None);
+        }
+        let path = setting("SECRETS_PATH").trim_matches('/').to_string();
+        if path.is_empty() {
+            anyhow::bail!("SECRETS_BACKEND={kind} needs SECRETS_PATH");
+        }
+        let refresh = match setting("SECRETS_REFRESH_SECS") {
+            s if s.is_empty() => DEFAULT_REFRESH_SECS,
+            s => s.parse::<u64>().ok().filter(|n| *n > 0).ok_or_else(|| anyhow::anyhow!("invalid SECRETS_REFRESH_SECS `{s}`, expected 1 or more"))?,
+        };
+        let backend = match kind.as_str() {
+            "vault" => {
+                let addr = setting("VAULT_ADDR").trim_end_matches('/').to_string();
+                let token = setting("VAULT_TOKEN");
+                if addr.is_empty() || token.is_empty() {
+                    anyhow::bail!("SECRETS_BACKEND=vault needs VAULT_ADDR and VAULT_TOKEN");
+                }
+                Backend::Vault { addr, token }
+            }
+            "aws" => {
+                // An ARN names its region; otherwise the SDKs' variables do
+                let region = path
+                    .strip_prefix("arn:")
+                    .and_then(|arn| arn.split(':').nth(2))
+                    .map(str::to_string)
+                    .or_else(|| aws_var("AWS_REGION"))
+                    .or_else(|| aws_var("AWS_DEFAULT_REGION"))
+                    .ok_or_else(|| anyhow::anyhow!("SECRETS_BACKEND=aws needs AWS_REGION, or an ARN as SECRETS_PATH"))?;
+                let endpoint = aws_var("AWS_ENDPOINT_URL_SECRETS_MANAGER").unwrap_or_else(|| format!("https://secretsmanager.{region}.amazonaws.com"));
+                Backend::Aws { region, endpoint: endpoint.trim_end_matches('/').to_string() }
+            }
+            other => anyhow::bail!("unknown SECRETS_BACKEND `{other}` (use vault or aws)"),
+        };
+        let proxy = match setting("OUTBOUND_PROXY_URL") {
+            url if url.is_empty() => None,
+            url => Some(OutboundProxy::parse(&url)?),
+        };
+        let http = proxied_client(proxy.as_ref(), TIMEOUT)?.build()?;
+        Ok(Some(Self { backend, path, refresh: Duration::from_secs(refresh), http }))
+    }
+
+    /// `vault:<path>` or `aws:<secret id>`.
+    pub fn source(&self) -> String {
+        match self.backend {
+            Backend::Vault { .. } => format!("vault:{}", self.path),
+            Backend::Aws { .. } => format!("aws:{}", self.path),
+        }
+    }
+
+    /// # SecretStore::load()
+    /// Read the secret and make its settings the ones [`crate::config::var`] returns first.
+    /// # Errors
+    /// The store cannot be reached, refuses the credentials, or the secret is not a JSON
+    /// object; the settings read before are kept.
+    pub async fn load(&self) -> Result<Loaded, String> {
+        let (object, lease) = self.fetch().await?;
+        let values = settings(object)?;
+        let changed = INSTALLED.load().values != values;
+        let settings = values.len();
+        if changed {
+            INSTALLED.store(Arc::new(Installed { source: self.source(), values }));
+        }
+        Ok(Loaded { settings, changed, lease })
+    }
+
+    /// The secret's JSON object, and its lease.
+    async fn fetch(&self) -> Result<(Value, Option<Duration>), String> {
+        match &self.backend {
+            Backend::Vault { addr, token } => {
+                let answer = self.call(self.http.get(format!("{addr}/v1/{}", self.path)).header("X-Vault-Token", token)).await?;
+                let lease = answer["lease_duration"].as_u64().filter(|s| *s > 0).map(Duration::from_secs);
+                // KV v2 nests the secret in `data.data`, next to its `data.metadata`
+                let data = &answer["data"];
+                let object = if data["metadata"].is_object() && data["data"].is_object() { data["data"].clone() } else { data.clone() };
+                Ok((object, lease))
+            }
+            Backend::Aws { region, endpoint } => {
+                let credentials = AwsCredentials::from_env().ok_or("AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY are not set")?;
+                let url = url::Url::parse(endpoint).map_err(|e| format!("invalid AWS_ENDPOINT_URL_SECRETS_MANAGER: {e}"))?;
+                let host = match (url.host_str(), url.port()) {
+                    (Some(host), Some(port)) => format!("{host}:{port}"),
+                    (Some(host), None) => host.to_string(),
+                    (None, _) => return Err("AWS_ENDPOINT_URL_SECRETS_MANAGER has no host".into()),
+                };
+                let body = serde_json::json!({ "SecretId": self.path }).to_string();
+                let mut headers = vec![("content-type", "application/x-amz-json-1.1"), ("x-amz-target", "secretsmanager.GetSecretValue")];
+                if let Some(token) = &credentials.session_token {
+                    headers.push(("x-amz-security-token", token));
+                }
+                let amz_date = amz_date(crate::storage::now());
+                let request = SignedRequest { method: "POST", host: &host, path: url.path(), headers: &headers, body: body.as_bytes() };
+                let authorization = credentials.authorization(region, "secretsmanager", &request, &amz_date);
+                let mut builder = self.http.post(url.as_str()).header("x-amz-date", &amz_date).header(reqwest::header::AUTHORIZATION, authorization);
+                for (name, value) in &headers {
+                    builder = builder.header(*name, *value);
+                }
+                let answer = self.call(builder.body(body)).await?;
+                let text = answer["SecretString"].as_str().ok_or("the secret has no SecretString (binary secrets are not supported)")?;
+                let object = serde_json::from_str(text).map_err(|e| format!("SecretString is not JSON: {e}"))?;
+                Ok((object, None))
+            }
+        }
+    }
+
+    /// Send `request` and read its JSON answer; an error answer names the store's reason.
+    async fn call(&self, request: reqwest::RequestBuilder) -> Result<Value, String> {
+        let response = request.send().await.map_err(|e| format!("{} unreachable: {e}", self.source()))?;
+        let status = response.status();
+        let answer: Value = response.json().await.unwrap_or_default();
+        if !status.is_success() {
+            // Vault answers `{"errors":[..]}`, AWS `{"__type":..,"message":..}`
+            let reason = answer["errors"][0].as_str().or(answer["message"].as_str()).or(answer["Message"].as_str()).unwrap_or_default();
+            return Err(format!("{} answered {status}: {reason}", self.source()));
+        }
+        Ok(answer)
+    }
+
+    /// Extend the Vault token's lease; tokens that cannot be renewed keep working until they expire.
+    async fn renew(&self) {
+        let Backend::Vault { addr, token } = &self.backend else { return };
+        let renewal = self.http.post(format!("{addr}/v1/auth/token/renew-self")).header("X-Vault-Token", token);
+        if let Err(e) = self.call(renewal).await {
+            tracing::debug!("Vault token not renewed: {e}");
+        }
+    }
+}
+
+/// # spawn_refresh()
+/// Read the secret again every `SECRETS_REFRESH_SECS` (or halfway through the lease
+/// `loaded` reported, when sooner), waking [`changed`] when a value changed.
+pub fn spawn_refresh(store: SecretStore, loaded: Loaded) {
+    tokio::spawn(async move {
+        let mut lease = loaded.lease;
+        loop {
+            let wait = lease.map_or(store.refresh, |l| (l / 2).max(Duration::from_secs(1)).min(store.refresh));
+            tokio::time::sleep(wait).await;
+            store.renew().await;
+            match store.load().await {
+                Ok(loaded) => {
+                    lease = loaded.lease;
+                    if loaded.changed {
+                        CHANGED.notify_one();
+                    }
+                }
+                Err(e) => tracing::warn!("Secrets not refreshed, keeping the ones read before: {e}"),
+            }
+        }
+    });
+}
+
+/// The settings of a secret's object: strings as they are, numbers and booleans written out.
+fn settings(object: Value) -> Result<HashMap<String, String>, String> {
+    let Value::Object(map) = object else { return Err("the secret is not a JSON object of settings".into()) };
+    let mut values = HashMap::new();
+    for (name, value) in map {
+        let name = name.strip_prefix(crate::config::ENV_PREFIX).unwrap_or(&name).to_string();
+        let value = match value {
+            Value::String(s) => s,
+            Value::Number(_) | Value::Bool(_) => value.to_string(),
+            _ => {
+                tracing::warn!("Secret setting {name} ignored: not a string, number or boolean");
+                continue;
+            }
+        };
+        values.insert(name, value);
+    }
+    Ok(values)
+}
+
+fn aws_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+/// `YYYYMMDDTHHMMSSZ` of `time`.
+fn amz_date(time: i64) -> String {
+    let seconds = time.rem_euclid(86_400);
+    format!("{}T{:02}{:02}{:02}Z", crate::warmup::format_date(time).replace('-', ""), seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+/// AWS credentials.
+#[derive(Debug, Clone)]
+pub struct AwsCredentials {
+    pub key_id: String,
+    pub secret: String,
+    pub session_token: Option<String>,
+}
+
+/// A request to sign. `headers` holds lowercase names and leaves out `host` and
+/// `x-amz-date`, which are signed from `host` and the date.
+#[derive(Debug, Clone, Copy)]
+pub struct SignedRequest<'a> {
+    pub method: &'a str,
+    pub host: &'a str,
+    /// The URL path, already encoded; requests with a query string are not supported
+    pub path: &'a str,
+    pub headers: &'a [(&'a str, &'a str)],
+    pub body: &'a [u8],
+}
+
+impl AwsCredentials {
+    /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`; `None` without a key.
+    pub fn from_env() -> Option<Self> {
+        Some(Self { key_id: aws_var("AWS_ACCESS_KEY_ID")?, secret: aws_var("AWS_SECRET_ACCESS_KEY")?, session_token: aws_var("AWS_SESSION_TOKEN") })
+    }
+
+    /// # AwsCredentials::authorization()
+    /// Signature Version 4 `Authorization` header of `request` made at `amz_date`
+    /// (`20150830T123600Z`), for `service` in `region`.
+    /// # Example
+    /// ```
+    /// use templar::secrets::{AwsCredentials, SignedRequest};
+    /// // `get-vanilla` of the AWS Signature Version 4 test suite
+    /// let credentials = AwsCredentials { key_id: "AKIDEXAMPLE".into(), secret: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".into(), session_token: None };
+    /// let request = SignedRequest { method: "GET", host: "example.amazonaws.com", path: "/", headers: &[], body: b"" };
+    /// assert_eq!(
+    ///     credentials.authorization("us-east-1", "service", &request, "20150830T123600Z"),
+    ///     "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+    ///      SignedHeaders=host;x-amz-date, Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+    /// );
+    /// ```
+    pub fn authorization(&self, region: &str, service: &str, request: &SignedRequest<'_>, amz_date: &str) -> String {
+        let date = amz_date.get(..8).unwrap_or(amz_date);
+        let mut headers: Vec<(String, String)> = request.headers.iter().map(|(n, v)| (n.to_ascii_lowercase(), v.trim().to_string())).collect();
+        headers.push(("host".into(), request.host.into()));
+        headers.push(("x-amz-date".into(), amz_date.into()));
+        headers.sort();
+        let signed = headers.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>().join(";");
+        let listed: String = headers.iter().map(|(n, v)| format!("{n}:{v}\n")).collect();
+        let canonical = format!("{}\n{}\n\n{listed}\n{signed}\n{}", request.method, request.path, crate::crypto::to_hex(&Sha256::digest(request.body)));
+        let scope = format!("{date}/{region}/{service}/aws4_request");
+        let to_sign = format!("AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}", crate::crypto::to_hex(&Sha256::digest(canonical.as_bytes())));
+        let key = [region, service, "aws4_request"].iter().fold(hmac(format!("AWS4{}", self.secret).as_bytes(), date.as_bytes()), |key, part| hmac(&key, part.as_bytes()));
+        let signature = crate::crypto::to_hex(&hmac(&key, to_sign.as_bytes()));
+        format!("AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed}, Signature={signature}", self.key_id)
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
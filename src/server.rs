@@ -0,0 +1,394 @@
+//! HTTP serving: accepts connections from a listener and serves the router over
+//! HTTP/1.1 and HTTP/2 with the tuning from `ApiConfig`, optionally behind TLS.
+//!
+//! Without TLS, HTTP/2 is cleartext with prior knowledge (h2c), as used by clients that
+//! multiplex sends over a few connections inside a private network; with
+//! `TLS_CERT_FILE`/`TLS_KEY_FILE` it is negotiated through ALPN. On shutdown the listener
+//! stops accepting, open connections finish the requests they have and
+//! [`serve()`] returns once all of them closed.
+//!
+//! Slow clients are cut off at each stage of a request: the HTTP/1.1 request head must
+//! arrive within `HEADER_READ_TIMEOUT_SECS` (which also closes idle keep-alive
+//! connections), the whole body within `BODY_READ_TIMEOUT_SECS` ([`body_deadline()`]),
+//! and a write of the response may stall for at most `WRITE_TIMEOUT_SECS`. Every
+//! connection is counted, with how long it lived and why it ended early, for
+//! `GET /admin/connections` ([`connection_stats()`]).
+//!
+//! Each request carries the connection's [`Peer`] address as an extension.
+
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    pin::Pin,
+    sync::{atomic::{AtomicU64, Ordering}, Arc},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    serve::Listener,
+    Json, Router,
+};
+use serde::Serialize;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::{conn::auto, graceful::{GracefulShutdown, Watcher}},
+    service::TowerToHyperService,
+};
+use tokio::{io::{AsyncRead, AsyncWrite, ReadBuf}, time::Sleep};
+use tokio_rustls::{rustls, TlsAcceptor};
+use tracing::debug;
+
+use crate::config::ApiConfig;
+
+/// How long a client may take to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connection settings shared by every listener.
+#[derive(Debug, Clone)]
+pub struct ServerTuning {
+    /// Serve HTTP/2 next to HTTP/1.1; off answers HTTP/1.1 only.
+    pub http2: bool,
+    pub http2_max_concurrent_streams: u32,
+    /// PING idle HTTP/2 connections this often (`None` = never).
+    pub http2_keep_alive_interval: Option<Duration>,
+    /// Close an HTTP/2 connection whose PING is not answered within this.
+    pub http2_keep_alive_timeout: Duration,
+    /// Keep HTTP/1.1 connections open between requests.
+    pub http1_keep_alive: bool,
+    /// Disable Nagle's algorithm on accepted TCP connections.
+    pub tcp_nodelay: bool,
+    /// Time for an HTTP/1.1 request head, counted from when the connection waits for it.
+    pub header_read_timeout: Option<Duration>,
+    /// Longest a single write of the response may stall.
+    pub write_timeout: Option<Duration>,
+}
+
+impl ServerTuning {
+    pub fn from_config(config: &ApiConfig) -> Self {
+        Self {
+            http2: config.http2,
+            http2_max_concurrent_streams: config.http2_max_concurrent_streams,
+            http2_keep_alive_interval: secs(config.http2_keepalive_interval_secs),
+            http2_keep_alive_timeout: Duration::from_secs(config.http2_keepalive_timeout_secs),
+            http1_keep_alive: config.http1_keepalive,
+            tcp_nodelay: config.tcp_nodelay,
+            header_read_timeout: secs(config.header_read_timeout_secs),
+            write_timeout: secs(config.write_timeout_secs),
+        }
+    }
+
+    fn builder(&self) -> auto::Builder<TokioExecutor> {
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+        builder.http1().timer(TokioTimer::new()).keep_alive(self.http1_keep_alive).header_read_timeout(self.header_read_timeout);
+        builder
+            .http2()
+            .timer(TokioTimer::new())
+            .max_concurrent_streams(self.http2_max_concurrent_streams)
+            .keep_alive_interval(self.http2_keep_alive_interval)
+            .keep_alive_timeout(self.http2_keep_alive_timeout);
+        if self.http2 { builder } else { builder.http1_only() }
+    }
+}
+
+/// `0` seconds turns a timeout off.
+fn secs(n: u64) -> Option<Duration> {
+    (n > 0).then(|| Duration::from_secs(n))
+}
+
+/// # tls_acceptor()
+/// Load a PEM certificate chain and private key for the TCP listeners. ALPN offers
+/// `h2` when `http2` is on, then `http/1.1`.
+/// # Errors
+/// Unreadable files, no certificate in `cert`, or a key that does not match.
+pub fn tls_acceptor(cert: &Path, key: &Path, http2: bool) -> anyhow::Result<TlsAcceptor> {
+    use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow::anyhow!("cannot read TLS_CERT_FILE {}: {e}", cert.display()))?;
+    if chain.is_empty() {
+        anyhow::bail!("no certificate in TLS_CERT_FILE {}", cert.display());
+    }
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| anyhow::anyhow!("cannot read TLS_KEY_FILE {}: {e}", key.display()))?;
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .map_err(|e| anyhow::anyhow!("invalid TLS certificate or key: {e}"))?;
+    tls.alpn_protocols = if http2 { vec![b"h2".to_vec(), b"http/1.1".to_vec()] } else { vec![b"http/1.1".to_vec()] };
+    Ok(TlsAcceptor::from(Arc::new(tls)))
+}
+
+/// The client end of a request's connection; `None` over a Unix socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peer(pub Option<IpAddr>);
+
+/// Listener addresses that may name the client's IP.
+pub trait PeerAddr {
+    fn peer(&self) -> Peer;
+}
+
+impl PeerAddr for SocketAddr {
+    fn peer(&self) -> Peer {
+        Peer(Some(self.ip()))
+    }
+}
+
+impl PeerAddr for tokio::net::unix::SocketAddr {
+    fn peer(&self) -> Peer {
+        Peer(None)
+    }
+}
+
+/// # serve()
+/// Serve `app` on every connection `listener` accepts until `stopping` resolves, then
+/// wait for the open connections to finish. `tls` wraps each connection first.
+pub async fn serve<L: Listener<Addr: PeerAddr>>(
+    mut listener: L,
+    app: Router,
+    tuning: &ServerTuning,
+    tls: Option<TlsAcceptor>,
+    stopping: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let builder = tuning.builder();
+    let graceful = GracefulShutdown::new();
+    let mut stopping = std::pin::pin!(stopping);
+    loop {
+        let (io, addr) = tokio::select! {
+            accepted = listener.accept() => accepted,
+            () = &mut stopping => break,
+        };
+        let io = WriteTimeout { io, timeout: tuning.write_timeout, stall: None };
+        let (builder, app, watcher, peer) = (builder.clone(), app.clone(), graceful.watcher(), addr.peer());
+        let lifetime = Lifetime::start();
+        match tls.clone() {
+            None => {
+                tokio::spawn(async move {
+                    serve_connection(io, builder, app, watcher, peer).await;
+                    drop(lifetime);
+                });
+            }
+            Some(acceptor) => {
+                tokio::spawn(async move {
+                    let _lifetime = lifetime;
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(io)).await {
+                        Ok(Ok(io)) => serve_connection(io, builder, app, watcher, peer).await,
+                        Ok(Err(e)) => debug!("TLS handshake failed: {e}"),
+                        Err(_) => debug!("TLS handshake timed out"),
+                    }
+                });
+            }
+        }
+    }
+    drop(listener);
+    graceful.shutdown().await;
+    Ok(())
+}
+
+async fn serve_connection<I>(io: I, builder: auto::Builder<TokioExecutor>, app: Router, watcher: Watcher, peer: Peer)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let app = tower::ServiceExt::map_request(app, move |mut req: Request<hyper::body::Incoming>| {
+        req.extensions_mut().insert(peer);
+        req
+    });
+    let conn = builder.serve_connection(TokioIo::new(io), TowerToHyperService::new(app));
+    if let Err(e) = watcher.watch(conn.into_owned()).await {
+        if e.downcast_ref::<hyper::Error>().is_some_and(hyper::Error::is_timeout) {
+            CONNECTIONS.header_timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+        debug!("Connection closed with an error: {e}");
+    }
+}
+
+/// # body_deadline()
+/// Middleware: read the whole request body within `timeout`, answering `408` otherwise,
+/// and hand it on buffered. `max_bytes` stops reading early, as the body limit would.
+pub async fn body_deadline(State((timeout, max_bytes)): State<(Duration, usize)>, req: Request, next: Next) -> Response {
+    let (parts, body) = req.into_parts();
+    let fail = |code: StatusCode, msg: String| (code, Json(serde_json::json!({ "error": msg }))).into_response();
+    let bytes = match tokio::time::timeout(timeout, axum::body::to_bytes(body, max_bytes)).await {
+        Ok(Ok(bytes)) => bytes,
+        Ok(Err(e)) if std::error::Error::source(&e).is_some_and(|s| s.is::<http_body_util::LengthLimitError>()) => {
+            return fail(StatusCode::PAYLOAD_TOO_LARGE, format!("request body is larger than MAX_BODY_BYTES={max_bytes}"));
+        }
+        Ok(Err(e)) => return fail(StatusCode::BAD_REQUEST, format!("cannot read the request body: {e}")),
+        Err(_) => {
+            CONNECTIONS.body_timeouts.fetch_add(1, Ordering::Relaxed);
+            return fail(StatusCode::REQUEST_TIMEOUT, format!("request body not received within BODY_READ_TIMEOUT_SECS={}", timeout.as_secs()));
+        }
+    };
+    next.run(Request::from_parts(parts, Body::from(bytes))).await
+}
+
+/// Connection counters since start, for `GET /admin/connections`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionStats {
+    pub open: u64,
+    pub accepted: u64,
+    pub closed: u64,
+    pub timeouts: Timeouts,
+    /// Mean and longest lifetime of the closed connections.
+    pub avg_lifetime_ms: u64,
+    pub max_lifetime_ms: u64,
+    pub lifetimes: Lifetimes,
+}
+
+/// Slow clients cut off, by stage.
+#[derive(Debug, Clone, Serialize)]
+pub struct Timeouts {
+    /// No complete request head in time, idle keep-alive connections included.
+    pub header: u64,
+    pub body: u64,
+    pub write: u64,
+}
+
+/// Closed connections by how long they lived.
+#[derive(Debug, Clone, Serialize)]
+pub struct Lifetimes {
+    pub under_1s: u64,
+    pub under_10s: u64,
+    pub under_1m: u64,
+    pub under_10m: u64,
+    pub longer: u64,
+}
+
+/// Upper bounds of the [`Lifetimes`] buckets in milliseconds; the last one is open.
+const LIFETIME_BUCKETS: [u64; 4] = [1_000, 10_000, 60_000, 600_000];
+
+struct Counters {
+    accepted: AtomicU64,
+    closed: AtomicU64,
+    header_timeouts: AtomicU64,
+    body_timeouts: AtomicU64,
+    write_timeouts: AtomicU64,
+    lifetime_ms_total: AtomicU64,
+    lifetime_ms_max: AtomicU64,
+    lifetimes: [AtomicU64; LIFETIME_BUCKETS.len() + 1],
+}
+
+static CONNECTIONS: Counters = Counters {
+    accepted: AtomicU64::new(0),
+    closed: AtomicU64::new(0),
+    header_timeouts: AtomicU64::new(0),
+    body_timeouts: AtomicU64::new(0),
+    write_timeouts: AtomicU64::new(0),
+    lifetime_ms_total: AtomicU64::new(0),
+    lifetime_ms_max: AtomicU64::new(0),
+    lifetimes: [const { AtomicU64::new(0) }; LIFETIME_BUCKETS.len() + 1],
+};
+
+/// # connection_stats()
+/// Connections served by every listener since start.
+pub fn connection_stats() -> ConnectionStats {
+    let c = &CONNECTIONS;
+    let get = |a: &AtomicU64| a.load(Ordering::Relaxed);
+    let (accepted, closed) = (get(&c.accepted), get(&c.closed));
+    ConnectionStats {
+        open: accepted.saturating_sub(closed),
+        accepted,
+        closed,
+        timeouts: Timeouts { header: get(&c.header_timeouts), body: get(&c.body_timeouts), write: get(&c.write_timeouts) },
+        avg_lifetime_ms: get(&c.lifetime_ms_total).checked_div(closed).unwrap_or(0),
+        max_lifetime_ms: get(&c.lifetime_ms_max),
+        lifetimes: Lifetimes {
+            under_1s: get(&c.lifetimes[0]),
+            under_10s: get(&c.lifetimes[1]),
+            under_1m: get(&c.lifetimes[2]),
+            under_10m: get(&c.lifetimes[3]),
+            longer: get(&c.lifetimes[4]),
+        },
+    }
+}
+
+/// Counts a connection as open until dropped, then records its lifetime.
+struct Lifetime(Instant);
+
+impl Lifetime {
+    fn start() -> Self {
+        CONNECTIONS.accepted.fetch_add(1, Ordering::Relaxed);
+        Self(Instant::now())
+    }
+}
+
+impl Drop for Lifetime {
+    fn drop(&mut self) {
+        let ms = self.0.elapsed().as_millis() as u64;
+        let bucket = LIFETIME_BUCKETS.iter().position(|&max| ms < max).unwrap_or(LIFETIME_BUCKETS.len());
+        CONNECTIONS.lifetimes[bucket].fetch_add(1, Ordering::Relaxed);
+        CONNECTIONS.lifetime_ms_total.fetch_add(ms, Ordering::Relaxed);
+        CONNECTIONS.lifetime_ms_max.fetch_max(ms, Ordering::Relaxed);
+        CONNECTIONS.closed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Fails a write (or flush) that makes no progress for `timeout`, so a client that
+/// stops reading cannot hold the connection and its response forever.
+struct WriteTimeout<I> {
+    io: I,
+    timeout: Option<Duration>,
+    /// Running while the current write is blocked.
+    stall: Option<Pin<Box<Sleep>>>,
+}
+
+impl<I> WriteTimeout<I> {
+    fn check<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if poll.is_ready() {
+            self.stall = None;
+            return poll;
+        }
+        let Some(timeout) = self.timeout else { return poll };
+        let stall = self.stall.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        if stall.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        self.stall = None;
+        CONNECTIONS.write_timeouts.fetch_add(1, Ordering::Relaxed);
+        Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "client stopped reading the response")))
+    }
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for WriteTimeout<I> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_read(cx, buf)
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for WriteTimeout<I> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.io).poll_write(cx, buf);
+        this.check(cx, poll)
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.io).poll_write_vectored(cx, bufs);
+        this.check(cx, poll)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.io).poll_flush(cx);
+        this.check(cx, poll)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.io).poll_shutdown(cx);
+        this.check(cx, poll)
+    }
+}
@@ -0,0 +1,240 @@
+//! Spam scores for templates before they go out: `POST /templates/{name}/spamcheck`
+//! builds the message `/send` would deliver, with the template's sample vars, and has
+//! the filter at `SPAMCHECK_URL` score it.
+//!
+//! - `spamd://host:783`: SpamAssassin's daemon, over the `spamc` protocol (`REPORT`)
+//! - `rspamd://host:11333`: rspamd's normal worker, over its HTTP `/checkv2` (or
+//!   `rspamds://` for HTTPS)
+//!
+//! Sample vars come from `<template>.sample.json` next to the template, a JSON object;
+//! the request's `vars` are merged over them.
+
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::config::ApiConfig;
+
+/// Largest report read back from spamd.
+const MAX_REPLY: u64 = 1024 * 1024;
+
+/// One rule the filter matched, with what it added to the score.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RuleHit {
+    pub name: String,
+    pub score: f64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+}
+
+/// What the filter made of a message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpamReport {
+    pub score: f64,
+    /// Score from which the filter calls a message spam
+    pub threshold: f64,
+    pub spam: bool,
+    /// rspamd's verdict (`no action`, `add header`, `reject`..)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    /// Matched rules, highest score first
+    pub rules: Vec<RuleHit>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Spamd,
+    Rspamd,
+}
+
+/// The configured spam filter (`SPAMCHECK_URL`).
+#[derive(Debug, Clone)]
+pub struct SpamChecker {
+    protocol: Protocol,
+    /// `host:port` for spamd, the base URL for rspamd
+    target: String,
+    http: reqwest::Client,
+    timeout: Duration,
+}
+
+impl SpamChecker {
+    /// # SpamChecker::new()
+    /// The filter at `url`, `spamd://host[:port]` (port 783) or `rspamd://host[:port]`
+    /// (port 11333; `rspamds://` for HTTPS), giving up after `timeout`.
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use templar::spamcheck::SpamChecker;
+    /// assert!(SpamChecker::new("spamd://spamassassin", Duration::from_secs(10)).is_ok());
+    /// assert!(SpamChecker::new("rspamd://rspamd:11333", Duration::from_secs(10)).is_ok());
+    /// assert!(SpamChecker::new("http://rspamd:11333", Duration::from_secs(10)).is_err());
+    /// ```
+    /// # Errors
+    /// `url` is not a `spamd://`, `rspamd://` or `rspamds://` URL with a host.
+    pub fn new(url: &str, timeout: Duration) -> Result<Self, String> {
+        let parsed = url::Url::parse(url).map_err(|e| format!("`{url}`: {e}"))?;
+        let host = parsed.host_str().filter(|h| !h.is_empty()).ok_or_else(|| format!("`{url}` has no host"))?;
+        let (protocol, target) = match parsed.scheme() {
+            "spamd" => (Protocol::Spamd, format!("{host}:{}", parsed.port().unwrap_or(783))),
+            "rspamd" | "rspamds" => {
+                let scheme = if parsed.scheme() == "rspamds" { "https" } else { "http" };
+                (Protocol::Rspamd, format!("{scheme}://{host}:{}{}", parsed.port().unwrap_or(11333), parsed.path().trim_end_matches('/')))
+            }
+            other => return Err(format!("`{url}`: scheme `{other}`, expected spamd://, rspamd:// or rspamds://")),
+        };
+        let http = crate::transport::proxy::http_client(timeout).and_then(|b| Ok(b.build()?)).map_err(|e| e.to_string())?;
+        Ok(Self { protocol, target, http, timeout })
+    }
+
+    /// The filter from `SPAMCHECK_URL`; `None` when it is empty.
+    pub fn from_config(config: &ApiConfig) -> Result<Option<Self>, anyhow::Error> {
+        let url = config.spamcheck_url.trim();
+        if url.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self::new(url, Duration::from_secs(config.spamcheck_timeout_secs)).map_err(|e| anyhow::anyhow!("invalid SPAMCHECK_URL {e}"))?))
+    }
+
+    /// # SpamChecker::check()
+    /// Scores `message`, the RFC 5322 bytes of a built email.
+    /// # Errors
+    /// The filter cannot be reached, times out, fails or answers something unreadable.
+    pub async fn check(&self, message: &[u8]) -> Result<SpamReport, String> {
+        let mut report = match self.protocol {
+            Protocol::Spamd => tokio::time::timeout(self.timeout, self.spamd(message))
+                .await
+                .map_err(|_| format!("spamd gave no answer within {}s", self.timeout.as_secs()))??,
+            Protocol::Rspamd => self.rspamd(message).await?,
+        };
+        report.rules.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.name.cmp(&b.name)));
+        Ok(report)
+    }
+
+    /// `REPORT SPAMC/1.5`: the answer carries `Spam: True ; 6.1 / 5.0` and the rule table.
+    async fn spamd(&self, message: &[u8]) -> Result<SpamReport, String> {
+        let io = |e: std::io::Error| format!("spamd at {}: {e}", self.target);
+        let mut stream = TcpStream::connect(&self.target).await.map_err(io)?;
+        let head = format!("REPORT SPAMC/1.5\r\nContent-length: {}\r\n\r\n", message.len());
+        stream.write_all(head.as_bytes()).await.map_err(io)?;
+        stream.write_all(message).await.map_err(io)?;
+        stream.shutdown().await.map_err(io)?;
+        let mut reply = String::new();
+        stream.take(MAX_REPLY).read_to_string(&mut reply).await.map_err(io)?;
+        parse_spamd(&reply)
+    }
+
+    /// `POST /checkv2` with the message as the body.
+    async fn rspamd(&self, message: &[u8]) -> Result<SpamReport, String> {
+        #[derive(Deserialize)]
+        struct Answer {
+            score: f64,
+            required_score: f64,
+            action: Option<String>,
+            #[serde(default)]
+            symbols: HashMap<String, Symbol>,
+        }
+        #[derive(Deserialize)]
+        struct Symbol {
+            #[serde(default)]
+            score: f64,
+            #[serde(default)]
+            description: String,
+        }
+
+        let url = format!("{}/checkv2", self.target);
+        let unavailable = |e: reqwest::Error| {
+            if e.is_timeout() { format!("rspamd gave no answer within {}s", self.timeout.as_secs()) } else { format!("rspamd at {url}: {e}") }
+        };
+        let response = self.http.post(&url).body(message.to_vec()).send().await.map_err(unavailable)?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("rspamd at {url} answered {status}"));
+        }
+        let answer: Answer = response.json().await.map_err(|e| format!("rspamd at {url}: unreadable answer: {e}"))?;
+        let spam = answer.action.as_deref().is_some_and(|a| a != "no action" && a != "greylist") || answer.score >= answer.required_score;
+        Ok(SpamReport {
+            score: answer.score,
+            threshold: answer.required_score,
+            spam,
+            action: answer.action,
+            rules: answer.symbols.into_iter().map(|(name, s)| RuleHit { name, score: s.score, description: s.description }).collect(),
+        })
+    }
+}
+
+/// # parse_spamd()
+/// The score and rules in a spamd `REPORT` answer.
+/// # Example
+/// ```
+/// use templar::spamcheck::parse_spamd;
+/// let reply = "SPAMD/1.1 0 EX_OK\r\nContent-length: 300\r\nSpam: True ; 6.1 / 5.0\r\n\r\n\
+///     Content analysis details:   (6.1 points, 5.0 required)\n\n \
+///     pts rule name              description\n\
+///     ---- ---------------------- --------------------------------------------------\n \
+///     2.5 HTML_IMAGE_ONLY_08     BODY: HTML: images with 400-800 bytes of\n                             words\n \
+///     3.6 BAYES_99               BODY: Bayes spam probability is 99 to 100%\n\
+///     -0.0 NO_RELAYS              Informational: message was not relayed via SMTP\n";
+/// let report = parse_spamd(reply).unwrap();
+/// assert!(report.spam);
+/// assert_eq!((report.score, report.threshold), (6.1, 5.0));
+/// assert_eq!(report.rules[0].name, "HTML_IMAGE_ONLY_08");
+/// assert_eq!(report.rules[0].description, "BODY: HTML: images with 400-800 bytes of words");
+/// assert_eq!(report.rules.len(), 3);
+/// ```
+/// # Errors
+/// spamd answered with an error code or without a `Spam:` header.
+pub fn parse_spamd(reply: &str) -> Result<SpamReport, String> {
+    let (head, body) = reply.split_once("\r\n\r\n").unwrap_or((reply, ""));
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("0") {
+        return Err(format!("spamd answered `{}`", status.trim()));
+    }
+    // `Spam: True ; 6.1 / 5.0`
+    let spam_header = head.lines().find_map(|l| l.strip_prefix("Spam:")).ok_or_else(|| format!("spamd answered without a score: `{}`", status.trim()))?;
+    let (verdict, scores) = spam_header.split_once(';').ok_or_else(|| format!("unreadable spamd score `{}`", spam_header.trim()))?;
+    let (score, threshold) = scores.split_once('/').ok_or_else(|| format!("unreadable spamd score `{}`", spam_header.trim()))?;
+    let number = |s: &str| s.trim().parse::<f64>().map_err(|_| format!("unreadable spamd score `{}`", spam_header.trim()));
+
+    let mut rules: Vec<RuleHit> = Vec::new();
+    let table = body.lines().skip_while(|l| !l.trim_start().starts_with("----")).skip(1);
+    for line in table {
+        let mut parts = line.split_whitespace();
+        match (line.starts_with("                "), parts.next().map(str::parse::<f64>)) {
+            // A wrapped description
+            (true, _) => {
+                if let Some(last) = rules.last_mut() {
+                    last.description = format!("{} {}", last.description, line.trim()).trim().to_string();
+                }
+            }
+            (false, Some(Ok(score))) => {
+                let Some(name) = parts.next() else { continue };
+                rules.push(RuleHit { name: name.to_string(), score, description: parts.collect::<Vec<_>>().join(" ") });
+            }
+            _ => {}
+        }
+    }
+    Ok(SpamReport {
+        score: number(score)?,
+        threshold: number(threshold)?,
+        spam: verdict.trim().eq_ignore_ascii_case("true") || verdict.trim().eq_ignore_ascii_case("yes"),
+        action: None,
+        rules,
+    })
+}
+
+/// # sample_vars()
+/// The sample vars of `template`, from `<dir>/<template>.sample.json`; empty without one.
+/// # Errors
+/// The file is unreadable or not a JSON object.
+pub fn sample_vars(dir: &Path, template: &str) -> Result<HashMap<String, serde_json::Value>, String> {
+    let path = dir.join(format!("{template}.sample.json"));
+    match std::fs::read_to_string(&path) {
+        Ok(src) => serde_json::from_str(&src).map_err(|e| format!("{}: expected a JSON object of vars: {e}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(format!("{}: {e}", path.display())),
+    }
+}
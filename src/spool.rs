@@ -0,0 +1,90 @@
+//! Shutdown spool: `/send` requests still running when `SHUTDOWN_GRACE_SECS` runs out
+//! are written to `SHUTDOWN_SPOOL_FILE`, one JSON line each, instead of being lost with
+//! the process. The next start schedules them to go out at once and removes the file.
+//!
+//! A spooled send may already have reached the relay before the process exited, so it
+//! can go out twice; a client that got no answer and retries may add another. Scheduled
+//! sends, outbox rows and queued digests need no spool, they are already in storage.
+
+use std::{collections::HashMap, io::{self, Write}, path::Path, sync::{atomic::{AtomicU64, Ordering}, Mutex}};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{routes::SendRequest, storage::{self, ScheduledSend, Storage}};
+
+/// A send running now, as it is spooled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Spooled {
+    /// `kid` of the API key it was made with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caller: Option<String>,
+    pub request: SendRequest,
+}
+
+static RUNNING: Lazy<Mutex<HashMap<u64, Spooled>>> = Lazy::new(Default::default);
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+/// A send registered with [`track`]; dropping it, once the send is over, forgets it.
+pub struct Tracked(u64);
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        RUNNING.lock().expect("spool lock").remove(&self.0);
+    }
+}
+
+/// # track()
+/// Remember `request` until the returned guard is dropped, so a shutdown can spool it.
+pub fn track(caller: Option<&str>, request: &SendRequest) -> Tracked {
+    let id = NEXT.fetch_add(1, Ordering::Relaxed);
+    RUNNING.lock().expect("spool lock").insert(id, Spooled { caller: caller.map(str::to_string), request: request.clone() });
+    Tracked(id)
+}
+
+/// # write()
+/// Append the sends still running to `path`; how many were written.
+/// # Errors
+/// `path` cannot be written.
+pub fn write(path: &Path) -> io::Result<usize> {
+    let running: Vec<Spooled> = RUNNING.lock().expect("spool lock").values().cloned().collect();
+    if running.is_empty() {
+        return Ok(0);
+    }
+    let mut lines = String::new();
+    for send in &running {
+        lines.push_str(&serde_json::to_string(send).map_err(io::Error::other)?);
+        lines.push('\n');
+    }
+    std::fs::OpenOptions::new().create(true).append(true).open(path)?.write_all(lines.as_bytes())?;
+    Ok(running.len())
+}
+
+/// # reload()
+/// Schedule the sends spooled in `path` for now and remove it; how many, `0` without a
+/// spool. A line that does not parse is logged and skipped.
+/// # Errors
+/// `path` cannot be read or removed, or a send cannot be scheduled; the file stays then.
+pub async fn reload(path: &Path, store: &dyn Storage) -> io::Result<usize> {
+    let text = match tokio::fs::read_to_string(path).await {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut count = 0;
+    for (n, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        let send: Spooled = match serde_json::from_str(line) {
+            Ok(send) => send,
+            Err(e) => {
+                tracing::warn!("Skipping line {} of shutdown spool {}: {e}", n + 1, path.display());
+                continue;
+            }
+        };
+        let now = storage::now();
+        let scheduled = ScheduledSend { id: crate::email::nanoid(), send_at: now, request: send.request, caller: send.caller, created_at: now };
+        store.schedule(&scheduled).await.map_err(io::Error::other)?;
+        count += 1;
+    }
+    tokio::fs::remove_file(path).await?;
+    Ok(count)
+}
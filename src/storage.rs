@@ -0,0 +1,595 @@
+//! Shared state behind the [`Storage`] trait: sent messages, suppressed recipients,
+//! scheduled sends, idempotency keys, API keys and runtime settings.
+//!
+//! `STORAGE_URL` picks the backend. The default, `sqlite://templar.db`, keeps a local
+//! file, which is enough for a single instance. Several replicas point at the same
+//! Postgres database (`postgres://..`, builds with `--features postgres`) so a
+//! suppression, a scheduled send or an idempotency key recorded by one is seen by all.
+//!
+//! Each backend carries its own migrations (`migrations/<backend>/`), embedded at build
+//! time and applied when the storage is opened. With `STORAGE_AUTO_MIGRATE=false` they
+//! are left to `templar --migrate-only` (or a DBA), and opening fails while any is
+//! pending instead of running on a schema it does not know. Times are Unix seconds. Addresses are stored lowercase, and the
+//! recipients of a message or scheduled send as `,a@x.com,b@y.com,` so one address can
+//! be matched exactly with `LIKE '%,a@x.com,%'`.
+//!
+//! `HISTORY_PII_MODE` says what message, scheduled send and complaint records keep of
+//! a recipient (see [`stored_recipient`]): by default (`hash`) an HMAC of the address,
+//! so a data subject deletion or a lookup by a known address still finds its records
+//! but nobody reading the database learns the address; `plain` keeps the address,
+//! `none` nothing. The key is `HISTORY_PII_SECRET`, kept out of the database: apart
+//! from `plain` storage is not opened without it. Addresses written into suppression
+//! reasons and stored `Idempotency-Key` answers are kept the same way. Scheduled sends
+//! hold their whole request, addresses included, until they go out; apart from `plain`
+//! their matching `recipients` column is hashed, so they can still be deleted.
+
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod sqlite;
+
+use std::{future::Future, pin::Pin, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::config::HistoryPiiMode;
+
+/// Storage failure, whatever the backend.
+#[derive(Debug, thiserror::Error)]
+#[error("storage error: {0}")]
+pub struct StorageError(pub String);
+
+impl From<sqlx::Error> for StorageError {
+    fn from(e: sqlx::Error) -> Self {
+        Self(e.to_string())
+    }
+}
+
+impl From<sqlx::migrate::MigrateError> for StorageError {
+    fn from(e: sqlx::migrate::MigrateError) -> Self {
+        Self(format!("migration failed: {e}"))
+    }
+}
+
+/// `HISTORY_PII_MODE` and its key, installed by [`protect_recipients`]; until then no
+/// recipient is kept.
+static PII: Lazy<ArcSwap<(HistoryPiiMode, Vec<u8>)>> = Lazy::new(|| ArcSwap::from_pointee((HistoryPiiMode::None, Vec::new())));
+
+/// What every [`Storage`] method returns.
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, StorageError>> + Send + 'a>>;
+
+/// The storage as held by handlers and background tasks.
+pub type Store = Arc<dyn Storage>;
+
+/// One message handed to the transport by `/send`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageRecord {
+    /// The pseudo message ID `/send` answered with.
+    pub id: String,
+    pub template: String,
+    pub recipients: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub campaign_id: Option<String>,
+    /// What the content policy found when it was sent (see `policy`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub policy: Vec<crate::policy::Finding>,
+    /// What it cost by `MESSAGE_COST`/`RELAY_COSTS` when it was sent; `None` for messages
+    /// recorded before it was kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+    /// The A/B variant of `template` it got (see `variants`), `None` for the template itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    /// The relays' final replies to it; none for the `file` transport or older records.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub smtp: Vec<crate::transport::SmtpReply>,
+    /// `kid` of the API key it was sent with, `None` without one or for older records.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caller: Option<String>,
+    pub created_at: i64,
+}
+
+/// Messages recorded for one caller, template and variant, and what they cost (`GET /admin/stats`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageTotals {
+    /// `kid` the messages were sent with, `None` for those sent without one.
+    pub caller: Option<String>,
+    pub template: String,
+    /// A/B variant, `None` for the template itself.
+    pub variant: Option<String>,
+    pub messages: i64,
+    /// Sum of their `cost`; messages recorded without one count as `0`.
+    pub cost: f64,
+}
+
+/// A recipient `/send` no longer mails.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Suppression {
+    pub address: String,
+    /// Free text: `unsubscribed`, `bounced`, a ticket number..
+    pub reason: String,
+    pub created_at: i64,
+}
+
+/// A `/send` request held until `send_at`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledSend {
+    pub id: String,
+    pub send_at: i64,
+    pub request: crate::routes::SendRequest,
+    /// `kid` of the API key it was made with, `None` without one (see `scheduled`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caller: Option<String>,
+    pub created_at: i64,
+}
+
+/// Recipients counted for one warming-up sending domain on one UTC day (see `warmup`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WarmupDay {
+    pub domain: String,
+    /// Unix time of the day's start
+    pub day: i64,
+    pub sent: i64,
+    /// Recipients moved on to a later day
+    pub deferred: i64,
+}
+
+/// A feedback loop complaint about a sent message (see `feedback`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Complaint {
+    /// The message it is about; `None` when the report did not point to one of ours
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// The complaining recipient, when the provider did not redact it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<String>,
+    /// `abuse`, `fraud`, `not-spam`..
+    pub feedback_type: String,
+    /// The provider's feedback loop that sent the report
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    /// When the provider received the original, as it wrote it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arrival_date: Option<String>,
+    pub created_at: i64,
+}
+
+/// An API key made with `POST /admin/api-keys` (see `apikeys`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiKeyRecord {
+    pub kid: String,
+    /// SHA-256 of the key, hex; the key itself is never stored
+    #[serde(skip)]
+    pub hash: String,
+    /// `send` or `admin` (see `scopes`)
+    pub scope: String,
+    /// Unix time from which it is refused
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    pub created_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<i64>,
+}
+
+/// Outcome of [`Storage::claim_idempotency`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdempotencyClaim {
+    /// The key is new (or expired): this request runs and completes or releases it.
+    Claimed,
+    /// Another request with the key is still running.
+    InProgress,
+    /// Already answered: the status and JSON body to repeat.
+    Done(u16, String),
+}
+
+/// What a data subject deletion removed from storage.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct StorageDeletion {
+    pub messages: u64,
+    pub scheduled: u64,
+    pub complaints: u64,
+    /// Stored `Idempotency-Key` answers mentioning it, replaced by [`ERASED_ANSWER`].
+    pub idempotency: u64,
+    /// Outbox rows claimed (`outbox:<table>:<id>`) whose stored outcome mentions it, replaced
+    /// too; the claim stays, so the row is not sent again.
+    pub outbox_claims: u64,
+}
+
+/// Body a stored answer mentioning an erased address is left with.
+pub const ERASED_ANSWER: &str = r#"{"status":"erased"}"#;
+
+/// Persistent state shared by every instance using the same `STORAGE_URL`.
+///
+/// Methods return boxed futures so backends can be picked at runtime (`Arc<dyn Storage>`).
+pub trait Storage: Send + Sync {
+    /// Backend name, as reported by `templar doctor` and the startup log.
+    fn backend(&self) -> &'static str;
+
+    /// Embedded migrations not applied yet, as `<version> <description>`.
+    fn pending_migrations(&self) -> StoreFuture<'_, Vec<String>>;
+    /// Apply pending migrations; returns the ones that were pending, some of which another
+    /// instance may have applied meanwhile.
+    fn migrate(&self) -> StoreFuture<'_, Vec<String>>;
+
+    fn record_message<'a>(&'a self, message: &'a MessageRecord) -> StoreFuture<'a, ()>;
+    fn message<'a>(&'a self, id: &'a str) -> StoreFuture<'a, Option<MessageRecord>>;
+    /// Messages recorded from `since` on, counted and costed per caller, template and variant.
+    fn message_totals(&self, since: i64) -> StoreFuture<'_, Vec<MessageTotals>>;
+
+    /// Add or replace the suppression for `suppression.address`.
+    fn suppress<'a>(&'a self, suppression: &'a Suppression) -> StoreFuture<'a, ()>;
+    /// Lift a suppression; `false` when there was none.
+    fn unsuppress<'a>(&'a self, address: &'a str) -> StoreFuture<'a, bool>;
+    /// The suppressions among `addresses` (lowercase).
+    fn suppressed<'a>(&'a self, addresses: &'a [String]) -> StoreFuture<'a, Vec<Suppression>>;
+    /// Every suppression, oldest first.
+    fn suppressions(&self) -> StoreFuture<'_, Vec<Suppression>>;
+
+    fn schedule<'a>(&'a self, send: &'a ScheduledSend) -> StoreFuture<'a, ()>;
+    /// Callers with sends due at `now` and not claimed (as in [`Storage::claim_due`]), and
+    /// how many each, the one with the oldest due send first.
+    fn due_callers(&self, now: i64, stale_secs: i64) -> StoreFuture<'_, Vec<(Option<String>, i64)>>;
+    /// Take up to `limit` of `caller`'s sends due at `now` (`None`: those made without a
+    /// key), oldest first. A claim left unfinished for `stale_secs` (an instance died
+    /// mid-send) can be claimed again.
+    fn claim_due<'a>(&'a self, caller: Option<&'a str>, now: i64, stale_secs: i64, limit: u32) -> StoreFuture<'a, Vec<ScheduledSend>>;
+    /// Remove a claimed send once it went out (or failed for good).
+    fn finish_scheduled<'a>(&'a self, id: &'a str) -> StoreFuture<'a, ()>;
+    /// Record campaign `id` as cancelled at `now` (an earlier cancellation stands) and remove
+    /// its scheduled sends; returns when it was cancelled and how many sends were removed.
+    fn cancel_campaign<'a>(&'a self, id: &'a str, now: i64) -> StoreFuture<'a, (i64, u64)>;
+    /// Every cancelled campaign and when it was cancelled.
+    fn cancelled_campaigns(&self) -> StoreFuture<'_, Vec<(String, i64)>>;
+
+    /// Reserve `key`, unless a claim made at or after `since` exists.
+    fn claim_idempotency<'a>(&'a self, key: &'a str, now: i64, since: i64) -> StoreFuture<'a, IdempotencyClaim>;
+    /// Store the answer to repeat for `key`.
+    fn complete_idempotency<'a>(&'a self, key: &'a str, status: u16, body: &'a str) -> StoreFuture<'a, ()>;
+    /// Drop a claim so a retry with the same key runs again.
+    fn release_idempotency<'a>(&'a self, key: &'a str) -> StoreFuture<'a, ()>;
+    /// Remove keys claimed before `before`; returns how many.
+    fn expire_idempotency(&self, before: i64) -> StoreFuture<'_, u64>;
+
+    /// Remove message records, and complaints, created before `before` (retention);
+    /// returns how many messages.
+    fn purge_messages(&self, before: i64) -> StoreFuture<'_, u64>;
+    /// Remove `address` from messages and scheduled sends, removing those left with no
+    /// recipient, and its complaints. Suppressions stay, so the address is not mailed again.
+    fn delete_recipient<'a>(&'a self, address: &'a str) -> StoreFuture<'a, StorageDeletion>;
+
+    fn record_complaint<'a>(&'a self, complaint: &'a Complaint) -> StoreFuture<'a, ()>;
+    /// Complaints about message `id`, oldest first.
+    fn complaints<'a>(&'a self, id: &'a str) -> StoreFuture<'a, Vec<Complaint>>;
+
+    /// Count `count` more recipients sent by `domain` on `day`, if that stays within `limit`;
+    /// `false` (and nothing counted) otherwise.
+    fn reserve_warmup<'a>(&'a self, domain: &'a str, day: i64, count: i64, limit: i64) -> StoreFuture<'a, bool>;
+    /// Add to `domain`'s counts for `day` without a limit; a negative `sent` gives recipients back.
+    fn count_warmup<'a>(&'a self, domain: &'a str, day: i64, sent: i64, deferred: i64) -> StoreFuture<'a, ()>;
+    /// Warm-up counts of every domain from `since` on, oldest first.
+    fn warmup_days(&self, since: i64) -> StoreFuture<'_, Vec<WarmupDay>>;
+
+    /// Add an API key; `false` (and nothing added) when its `kid` is taken.
+    fn create_api_key<'a>(&'a self, key: &'a ApiKeyRecord) -> StoreFuture<'a, bool>;
+    /// Every API key, revoked ones included, oldest first.
+    fn api_keys(&self) -> StoreFuture<'_, Vec<ApiKeyRecord>>;
+    /// Refuse key `kid` from `now` on; `false` when there is no such key or it was already revoked.
+    fn revoke_api_key<'a>(&'a self, kid: &'a str, now: i64) -> StoreFuture<'a, bool>;
+
+    /// A runtime setting shared by replicas (`templates.active`..), `None` when never set.
+    fn setting<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<String>>;
+    fn set_setting<'a>(&'a self, key: &'a str, value: &'a str) -> StoreFuture<'a, ()>;
+}
+
+/// # open()
+/// Connect to `url`, then apply pending migrations (`auto_migrate`) or refuse to go on
+/// while there are any.
+/// # Example
+/// ```
+/// # #[tokio::main] async fn main() {
+/// let store = templar::storage::open("sqlite::memory:", true).await.unwrap();
+/// assert_eq!(store.backend(), "sqlite");
+/// assert!(store.pending_migrations().await.unwrap().is_empty());
+/// assert!(store.suppressions().await.unwrap().is_empty());
+/// # }
+/// ```
+/// # Errors
+/// An unknown scheme, a backend not built in, the connection or migrations failing, or
+/// pending migrations without `auto_migrate`.
+pub async fn open(url: &str, auto_migrate: bool) -> Result<Store, StorageError> {
+    let store = connect(url).await?;
+    if auto_migrate {
+        let applied = store.migrate().await?;
+        if !applied.is_empty() {
+            info!("Storage schema brought up to date: {}", applied.join(", "));
+        }
+    } else {
+        let pending = store.pending_migrations().await?;
+        if !pending.is_empty() {
+            return Err(StorageError(format!(
+                "{} pending migration(s) ({}) and STORAGE_AUTO_MIGRATE=false; run `templar --migrate-only`",
+                pending.len(),
+                pending.join(", ")
+            )));
+        }
+    }
+    Ok(store)
+}
+
+/// # protect_recipients()
+/// Keep recipients as `mode` says from now on, hashed with `secret`.
+/// # Errors
+/// `secret` is empty while `mode` hashes anything (all but `plain`): a key generated and
+/// kept next to the hashes would let anyone with the database test addresses against them.
+pub fn protect_recipients(mode: HistoryPiiMode, secret: &str) -> Result<(), StorageError> {
+    if secret.is_empty() && mode != HistoryPiiMode::Plain {
+        return Err(StorageError(format!(
+            "HISTORY_PII_MODE={mode} needs HISTORY_PII_SECRET, the key recipients are hashed with; set it or use HISTORY_PII_MODE=plain"
+        )));
+    }
+    PII.store(Arc::new((mode, secret.as_bytes().to_vec())));
+    Ok(())
+}
+
+/// # stored_recipient()
+/// What a record keeps of `address` under `mode`: the lowercase address (`plain`), its
+/// HMAC-SHA256 under `secret` as `hmac:<hex>` (`hash`), or nothing (`none`).
+/// # Example
+/// ```
+/// use templar::config::HistoryPiiMode;
+/// use templar::storage::stored_recipient;
+/// assert_eq!(stored_recipient(HistoryPiiMode::Plain, b"key", "Ada@Example.com").as_deref(), Some("ada@example.com"));
+/// let hashed = stored_recipient(HistoryPiiMode::Hash, b"key", "Ada@Example.com").unwrap();
+/// assert!(hashed.starts_with("hmac:") && !hashed.contains('@'));
+/// assert_eq!(stored_recipient(HistoryPiiMode::Hash, b"key", "ada@example.com"), Some(hashed.clone()));
+/// assert_ne!(stored_recipient(HistoryPiiMode::Hash, b"other key", "ada@example.com"), Some(hashed));
+/// assert_eq!(stored_recipient(HistoryPiiMode::None, b"key", "ada@example.com"), None);
+/// ```
+pub fn stored_recipient(mode: HistoryPiiMode, secret: &[u8], address: &str) -> Option<String> {
+    let address = address.trim().to_lowercase();
+    match mode {
+        HistoryPiiMode::Hash => Some(format!("hmac:{}", crate::crypto::sign(secret, address.as_bytes()))),
+        HistoryPiiMode::Plain => Some(address),
+        HistoryPiiMode::None => None,
+    }
+}
+
+/// `address` as message and complaint records keep it now.
+fn history_recipient(address: &str) -> Option<String> {
+    let pii = PII.load();
+    stored_recipient(pii.0, &pii.1, address)
+}
+
+/// `address` as a scheduled send's `recipients` column keeps it now: hashed unless `plain`.
+fn scheduled_recipient(address: &str) -> String {
+    let pii = PII.load();
+    let mode = if pii.0 == HistoryPiiMode::Plain { HistoryPiiMode::Plain } else { HistoryPiiMode::Hash };
+    stored_recipient(mode, &pii.1, address).expect("plain and hash keep something")
+}
+
+/// Anything shaped like an address in free text.
+static ADDRESS: Lazy<Regex> = Lazy::new(|| Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)+").expect("valid pattern"));
+
+/// # redact_addresses()
+/// `text` with every address in it as [`stored_recipient`] keeps it under `mode`, and
+/// `[redacted]` for `none`; for suppression reasons and stored `Idempotency-Key` answers.
+/// # Example
+/// ```
+/// use templar::config::HistoryPiiMode;
+/// use templar::storage::redact_addresses;
+/// let reason = "bounced: 550 <Ada@Example.com> unknown";
+/// assert_eq!(redact_addresses(HistoryPiiMode::Plain, b"key", reason), reason);
+/// assert_eq!(redact_addresses(HistoryPiiMode::None, b"key", reason), "bounced: 550 <[redacted]> unknown");
+/// let hashed = redact_addresses(HistoryPiiMode::Hash, b"key", r#"{"rejected":["ada@example.com"]}"#);
+/// assert!(hashed.starts_with(r#"{"rejected":["hmac:"#) && !hashed.contains('@'));
+/// ```
+pub fn redact_addresses(mode: HistoryPiiMode, secret: &[u8], text: &str) -> String {
+    if mode == HistoryPiiMode::Plain {
+        return text.to_string();
+    }
+    ADDRESS.replace_all(text, |c: &regex::Captures| stored_recipient(mode, secret, &c[0]).unwrap_or_else(|| "[redacted]".into())).into_owned()
+}
+
+/// `text` as suppression reasons and stored answers keep it now.
+fn history_text(text: &str) -> String {
+    let pii = PII.load();
+    redact_addresses(pii.0, &pii.1, text)
+}
+
+/// Every form `address` may have been stored in, whatever the mode was then.
+fn recipient_forms(address: &str) -> [String; 2] {
+    let pii = PII.load();
+    let hashed = stored_recipient(HistoryPiiMode::Hash, &pii.1, address).expect("hash keeps something");
+    [address.trim().to_lowercase(), hashed]
+}
+
+/// # connect()
+/// Connect to `url` without touching the schema (`templar --migrate-only`, `templar doctor`).
+/// # Errors
+/// An unknown scheme, a backend not built in, or the connection failing.
+pub async fn connect(url: &str) -> Result<Store, StorageError> {
+    match url.split_once(':').map(|(scheme, _)| scheme) {
+        Some("sqlite") => Ok(Arc::new(sqlite::SqliteStorage::connect(url).await?)),
+        #[cfg(feature = "postgres")]
+        Some("postgres" | "postgresql") => Ok(Arc::new(postgres::PgStorage::connect(url).await?)),
+        #[cfg(not(feature = "postgres"))]
+        Some("postgres" | "postgresql") => Err(StorageError("postgres storage needs a build with --features postgres".into())),
+        _ => Err(StorageError(format!("unsupported STORAGE_URL `{url}`, expected sqlite:// or postgres://"))),
+    }
+}
+
+/// Migrations in `migrator` missing from `applied`, as `<version> <description>`.
+fn pending_of(migrator: &sqlx::migrate::Migrator, applied: &[sqlx::migrate::AppliedMigration]) -> Vec<String> {
+    migrator
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration() && !applied.iter().any(|a| a.version == m.version))
+        .map(|m| format!("{} {}", m.version, m.description))
+        .collect()
+}
+
+/// Current Unix time in seconds.
+pub fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or_default()
+}
+
+/// `,a@x.com,b@y.com,`: recipients as stored, see the module docs.
+fn join_recipients<S: AsRef<str>>(recipients: &[S]) -> String {
+    let mut out = String::from(",");
+    for r in recipients {
+        out.push_str(&r.as_ref().to_lowercase());
+        out.push(',');
+    }
+    out
+}
+
+/// A message's `recipients` column under `HISTORY_PII_MODE`; `,` when none are kept.
+fn message_recipients(recipients: &[String]) -> String {
+    join_recipients(&recipients.iter().filter_map(|r| history_recipient(r)).collect::<Vec<_>>())
+}
+
+/// A scheduled send's `recipients` column under `HISTORY_PII_MODE`.
+fn scheduled_recipients(req: &crate::routes::SendRequest) -> String {
+    join_recipients(&request_recipients(req).iter().map(|r| scheduled_recipient(r)).collect::<Vec<_>>())
+}
+
+/// A complaint's `recipient` column under `HISTORY_PII_MODE`.
+fn complaint_recipient(complaint: &Complaint) -> Option<String> {
+    complaint.recipient.as_deref().and_then(history_recipient)
+}
+
+/// Addresses in a request's `to`, without display names.
+fn request_recipients(req: &crate::routes::SendRequest) -> Vec<String> {
+    req.to
+        .split(',')
+        .map(|r| r.trim().parse::<lettre::message::Mailbox>().map_or_else(|_| r.trim().to_string(), |mb| mb.email.to_string()))
+        .collect()
+}
+
+fn split_recipients(stored: &str) -> Vec<String> {
+    stored.split(',').filter(|r| !r.is_empty()).map(str::to_string).collect()
+}
+
+/// A `messages` row: id, template, recipients, campaign, time, policy findings, cost, variant,
+/// SMTP replies and caller.
+type MessageRow = (String, String, String, Option<String>, i64, Option<String>, Option<f64>, Option<String>, Option<String>, Option<String>);
+
+/// A `GET /admin/stats` row: caller, template, variant, messages and summed cost.
+type TotalsRow = (Option<String>, String, Option<String>, i64, f64);
+
+/// Content policy findings as stored: a JSON array, `NULL` when there are none.
+fn policy_column(findings: &[crate::policy::Finding]) -> Option<String> {
+    (!findings.is_empty()).then(|| serde_json::to_string(findings).expect("findings serialize"))
+}
+
+/// A stored `policy` column; a value that no longer parses reads as no findings.
+fn policy_findings(stored: Option<&str>) -> Vec<crate::policy::Finding> {
+    stored.and_then(|s| serde_json::from_str(s).ok()).unwrap_or_default()
+}
+
+/// SMTP replies as stored: a JSON array with addresses kept as recipients are, `NULL`
+/// when there are none.
+fn smtp_column(replies: &[crate::transport::SmtpReply]) -> Option<String> {
+    (!replies.is_empty()).then(|| history_text(&serde_json::to_string(replies).expect("replies serialize")))
+}
+
+/// A stored `smtp` column; a value that no longer parses reads as no replies.
+fn smtp_replies(stored: Option<&str>) -> Vec<crate::transport::SmtpReply> {
+    stored.and_then(|s| serde_json::from_str(s).ok()).unwrap_or_default()
+}
+
+/// `text` lowercase, escaped for a `LIKE` pattern.
+fn like_escaped(text: &str) -> String {
+    text.to_lowercase().replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+/// `LIKE` pattern matching one stored recipient exactly.
+fn recipient_pattern(address: &str) -> String {
+    format!("%,{},%", like_escaped(address))
+}
+
+/// `LIKE` pattern of text containing `address` somewhere; [`mentions_any`] tells a whole
+/// address from a longer one.
+fn mention_pattern(address: &str) -> String {
+    format!("%{}%", like_escaped(address))
+}
+
+/// True if `text` mentions any of `forms` (lowercase) as a whole address.
+fn mentions_any(text: &str, forms: &[String]) -> bool {
+    let text = text.to_lowercase();
+    forms.iter().any(|f| mentions(text.as_bytes(), f.as_bytes()))
+}
+
+/// True if `needle` occurs in `haystack` as a whole address,
+/// so `bob@x.com` does not match inside `jimbob@x.com`.
+pub(crate) fn mentions(haystack: &[u8], needle: &[u8]) -> bool {
+    // Bytes of non-ASCII characters count as letters
+    let is_addr_byte = |b: u8| b.is_ascii_alphanumeric() || !b.is_ascii() || b"._%+-@".contains(&b);
+    !needle.is_empty()
+        && haystack.windows(needle.len()).enumerate().any(|(at, window)| {
+            window == needle
+                && !at.checked_sub(1).is_some_and(|i| is_addr_byte(haystack[i]))
+                && !haystack.get(at + needle.len()).is_some_and(|&b| b != b'.' && is_addr_byte(b))
+        })
+}
+
+/// A `complaints` row: message, recipient, feedback type, user agent, arrival date and time.
+type ComplaintRow = (Option<String>, Option<String>, String, Option<String>, Option<String>, i64);
+
+fn complaint_rows(rows: Vec<ComplaintRow>) -> Vec<Complaint> {
+    rows.into_iter()
+        .map(|(message_id, recipient, feedback_type, user_agent, arrival_date, created_at)| Complaint { message_id, recipient, feedback_type, user_agent, arrival_date, created_at })
+        .collect()
+}
+
+/// A stored recipient list without `forms`, `None` when it held none of them.
+fn without_recipient(stored: &str, forms: &[String]) -> Option<String> {
+    let recipients = split_recipients(stored);
+    let left: Vec<&String> = recipients.iter().filter(|r| !forms.contains(r)).collect();
+    (left.len() < recipients.len()).then(|| join_recipients(&left))
+}
+
+/// What is left of a scheduled send once `address` is taken out of its `to`: the request
+/// and its `recipients` column, or `None` to remove the send (nobody else is left, or the
+/// request no longer parses).
+fn scheduled_without(request: &str, address: &str) -> Option<(String, String)> {
+    let mut request: crate::routes::SendRequest = serde_json::from_str(request).ok()?;
+    let to: Vec<&str> = request.to.split(',').map(str::trim).filter(|r| !r.is_empty()).collect();
+    let left: Vec<&str> = to
+        .into_iter()
+        .filter(|r| r.parse::<lettre::message::Mailbox>().map_or_else(|_| r.to_lowercase(), |mb| mb.email.to_string().to_lowercase()) != address)
+        .collect();
+    if left.is_empty() {
+        return None;
+    }
+    request.to = left.join(", ");
+    let recipients = scheduled_recipients(&request);
+    Some((serde_json::to_string(&request).ok()?, recipients))
+}
+
+/// An `api_keys` row: kid, hash, scope, expiry, creation and revocation.
+type ApiKeyRow = (String, String, String, Option<i64>, i64, Option<i64>);
+
+fn api_key_rows(rows: Vec<ApiKeyRow>) -> Vec<ApiKeyRecord> {
+    rows.into_iter()
+        .map(|(kid, hash, scope, expires_at, created_at, revoked_at)| ApiKeyRecord { kid, hash, scope, expires_at, created_at, revoked_at })
+        .collect()
+}
+
+/// A `scheduled_sends` row as read back: id, send time, request, caller and creation.
+type ScheduledRow = (String, i64, String, Option<String>, i64);
+
+/// Rows of `(id, send_at, request, caller, created_at)`; a request that no longer parses is skipped.
+fn scheduled_rows(rows: Vec<ScheduledRow>) -> Vec<ScheduledSend> {
+    rows.into_iter()
+        .filter_map(|(id, send_at, request, caller, created_at)| match serde_json::from_str(&request) {
+            Ok(request) => Some(ScheduledSend { id, send_at, request, caller, created_at }),
+            Err(e) => {
+                warn!(id, "Scheduled send cannot be read, skipped: {e}");
+                None
+            }
+        })
+        .collect()
+}
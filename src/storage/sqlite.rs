@@ -0,0 +1,489 @@
+//! SQLite backend, the default (`STORAGE_URL=sqlite://templar.db`): one file, one instance.
+
+use std::str::FromStr;
+
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions};
+use sqlx::ConnectOptions;
+
+use super::{
+    api_key_rows, complaint_recipient, complaint_rows, history_text, mention_pattern, mentions_any, message_recipients, pending_of, policy_column, policy_findings, recipient_forms, recipient_pattern, scheduled_recipients, scheduled_rows, scheduled_without, smtp_column, smtp_replies, split_recipients, without_recipient, Complaint, ComplaintRow, IdempotencyClaim, MessageRecord, MessageRow, MessageTotals, ScheduledSend, Storage, StorageDeletion, TotalsRow,
+    ApiKeyRecord, ApiKeyRow, ScheduledRow, ERASED_ANSWER, StorageError, StoreFuture, Suppression, WarmupDay,
+};
+
+/// `migrations/sqlite/`, embedded at build time.
+static MIGRATOR: Migrator = sqlx::migrate!("migrations/sqlite");
+
+pub struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    /// Open the database at `url`, creating the file if needed; migrations are left to the caller.
+    pub async fn connect(url: &str) -> Result<Self, StorageError> {
+        // The scheduler polls every few seconds; per-statement logs would drown debug output
+        let options =
+            SqliteConnectOptions::from_str(url)?.create_if_missing(true).journal_mode(SqliteJournalMode::Wal).disable_statement_logging();
+        // Each connection to `:memory:` would get its own empty database
+        let max = if url.contains(":memory:") { 1 } else { 4 };
+        let pool = SqlitePoolOptions::new().max_connections(max).connect_with(options).await?;
+        Ok(Self { pool })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn backend(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn pending_migrations(&self) -> StoreFuture<'_, Vec<String>> {
+        Box::pin(async move {
+            let mut conn = self.pool.acquire().await?;
+            // No migrations table yet means nothing was applied
+            let applied = conn.list_applied_migrations().await.unwrap_or_default();
+            Ok(pending_of(&MIGRATOR, &applied))
+        })
+    }
+
+    fn migrate(&self) -> StoreFuture<'_, Vec<String>> {
+        Box::pin(async move {
+            let pending = self.pending_migrations().await?;
+            MIGRATOR.run(&self.pool).await?;
+            Ok(pending)
+        })
+    }
+
+    fn record_message<'a>(&'a self, m: &'a MessageRecord) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            sqlx::query("INSERT INTO messages (id, template, recipients, campaign_id, created_at, policy, cost, variant, smtp, caller) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)")
+                .bind(&m.id)
+                .bind(&m.template)
+                .bind(message_recipients(&m.recipients))
+                .bind(&m.campaign_id)
+                .bind(m.created_at)
+                .bind(policy_column(&m.policy))
+                .bind(m.cost)
+                .bind(&m.variant)
+                .bind(smtp_column(&m.smtp))
+                .bind(&m.caller)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn message<'a>(&'a self, id: &'a str) -> StoreFuture<'a, Option<MessageRecord>> {
+        Box::pin(async move {
+            let row: Option<MessageRow> =
+                sqlx::query_as("SELECT id, template, recipients, campaign_id, created_at, policy, cost, variant, smtp, caller FROM messages WHERE id = ?1")
+                    .bind(id)
+                    .fetch_optional(&self.pool)
+                    .await?;
+            Ok(row.map(|(id, template, recipients, campaign_id, created_at, policy, cost, variant, smtp, caller)| MessageRecord {
+                id,
+                template,
+                recipients: split_recipients(&recipients),
+                campaign_id,
+                policy: policy_findings(policy.as_deref()),
+                cost,
+                variant,
+                smtp: smtp_replies(smtp.as_deref()),
+                caller,
+                created_at,
+            }))
+        })
+    }
+
+    fn message_totals(&self, since: i64) -> StoreFuture<'_, Vec<MessageTotals>> {
+        Box::pin(async move {
+            let rows: Vec<TotalsRow> = sqlx::query_as(
+                "SELECT caller, template, variant, COUNT(*), COALESCE(SUM(cost), 0.0) FROM messages WHERE created_at >= ?1
+                 GROUP BY caller, template, variant ORDER BY caller, template, variant",
+            )
+            .bind(since)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows.into_iter().map(|(caller, template, variant, messages, cost)| MessageTotals { caller, template, variant, messages, cost }).collect())
+        })
+    }
+
+    fn suppress<'a>(&'a self, s: &'a Suppression) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO suppressions (address, reason, created_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (address) DO UPDATE SET reason = excluded.reason, created_at = excluded.created_at",
+            )
+            .bind(s.address.to_lowercase())
+            .bind(history_text(&s.reason))
+            .bind(s.created_at)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn unsuppress<'a>(&'a self, address: &'a str) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let done = sqlx::query("DELETE FROM suppressions WHERE address = ?1").bind(address.to_lowercase()).execute(&self.pool).await?;
+            Ok(done.rows_affected() > 0)
+        })
+    }
+
+    fn suppressed<'a>(&'a self, addresses: &'a [String]) -> StoreFuture<'a, Vec<Suppression>> {
+        Box::pin(async move {
+            let mut found = Vec::new();
+            for address in addresses {
+                let row: Option<(String, String, i64)> = sqlx::query_as("SELECT address, reason, created_at FROM suppressions WHERE address = ?1")
+                    .bind(address)
+                    .fetch_optional(&self.pool)
+                    .await?;
+                found.extend(row.map(|(address, reason, created_at)| Suppression { address, reason, created_at }));
+            }
+            Ok(found)
+        })
+    }
+
+    fn suppressions(&self) -> StoreFuture<'_, Vec<Suppression>> {
+        Box::pin(async move {
+            let rows: Vec<(String, String, i64)> =
+                sqlx::query_as("SELECT address, reason, created_at FROM suppressions ORDER BY created_at, address").fetch_all(&self.pool).await?;
+            Ok(rows.into_iter().map(|(address, reason, created_at)| Suppression { address, reason, created_at }).collect())
+        })
+    }
+
+    fn schedule<'a>(&'a self, send: &'a ScheduledSend) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            let request = serde_json::to_string(&send.request).map_err(|e| StorageError(e.to_string()))?;
+            sqlx::query(
+                "INSERT INTO scheduled_sends (id, send_at, request, recipients, caller, campaign_id, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )
+            .bind(&send.id)
+            .bind(send.send_at)
+            .bind(request)
+            .bind(scheduled_recipients(&send.request))
+            .bind(&send.caller)
+            .bind(&send.request.campaign_id)
+            .bind(send.created_at)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn due_callers(&self, now: i64, stale_secs: i64) -> StoreFuture<'_, Vec<(Option<String>, i64)>> {
+        Box::pin(async move {
+            let rows: Vec<(Option<String>, i64)> = sqlx::query_as(
+                "SELECT caller, COUNT(*) FROM scheduled_sends
+                 WHERE send_at <= ?1 AND (claimed_at IS NULL OR claimed_at <= ?2)
+                 GROUP BY caller ORDER BY MIN(send_at), caller",
+            )
+            .bind(now)
+            .bind(now - stale_secs)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn claim_due<'a>(&'a self, caller: Option<&'a str>, now: i64, stale_secs: i64, limit: u32) -> StoreFuture<'a, Vec<ScheduledSend>> {
+        Box::pin(async move {
+            let rows: Vec<ScheduledRow> = sqlx::query_as(
+                "UPDATE scheduled_sends SET claimed_at = ?1
+                 WHERE id IN (SELECT id FROM scheduled_sends
+                              WHERE send_at <= ?1 AND (claimed_at IS NULL OR claimed_at <= ?2) AND caller IS ?4
+                              ORDER BY send_at LIMIT ?3)
+                 RETURNING id, send_at, request, caller, created_at",
+            )
+            .bind(now)
+            .bind(now - stale_secs)
+            .bind(i64::from(limit))
+            .bind(caller)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(scheduled_rows(rows))
+        })
+    }
+
+    fn finish_scheduled<'a>(&'a self, id: &'a str) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            sqlx::query("DELETE FROM scheduled_sends WHERE id = ?1").bind(id).execute(&self.pool).await?;
+            Ok(())
+        })
+    }
+
+    fn cancel_campaign<'a>(&'a self, id: &'a str, now: i64) -> StoreFuture<'a, (i64, u64)> {
+        Box::pin(async move {
+            let mut tx = self.pool.begin().await?;
+            sqlx::query("INSERT INTO cancelled_campaigns (campaign_id, cancelled_at) VALUES (?1, ?2) ON CONFLICT (campaign_id) DO NOTHING")
+                .bind(id)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?;
+            let (cancelled_at,): (i64,) =
+                sqlx::query_as("SELECT cancelled_at FROM cancelled_campaigns WHERE campaign_id = ?1").bind(id).fetch_one(&mut *tx).await?;
+            let removed = sqlx::query("DELETE FROM scheduled_sends WHERE campaign_id = ?1").bind(id).execute(&mut *tx).await?;
+            tx.commit().await?;
+            Ok((cancelled_at, removed.rows_affected()))
+        })
+    }
+
+    fn cancelled_campaigns(&self) -> StoreFuture<'_, Vec<(String, i64)>> {
+        Box::pin(async move {
+            let rows: Vec<(String, i64)> = sqlx::query_as("SELECT campaign_id, cancelled_at FROM cancelled_campaigns").fetch_all(&self.pool).await?;
+            Ok(rows)
+        })
+    }
+
+    fn claim_idempotency<'a>(&'a self, key: &'a str, now: i64, since: i64) -> StoreFuture<'a, IdempotencyClaim> {
+        Box::pin(async move {
+            let claimed = sqlx::query(
+                "INSERT INTO idempotency_keys (key, created_at) VALUES (?1, ?2)
+                 ON CONFLICT (key) DO UPDATE SET status = NULL, body = NULL, created_at = excluded.created_at
+                 WHERE idempotency_keys.created_at < ?3",
+            )
+            .bind(key)
+            .bind(now)
+            .bind(since)
+            .execute(&self.pool)
+            .await?;
+            if claimed.rows_affected() > 0 {
+                return Ok(IdempotencyClaim::Claimed);
+            }
+            let row: Option<(Option<i64>, Option<String>)> =
+                sqlx::query_as("SELECT status, body FROM idempotency_keys WHERE key = ?1").bind(key).fetch_optional(&self.pool).await?;
+            Ok(match row {
+                Some((Some(status), Some(body))) => IdempotencyClaim::Done(status as u16, body),
+                _ => IdempotencyClaim::InProgress,
+            })
+        })
+    }
+
+    fn complete_idempotency<'a>(&'a self, key: &'a str, status: u16, body: &'a str) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            sqlx::query("UPDATE idempotency_keys SET status = ?2, body = ?3 WHERE key = ?1")
+                .bind(key)
+                .bind(i64::from(status))
+                .bind(history_text(body))
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn release_idempotency<'a>(&'a self, key: &'a str) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            sqlx::query("DELETE FROM idempotency_keys WHERE key = ?1 AND status IS NULL").bind(key).execute(&self.pool).await?;
+            Ok(())
+        })
+    }
+
+    fn expire_idempotency(&self, before: i64) -> StoreFuture<'_, u64> {
+        Box::pin(async move {
+            let done = sqlx::query("DELETE FROM idempotency_keys WHERE created_at < ?1").bind(before).execute(&self.pool).await?;
+            Ok(done.rows_affected())
+        })
+    }
+
+    fn purge_messages(&self, before: i64) -> StoreFuture<'_, u64> {
+        Box::pin(async move {
+            let done = sqlx::query("DELETE FROM messages WHERE created_at < ?1").bind(before).execute(&self.pool).await?;
+            sqlx::query("DELETE FROM complaints WHERE created_at < ?1").bind(before).execute(&self.pool).await?;
+            Ok(done.rows_affected())
+        })
+    }
+
+    fn delete_recipient<'a>(&'a self, address: &'a str) -> StoreFuture<'a, StorageDeletion> {
+        Box::pin(async move {
+            // Records made under another HISTORY_PII_MODE hold the other form
+            let forms = recipient_forms(address);
+            let [plain, hashed] = &forms;
+            let (plain_pattern, hashed_pattern) = (recipient_pattern(plain), recipient_pattern(hashed));
+            let mut tx = self.pool.begin().await?;
+            let mut deletion = StorageDeletion::default();
+
+            let rows: Vec<(String, String)> = sqlx::query_as("SELECT id, recipients FROM messages WHERE recipients LIKE ?1 ESCAPE '\\' OR recipients LIKE ?2 ESCAPE '\\'")
+                .bind(&plain_pattern)
+                .bind(&hashed_pattern)
+                .fetch_all(&mut *tx)
+                .await?;
+            for (id, recipients) in rows {
+                let Some(left) = without_recipient(&recipients, &forms) else { continue };
+                if left == "," {
+                    sqlx::query("DELETE FROM messages WHERE id = ?1").bind(&id).execute(&mut *tx).await?;
+                } else {
+                    sqlx::query("UPDATE messages SET recipients = ?2 WHERE id = ?1").bind(&id).bind(left).execute(&mut *tx).await?;
+                }
+                deletion.messages += 1;
+            }
+
+            let rows: Vec<(String, String)> =
+                sqlx::query_as("SELECT id, request FROM scheduled_sends WHERE recipients LIKE ?1 ESCAPE '\\' OR recipients LIKE ?2 ESCAPE '\\'")
+                    .bind(&plain_pattern)
+                    .bind(&hashed_pattern)
+                    .fetch_all(&mut *tx)
+                    .await?;
+            for (id, request) in rows {
+                match scheduled_without(&request, plain) {
+                    Some((request, recipients)) => {
+                        sqlx::query("UPDATE scheduled_sends SET request = ?2, recipients = ?3 WHERE id = ?1")
+                            .bind(&id)
+                            .bind(request)
+                            .bind(recipients)
+                            .execute(&mut *tx)
+                            .await?;
+                    }
+                    None => {
+                        sqlx::query("DELETE FROM scheduled_sends WHERE id = ?1").bind(&id).execute(&mut *tx).await?;
+                    }
+                }
+                deletion.scheduled += 1;
+            }
+
+            let complaints = sqlx::query("DELETE FROM complaints WHERE recipient IN (?1, ?2)").bind(plain).bind(hashed).execute(&mut *tx).await?;
+            deletion.complaints = complaints.rows_affected();
+
+            let rows: Vec<(String, String)> =
+                sqlx::query_as("SELECT key, body FROM idempotency_keys WHERE LOWER(body) LIKE ?1 ESCAPE '\\' OR LOWER(body) LIKE ?2 ESCAPE '\\'")
+                    .bind(mention_pattern(plain))
+                    .bind(mention_pattern(hashed))
+                    .fetch_all(&mut *tx)
+                    .await?;
+            for (key, _) in rows.into_iter().filter(|(_, body)| mentions_any(body, &forms)) {
+                sqlx::query("UPDATE idempotency_keys SET body = ?2 WHERE key = ?1").bind(&key).bind(ERASED_ANSWER).execute(&mut *tx).await?;
+                if key.starts_with("outbox:") {
+                    deletion.outbox_claims += 1;
+                } else {
+                    deletion.idempotency += 1;
+                }
+            }
+            tx.commit().await?;
+            Ok(deletion)
+        })
+    }
+
+    fn record_complaint<'a>(&'a self, complaint: &'a Complaint) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO complaints (message_id, recipient, feedback_type, user_agent, arrival_date, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )
+            .bind(&complaint.message_id)
+            .bind(complaint_recipient(complaint))
+            .bind(&complaint.feedback_type)
+            .bind(&complaint.user_agent)
+            .bind(&complaint.arrival_date)
+            .bind(complaint.created_at)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn complaints<'a>(&'a self, id: &'a str) -> StoreFuture<'a, Vec<Complaint>> {
+        Box::pin(async move {
+            let rows: Vec<ComplaintRow> = sqlx::query_as(
+                "SELECT message_id, recipient, feedback_type, user_agent, arrival_date, created_at FROM complaints
+                 WHERE message_id = ?1 ORDER BY created_at",
+            )
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(complaint_rows(rows))
+        })
+    }
+
+    fn reserve_warmup<'a>(&'a self, domain: &'a str, day: i64, count: i64, limit: i64) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let reserved = sqlx::query(
+                "INSERT INTO warmup (domain, day, sent) SELECT ?1, ?2, ?3 WHERE ?3 <= ?4
+                 ON CONFLICT (domain, day) DO UPDATE SET sent = warmup.sent + excluded.sent
+                 WHERE warmup.sent + excluded.sent <= ?4",
+            )
+            .bind(domain)
+            .bind(day)
+            .bind(count)
+            .bind(limit)
+            .execute(&self.pool)
+            .await?;
+            Ok(reserved.rows_affected() > 0)
+        })
+    }
+
+    fn count_warmup<'a>(&'a self, domain: &'a str, day: i64, sent: i64, deferred: i64) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO warmup (domain, day, sent, deferred) VALUES (?1, ?2, MAX(?3, 0), MAX(?4, 0))
+                 ON CONFLICT (domain, day) DO UPDATE SET sent = MAX(warmup.sent + ?3, 0), deferred = MAX(warmup.deferred + ?4, 0)",
+            )
+            .bind(domain)
+            .bind(day)
+            .bind(sent)
+            .bind(deferred)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn warmup_days(&self, since: i64) -> StoreFuture<'_, Vec<WarmupDay>> {
+        Box::pin(async move {
+            let rows: Vec<(String, i64, i64, i64)> =
+                sqlx::query_as("SELECT domain, day, sent, deferred FROM warmup WHERE day >= ?1 ORDER BY day, domain").bind(since).fetch_all(&self.pool).await?;
+            Ok(rows.into_iter().map(|(domain, day, sent, deferred)| WarmupDay { domain, day, sent, deferred }).collect())
+        })
+    }
+
+    fn create_api_key<'a>(&'a self, key: &'a ApiKeyRecord) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let done = sqlx::query(
+                "INSERT INTO api_keys (kid, key_hash, scope, expires_at, created_at) VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (kid) DO NOTHING",
+            )
+            .bind(&key.kid)
+            .bind(&key.hash)
+            .bind(&key.scope)
+            .bind(key.expires_at)
+            .bind(key.created_at)
+            .execute(&self.pool)
+            .await?;
+            Ok(done.rows_affected() == 1)
+        })
+    }
+
+    fn api_keys(&self) -> StoreFuture<'_, Vec<ApiKeyRecord>> {
+        Box::pin(async move {
+            let rows: Vec<ApiKeyRow> =
+                sqlx::query_as("SELECT kid, key_hash, scope, expires_at, created_at, revoked_at FROM api_keys ORDER BY created_at, kid").fetch_all(&self.pool).await?;
+            Ok(api_key_rows(rows))
+        })
+    }
+
+    fn revoke_api_key<'a>(&'a self, kid: &'a str, now: i64) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            let done = sqlx::query("UPDATE api_keys SET revoked_at = ?2 WHERE kid = ?1 AND revoked_at IS NULL").bind(kid).bind(now).execute(&self.pool).await?;
+            Ok(done.rows_affected() == 1)
+        })
+    }
+
+    fn setting<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<String>> {
+        Box::pin(async move {
+            let row: Option<(String,)> = sqlx::query_as("SELECT value FROM settings WHERE key = ?1").bind(key).fetch_optional(&self.pool).await?;
+            Ok(row.map(|(value,)| value))
+        })
+    }
+
+    fn set_setting<'a>(&'a self, key: &'a str, value: &'a str) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO settings (key, value, updated_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at",
+            )
+            .bind(key)
+            .bind(value)
+            .bind(super::now())
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,114 @@
+//! systemd integration: socket activation, readiness notification and watchdog pings.
+//!
+//! Implements the `sd_listen_fds(3)` and `sd_notify(3)` protocols directly from their
+//! environment variables, so no libsystemd is needed. Outside systemd every function
+//! here is a no-op.
+//!
+//! With socket activation systemd owns the listening sockets and passes them to each
+//! new process, so restarting Templar never closes the port: connections queue in the
+//! kernel until the new process is ready.
+
+use std::{
+    net::TcpListener,
+    os::{fd::{FromRawFd, RawFd}, unix::net::{UnixDatagram, UnixListener}},
+    time::Duration,
+};
+
+use socket2::Socket;
+use tracing::{debug, warn};
+
+/// First file descriptor passed by systemd (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket inherited from systemd.
+pub enum ActivatedListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// One inherited socket plus its `FileDescriptorName=` (`unknown` when the unit sets none).
+pub struct ActivatedSocket {
+    pub name: String,
+    pub listener: ActivatedListener,
+}
+
+/// # listen_fds()
+/// Take the sockets systemd passed to this process (`LISTEN_FDS`, `LISTEN_PID`,
+/// `LISTEN_FDNAMES`). Returns an empty list when not socket-activated.
+/// The variables are removed afterwards so child processes do not inherit them.
+/// # Errors
+/// Fails when an inherited descriptor is not a listening TCP or Unix stream socket.
+pub fn listen_fds() -> std::io::Result<Vec<ActivatedSocket>> {
+    let for_us = std::env::var("LISTEN_PID").ok().and_then(|p| p.parse::<u32>().ok()) == Some(std::process::id());
+    let count = std::env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<RawFd>().ok()).unwrap_or(0);
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    // SAFETY: called once at the top of `main`, before any other thread reads the environment.
+    unsafe {
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+    }
+    if !for_us || count <= 0 {
+        return Ok(Vec::new());
+    }
+    let mut names = names.split(':');
+    let mut sockets = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // SAFETY: systemd hands these descriptors to this process exclusively.
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        let name = names.next().filter(|n| !n.is_empty()).unwrap_or("unknown").to_string();
+        let local = socket.local_addr()?;
+        let listener = if local.as_socket().is_some() {
+            ActivatedListener::Tcp(socket.into())
+        } else if local.is_unix() {
+            ActivatedListener::Unix(socket.into())
+        } else {
+            return Err(std::io::Error::other(format!("inherited fd {fd} ({name}) is not a TCP or Unix socket")));
+        };
+        debug!(fd, name, "Using socket passed by systemd");
+        sockets.push(ActivatedSocket { name, listener });
+    }
+    Ok(sockets)
+}
+
+/// # notify()
+/// Send a state string such as `READY=1` or `WATCHDOG=1` to `NOTIFY_SOCKET`.
+/// Does nothing when the service manager did not ask for notifications.
+pub fn notify(state: &str) {
+    let Ok(path) = std::env::var("NOTIFY_SOCKET") else { return };
+    let result = UnixDatagram::unbound().and_then(|sock| match path.strip_prefix('@') {
+        // Abstract namespace socket
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            sock.send_to_addr(state.as_bytes(), &addr)
+        }
+        None => sock.send_to(state.as_bytes(), &path),
+    });
+    if let Err(e) = result {
+        warn!("sd_notify({state}) failed: {e}");
+    }
+}
+
+/// # spawn_watchdog()
+/// When the unit sets `WatchdogSec=`, ping the watchdog at half the interval.
+/// The pings come from a Tokio task, so a wedged runtime stops them and systemd restarts the service.
+pub fn spawn_watchdog() {
+    let for_us = match std::env::var("WATCHDOG_PID") {
+        Ok(pid) => pid.parse::<u32>().ok() == Some(std::process::id()),
+        Err(_) => true,
+    };
+    let usec = std::env::var("WATCHDOG_USEC").ok().and_then(|u| u.parse::<u64>().ok()).unwrap_or(0);
+    if !for_us || usec == 0 {
+        return;
+    }
+    let every = Duration::from_micros(usec / 2);
+    debug!("systemd watchdog enabled, pinging every {}ms", every.as_millis());
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(every);
+        loop {
+            tick.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
@@ -0,0 +1,369 @@
+//! Outbound transports: the `Mailer` stored in `EmailState` and the SMTP pieces behind it.
+
+pub mod direct;
+pub mod pool;
+pub mod providers;
+pub mod proxy;
+pub mod routing;
+
+use std::{path::Path, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use lettre::{address::Envelope, transport::{file::AsyncFileTransport, smtp::response::Response}, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use self::{direct::DirectDelivery, pool::Pool, providers::Throttled, proxy::{OutboundProxy, ProxiedSmtp}, routing::Routes};
+
+/// Transport selected at runtime (SMTP for prod, FILE for local dev, MX for relay-less networks).
+#[derive(Clone)]
+pub enum Mailer {
+    Smtp(SmtpClient),
+    File(AsyncFileTransport<Tokio1Executor>),
+    /// Primary SMTP relay with a secondary that takes over while the primary is unreachable.
+    Failover(Arc<SmtpFailover>),
+    /// Several SMTP relays sharing traffic by weight.
+    Pool(Arc<Pool>),
+    /// Recipients on matching domains go to dedicated relays, the rest to a default mailer.
+    Routed(Arc<Routes>),
+    /// No relay: deliver straight to each recipient domain's MX hosts.
+    Direct(Arc<DirectDelivery>),
+    /// Another mailer behind per-provider rate and concurrency buckets.
+    Throttled(Arc<Throttled>),
+}
+
+impl Mailer {
+    /// Transport name as reported by `/version` and the startup log.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Mailer::Smtp(_) => "smtp",
+            Mailer::File(_) => "file",
+            Mailer::Failover(_) => "smtp-failover",
+            Mailer::Pool(_) => "smtp-pool",
+            Mailer::Routed(_) => "routed",
+            Mailer::Direct(_) => "mx",
+            Mailer::Throttled(t) => t.inner().kind(),
+        }
+    }
+
+    /// The per-provider buckets, when `PROVIDER_RATES` or `PROVIDER_CONCURRENCY` set any.
+    pub fn providers(&self) -> Option<&Throttled> {
+        match self {
+            Mailer::Throttled(t) => Some(t.as_ref()),
+            _ => None,
+        }
+    }
+
+    /// Unified `send` so callers don't care which transport we're using.
+    /// Fails only when no recipient was accepted; see [`Delivery`].
+    pub async fn send(&self, email: Message) -> Result<Delivery, TransportError> {
+        self.send_raw(email.envelope(), &email.formatted()).await
+    }
+
+    /// [`Mailer::send_raw`] as a boxed `Send` future, for mailers that wrap another one
+    /// and send from spawned tasks.
+    pub(crate) fn send_boxed<'a>(&'a self, envelope: &'a Envelope, raw: &'a [u8]) -> std::pin::Pin<Box<dyn Future<Output = Result<Delivery, TransportError>> + Send + 'a>> {
+        Box::pin(self.send_raw(envelope, raw))
+    }
+
+    /// Deliver already-formatted bytes to the recipients in `envelope`.
+    pub async fn send_raw(&self, envelope: &Envelope, raw: &[u8]) -> Result<Delivery, TransportError> {
+        match self {
+            Mailer::Smtp(m) => m.send_raw(envelope, raw).await.map(Delivery::from),
+            Mailer::File(f) => f.send_raw(envelope, raw).await.map(|_| Delivery::default()).map_err(|e| TransportError::Other(e.to_string())),
+            Mailer::Failover(f) => f.send_raw(envelope, raw).await,
+            Mailer::Pool(p) => p.send_raw(envelope, raw).await,
+            Mailer::Routed(r) => r.send_raw(envelope, raw).await,
+            Mailer::Direct(d) => d.send_raw(envelope, raw).await,
+            Mailer::Throttled(t) => t.send_raw(envelope, raw).await,
+        }
+    }
+}
+
+/// What a send delivered. Transports that split the envelope (routes, direct MX) keep
+/// going when one group fails and list its recipients in `rejected`.
+#[derive(Debug, Clone, Default)]
+pub struct Delivery {
+    /// The relays' final replies: one per SMTP transaction, none for `file`.
+    pub replies: Vec<SmtpReply>,
+    pub rejected: Vec<Rejection>,
+}
+
+impl Delivery {
+    /// Add a group's delivery to this one.
+    pub(crate) fn merge(&mut self, other: Delivery) {
+        self.replies.extend(other.replies);
+        self.rejected.extend(other.rejected);
+    }
+
+    /// Record every address in `to` as refused with `error`.
+    pub(crate) fn reject(&mut self, to: &[lettre::Address], error: &TransportError) {
+        self.rejected.extend(to.iter().map(|a| Rejection { recipient: a.to_string(), error: error.to_string() }));
+    }
+}
+
+impl From<SmtpReply> for Delivery {
+    fn from(reply: SmtpReply) -> Self {
+        Self { replies: vec![reply], rejected: Vec::new() }
+    }
+}
+
+/// A recipient that was not delivered while others in the same send were.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rejection {
+    pub recipient: String,
+    pub error: String,
+}
+
+/// The relay's reply to the end of a message (`250 2.0.0 Ok: queued as 4Bx9Yq1cZ`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SmtpReply {
+    pub code: u16,
+    /// Reply text, multi-line replies joined with spaces.
+    pub message: String,
+    /// The relay's id for the message, when the text carries one in a known form.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_id: Option<String>,
+    /// Which relay replied: the SMTP profile, `primary`/`secondary` or `mx`; `None` for
+    /// the single `SMTP_HOST` relay.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay: Option<String>,
+}
+
+impl SmtpReply {
+    /// # SmtpReply::new()
+    /// Reply with the queue id picked out of `message`: Postfix/Exim/SendGrid's
+    /// `queued as <id>`, or an `id=<id>` token.
+    /// # Example
+    /// ```
+    /// use templar::transport::SmtpReply;
+    /// let postfix = SmtpReply::new(250, "2.0.0 Ok: queued as 4Bx9Yq1cZ".into());
+    /// assert_eq!(postfix.queue_id.as_deref(), Some("4Bx9Yq1cZ"));
+    /// let tagged = SmtpReply::new(250, "OK id=1rXyZ-0003Ab-Qm".into());
+    /// assert_eq!(tagged.queue_id.as_deref(), Some("1rXyZ-0003Ab-Qm"));
+    /// assert_eq!(SmtpReply::new(250, "Great success".into()).queue_id, None);
+    /// ```
+    pub fn new(code: u16, message: String) -> Self {
+        let lower = message.to_ascii_lowercase();
+        let token = |at: usize| {
+            let id: String = message[at..].chars().take_while(|c| c.is_ascii_alphanumeric() || "-_.@<>".contains(*c)).collect();
+            Some(id.trim_matches(['<', '>', '.']).to_string()).filter(|id| !id.is_empty())
+        };
+        let queue_id = match lower.find("queued as ") {
+            Some(i) => token(i + "queued as ".len()),
+            None => lower
+                .match_indices("id=")
+                .find(|(i, _)| *i == 0 || lower.as_bytes()[i - 1] == b' ')
+                .and_then(|(i, _)| token(i + "id=".len())),
+        };
+        Self { code, message, queue_id, relay: None }
+    }
+
+    /// The same reply, from `relay`.
+    pub(crate) fn via(self, relay: &str) -> Self {
+        Self { relay: Some(relay.to_string()), ..self }
+    }
+
+    fn from_response(r: &Response) -> Self {
+        let code = r.code().to_string().parse().unwrap_or_default();
+        Self::new(code, r.message().collect::<Vec<_>>().join(" "))
+    }
+}
+
+/// Why a send failed, so callers can tell a relay outage from a rejected message.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TransportError {
+    /// The relay never answered (network, TLS, proxy, DNS), so another relay may succeed.
+    #[error("{0}")]
+    Unreachable(String),
+    #[error("{0}")]
+    Timeout(String),
+    /// The relay refused our credentials (`530`/`534`/`535`/`538`).
+    #[error("{0}")]
+    Auth(String),
+    /// A `4xx` reply: the relay may accept the message later.
+    #[error("{0}")]
+    Transient(String),
+    /// A `5xx` reply, or a relay that cannot take this message at all.
+    #[error("{0}")]
+    Permanent(String),
+    /// Failed on our side before or outside the SMTP session (envelope, file transport).
+    #[error("{0}")]
+    Other(String),
+}
+
+impl TransportError {
+    /// # TransportError::is_transient()
+    /// Worth retrying, here on another relay or later by the caller.
+    /// # Example
+    /// ```
+    /// use templar::transport::TransportError;
+    /// assert!(TransportError::Transient("451 4.7.1 try again later".into()).is_transient());
+    /// assert!(!TransportError::Auth("535 5.7.8 bad credentials".into()).is_transient());
+    /// ```
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::Unreachable(_) | Self::Timeout(_) | Self::Transient(_))
+    }
+
+    /// The relay itself is down rather than answering, which counts against its health.
+    pub fn is_unreachable(&self) -> bool {
+        matches!(self, Self::Unreachable(_) | Self::Timeout(_))
+    }
+
+    /// Same kind of error with a different message.
+    pub fn map_message(self, f: impl FnOnce(String) -> String) -> Self {
+        match self {
+            Self::Unreachable(m) => Self::Unreachable(f(m)),
+            Self::Timeout(m) => Self::Timeout(f(m)),
+            Self::Auth(m) => Self::Auth(f(m)),
+            Self::Transient(m) => Self::Transient(f(m)),
+            Self::Permanent(m) => Self::Permanent(f(m)),
+            Self::Other(m) => Self::Other(f(m)),
+        }
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for TransportError {
+    fn from(e: lettre::transport::smtp::Error) -> Self {
+        let message = e.to_string();
+        let code = e.status().map(|c| c.to_string());
+        if e.is_timeout() {
+            Self::Timeout(message)
+        } else if matches!(code.as_deref(), Some("530" | "534" | "535" | "538")) {
+            Self::Auth(message)
+        } else if e.is_transient() {
+            Self::Transient(message)
+        } else if e.is_permanent() || e.is_response() {
+            Self::Permanent(message)
+        } else if e.is_client() {
+            Self::Other(message)
+        } else {
+            Self::Unreachable(message)
+        }
+    }
+}
+
+/// One SMTP relay, dialled directly or through `OUTBOUND_PROXY_URL`.
+#[derive(Clone)]
+pub enum SmtpClient {
+    Direct(AsyncSmtpTransport<Tokio1Executor>),
+    Proxied(Arc<ProxiedSmtp>),
+}
+
+impl SmtpClient {
+    pub(crate) async fn send_raw(&self, envelope: &Envelope, raw: &[u8]) -> Result<SmtpReply, TransportError> {
+        #[cfg(feature = "faults")]
+        crate::faults::smtp().await?;
+        match self {
+            SmtpClient::Direct(t) => t.send_raw(envelope, raw).await.map(|r| SmtpReply::from_response(&r)).map_err(Into::into),
+            SmtpClient::Proxied(p) => p.send_raw(envelope, raw).await,
+        }
+    }
+}
+
+/// Primary/secondary SMTP pair.
+///
+/// A connection-level failure on the primary (network, TLS, timeout — not an SMTP
+/// reply) retries the message on the secondary and keeps routing there for
+/// `failback_after`. The first send after that window probes the primary again.
+/// A `4xx` reply from the primary also retries on the secondary, without failing over.
+pub struct SmtpFailover {
+    primary: SmtpClient,
+    secondary: SmtpClient,
+    /// When the primary last failed to connect; `None` while it is healthy.
+    primary_down_since: Mutex<Option<Instant>>,
+    failback_after: Duration,
+}
+
+impl SmtpFailover {
+    pub fn new(
+        primary: SmtpClient,
+        secondary: SmtpClient,
+        failback_after: Duration,
+    ) -> Self {
+        Self { primary, secondary, primary_down_since: Mutex::new(None), failback_after }
+    }
+
+    async fn send_raw(&self, envelope: &Envelope, raw: &[u8]) -> Result<Delivery, TransportError> {
+        let down_since = *self.primary_down_since.lock().unwrap();
+        if down_since.is_none_or(|at| at.elapsed() >= self.failback_after) {
+            match self.primary.send_raw(envelope, raw).await {
+                Ok(reply) => {
+                    if self.primary_down_since.lock().unwrap().take().is_some() {
+                        info!("Primary SMTP relay reachable again, failing back");
+                    }
+                    info!(relay = "primary", code = reply.code, queue_id = reply.queue_id.as_deref(), "Message sent");
+                    return Ok(reply.via("primary").into());
+                }
+                Err(e) if e.is_unreachable() => {
+                    warn!("Primary SMTP relay unreachable, failing over to secondary: {e}");
+                    if self.primary_down_since.lock().unwrap().replace(Instant::now()).is_none() {
+                        crate::alerts::breaker_opened("primary", &format!("unreachable ({e}), failed over to the secondary for {}s", self.failback_after.as_secs()));
+                    }
+                }
+                Err(e) if e.is_transient() => warn!("Primary SMTP relay deferred the message, trying the secondary: {e}"),
+                Err(e) => return Err(e),
+            }
+        }
+        let reply = self.secondary.send_raw(envelope, raw).await?;
+        info!(relay = "secondary", code = reply.code, queue_id = reply.queue_id.as_deref(), "Message sent");
+        Ok(reply.via("secondary").into())
+    }
+}
+
+/// A named SMTP relay, configured through `SMTP_PROFILE_<NAME>_*` settings (read into
+/// `ApiConfig::profiles`).
+#[derive(Debug, Clone)]
+pub struct SmtpProfile {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    /// Relative share of traffic inside a pool.
+    pub weight: u32,
+    /// Messages per minute this relay may take (0 = unlimited).
+    pub rate_per_min: u32,
+}
+
+impl SmtpProfile {
+    pub fn build_client(&self) -> Result<SmtpClient, anyhow::Error> {
+        build_smtp_client(&self.host, self.port, &self.username, &self.password)
+    }
+}
+
+/// Build a STARTTLS SMTP client with creds and short timeout, tunnelled through
+/// `OUTBOUND_PROXY_URL` when one is configured.
+pub fn build_smtp_client(
+    host: &str,
+    port: u16,
+    user: &str,
+    pass: &str,
+) -> Result<SmtpClient, anyhow::Error> {
+    use lettre::transport::smtp::authentication::Credentials;
+
+    let creds = Credentials::new(user.to_string(), pass.to_string());
+    if let Some(proxy) = OutboundProxy::configured() {
+        return Ok(SmtpClient::Proxied(Arc::new(ProxiedSmtp {
+            proxy,
+            host: host.to_string(),
+            port,
+            require_tls: true,
+            accept_invalid_certs: false,
+            credentials: Some(creds),
+            timeout: Duration::from_secs(15),
+        })));
+    }
+    Ok(SmtpClient::Direct(
+        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?
+            .port(port)
+            .credentials(creds)
+            .timeout(Some(Duration::from_secs(15)))
+            .build(),
+    ))
+}
+
+/// Build a file transport (writes `.eml` files), used for local/dev.
+pub fn build_file_mailer(dir: &Path) -> Result<Mailer, anyhow::Error> {
+    std::fs::create_dir_all(dir)?;
+    Ok(Mailer::File(AsyncFileTransport::new(dir)))
+}
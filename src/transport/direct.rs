@@ -0,0 +1,160 @@
+//! Direct delivery: look up each recipient domain's MX records and hand the message
+//! to those hosts on port 25, without a smart host in between. Meant for internal or
+//! air-gapped networks where no relay exists.
+//!
+//! MX answers are cached for their DNS TTL, and one SMTP transport (with lettre's
+//! connection pool) is kept per MX host, so repeated sends to a domain reuse
+//! connections. Hosts are tried in preference order and an unreachable host moves on
+//! to the next. A domain without MX records falls back to its own address
+//! (RFC 5321 §5.1); a null MX (`.`, RFC 7505) means the domain accepts no mail.
+//! With `OUTBOUND_PROXY_URL` set, sessions to the MX hosts go through the proxy.
+
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use hickory_resolver::TokioResolver;
+use lettre::{
+    address::Envelope,
+    transport::smtp::client::{Tls, TlsParameters},
+    Address, AsyncSmtpTransport, Tokio1Executor,
+};
+use tracing::{info, warn};
+
+use super::{proxy::{OutboundProxy, ProxiedSmtp}, Delivery, SmtpClient, SmtpReply, TransportError};
+
+/// Resolver plus per-domain MX and per-host transport caches.
+pub struct DirectDelivery {
+    resolver: TokioResolver,
+    proxy: Option<OutboundProxy>,
+    /// domain → (MX hosts by preference, valid until)
+    mx_cache: Mutex<HashMap<String, (Vec<String>, Instant)>>,
+    clients: Mutex<HashMap<String, SmtpClient>>,
+}
+
+impl DirectDelivery {
+    /// Build with the system resolver configuration (`/etc/resolv.conf` on Unix).
+    pub fn new() -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            resolver: TokioResolver::builder_tokio()?.build(),
+            proxy: OutboundProxy::configured(),
+            mx_cache: Mutex::new(HashMap::new()),
+            clients: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Deliver to every recipient domain in `envelope`. A domain that cannot be delivered
+    /// to has its recipients listed as rejected; the send fails only when every domain did.
+    pub(crate) async fn send_raw(&self, envelope: &Envelope, raw: &[u8]) -> Result<Delivery, TransportError> {
+        let mut by_domain: Vec<(String, Vec<Address>)> = Vec::new();
+        for to in envelope.to() {
+            let domain = to.domain().to_lowercase();
+            match by_domain.iter_mut().find(|(d, _)| *d == domain) {
+                Some((_, list)) => list.push(to.clone()),
+                None => by_domain.push((domain, vec![to.clone()])),
+            }
+        }
+        let (mut delivery, mut first_error) = (Delivery::default(), None);
+        for (domain, to) in by_domain {
+            let part = Envelope::new(envelope.from().cloned(), to).map_err(|e| TransportError::Other(e.to_string()))?;
+            match self.deliver_domain(&domain, &part, raw).await {
+                Ok(reply) => delivery.replies.push(reply.via("mx")),
+                Err(e) => {
+                    warn!(domain, recipients = part.to().len(), "Recipients not delivered: {e}");
+                    delivery.reject(part.to(), &e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match first_error {
+            Some(e) if delivery.replies.is_empty() => Err(e),
+            _ => Ok(delivery),
+        }
+    }
+
+    /// Try `domain`'s MX hosts in order, moving on when one is unreachable or defers
+    /// with a `4xx`.
+    async fn deliver_domain(&self, domain: &str, envelope: &Envelope, raw: &[u8]) -> Result<SmtpReply, TransportError> {
+        let hosts = self.mx_hosts(domain).await?;
+        let mut last_error = TransportError::Unreachable(String::new());
+        for host in &hosts {
+            let client = self.client_for(host)?;
+            match client.send_raw(envelope, raw).await {
+                Ok(reply) => {
+                    info!(domain, mx = %host, code = reply.code, queue_id = reply.queue_id.as_deref(), "Message delivered directly");
+                    return Ok(reply);
+                }
+                Err(e) if e.is_transient() => {
+                    warn!(domain, mx = %host, "MX host unreachable or deferring, trying the next one: {e}");
+                    last_error = e;
+                }
+                Err(e) => return Err(e.map_message(|m| format!("{domain} ({host}): {m}"))),
+            }
+        }
+        Err(last_error.map_message(|m| format!("no MX host for {domain} accepted the message: {m}")))
+    }
+
+    /// MX hosts for `domain`, most preferred first.
+    async fn mx_hosts(&self, domain: &str) -> Result<Vec<String>, TransportError> {
+        if let Some((hosts, until)) = self.mx_cache.lock().unwrap().get(domain)
+            && Instant::now() < *until
+        {
+            return Ok(hosts.clone());
+        }
+        let (hosts, until) = match self.resolver.mx_lookup(domain).await {
+            Ok(lookup) => {
+                let mut records: Vec<_> = lookup.iter().map(|mx| (mx.preference(), mx.exchange().to_utf8())).collect();
+                records.sort_by_key(|(pref, _)| *pref);
+                let hosts: Vec<String> = records.into_iter().map(|(_, h)| h.trim_end_matches('.').to_string()).collect();
+                if hosts.iter().all(String::is_empty) {
+                    return Err(TransportError::Permanent(format!("{domain} publishes a null MX and accepts no mail")));
+                }
+                (hosts, lookup.valid_until())
+            }
+            // No MX at all: the domain itself is the implicit MX.
+            Err(e) if e.is_no_records_found() && !e.is_nx_domain() => {
+                (vec![domain.to_string()], Instant::now() + Duration::from_secs(300))
+            }
+            Err(e) if e.is_nx_domain() => return Err(TransportError::Permanent(format!("MX lookup for {domain} failed: {e}"))),
+            Err(e) => return Err(TransportError::Unreachable(format!("MX lookup for {domain} failed: {e}"))),
+        };
+        self.mx_cache.lock().unwrap().insert(domain.to_string(), (hosts.clone(), until));
+        Ok(hosts)
+    }
+
+    /// Cached client for one MX host: port 25, STARTTLS when offered.
+    fn client_for(&self, host: &str) -> Result<SmtpClient, TransportError> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(c) = clients.get(host) {
+            return Ok(c.clone());
+        }
+        let timeout = Duration::from_secs(30);
+        if let Some(proxy) = &self.proxy {
+            let client = SmtpClient::Proxied(Arc::new(ProxiedSmtp {
+                proxy: proxy.clone(),
+                host: host.to_string(),
+                port: 25,
+                require_tls: false,
+                accept_invalid_certs: true,
+                credentials: None,
+                timeout,
+            }));
+            clients.insert(host.to_string(), client.clone());
+            return Ok(client);
+        }
+        // Opportunistic TLS encrypts without authenticating (RFC 7435): MX hosts commonly
+        // present self-signed or mismatched certificates, and refusing them would just
+        // fall back to plaintext anyway.
+        let tls = TlsParameters::builder(host.to_string())
+            .dangerous_accept_invalid_certs(true)
+            .build()
+            .map_err(|e| TransportError::Other(e.to_string()))?;
+        let client = SmtpClient::Direct(
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+                .port(25)
+                .tls(Tls::Opportunistic(tls))
+                .timeout(Some(timeout))
+                .build(),
+        );
+        clients.insert(host.to_string(), client.clone());
+        Ok(client)
+    }
+}
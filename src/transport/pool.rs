@@ -0,0 +1,139 @@
+//! Weighted round-robin across several SMTP relays.
+//!
+//! Each relay gets a share of traffic proportional to its weight. The pick is
+//! "smooth" (as in nginx), so a 3:1 split interleaves `A A B A` instead of bursting.
+//! A relay is skipped while it is over its per-minute limit, or for `COOLDOWN`
+//! after `UNHEALTHY_AFTER` consecutive connection failures. A message whose relay
+//! cannot be reached, or answers with a `4xx`, is retried on the next eligible relay.
+
+use std::{sync::Mutex, time::{Duration, Instant}};
+
+use lettre::address::Envelope;
+use tracing::{info, warn};
+
+use super::{Delivery, SmtpClient, SmtpProfile, TransportError};
+
+/// Consecutive connection failures before a relay is taken out of rotation.
+const UNHEALTHY_AFTER: u32 = 3;
+/// How long an unhealthy relay sits out before it is tried again.
+const COOLDOWN: Duration = Duration::from_secs(30);
+/// Length of the rate-limit window.
+const WINDOW: Duration = Duration::from_secs(60);
+
+struct Relay {
+    name: String,
+    client: SmtpClient,
+    weight: i64,
+    rate_per_min: u32,
+}
+
+/// Mutable bookkeeping for one relay, kept behind the pool's lock.
+struct RelayState {
+    /// Smooth weighted round-robin counter.
+    current: i64,
+    window_start: Instant,
+    sent_in_window: u32,
+    consecutive_failures: u32,
+    unhealthy_since: Option<Instant>,
+}
+
+/// A set of SMTP relays sharing traffic by weight.
+pub struct Pool {
+    relays: Vec<Relay>,
+    state: Mutex<Vec<RelayState>>,
+}
+
+impl Pool {
+    /// Build one SMTP client per profile. Profiles with weight 0 are ignored.
+    pub fn new(profiles: &[SmtpProfile]) -> Result<Self, anyhow::Error> {
+        let mut relays = Vec::new();
+        for p in profiles.iter().filter(|p| p.weight > 0) {
+            relays.push(Relay {
+                name: p.name.clone(),
+                client: p.build_client()?,
+                weight: p.weight.into(),
+                rate_per_min: p.rate_per_min,
+            });
+        }
+        if relays.is_empty() {
+            anyhow::bail!("SMTP pool needs at least one profile with a weight above 0");
+        }
+        let now = Instant::now();
+        let state = relays
+            .iter()
+            .map(|_| RelayState { current: 0, window_start: now, sent_in_window: 0, consecutive_failures: 0, unhealthy_since: None })
+            .collect();
+        Ok(Self { relays, state: Mutex::new(state) })
+    }
+
+    /// Send through the next eligible relay, moving on to another one if it cannot be reached.
+    pub(crate) async fn send_raw(&self, envelope: &Envelope, raw: &[u8]) -> Result<Delivery, TransportError> {
+        let mut tried = vec![false; self.relays.len()];
+        while let Some(i) = self.pick(&tried) {
+            tried[i] = true;
+            let relay = &self.relays[i];
+            match relay.client.send_raw(envelope, raw).await {
+                Ok(reply) => {
+                    self.record(i, true);
+                    info!(relay = %relay.name, code = reply.code, queue_id = reply.queue_id.as_deref(), "Message sent");
+                    return Ok(reply.via(&relay.name).into());
+                }
+                Err(e) if e.is_unreachable() => {
+                    self.record(i, false);
+                    warn!(relay = %relay.name, "SMTP relay unreachable, trying the next one: {e}");
+                }
+                Err(e) if e.is_transient() => warn!(relay = %relay.name, "SMTP relay deferred the message, trying the next one: {e}"),
+                Err(e) => return Err(e),
+            }
+        }
+        Err(TransportError::Unreachable("no SMTP relay available (all unreachable, deferring, cooling down or rate-limited)".into()))
+    }
+
+    /// Choose among relays that are not in `tried`, healthy and under their rate limit,
+    /// and count the pick against the chosen relay's window.
+    fn pick(&self, tried: &[bool]) -> Option<usize> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let mut eligible = Vec::new();
+        for (i, (relay, st)) in self.relays.iter().zip(state.iter_mut()).enumerate() {
+            if now.duration_since(st.window_start) >= WINDOW {
+                st.window_start = now;
+                st.sent_in_window = 0;
+            }
+            let cooling = st.unhealthy_since.is_some_and(|at| now.duration_since(at) < COOLDOWN);
+            let limited = relay.rate_per_min > 0 && st.sent_in_window >= relay.rate_per_min;
+            if !tried[i] && !cooling && !limited {
+                eligible.push(i);
+            }
+        }
+        let total: i64 = eligible.iter().map(|&i| self.relays[i].weight).sum();
+        for &i in &eligible {
+            state[i].current += self.relays[i].weight;
+        }
+        let chosen = eligible.into_iter().max_by_key(|&i| state[i].current)?;
+        state[chosen].current -= total;
+        state[chosen].sent_in_window += 1;
+        Some(chosen)
+    }
+
+    fn record(&self, i: usize, ok: bool) {
+        let mut state = self.state.lock().unwrap();
+        let st = &mut state[i];
+        if ok {
+            if st.unhealthy_since.take().is_some() {
+                info!(relay = %self.relays[i].name, "SMTP relay healthy again");
+            }
+            st.consecutive_failures = 0;
+        } else {
+            st.consecutive_failures += 1;
+            if st.consecutive_failures >= UNHEALTHY_AFTER {
+                if st.unhealthy_since.is_none() {
+                    warn!(relay = %self.relays[i].name, "SMTP relay marked unhealthy for {}s", COOLDOWN.as_secs());
+                    let reason = format!("{UNHEALTHY_AFTER} connection failures in a row, out of the pool for {}s", COOLDOWN.as_secs());
+                    crate::alerts::breaker_opened(&self.relays[i].name, &reason);
+                }
+                st.unhealthy_since = Some(Instant::now());
+            }
+        }
+    }
+}
@@ -0,0 +1,355 @@
+//! Per-provider buckets: recipients are grouped by the mailbox provider behind their
+//! domain (Google, Microsoft, Yahoo, Apple..), and each provider gets its own rate and
+//! concurrency limit, so Gmail throttling one send does not hold back Outlook or
+//! company recipients of the next.
+//!
+//! `PROVIDER_RATES` (`google=120/m,microsoft=60/m`, recipients per `s`, `m` or `h`) and
+//! `PROVIDER_CONCURRENCY` (`google=4`, transactions at once) set the buckets; `other`
+//! is the bucket of every recipient no provider claims. A provider without a setting is
+//! not limited.
+//!
+//! The provider comes from the recipient domain (`gmail.com`) or, for hosted domains,
+//! from its MX hosts (`aspmx.l.google.com`), looked up once an hour per domain.
+//! `PROVIDER_DOMAINS` (`*.mimecast.com=mimecast,corp.example=microsoft`) adds patterns,
+//! matched like `SMTP_ROUTES` against both, ahead of the built-in ones.
+//!
+//! A group waits for its bucket for at most `PROVIDER_MAX_WAIT_SECS`; past that its
+//! recipients are rejected with a `4xx`-like error and the caller retries them later.
+//! Groups of one send go out concurrently.
+
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use hickory_resolver::TokioResolver;
+use lettre::{address::Envelope, Address};
+use serde::Serialize;
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::warn;
+
+use super::{Delivery, Mailer, TransportError};
+
+/// Bucket of recipients no provider claims.
+pub const OTHER: &str = "other";
+/// How long a domain's provider is remembered.
+const CACHE_FOR: Duration = Duration::from_secs(3600);
+/// How long an MX lookup may take before the domain counts as `other`.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Domains and MX hosts of the large providers, matched after `PROVIDER_DOMAINS`.
+const BUILT_IN: &[(&str, &str)] = &[
+    ("gmail.com", "google"),
+    ("googlemail.com", "google"),
+    ("*.google.com", "google"),
+    ("*.googlemail.com", "google"),
+    ("outlook.com", "microsoft"),
+    ("hotmail.com", "microsoft"),
+    ("hotmail.co.uk", "microsoft"),
+    ("hotmail.fr", "microsoft"),
+    ("live.com", "microsoft"),
+    ("msn.com", "microsoft"),
+    ("*.outlook.com", "microsoft"),
+    ("yahoo.com", "yahoo"),
+    ("ymail.com", "yahoo"),
+    ("aol.com", "yahoo"),
+    ("*.yahoodns.net", "yahoo"),
+    ("icloud.com", "apple"),
+    ("me.com", "apple"),
+    ("mac.com", "apple"),
+    ("*.icloud.com", "apple"),
+];
+
+/// Whether `host` matches `pattern`: `gmail.com` (that domain only) or `*.google.com`
+/// (its subdomains).
+fn matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix('*') {
+        Some(suffix) => host.ends_with(suffix),
+        None => host == pattern,
+    }
+}
+
+/// What limits one bucket.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limit {
+    /// Time one recipient takes from the rate; `None` when the rate is not limited
+    pub interval: Option<Duration>,
+    /// Transactions at once; `None` when not limited
+    pub concurrency: Option<usize>,
+}
+
+/// The configured limits and patterns (`PROVIDER_*`).
+#[derive(Debug, Clone, Default)]
+pub struct ProviderLimits {
+    pub limits: HashMap<String, Limit>,
+    /// `PROVIDER_DOMAINS`: `(pattern, provider)`, in configuration order
+    patterns: Vec<(String, String)>,
+    pub max_wait: Duration,
+}
+
+impl ProviderLimits {
+    pub fn from_config(config: &crate::config::ApiConfig) -> Result<Self, anyhow::Error> {
+        Self::parse(&config.provider_rates, &config.provider_concurrency, &config.provider_domains, Duration::from_secs(config.provider_max_wait_secs))
+            .map_err(|e| anyhow::anyhow!("invalid {e}"))
+    }
+
+    /// # ProviderLimits::parse()
+    /// Limits from `rates` (`provider=N/s|m|h`), `concurrency` (`provider=N`) and extra
+    /// `domains` patterns (`pattern=provider`), all comma-separated.
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use templar::transport::providers::ProviderLimits;
+    /// let limits = ProviderLimits::parse("google=120/m,other=10/s", "google=4", "*.mimecast.com=mimecast", Duration::from_secs(30)).unwrap();
+    /// assert_eq!(limits.provider_of("gmail.com", &[]), Some("google"));
+    /// assert_eq!(limits.provider_of("corp.example", &["eu-smtp-1.mimecast.com".into()]), Some("mimecast"));
+    /// assert_eq!(limits.provider_of("corp.example", &["mx.corp.example".into()]), None);
+    /// assert_eq!(limits.limits["google"].interval, Some(Duration::from_millis(500)));
+    /// assert_eq!(limits.limits["google"].concurrency, Some(4));
+    /// assert!(ProviderLimits::parse("google=fast", "", "", Duration::from_secs(30)).is_err());
+    /// ```
+    /// # Errors
+    /// An entry that is not `name=value`, a rate that is not `N/s`, `N/m` or `N/h` with
+    /// `N` above 0, or a concurrency of 0.
+    pub fn parse(rates: &str, concurrency: &str, domains: &str, max_wait: Duration) -> Result<Self, String> {
+        let pairs = |spec: &str, setting: &str| -> Result<Vec<(String, String)>, String> {
+            spec.split(',')
+                .map(str::trim)
+                .filter(|e| !e.is_empty())
+                .map(|e| {
+                    e.split_once('=')
+                        .map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_string()))
+                        .filter(|(k, v)| !k.is_empty() && !v.is_empty())
+                        .ok_or_else(|| format!("{setting} entry `{e}`, expected name=value"))
+                })
+                .collect()
+        };
+        let mut limits: HashMap<String, Limit> = HashMap::new();
+        for (provider, rate) in pairs(rates, "PROVIDER_RATES")? {
+            let invalid = || format!("PROVIDER_RATES rate `{rate}` for {provider}, expected N/s, N/m or N/h");
+            let (n, unit) = rate.split_once('/').ok_or_else(invalid)?;
+            let n: u32 = n.trim().parse().ok().filter(|n| *n > 0).ok_or_else(invalid)?;
+            let per = match unit.trim() {
+                "s" => Duration::from_secs(1),
+                "m" => Duration::from_secs(60),
+                "h" => Duration::from_secs(3600),
+                _ => return Err(invalid()),
+            };
+            limits.entry(provider).or_default().interval = Some(per / n);
+        }
+        for (provider, n) in pairs(concurrency, "PROVIDER_CONCURRENCY")? {
+            let n: usize = n.parse().ok().filter(|n| *n > 0).ok_or_else(|| format!("PROVIDER_CONCURRENCY `{n}` for {provider}, expected 1 or more"))?;
+            limits.entry(provider).or_default().concurrency = Some(n);
+        }
+        let patterns = pairs(domains, "PROVIDER_DOMAINS")?.into_iter().map(|(pattern, provider)| (pattern, provider.to_lowercase())).collect();
+        Ok(Self { limits, patterns, max_wait })
+    }
+
+    /// Whether any bucket is limited.
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty()
+    }
+
+    /// # ProviderLimits::provider_of()
+    /// The provider of recipient `domain`, from the domain itself or one of its `mx` hosts;
+    /// `None` when no pattern claims it.
+    pub fn provider_of(&self, domain: &str, mx: &[String]) -> Option<&str> {
+        let provider = |host: &str| -> Option<&str> {
+            let custom = self.patterns.iter().map(|(p, name)| (p.as_str(), name.as_str()));
+            custom.chain(BUILT_IN.iter().copied()).find(|(p, _)| matches(p, host)).map(|(_, name)| name)
+        };
+        provider(domain).or_else(|| mx.iter().find_map(|host| provider(host)))
+    }
+}
+
+/// Counts of one bucket, for `GET /admin/providers`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BucketStats {
+    pub provider: String,
+    /// Recipients per second, `null` when unlimited
+    pub rate_per_sec: Option<f64>,
+    pub concurrency: Option<usize>,
+    /// Transactions running now
+    pub in_flight: u64,
+    /// Groups waiting for a slot now
+    pub waiting: u64,
+    /// Recipients handed on since start
+    pub sent: u64,
+    /// Recipients rejected after waiting `PROVIDER_MAX_WAIT_SECS`
+    pub refused: u64,
+}
+
+struct Bucket {
+    limit: Limit,
+    permits: Option<Arc<Semaphore>>,
+    /// When the rate allows the next recipient
+    next: Mutex<Instant>,
+    stats: Mutex<BucketStats>,
+}
+
+impl Bucket {
+    /// Wait for room for `recipients`, at most until `deadline`.
+    async fn acquire(&self, recipients: usize, deadline: Instant) -> Result<Option<tokio::sync::OwnedSemaphorePermit>, ()> {
+        self.stats.lock().unwrap().waiting += 1;
+        let acquired = self.wait(recipients, deadline).await;
+        let mut stats = self.stats.lock().unwrap();
+        stats.waiting -= 1;
+        match &acquired {
+            Ok(_) => {
+                stats.in_flight += 1;
+                stats.sent += recipients as u64;
+            }
+            Err(()) => stats.refused += recipients as u64,
+        }
+        acquired
+    }
+
+    async fn wait(&self, recipients: usize, deadline: Instant) -> Result<Option<tokio::sync::OwnedSemaphorePermit>, ()> {
+        if let Some(interval) = self.limit.interval {
+            let slot = {
+                let mut next = self.next.lock().unwrap();
+                let slot = (*next).max(Instant::now());
+                if slot > deadline {
+                    return Err(());
+                }
+                *next = slot + interval * recipients as u32;
+                slot
+            };
+            tokio::time::sleep_until(slot.into()).await;
+        }
+        match &self.permits {
+            Some(permits) => match tokio::time::timeout_at(deadline.into(), permits.clone().acquire_owned()).await {
+                Ok(Ok(permit)) => Ok(Some(permit)),
+                _ => Err(()),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn release(&self) {
+        self.stats.lock().unwrap().in_flight -= 1;
+    }
+}
+
+/// The mailer behind per-provider buckets.
+pub struct Throttled {
+    inner: Mailer,
+    limits: ProviderLimits,
+    buckets: HashMap<String, Bucket>,
+    resolver: TokioResolver,
+    /// domain → (provider, valid until)
+    providers: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl Throttled {
+    pub fn new(limits: ProviderLimits, inner: Mailer) -> Result<Self, anyhow::Error> {
+        let buckets = limits
+            .limits
+            .iter()
+            .map(|(name, limit)| {
+                let stats = BucketStats {
+                    provider: name.clone(),
+                    rate_per_sec: limit.interval.map(|i| 1.0 / i.as_secs_f64()),
+                    concurrency: limit.concurrency,
+                    ..BucketStats::default()
+                };
+                let bucket = Bucket {
+                    limit: *limit,
+                    permits: limit.concurrency.map(|n| Arc::new(Semaphore::new(n))),
+                    next: Mutex::new(Instant::now()),
+                    stats: Mutex::new(stats),
+                };
+                (name.clone(), bucket)
+            })
+            .collect();
+        Ok(Self { inner, limits, buckets, resolver: TokioResolver::builder_tokio()?.build(), providers: Mutex::new(HashMap::new()) })
+    }
+
+    /// The mailer the buckets hand groups to.
+    pub fn inner(&self) -> &Mailer {
+        &self.inner
+    }
+
+    /// Counts of every bucket, by provider name.
+    pub fn stats(&self) -> Vec<BucketStats> {
+        let mut stats: Vec<BucketStats> = self.buckets.values().map(|b| b.stats.lock().unwrap().clone()).collect();
+        stats.sort_by(|a, b| a.provider.cmp(&b.provider));
+        stats
+    }
+
+    /// The bucket name of recipient `domain`: its provider, or [`OTHER`].
+    async fn provider(&self, domain: &str) -> String {
+        if let Some((provider, until)) = self.providers.lock().unwrap().get(domain)
+            && Instant::now() < *until
+        {
+            return provider.clone();
+        }
+        let provider = match self.limits.provider_of(domain, &[]) {
+            Some(p) => p.to_string(),
+            None => {
+                let mx: Vec<String> = match tokio::time::timeout(LOOKUP_TIMEOUT, self.resolver.mx_lookup(domain)).await {
+                    Ok(Ok(lookup)) => lookup.iter().map(|mx| mx.exchange().to_utf8().trim_end_matches('.').to_lowercase()).collect(),
+                    _ => Vec::new(),
+                };
+                self.limits.provider_of(domain, &mx).unwrap_or(OTHER).to_string()
+            }
+        };
+        self.providers.lock().unwrap().insert(domain.to_string(), (provider.clone(), Instant::now() + CACHE_FOR));
+        provider
+    }
+
+    /// Split the envelope by provider and send each group once its bucket has room, the
+    /// groups concurrently. A group's failure rejects its recipients; the send fails only
+    /// when every group did.
+    pub(crate) async fn send_raw(self: &Arc<Self>, envelope: &Envelope, raw: &[u8]) -> Result<Delivery, TransportError> {
+        let mut groups: Vec<(String, Vec<Address>)> = Vec::new();
+        for to in envelope.to() {
+            let provider = self.provider(&to.domain().to_lowercase()).await;
+            match groups.iter_mut().find(|(p, _)| *p == provider) {
+                Some((_, list)) => list.push(to.clone()),
+                None => groups.push((provider, vec![to.clone()])),
+            }
+        }
+        let deadline = Instant::now() + self.limits.max_wait;
+        let raw: Arc<[u8]> = raw.into();
+        let mut running = JoinSet::new();
+        for (provider, to) in groups {
+            let part = Envelope::new(envelope.from().cloned(), to).map_err(|e| TransportError::Other(e.to_string()))?;
+            let (this, raw) = (Arc::clone(self), Arc::clone(&raw));
+            running.spawn(async move {
+                let result = this.send_group(&provider, &part, &raw, deadline).await;
+                (provider, part, result)
+            });
+        }
+        let (mut delivery, mut sent, mut first_error) = (Delivery::default(), false, None);
+        while let Some(joined) = running.join_next().await {
+            let (provider, part, result) = joined.map_err(|e| TransportError::Other(format!("provider group task failed: {e}")))?;
+            match result {
+                Ok(d) => {
+                    sent = true;
+                    delivery.merge(d);
+                }
+                Err(e) => {
+                    warn!(provider, recipients = part.to().len(), "Provider group not sent: {e}");
+                    delivery.reject(part.to(), &e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match first_error {
+            Some(e) if !sent => Err(e),
+            _ => Ok(delivery),
+        }
+    }
+
+    async fn send_group(&self, provider: &str, envelope: &Envelope, raw: &[u8], deadline: Instant) -> Result<Delivery, TransportError> {
+        let Some(bucket) = self.buckets.get(provider) else { return self.inner.send_boxed(envelope, raw).await };
+        let Ok(permit) = bucket.acquire(envelope.to().len(), deadline).await else {
+            return Err(TransportError::Transient(format!(
+                "no room in the {provider} bucket within PROVIDER_MAX_WAIT_SECS ({}s), try again later",
+                self.limits.max_wait.as_secs()
+            )));
+        };
+        let result = self.inner.send_boxed(envelope, raw).await;
+        drop(permit);
+        bucket.release();
+        result
+    }
+}
@@ -0,0 +1,128 @@
+//! Per-domain routing: recipients on selected domains go through dedicated relays.
+//!
+//! Rules come from `SMTP_ROUTES`, e.g. `*.gov.br=relay-b,gmail.com=ses`, where each
+//! target is an SMTP profile name (`SMTP_PROFILE_<NAME>_*`). `gmail.com` matches that
+//! domain only, `*.gov.br` matches any subdomain of `gov.br`. The first matching rule
+//! wins; unmatched recipients use the default mailer.
+//!
+//! Only the SMTP envelope is split per route. Every group receives the same message
+//! bytes, so the headers still list all recipients.
+
+use std::collections::BTreeMap;
+
+use lettre::{address::Envelope, Address};
+use tracing::{info, warn};
+
+use super::{Delivery, Mailer, SmtpClient, SmtpProfile, TransportError};
+
+enum DomainPattern {
+    /// `gmail.com`
+    Exact(String),
+    /// `*.gov.br`, stored as `.gov.br`
+    Subdomain(String),
+}
+
+impl DomainPattern {
+    fn matches(&self, domain: &str) -> bool {
+        match self {
+            DomainPattern::Exact(d) => domain == d,
+            DomainPattern::Subdomain(suffix) => domain.ends_with(suffix.as_str()),
+        }
+    }
+}
+
+struct Target {
+    name: String,
+    client: SmtpClient,
+}
+
+/// Routing table plus the mailer used for everything it does not match.
+pub struct Routes {
+    /// `(pattern, index into targets)`, in configuration order.
+    rules: Vec<(DomainPattern, usize)>,
+    targets: Vec<Target>,
+    default: Mailer,
+}
+
+impl Routes {
+    /// Parse `pattern=profile` pairs separated by commas, building one SMTP
+    /// client per distinct profile out of `profiles` (see `ApiConfig::profiles`).
+    pub fn parse(spec: &str, profiles: &BTreeMap<String, SmtpProfile>, default: Mailer) -> Result<Self, anyhow::Error> {
+        let mut rules = Vec::new();
+        let mut targets: Vec<Target> = Vec::new();
+        for rule in spec.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            let (pattern, profile) = rule
+                .split_once('=')
+                .map(|(p, t)| (p.trim().to_lowercase(), t.trim()))
+                .filter(|(p, t)| !p.is_empty() && !t.is_empty())
+                .ok_or_else(|| anyhow::anyhow!("invalid SMTP_ROUTES rule `{rule}`, expected domain=profile"))?;
+            let pattern = match pattern.strip_prefix("*.") {
+                Some(rest) => DomainPattern::Subdomain(format!(".{rest}")),
+                None => DomainPattern::Exact(pattern),
+            };
+            let target = match targets.iter().position(|t| t.name == profile) {
+                Some(i) => i,
+                None => {
+                    let client = profiles.get(profile).ok_or_else(|| anyhow::anyhow!("SMTP_ROUTES target `{profile}` is not a configured profile"))?.build_client()?;
+                    targets.push(Target { name: profile.to_string(), client });
+                    targets.len() - 1
+                }
+            };
+            rules.push((pattern, target));
+        }
+        Ok(Self { rules, targets, default })
+    }
+
+    /// Profile name a recipient domain is routed to, `None` for the default mailer.
+    pub fn target_for(&self, domain: &str) -> Option<&str> {
+        self.route(domain).map(|i| self.targets[i].name.as_str())
+    }
+
+    fn route(&self, domain: &str) -> Option<usize> {
+        let domain = domain.to_lowercase();
+        self.rules.iter().find(|(p, _)| p.matches(&domain)).map(|(_, t)| *t)
+    }
+
+    /// Split the envelope by route and deliver each group. A failing group's recipients
+    /// are listed as rejected; the send fails only when every group did.
+    pub(crate) async fn send_raw(&self, envelope: &Envelope, raw: &[u8]) -> Result<Delivery, TransportError> {
+        let mut groups: Vec<(Option<usize>, Vec<Address>)> = Vec::new();
+        for to in envelope.to() {
+            let route = self.route(to.domain());
+            match groups.iter_mut().find(|(r, _)| *r == route) {
+                Some((_, list)) => list.push(to.clone()),
+                None => groups.push((route, vec![to.clone()])),
+            }
+        }
+        let (mut delivery, mut sent, mut first_error) = (Delivery::default(), false, None);
+        for (route, to) in groups {
+            let part = Envelope::new(envelope.from().cloned(), to).map_err(|e| TransportError::Other(e.to_string()))?;
+            let result = match route {
+                Some(i) => {
+                    let target = &self.targets[i];
+                    target.client.send_raw(&part, raw).await.map(|reply| {
+                        info!(route = %target.name, recipients = part.to().len(), code = reply.code, queue_id = reply.queue_id.as_deref(), "Routed recipients sent");
+                        Delivery::from(reply.via(&target.name))
+                    })
+                }
+                // Boxed: the default is itself a `Mailer`, so this recursion needs indirection.
+                None => Box::pin(self.default.send_raw(&part, raw)).await,
+            };
+            match result {
+                Ok(d) => {
+                    sent = true;
+                    delivery.merge(d);
+                }
+                Err(e) => {
+                    warn!(recipients = part.to().len(), "Routed recipients not sent: {e}");
+                    delivery.reject(part.to(), &e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match first_error {
+            Some(e) if !sent => Err(e),
+            _ => Ok(delivery),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! A/B template variants.
+//!
+//! A template with variant files next to it (`welcome.a.hbs`, `welcome.b.hbs`) is sent as
+//! one of them: `/send` with `template: "welcome"` picks a variant per recipient and the
+//! message carries an `X-Template-Variant: a` header. Variants win over a plain
+//! `welcome.hbs`, and a variant can still be sent directly as `welcome.a`.
+//!
+//! Weights and the picking mode come from an optional `<template>.variants` file:
+//!
+//! ```text
+//! # variant weight; variants not listed here are never picked
+//! a 70
+//! b 30
+//! # `pick recipient` (default): same recipient, same variant. `pick random`: per send
+//! pick random
+//! ```
+//!
+//! Without the file every variant has weight 1 and is picked by recipient.
+
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+/// How a variant is chosen for a recipient.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pick {
+    /// Hash of template and recipient address, so repeat sends agree.
+    Recipient,
+    /// Independent weighted draw on every send.
+    Random,
+}
+
+/// The weighted variants of one template.
+#[derive(Debug, Clone)]
+pub struct Variants {
+    pub pick: Pick,
+    /// `(variant, weight)`, sorted by variant name; weights are positive.
+    pub weights: Vec<(String, u64)>,
+}
+
+impl Variants {
+    /// # Variants::choose()
+    /// Variant for `recipient` of `template`.
+    /// # Example
+    /// ```
+    /// use templar::variants::{Pick, Variants};
+    /// let v = Variants { pick: Pick::Recipient, weights: vec![("a".into(), 1), ("b".into(), 1)] };
+    /// let first = v.choose("welcome", "ana@example.com");
+    /// assert!(first == "a" || first == "b");
+    /// assert_eq!(v.choose("welcome", "ana@example.com"), first);
+    /// ```
+    pub fn choose(&self, template: &str, recipient: &str) -> &str {
+        let total: u64 = self.weights.iter().map(|(_, w)| w).sum();
+        let mut point = match self.pick {
+            Pick::Recipient => {
+                let digest = Sha256::digest(format!("{template}\n{}", recipient.to_lowercase()));
+                u64::from_be_bytes(digest[..8].try_into().unwrap()) % total
+            }
+            Pick::Random => rand::random_range(0..total),
+        };
+        for (name, weight) in &self.weights {
+            if point < *weight {
+                return name;
+            }
+            point -= weight;
+        }
+        &self.weights[self.weights.len() - 1].0
+    }
+}
+
+/// # load()
+/// Variants of `template` found in `dir`, `None` when it has no variant files.
+/// # Errors
+/// The `.variants` file is unreadable or malformed, names a variant without a file,
+/// or leaves no variant with a positive weight.
+pub fn load(dir: &Path, template: &str) -> Result<Option<Variants>, String> {
+    let prefix = format!("{template}.");
+    let mut found: Vec<String> = std::fs::read_dir(dir)
+        .map_err(|e| format!("{}: {e}", dir.display()))?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().to_str()?.strip_prefix(&prefix)?.strip_suffix(".hbs")?.to_string();
+            (!name.is_empty() && !name.contains('.')).then_some(name)
+        })
+        .collect();
+    if found.is_empty() {
+        return Ok(None);
+    }
+    found.sort();
+
+    let path = dir.join(format!("{template}.variants"));
+    let src = match std::fs::read_to_string(&path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Some(Variants { pick: Pick::Recipient, weights: found.into_iter().map(|v| (v, 1)).collect() }));
+        }
+        Err(e) => return Err(format!("{}: {e}", path.display())),
+    };
+
+    let mut variants = Variants { pick: Pick::Recipient, weights: Vec::new() };
+    for (n, line) in src.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fail = |msg: String| format!("{} line {n}: {msg}", path.display());
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["pick", "recipient"] => variants.pick = Pick::Recipient,
+            ["pick", "random"] => variants.pick = Pick::Random,
+            ["pick", other] => return Err(fail(format!("unknown pick mode `{other}`, expected `recipient` or `random`"))),
+            [name, weight] => {
+                let weight: u64 = weight.parse().map_err(|_| fail(format!("weight `{weight}` is not a whole number")))?;
+                if !found.iter().any(|v| v == name) {
+                    return Err(fail(format!("no template file {template}.{name}.hbs")));
+                }
+                if weight > 0 {
+                    variants.weights.push((name.to_string(), weight));
+                }
+            }
+            _ => return Err(fail("expected `<variant> <weight>` or `pick <mode>`".into())),
+        }
+    }
+    if variants.weights.is_empty() {
+        return Err(format!("{}: no variant has a positive weight", path.display()));
+    }
+    variants.weights.sort();
+    Ok(Some(variants))
+}
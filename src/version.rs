@@ -0,0 +1,44 @@
+//! What this binary is: crate version, git commit, build time and compiled-in
+//! features, as served by `GET /version` and logged at startup.
+
+use serde::Serialize;
+
+/// Build-time facts about the running binary.
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// Short commit hash, `unknown` outside a checkout (set `TEMPLAR_GIT_COMMIT` when building from a tarball).
+    pub git_commit: &'static str,
+    /// UTC RFC 3339 build time.
+    pub built_at: &'static str,
+    /// Optional cargo features compiled in.
+    pub features: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    /// # BuildInfo::current()
+    /// Info for this build.
+    /// # Example
+    /// ```
+    /// let info = templar::version::BuildInfo::current();
+    /// assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    /// assert!(info.built_at.ends_with('Z'));
+    /// ```
+    pub fn current() -> Self {
+        let features = [
+            ("client", cfg!(feature = "client")),
+            ("faults", cfg!(feature = "faults")),
+            ("pdf", cfg!(feature = "pdf")),
+            ("postgres", cfg!(feature = "postgres")),
+            ("rhai-helpers", cfg!(feature = "rhai-helpers")),
+            ("wasm-helpers", cfg!(feature = "wasm-helpers")),
+            ("xlsx", cfg!(feature = "xlsx")),
+        ];
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: env!("TEMPLAR_GIT_COMMIT"),
+            built_at: env!("TEMPLAR_BUILT_AT"),
+            features: features.into_iter().filter(|(_, on)| *on).map(|(name, _)| name).collect(),
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! Warm-up of new sending domains: a domain moving to dedicated IPs is allowed a daily
+//! number of recipients that grows along `WARMUP_CURVE`, so receivers build its
+//! reputation before they see full volume.
+//!
+//! `WARMUP_DOMAINS` lists `domain@YYYY-MM-DD`, the day each ramp starts: day 1 allows the
+//! first entry of the curve, day 2 the second, and so on; past the last entry the domain
+//! is warm and unlimited. Days are UTC and counted in storage, so replicas sharing
+//! `STORAGE_URL` share the allowance. Only the domain of `MAIL_FROM` is counted against.
+//!
+//! A `/send` (or scheduled or outbox send) that does not fit in what is left of the day
+//! goes out to as many recipients as fit; the others are stored as a scheduled send for
+//! the next day (`"deferred"` in the answer), and deferred again while that day is full.
+
+use serde::Serialize;
+
+use crate::config::ApiConfig;
+use crate::routes::SendRequest;
+use crate::storage::{ScheduledSend, Storage, StorageError};
+
+const DAY: i64 = 86_400;
+/// Days of counts `GET /admin/warmup` shows per domain.
+const HISTORY_DAYS: i64 = 14;
+
+/// Where one domain's ramp starts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Schedule {
+    domain: String,
+    /// Unix time of the first day's start
+    start: i64,
+}
+
+/// Today's allowance of the sending domain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    /// Unix time of the day's start
+    pub day: i64,
+    /// `1` on the first day of the ramp
+    pub ramp_day: i64,
+    pub limit: i64,
+}
+
+/// Recipients held over to a later day.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, serde::Deserialize)]
+pub struct Deferral {
+    /// ID of the scheduled send carrying them
+    pub id: String,
+    pub send_at: i64,
+    pub recipients: Vec<String>,
+}
+
+/// What [`Warmup::admit`] let through for a send.
+#[derive(Debug, Clone, Default)]
+pub struct Admitted {
+    /// The day counted against; `None` when the domain is not warming up
+    pub day: Option<i64>,
+    /// Recipients counted towards the day
+    pub reserved: i64,
+    pub deferred: Option<Deferral>,
+}
+
+/// The configured ramps (`WARMUP_*`).
+#[derive(Debug, Clone, Default)]
+pub struct Warmup {
+    /// `MAIL_FROM`'s domain, the one sends count against
+    domain: String,
+    schedules: Vec<Schedule>,
+    curve: Vec<i64>,
+}
+
+impl Warmup {
+    pub fn from_config(config: &ApiConfig) -> Result<Self, anyhow::Error> {
+        let domain = config.mail_from.as_ref().map(|mb| mb.email.domain().to_lowercase()).unwrap_or_default();
+        Self::parse(&domain, &config.warmup_domains, &config.warmup_curve).map_err(|e| anyhow::anyhow!("invalid WARMUP_* setting: {e}"))
+    }
+
+    /// # Warmup::parse()
+    /// The ramps of `domains` (`domain@YYYY-MM-DD`, comma-separated) along `curve`
+    /// (daily limits, comma-separated), for sends from `domain`.
+    /// # Example
+    /// ```
+    /// use templar::warmup::Warmup;
+    /// let warmup = Warmup::parse("news.example.com", "news.example.com@2026-10-01", "50,100,250").unwrap();
+    /// let oct_2 = 1_790_899_200; // 2026-10-02T00:00:00Z
+    /// let quota = warmup.quota(oct_2 + 3600).unwrap();
+    /// assert_eq!((quota.ramp_day, quota.limit, quota.day), (2, 100, oct_2));
+    /// assert!(warmup.quota(oct_2 + 5 * 86_400).is_none()); // warm
+    /// assert!(Warmup::parse("a.com", "a.com@2026-02-30", "50").is_err());
+    /// assert!(Warmup::parse("a.com", "a.com@2026-10-01", "50,0").is_err());
+    /// ```
+    /// # Errors
+    /// An entry without a valid date, or a curve entry that is not a positive number.
+    pub fn parse(domain: &str, domains: &str, curve: &str) -> Result<Self, String> {
+        let mut schedules = Vec::new();
+        for entry in domains.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, date) = entry.split_once('@').ok_or_else(|| format!("`{entry}`: expected domain@YYYY-MM-DD"))?;
+            let start = parse_date(date.trim()).ok_or_else(|| format!("`{entry}`: `{}` is not a YYYY-MM-DD date", date.trim()))?;
+            schedules.push(Schedule { domain: name.trim().trim_end_matches('.').to_lowercase(), start });
+        }
+        let curve = curve
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(|c| c.parse::<i64>().ok().filter(|n| *n > 0).ok_or_else(|| format!("WARMUP_CURVE entry `{c}` is not a positive number")))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !schedules.is_empty() && curve.is_empty() {
+            return Err("WARMUP_CURVE is empty".into());
+        }
+        Ok(Self { domain: domain.to_lowercase(), schedules, curve })
+    }
+
+    /// `domain`'s allowance on the day of `now`; `None` when it has no ramp or is warm.
+    fn quota_of(&self, domain: &str, now: i64) -> Option<Quota> {
+        let schedule = self.schedules.iter().find(|s| s.domain == domain)?;
+        let day = now.div_euclid(DAY) * DAY;
+        // A ramp that has not started yet holds the domain at its first day
+        let ramp_day = ((day - schedule.start) / DAY).max(0) + 1;
+        let limit = *self.curve.get(ramp_day as usize - 1)?;
+        Some(Quota { day, ramp_day, limit })
+    }
+
+    /// # Warmup::quota()
+    /// The sending domain's allowance on the day of `now`; `None` when it is not warming up.
+    pub fn quota(&self, now: i64) -> Option<Quota> {
+        self.quota_of(&self.domain, now)
+    }
+
+    /// # Warmup::admit()
+    /// Counts the recipients of `payload` that fit in today's allowance and leaves only
+    /// them in `to`; the rest are scheduled for the next day, on behalf of `caller`.
+    /// # Errors
+    /// Storage cannot be read or written; nothing is sent.
+    pub async fn admit(&self, store: &dyn Storage, payload: &mut SendRequest, caller: Option<&str>, now: i64) -> Result<Admitted, StorageError> {
+        let Some(quota) = self.quota(now) else { return Ok(Admitted::default()) };
+        // Unparsable recipients are left for `render_and_send` to report
+        let Ok(recipients) = crate::email::parse_recipients(&payload.to) else { return Ok(Admitted::default()) };
+        let count = recipients.len() as i64;
+        let sent = store.warmup_days(quota.day).await?.into_iter().find(|d| d.domain == self.domain && d.day == quota.day).map_or(0, |d| d.sent);
+        let mut room = (quota.limit - sent).clamp(0, count);
+        // Another send took the room meanwhile: all of this one waits
+        if room > 0 && !store.reserve_warmup(&self.domain, quota.day, room, quota.limit).await? {
+            room = 0;
+        }
+        let mut admitted = Admitted { day: Some(quota.day), reserved: room, deferred: None };
+        if room < count {
+            let (now_to, later) = recipients.split_at(room as usize);
+            let send_at = quota.day + DAY;
+            let later_to: Vec<String> = later.iter().map(|mb| mb.to_string()).collect();
+            let request = SendRequest { to: later_to.join(", "), send_at: Some(send_at), ..payload.clone() };
+            let send = ScheduledSend { id: crate::email::nanoid(), send_at, request, caller: caller.map(str::to_string), created_at: now };
+            if let Err(e) = store.schedule(&send).await {
+                self.release(store, &admitted).await;
+                return Err(e);
+            }
+            if let Err(e) = store.count_warmup(&self.domain, quota.day, 0, count - room).await {
+                tracing::warn!("Deferred recipients not counted: {e}");
+            }
+            tracing::info!(id = send.id, domain = self.domain, limit = quota.limit, deferred = count - room, "Warm-up limit reached, recipients deferred to the next day");
+            payload.to = now_to.iter().map(|mb| mb.to_string()).collect::<Vec<_>>().join(", ");
+            admitted.deferred = Some(Deferral { id: send.id, send_at, recipients: later.iter().map(|mb| mb.email.to_string()).collect() });
+        }
+        Ok(admitted)
+    }
+
+    /// Gives back what [`Warmup::admit`] counted for a send that failed.
+    pub async fn release(&self, store: &dyn Storage, admitted: &Admitted) {
+        let Some(day) = admitted.day.filter(|_| admitted.reserved > 0) else { return };
+        if let Err(e) = store.count_warmup(&self.domain, day, -admitted.reserved, 0).await {
+            tracing::warn!("Warm-up count not released for a failed send: {e}");
+        }
+    }
+
+    /// # Warmup::stats()
+    /// Each ramp with today's allowance and the counts of the last days, for `GET /admin/warmup`.
+    /// # Errors
+    /// Storage cannot be read.
+    pub async fn stats(&self, store: &dyn Storage, now: i64) -> Result<serde_json::Value, StorageError> {
+        let today = now.div_euclid(DAY) * DAY;
+        let days = store.warmup_days(today - (HISTORY_DAYS - 1) * DAY).await?;
+        let domains: Vec<serde_json::Value> = self
+            .schedules
+            .iter()
+            .map(|s| {
+                let quota = self.quota_of(&s.domain, now);
+                let counted: Vec<_> = days.iter().filter(|d| d.domain == s.domain).collect();
+                let sent = counted.iter().find(|d| d.day == today).map_or(0, |d| d.sent);
+                serde_json::json!({
+                    "domain": s.domain,
+                    "started": format_date(s.start),
+                    "sending": s.domain == self.domain,
+                    "warm": quota.is_none(),
+                    "day": quota.map(|q| q.ramp_day),
+                    "limit": quota.map(|q| q.limit),
+                    "sent": sent,
+                    "remaining": quota.map(|q| (q.limit - sent).max(0)),
+                    "history": counted.iter().map(|d| serde_json::json!({ "day": format_date(d.day), "sent": d.sent, "deferred": d.deferred })).collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        Ok(serde_json::json!({ "curve": self.curve, "domains": domains }))
+    }
+}
+
+/// Unix time of the start of `YYYY-MM-DD` (UTC).
+pub(crate) fn parse_date(date: &str) -> Option<i64> {
+    let mut parts = date.splitn(3, '-');
+    let (y, m, d): (i64, i64, i64) = (parts.next()?.parse().ok()?, parts.next()?.parse().ok()?, parts.next()?.parse().ok()?);
+    let leap = (y % 4 == 0 && y % 100 != 0) || y % 400 == 0;
+    let month_days = [31, if leap { 29 } else { 28 }, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    if !(1..=12).contains(&m) || d < 1 || d > month_days[m as usize - 1] {
+        return None;
+    }
+    // Days from civil date (Howard Hinnant's algorithm)
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some((era * 146_097 + doe - 719_468) * DAY)
+}
+
+/// `YYYY-MM-DD` of the UTC day `time` falls in.
+pub(crate) fn format_date(time: i64) -> String {
+    let z = time.div_euclid(DAY) + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    format!("{y:04}-{m:02}-{d:02}")
+}
@@ -0,0 +1,113 @@
+//! Outbound webhooks: JSON events POSTed to `WEBHOOK_URL`, signed with the active
+//! key of `WEBHOOK_SECRET` (see `crypto::Keyring`) in the `X-Templar-Signature` header.
+//! Previous keys sign too, each in one more such header, until their end date, so
+//! receivers can switch to a new key after Templar does.
+//!
+//! Receivers verify the raw request body against one of those headers, for example with
+//! `templar::crypto::Keyring::verify`, and can read it as a `templar::events::Event`. `POST /admin/webhooks/test` sends a sample
+//! event so a receiver can be checked before real events flow.
+
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::ApiConfig;
+use crate::crypto::Keyring;
+use crate::events::{Event, EventKind, Test};
+
+/// Header carrying `kid=<key id>,sig=<hex HMAC-SHA256 of the body>`.
+pub const SIGNATURE_HEADER: &str = "X-Templar-Signature";
+
+/// How long a receiver may take to answer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The configured receiver.
+#[derive(Clone)]
+pub struct Webhook {
+    url: String,
+    keyring: Keyring,
+    http: reqwest::Client,
+}
+
+/// What happened to one delivery attempt.
+#[derive(Debug, Clone, Serialize)]
+pub struct Attempt {
+    pub url: String,
+    /// The exact body sent, as JSON.
+    pub event: Value,
+    /// The `X-Templar-Signature` value sent with it.
+    pub signature: String,
+    /// More `X-Templar-Signature` headers, made with previous keys still in their grace period.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub previous_signatures: Vec<String>,
+    /// Receiver's HTTP status; absent when no response arrived.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    pub latency_ms: u64,
+    /// A `2xx` answer.
+    pub delivered: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Webhook {
+    /// The receiver from `WEBHOOK_URL` and `WEBHOOK_SECRET`; `None` when no URL is set.
+    pub fn from_config(config: &ApiConfig) -> Result<Option<Self>, anyhow::Error> {
+        let url = config.webhook_url.trim();
+        if url.is_empty() {
+            return Ok(None);
+        }
+        let keyring = Keyring::parse(&config.webhook_secret).map_err(|e| anyhow::anyhow!("invalid WEBHOOK_SECRET: {e}"))?;
+        Ok(Some(Self::new(url, keyring)?))
+    }
+
+    /// A receiver at `url`, signed with `keyring`; the fallback channel's is one too (see `channels`).
+    pub fn new(url: &str, keyring: Keyring) -> Result<Self, anyhow::Error> {
+        let http = crate::transport::proxy::http_client(TIMEOUT)?.build()?;
+        Ok(Self { url: url.to_string(), keyring, http })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// POST `event` once, signed; never retried.
+    pub async fn deliver(&self, event: Value) -> Attempt {
+        let body = event.to_string();
+        let mut signatures = self.keyring.sign_all(body.as_bytes());
+        let signature = signatures.remove(0);
+        let mut request = self.http.post(&self.url).header(reqwest::header::CONTENT_TYPE, "application/json").header(SIGNATURE_HEADER, &signature);
+        for previous in &signatures {
+            request = request.header(SIGNATURE_HEADER, previous);
+        }
+        let start = Instant::now();
+        let result = request.body(body).send().await;
+        let latency_ms = start.elapsed().as_millis() as u64;
+        let (status, error) = match result {
+            Ok(r) if r.status().is_success() => (Some(r.status().as_u16()), None),
+            Ok(r) => (Some(r.status().as_u16()), Some(format!("receiver answered {}", r.status()))),
+            Err(e) => (None, Some(describe(&e))),
+        };
+        Attempt { url: self.url.clone(), event, signature, previous_signatures: signatures, status, latency_ms, delivered: error.is_none(), error }
+    }
+
+    /// Send a sample `test` event.
+    pub async fn test_fire(&self) -> Attempt {
+        let event = Event::new(EventKind::Test(Test {
+            message: "Test event from Templar; verify the X-Templar-Signature header against the raw body".into(),
+        }));
+        self.deliver(serde_json::to_value(event).expect("events serialize")).await
+    }
+}
+
+/// reqwest's display hides the cause (`error sending request`); include it.
+fn describe(e: &reqwest::Error) -> String {
+    let mut msg = if e.is_timeout() { format!("no answer within {}s", TIMEOUT.as_secs()) } else { e.to_string() };
+    let mut source = std::error::Error::source(e);
+    while let Some(s) = source {
+        msg = format!("{msg}: {s}");
+        source = s.source();
+    }
+    msg
+}